@@ -0,0 +1,222 @@
+use clap::ValueEnum;
+use coap_lite::{ContentFormat, RequestType};
+use futures::stream::{self, TryStreamExt};
+use log::info;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use crate::coap_helper::*;
+use crate::content::{self, PayloadEncoding};
+use crate::transport::Connection;
+use crate::{coap_delete, coap_get, coap_submit, request_url, Args, Preconditions};
+
+/// Requests made by `batch`, in the order given. A `{name}` anywhere in a request is replaced by
+/// the variable of that name, given with `--set` or else taken from the environment.
+///
+/// ```toml
+/// [[request]]
+/// method = "POST"
+/// path = "/3303"
+/// content_format = "application/json"
+/// payload = '{"5701": "{unit}"}'
+///
+/// [[request]]
+/// method = "PUT"
+/// path = "/3303/{instance}/5750"
+/// payload = "{name}"
+/// options = ["65000:str:{tenant}"]
+/// ```
+#[derive(Debug, Deserialize)]
+struct Batch {
+    #[serde(rename = "request", default)]
+    requests: Vec<RequestSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RequestSpec {
+    #[serde(default = "default_method")]
+    method: String,
+    /// Path, or URL on the same server, resolved against the URL
+    path: String,
+    #[serde(default)]
+    payload: String,
+    content_format: Option<String>,
+    /// Encoding to convert a JSON payload to, as with `--encode`
+    encode: Option<String>,
+    #[serde(default)]
+    accept: Vec<String>,
+    /// Options to add to the request, written as for `--option`
+    #[serde(default)]
+    options: Vec<String>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+/// A request from the batch file with its variables substituted, ready to send.
+struct BatchRequest {
+    method: RequestType,
+    url: String,
+    payload: Vec<u8>,
+    content_format: Option<ContentFormat>,
+    accept: Vec<String>,
+    options: Vec<CustomOption>,
+}
+
+/// Parses a variable given with `--set`, written as `NAME=VALUE`.
+pub fn parse_variable(s: &str) -> std::result::Result<(String, String), String> {
+    let (name, value) = s.split_once('=').ok_or_else(|| format!("expected NAME=VALUE: {}", s))?;
+    if name.is_empty() {
+        return Err(format!("variable name must not be empty: {}", s));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Replaces each `{name}` in the text with the variable's value. Braces around anything but a
+/// name, such as those of a JSON object, are left alone.
+fn substitute(text: &str, variables: &HashMap<String, String>) -> Result<String> {
+    let placeholder = Regex::new(r"\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    let mut missing = None;
+    let substituted = placeholder.replace_all(text, |captures: &Captures| {
+        let name = &captures[1];
+        match variables.get(name).cloned().or_else(|| std::env::var(name).ok()) {
+            Some(value) => value,
+            None => {
+                missing.get_or_insert_with(|| name.to_string());
+                String::new()
+            }
+        }
+    });
+    match missing {
+        Some(name) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("no value for variable {}; give one with --set {}=VALUE", name, name),
+        )),
+        None => Ok(substituted.into_owned()),
+    }
+}
+
+pub fn parse_method(method: &str) -> Result<RequestType> {
+    match method.to_uppercase().as_str() {
+        "GET" => Ok(RequestType::Get),
+        "POST" => Ok(RequestType::Post),
+        "PUT" => Ok(RequestType::Put),
+        "DELETE" => Ok(RequestType::Delete),
+        "FETCH" => Ok(RequestType::Fetch),
+        "PATCH" => Ok(RequestType::Patch),
+        "IPATCH" => Ok(RequestType::IPatch),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("unknown method: {}", method),
+        )),
+    }
+}
+
+impl RequestSpec {
+    fn prepare(&self, args: &Args, variables: &HashMap<String, String>) -> Result<BatchRequest> {
+        let substitute = |text: &str| substitute(text, variables);
+        let invalid = |e: String| Error::new(ErrorKind::InvalidInput, e);
+        let content_format = self
+            .content_format
+            .as_deref()
+            .map(|cf| parse_content_format(&substitute(cf)?))
+            .transpose()?;
+        let payload = substitute(&self.payload)?.into_bytes();
+        let (payload, content_format) = match &self.encode {
+            Some(encoding) => {
+                let encoding = PayloadEncoding::from_str(encoding, true).map_err(invalid)?;
+                let (payload, content_format) =
+                    content::encode_payload(&payload, encoding, content_format)?;
+                (payload, Some(content_format))
+            }
+            None => (payload, content_format),
+        };
+        if args.validate {
+            content::validate_payload(&payload, content_format)?;
+        }
+        Ok(BatchRequest {
+            method: parse_method(&substitute(&self.method)?)?,
+            url: request_url(args, &substitute(&self.path)?)?,
+            payload,
+            content_format,
+            accept: self.accept.iter().map(|a| substitute(a)).collect::<Result<_>>()?,
+            options: self
+                .options
+                .iter()
+                .map(|o| parse_custom_option(&substitute(o)?).map_err(invalid))
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+fn load_batch(
+    args: &Args,
+    path: &Path,
+    variables: &HashMap<String, String>,
+) -> Result<Vec<BatchRequest>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    let batch: Batch = toml::from_str(&contents)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+    batch
+        .requests
+        .iter()
+        .enumerate()
+        .map(|(i, spec)| {
+            spec.prepare(args, variables).map_err(|e| {
+                Error::new(e.kind(), format!("{}: request {}: {}", path.display(), i + 1, e))
+            })
+        })
+        .collect()
+}
+
+async fn run_request(conn: &Connection, args: &Args, request: BatchRequest) -> Result<()> {
+    let args = Args {
+        url: request.url,
+        options: [args.options.clone(), request.options].concat(),
+        ..args.clone()
+    };
+    match request.method {
+        RequestType::Get => coap_get(conn, &args, &request.accept, None, &[], None).await,
+        RequestType::Delete => {
+            coap_delete(conn, &args, &request.accept, Preconditions::default()).await
+        }
+        method => {
+            coap_submit(
+                conn,
+                &args,
+                method,
+                &request.accept,
+                request.content_format,
+                request.payload,
+                Preconditions::default(),
+            )
+            .await
+            .map(|_| ())
+        }
+    }
+}
+
+/// Makes the requests listed in a batch file, one after another or up to `concurrency` at once,
+/// stopping at the first that fails. Every request is checked before any is sent, so that a
+/// missing variable or a mistake in the file doesn't leave the job half done.
+pub async fn coap_batch(
+    conn: &Connection,
+    args: &Args,
+    file: &Path,
+    variables: &[(String, String)],
+    concurrency: usize,
+) -> Result<()> {
+    let variables: HashMap<String, String> = variables.iter().cloned().collect();
+    let requests = load_batch(args, file, &variables)?;
+    let count = requests.len();
+    stream::iter(requests.into_iter().map(Ok))
+        .try_for_each_concurrent(concurrency.max(1), |request| run_request(conn, args, request))
+        .await?;
+    info!("{} request(s) made", count);
+    Ok(())
+}
@@ -0,0 +1,572 @@
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::info;
+use serde::{Deserialize, Serialize};
+use hdrhistogram::Histogram;
+use std::borrow::Borrow;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::coap_helper::*;
+use crate::report::print_report;
+use crate::transport::Connection;
+use crate::{assign_token, create_connection_to, exchange, send_request, Args};
+
+/// The outcome of a single benchmark request.
+#[derive(Debug, Serialize)]
+pub struct Sample {
+    pub index: usize,
+    pub target: String,
+    /// Which of the target's pooled connections carried the request
+    pub connection: usize,
+    /// Milliseconds from the start of the run until the request was sent
+    pub start_ms: f64,
+    pub latency_ms: f64,
+    pub code: Option<String>,
+    pub error: Option<String>,
+    pub retransmissions: usize,
+    pub response_bytes: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Summary {
+    pub requests: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Requests answered with a 4.xx or 5.xx response, which count as succeeded
+    #[serde(default)]
+    pub error_responses: usize,
+    pub duration_ms: f64,
+    pub requests_per_second: f64,
+    pub retransmissions: usize,
+    pub min_ms: f64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct TargetSummary<'a> {
+    url: &'a str,
+    summary: Summary,
+}
+
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    summary: &'a Summary,
+    targets: &'a [TargetSummary<'a>],
+    samples: &'a [Sample],
+}
+
+// only the statistics are needed from a baseline, not its samples
+#[derive(Debug, Deserialize)]
+struct Baseline {
+    summary: Summary,
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+// latencies are recorded in microseconds, to three significant figures
+const HISTOGRAM_SIGFIG: u8 = 3;
+// an hour; saturating_record clamps to the histogram's bounds instead of growing it
+const HISTOGRAM_MAX_MICROS: u64 = 3_600_000_000;
+
+fn latency_histogram(samples: &[&Sample]) -> Histogram<u64> {
+    let mut histogram = Histogram::new_with_bounds(1, HISTOGRAM_MAX_MICROS, HISTOGRAM_SIGFIG).unwrap();
+    for sample in samples.iter().filter(|s| s.error.is_none()) {
+        histogram.saturating_record((sample.latency_ms * 1000.0).round() as u64);
+    }
+    histogram
+}
+
+fn histogram_millis(micros: u64) -> f64 {
+    micros as f64 / 1000.0
+}
+
+pub fn summarize<S: Borrow<Sample>>(samples: &[S], duration: Duration) -> Summary {
+    let samples: Vec<&Sample> = samples.iter().map(|s| s.borrow()).collect();
+    let histogram = latency_histogram(&samples);
+    let succeeded = histogram.len() as usize;
+    let quantile = |q| histogram_millis(histogram.value_at_quantile(q));
+    Summary {
+        requests: samples.len(),
+        succeeded,
+        failed: samples.len() - succeeded,
+        error_responses: samples
+            .iter()
+            .filter(|s| s.code.as_deref().is_some_and(|c| c.starts_with(['4', '5'])))
+            .count(),
+        duration_ms: millis(duration),
+        requests_per_second: samples.len() as f64 / duration.as_secs_f64().max(f64::EPSILON),
+        retransmissions: samples.iter().map(|s| s.retransmissions).sum(),
+        min_ms: histogram_millis(histogram.min()),
+        mean_ms: histogram.mean() / 1000.0,
+        max_ms: histogram_millis(histogram.max()),
+        p50_ms: quantile(0.5),
+        p90_ms: quantile(0.9),
+        p99_ms: quantile(0.99),
+    }
+}
+
+/// Writes the latency distribution in the percentile format used by the HdrHistogram tools, with
+/// values in milliseconds.
+fn write_hgrm(path: &Path, samples: &[Sample]) -> Result<()> {
+    let samples: Vec<&Sample> = samples.iter().collect();
+    let histogram = latency_histogram(&samples);
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(
+        out,
+        "{:>12} {:>14} {:>10} {:>14}\n",
+        "Value", "Percentile", "TotalCount", "1/(1-Percentile)"
+    )?;
+    let mut total = 0;
+    for value in histogram.iter_quantiles(5) {
+        total += value.count_since_last_iteration();
+        let quantile = value.quantile_iterated_to();
+        let millis = histogram_millis(value.value_iterated_to());
+        if quantile < 1.0 {
+            writeln!(
+                out,
+                "{:12.3} {:2.12} {:10} {:14.2}",
+                millis,
+                quantile,
+                total,
+                1.0 / (1.0 - quantile)
+            )?;
+        } else {
+            writeln!(out, "{:12.3} {:2.12} {:10}", millis, quantile, total)?;
+        }
+    }
+    let sub_buckets = 1u64 << (2.0 * 10f64.powi(i32::from(HISTOGRAM_SIGFIG))).log2().ceil() as u32;
+    writeln!(
+        out,
+        "#[Mean    = {:12.3}, StdDeviation   = {:12.3}]",
+        histogram.mean() / 1000.0,
+        histogram.stdev() / 1000.0
+    )?;
+    writeln!(
+        out,
+        "#[Max     = {:12.3}, Total count    = {:12}]",
+        histogram_millis(histogram.max()),
+        histogram.len()
+    )?;
+    writeln!(
+        out,
+        "#[Buckets = {:12}, SubBuckets     = {:12}]",
+        histogram.buckets(),
+        sub_buckets
+    )?;
+    out.flush()
+}
+
+fn print_summary(summary: &Summary) {
+    println!(
+        "{} request(s) in {:.1}ms: {} succeeded ({} with an error response), {} failed, {} retransmission(s)",
+        summary.requests,
+        summary.duration_ms,
+        summary.succeeded,
+        summary.error_responses,
+        summary.failed,
+        summary.retransmissions
+    );
+    println!("throughput: {:.1} requests/s", summary.requests_per_second);
+    println!(
+        "latency: min {:.2}ms, mean {:.2}ms, max {:.2}ms",
+        summary.min_ms, summary.mean_ms, summary.max_ms
+    );
+    println!(
+        "percentiles: p50 {:.2}ms, p90 {:.2}ms, p99 {:.2}ms",
+        summary.p50_ms, summary.p90_ms, summary.p99_ms
+    );
+}
+
+fn write_json(
+    path: &Path,
+    summary: &Summary,
+    targets: &[TargetSummary],
+    samples: &[Sample],
+) -> Result<()> {
+    let report = Report {
+        summary,
+        targets,
+        samples,
+    };
+    let file = std::fs::File::create(path)?;
+    serde_json::to_writer_pretty(file, &report).map_err(Error::other)
+}
+
+/// Writes one row per sample to `path`, and the aggregate statistics to a `-summary` file next to
+/// it, so that both load directly as data frames.
+fn write_csv(path: &Path, summary: &Summary, samples: &[Sample]) -> Result<()> {
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(
+        out,
+        "index,target,connection,start_ms,latency_ms,code,error,retransmissions,response_bytes"
+    )?;
+    for s in samples {
+        writeln!(
+            out,
+            "{},{},{},{:.3},{:.3},{},{},{},{}",
+            s.index,
+            csv_field(&s.target),
+            s.connection,
+            s.start_ms,
+            s.latency_ms,
+            s.code.as_deref().unwrap_or(""),
+            csv_field(s.error.as_deref().unwrap_or("")),
+            s.retransmissions,
+            s.response_bytes
+        )?;
+    }
+    out.flush()?;
+
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let summary_path = path.with_file_name(format!("{}-summary.csv", stem));
+    let mut out = std::fs::File::create(summary_path)?;
+    writeln!(
+        out,
+        "requests,succeeded,failed,error_responses,duration_ms,requests_per_second,retransmissions,min_ms,mean_ms,max_ms,p50_ms,p90_ms,p99_ms"
+    )?;
+    writeln!(
+        out,
+        "{},{},{},{},{:.3},{:.3},{},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3}",
+        summary.requests,
+        summary.succeeded,
+        summary.failed,
+        summary.error_responses,
+        summary.duration_ms,
+        summary.requests_per_second,
+        summary.retransmissions,
+        summary.min_ms,
+        summary.mean_ms,
+        summary.max_ms,
+        summary.p50_ms,
+        summary.p90_ms,
+        summary.p99_ms
+    )
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn load_baseline(path: &Path) -> Result<Summary> {
+    let file = std::fs::File::open(path)?;
+    let baseline: Baseline = serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    Ok(baseline.summary)
+}
+
+/// Parses a threshold such as `10%` (or just `10`) into a fraction.
+pub fn parse_percentage(s: &str) -> std::result::Result<f64, String> {
+    let value: f64 = s
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| format!("invalid percentage: {}", s))?;
+    if value < 0.0 {
+        return Err(format!("invalid percentage: {}", s));
+    }
+    Ok(value / 100.0)
+}
+
+/// Parses a request rate, in requests per second.
+pub fn parse_rate(s: &str) -> std::result::Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("invalid rate: {}", s)),
+    }
+}
+
+/// Prints how the run compares with the baseline and returns the metrics which got worse by more
+/// than `threshold`.
+fn compare_with_baseline(
+    baseline: &Summary,
+    current: &Summary,
+    threshold: Option<f64>,
+) -> Vec<&'static str> {
+    // (name, baseline, current, whether higher is better)
+    let metrics = [
+        ("mean latency", baseline.mean_ms, current.mean_ms, false),
+        ("p50 latency", baseline.p50_ms, current.p50_ms, false),
+        ("p90 latency", baseline.p90_ms, current.p90_ms, false),
+        ("p99 latency", baseline.p99_ms, current.p99_ms, false),
+        (
+            "throughput",
+            baseline.requests_per_second,
+            current.requests_per_second,
+            true,
+        ),
+    ];
+    let mut regressions = vec![];
+    println!("{:<14}{:>12}{:>12}{:>10}", "", "baseline", "current", "change");
+    for (name, before, after, higher_is_better) in metrics {
+        let change = if before > 0.0 { (after - before) / before } else { 0.0 };
+        let worse_by = if higher_is_better { -change } else { change };
+        let regressed = threshold.is_some_and(|t| worse_by > t);
+        println!(
+            "{:<14}{:>12.2}{:>12.2}{:>+9.1}%{}",
+            name,
+            before,
+            after,
+            change * 100.0,
+            if regressed { "  REGRESSION" } else { "" }
+        );
+        if regressed {
+            regressions.push(name);
+        }
+    }
+    regressions
+}
+
+/// Parses a target weight, which must be positive.
+pub fn parse_weight(s: &str) -> std::result::Result<u32, String> {
+    match s.parse() {
+        Ok(weight) if weight > 0 => Ok(weight),
+        _ => Err(format!("invalid weight: {}", s)),
+    }
+}
+
+/// Picks targets in proportion to their weights, spreading each target's turns evenly over the
+/// run rather than sending them in bursts (smooth weighted round-robin).
+struct Scheduler {
+    weights: Vec<i64>,
+    current: Vec<i64>,
+}
+
+impl Scheduler {
+    fn new(weights: Vec<i64>) -> Self {
+        let current = vec![0; weights.len()];
+        Scheduler { weights, current }
+    }
+
+    fn next(&mut self) -> usize {
+        let total: i64 = self.weights.iter().sum();
+        for (current, weight) in self.current.iter_mut().zip(&self.weights) {
+            *current += weight;
+        }
+        let (chosen, _) = self
+            .current
+            .iter()
+            .enumerate()
+            .max_by_key(|(i, c)| (**c, std::cmp::Reverse(*i)))
+            .unwrap();
+        self.current[chosen] -= total;
+        chosen
+    }
+}
+
+pub struct BenchOptions<'a> {
+    pub method: RequestMethod,
+    pub data: Option<&'a str>,
+    pub requests: usize,
+    pub json: Option<&'a Path>,
+    pub csv: Option<&'a Path>,
+    pub hgrm: Option<&'a Path>,
+    pub baseline: Option<&'a Path>,
+    pub fail_if_slower: Option<f64>,
+    /// Targets to spread the requests over besides the URL
+    pub targets: &'a [String],
+    /// Relative share of the requests for the URL followed by each target
+    pub weights: &'a [u32],
+    /// Number of persistent connections per target to rotate the requests over
+    pub connections: usize,
+    /// Number of requests that may be outstanding on each connection at once; by default one,
+    /// or as many as `concurrency` needs
+    pub window: Option<usize>,
+    /// Number of requests that may be outstanding at once over all the connections
+    pub concurrency: Option<usize>,
+    /// Requests to send per second, at evenly spaced times
+    pub rate: Option<f64>,
+}
+
+pub async fn coap_bench(conn: &Connection, args: &Args, options: &BenchOptions<'_>) -> Result<()> {
+    info!("BENCH {} x{}", args.url, options.requests);
+    // read the baseline up front so that a bad path doesn't waste a run
+    let baseline = options.baseline.map(load_baseline).transpose()?;
+    let payload = options.data.map(|d| d.as_bytes().to_vec());
+    if args.dry_run {
+        let request =
+            build_coap_request_for_url(&args.url, options.method.into(), payload, None, None)?;
+        send_request(conn, args, request).await?;
+        return Ok(());
+    }
+    let urls: Vec<&str> = std::iter::once(args.url.as_str())
+        .chain(options.targets.iter().map(String::as_str))
+        .collect();
+    if !options.weights.is_empty() && options.weights.len() != urls.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("expected {} weights, one for each target", urls.len()),
+        ));
+    }
+    // a pool of persistent connections for each target, opened before the clock starts
+    let mut pools: Vec<Vec<Connection>> = vec![];
+    for (i, url) in urls.iter().enumerate() {
+        // the main URL's pool includes the connection it was given
+        let count = options.connections.max(1) - usize::from(i == 0);
+        let mut pool = vec![];
+        for _ in 0..count {
+            pool.push(create_connection_to(args, url).await?);
+        }
+        pools.push(pool);
+    }
+    let pools: Vec<Vec<&Connection>> = pools
+        .iter()
+        .enumerate()
+        .map(|(i, pool)| {
+            let first = (i == 0).then_some(conn);
+            first.into_iter().chain(pool).collect()
+        })
+        .collect();
+    let mut next_connection = vec![0; urls.len()];
+    let weights = match options.weights {
+        [] => vec![1; urls.len()],
+        weights => weights.iter().map(|w| i64::from(*w)).collect(),
+    };
+    let mut scheduler = Scheduler::new(weights);
+
+    let connection_count: usize = pools.iter().map(Vec::len).sum();
+    let window = options
+        .window
+        .unwrap_or_else(|| options.concurrency.map_or(1, |c| c.div_ceil(connection_count)))
+        .max(1);
+    let concurrency = options.concurrency.unwrap_or(usize::MAX).max(1);
+    let mut in_flight: Vec<Vec<usize>> = pools.iter().map(|pool| vec![0; pool.len()]).collect();
+    let mut pending = FuturesUnordered::new();
+    // a target chosen by the scheduler whose connections were all busy
+    let mut waiting_target = None;
+
+    let mut samples = Vec::with_capacity(options.requests);
+    let start = Instant::now();
+    // when the request with the given index is due to be sent, at the requested rate
+    let due = |index: usize| options.rate.map(|rate| start + Duration::from_secs_f64(index as f64 / rate));
+    let mut index = 0;
+    while index < options.requests || !pending.is_empty() {
+        let not_yet_due = due(index).filter(|at| *at > Instant::now());
+        if index < options.requests && pending.len() < concurrency && not_yet_due.is_none() {
+            let target = *waiting_target.get_or_insert_with(|| scheduler.next());
+            let len = pools[target].len();
+            let free = (0..len)
+                .map(|i| (next_connection[target] + i) % len)
+                .find(|c| in_flight[target][*c] < window);
+            if let Some(connection) = free {
+                waiting_target = None;
+                next_connection[target] = (connection + 1) % len;
+                in_flight[target][connection] += 1;
+                let conn = pools[target][connection];
+                let mut request = build_coap_request_for_url(
+                    urls[target],
+                    options.method.into(),
+                    payload.clone(),
+                    None,
+                    None,
+                )?;
+                // responses are routed by token, so requests in flight together need distinct
+                // ones unless the user chose otherwise
+                if window > 1 && !args.token_chosen() {
+                    request.message.set_token(rand::random::<[u8; 8]>().to_vec());
+                }
+                // given now, so that the request's own datagrams can be told apart by it
+                assign_token(conn, args, &mut request);
+                pending.push(async move {
+                    let log = conn.log.exchange(request.message.get_token());
+                    let sent = Instant::now();
+                    let result = exchange(conn, args, request).await;
+                    let retransmissions = log.retransmissions();
+                    (index, target, connection, sent, sent.elapsed(), retransmissions, result)
+                });
+                index += 1;
+                continue;
+            }
+        }
+        let completed = match not_yet_due {
+            Some(at) if index < options.requests => tokio::select! {
+                Some(completed) = pending.next(), if !pending.is_empty() => Some(completed),
+                _ = tokio::time::sleep_until(at.into()) => continue,
+            },
+            _ => pending.next().await,
+        };
+        let Some((index, target, connection, sent, latency, retransmissions, result)) = completed else {
+            break;
+        };
+        in_flight[target][connection] -= 1;
+        let (code, error, response_bytes) = match result {
+            Ok(response) => (
+                Some(response.message.header.code.to_string()),
+                None,
+                response.message.payload.len(),
+            ),
+            Err(e) => (None, Some(e.to_string()), 0),
+        };
+        samples.push(Sample {
+            index,
+            target: urls[target].to_string(),
+            connection,
+            start_ms: millis(sent.duration_since(start)),
+            latency_ms: millis(latency),
+            code,
+            error,
+            retransmissions,
+            response_bytes,
+        });
+    }
+    samples.sort_by_key(|s| s.index);
+    let duration = start.elapsed();
+    let summary = summarize(&samples, duration);
+    let targets: Vec<TargetSummary> = urls
+        .iter()
+        .map(|url| {
+            let samples: Vec<&Sample> = samples.iter().filter(|s| s.target == *url).collect();
+            TargetSummary {
+                url,
+                summary: summarize(&samples, duration),
+            }
+        })
+        .collect();
+
+    if let Some(template) = &args.template {
+        let report = Report {
+            summary: &summary,
+            targets: &targets,
+            samples: &samples,
+        };
+        print_report(template, &report)?;
+    } else {
+        if targets.len() > 1 {
+            for target in &targets {
+                println!("{}", target.url);
+                print_summary(&target.summary);
+                println!();
+            }
+            println!("all targets");
+        }
+        print_summary(&summary);
+    }
+    if let Some(path) = options.json {
+        write_json(path, &summary, &targets, &samples)?;
+    }
+    if let Some(path) = options.csv {
+        write_csv(path, &summary, &samples)?;
+    }
+    if let Some(path) = options.hgrm {
+        write_hgrm(path, &samples)?;
+    }
+    if let Some(baseline) = baseline {
+        let regressions = compare_with_baseline(&baseline, &summary, options.fail_if_slower);
+        if !regressions.is_empty() {
+            return Err(Error::other(format!(
+                "performance regression in {}",
+                regressions.join(", ")
+            )));
+        }
+    }
+    Ok(())
+}
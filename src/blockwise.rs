@@ -0,0 +1,423 @@
+use coap_lite::{CoapOption, CoapRequest, CoapResponse, MessageClass, ResponseType};
+use log::debug;
+use std::io::{Error, ErrorKind, IsTerminal, Result, Write};
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use crate::coap_helper::encode_uint;
+use crate::error::CoapCliError;
+use crate::message_dump::decode_uint;
+use crate::transport::Connection;
+
+// block numbers are 20-bit
+const MAX_BLOCK_NUM: usize = (1 << 20) - 1;
+
+/// The value of a Block1 or Block2 option (RFC 7959): the block number, whether more blocks
+/// follow, and the block size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Block {
+    pub num: usize,
+    pub more: bool,
+    pub size: usize,
+}
+
+impl Block {
+    pub fn decode(value: &[u8]) -> Result<Block> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "invalid block option");
+        if value.len() > 3 {
+            return Err(invalid());
+        }
+        let value = decode_uint(value) as usize;
+        // size exponent 7 is reserved for BERT, which only exists over reliable transports
+        if value & 7 == 7 {
+            return Err(invalid());
+        }
+        Ok(Block {
+            num: value >> 4,
+            more: value & 8 != 0,
+            size: 16 << (value & 7),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let szx = self.size.trailing_zeros() - 4;
+        encode_uint((self.num as u32) << 4 | u32::from(self.more) << 3 | szx)
+    }
+}
+
+/// Parses a block size, which must be a power of two from 16 to 1024 bytes.
+pub fn parse_block_size(s: &str) -> std::result::Result<usize, String> {
+    let size: usize = s.parse().map_err(|_| format!("invalid block size: {}", s))?;
+    if !(16..=1024).contains(&size) || !size.is_power_of_two() {
+        return Err(format!("block size must be a power of two from 16 to 1024: {}", s));
+    }
+    Ok(size)
+}
+
+/// Reports the progress of a block-wise transfer on stderr, on a single line which is updated in
+/// place when stderr is a terminal.
+struct Progress {
+    verb: &'static str,
+    total: Option<usize>,
+    bytes: usize,
+    blocks: usize,
+    interactive: bool,
+}
+
+impl Progress {
+    fn new(verb: &'static str, total: Option<usize>) -> Progress {
+        Progress {
+            verb,
+            total,
+            bytes: 0,
+            blocks: 0,
+            interactive: std::io::stderr().is_terminal(),
+        }
+    }
+
+    fn update(&mut self, bytes: usize) {
+        self.bytes = bytes;
+        self.blocks += 1;
+        if self.interactive {
+            eprint!("\r{}", self.line());
+            let _ = std::io::stderr().flush();
+        }
+    }
+
+    fn finish(&self) {
+        match (self.blocks, self.interactive) {
+            (0, _) => {}
+            (_, true) => eprintln!(),
+            (_, false) => eprintln!("{}", self.line()),
+        }
+    }
+
+    fn line(&self) -> String {
+        match self.total {
+            Some(total) if total > 0 => format!(
+                "{} {}/{} bytes ({}%) in {} block(s)",
+                self.verb,
+                self.bytes,
+                total,
+                self.bytes * 100 / total,
+                self.blocks
+            ),
+            _ => format!("{} {} bytes in {} block(s)", self.verb, self.bytes, self.blocks),
+        }
+    }
+}
+
+async fn send_message(
+    conn: &Connection,
+    request: &mut CoapRequest<SocketAddr>,
+) -> Result<CoapResponse> {
+    request.message.header.message_id = conn.next_message_id();
+    conn.send_single_request(request).await
+}
+
+fn block_option(response: &CoapResponse, option: CoapOption) -> Result<Option<Block>> {
+    response
+        .message
+        .get_first_option(option)
+        .map(|value| Block::decode(value))
+        .transpose()
+}
+
+fn set_block(request: &mut CoapRequest<SocketAddr>, option: CoapOption, block: Block) {
+    request.message.clear_option(option);
+    request.message.add_option(option, block.encode());
+}
+
+/// Sends a request and returns the complete response. A payload larger than `block1_size` is sent
+/// in Block1 blocks, and a response the server splits into Block2 blocks is fetched block by
+/// block, asking for blocks of `block2_size` if given, and reassembled.
+pub async fn send(
+    conn: &Connection,
+    mut request: CoapRequest<SocketAddr>,
+    block1_size: Option<usize>,
+    block2_size: Option<usize>,
+) -> Result<CoapResponse> {
+    let response = match block1_size {
+        Some(size) if request.message.payload.len() > size => {
+            let payload = std::mem::take(&mut request.message.payload);
+            let mut source = |offset: usize, len: usize| Ok(payload[offset..offset + len].to_vec());
+            upload(conn, &mut request, payload.len(), size, block2_size, &mut source).await?
+        }
+        _ => {
+            if let Some(size) = block2_size {
+                set_block(&mut request, CoapOption::Block2, Block { num: 0, more: false, size });
+            }
+            if request.message.header.message_id == 0 {
+                request.message.header.message_id = conn.next_message_id();
+            }
+            conn.send_single_request(&request).await?
+        }
+    };
+    download(conn, request, response).await
+}
+
+/// Sends a request with a payload of `len` bytes that is read a block at a time from `source`,
+/// given the offset and length of each, rather than held in memory, and returns the complete
+/// response as `send` does. A payload larger than `block1_size` is sent in Block1 blocks.
+pub async fn send_streamed(
+    conn: &Connection,
+    mut request: CoapRequest<SocketAddr>,
+    len: usize,
+    block1_size: usize,
+    block2_size: Option<usize>,
+    source: &mut (dyn FnMut(usize, usize) -> Result<Vec<u8>> + Send),
+) -> Result<CoapResponse> {
+    if len <= block1_size {
+        request.message.payload = source(0, len)?;
+        return send(conn, request, None, block2_size).await;
+    }
+    let response = upload(conn, &mut request, len, block1_size, block2_size, source).await?;
+    download(conn, request, response).await
+}
+
+async fn upload(
+    conn: &Connection,
+    request: &mut CoapRequest<SocketAddr>,
+    len: usize,
+    mut size: usize,
+    block2_size: Option<usize>,
+    source: &mut (dyn FnMut(usize, usize) -> Result<Vec<u8>> + Send),
+) -> Result<CoapResponse> {
+    let mut progress = Progress::new("sent", Some(len));
+    let started = Instant::now();
+    request.message.add_option(CoapOption::Size1, encode_uint(len as u32));
+    let mut offset = 0;
+    loop {
+        let end = len.min(offset + size);
+        let block = Block {
+            num: offset / size,
+            more: end < len,
+            size,
+        };
+        if block.num > MAX_BLOCK_NUM {
+            progress.finish();
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("payload is too large to send in {}-byte blocks", size),
+            ));
+        }
+        set_block(request, CoapOption::Block1, block);
+        if let (false, Some(size)) = (block.more, block2_size) {
+            set_block(request, CoapOption::Block2, Block { num: 0, more: false, size });
+        }
+        request.message.payload = match source(offset, end - offset) {
+            Ok(payload) => payload,
+            Err(e) => {
+                progress.finish();
+                return Err(e);
+            }
+        };
+        debug!("sending block {} ({} bytes, more: {})", block.num, end - offset, block.more);
+        // an interrupted transfer still reports how far it got
+        let response = match send_message(conn, request).await {
+            Ok(response) => response,
+            Err(e) => {
+                progress.finish();
+                return Err(CoapCliError::at_block(e, block.num, started));
+            }
+        };
+        progress.update(end);
+        if !block.more || *response.get_status() != ResponseType::Continue {
+            // either the last block, or the server has given up on the transfer, e.g. with 4.13
+            progress.finish();
+            return Ok(response);
+        }
+        let acknowledged = block_option(&response, CoapOption::Block1)?.ok_or(Error::new(
+            ErrorKind::InvalidData,
+            "server continued the transfer without acknowledging the block",
+        ))?;
+        // the server may ask for smaller blocks in its acknowledgement
+        size = size.min(acknowledged.size);
+        offset = end;
+        request.message.clear_option(CoapOption::Size1);
+    }
+}
+
+async fn download(
+    conn: &Connection,
+    request: CoapRequest<SocketAddr>,
+    response: CoapResponse,
+) -> Result<CoapResponse> {
+    if !block_option(&response, CoapOption::Block2)?.is_some_and(|block| block.more) {
+        return Ok(response);
+    }
+    let mut payload = vec![];
+    let mut response = receive_blocks(conn, request, response, &mut |_, block| {
+        payload.extend_from_slice(block);
+        Ok(())
+    })
+    .await?;
+    if !matches!(response.message.header.code, MessageClass::Response(code) if code.is_error()) {
+        response.message.payload = payload;
+    }
+    Ok(response)
+}
+
+/// Asks for a single block of a response, e.g. to resume a download part of the way through.
+pub async fn request_block(
+    conn: &Connection,
+    request: &mut CoapRequest<SocketAddr>,
+    block: Block,
+) -> Result<CoapResponse> {
+    set_block(request, CoapOption::Block2, block);
+    send_message(conn, request).await
+}
+
+/// Fetches the rest of a response that the server split into Block2 blocks, from the block in
+/// `response` on, passing the payload of each block to `sink` as it arrives, along with where in
+/// the representation it goes. A response that isn't split is passed whole. Returns the last
+/// response, or an error response if the server gives up on the transfer.
+pub async fn receive_blocks(
+    conn: &Connection,
+    mut request: CoapRequest<SocketAddr>,
+    mut response: CoapResponse,
+    sink: &mut (dyn FnMut(usize, &[u8]) -> Result<()> + Send),
+) -> Result<CoapResponse> {
+    let Some(mut block) = block_option(&response, CoapOption::Block2)? else {
+        sink(0, &response.message.payload)?;
+        return Ok(response);
+    };
+    debug!(
+        "received block {} ({} bytes, more: {})",
+        block.num,
+        response.message.payload.len(),
+        block.more
+    );
+    sink(block.num * block.size, &response.message.payload)?;
+    if !block.more {
+        return Ok(response);
+    }
+    let total = response
+        .message
+        .get_first_option(CoapOption::Size2)
+        .map(|value| decode_uint(value) as usize);
+    let etag = response.message.get_first_option(CoapOption::ETag).cloned();
+    let size2 = response.message.get_first_option(CoapOption::Size2).cloned();
+    let mut end = block.num * block.size + response.message.payload.len();
+    let mut progress = Progress::new("received", total);
+    let started = Instant::now();
+    progress.update(end);
+
+    // the follow-up requests only ask for the remaining blocks of the response
+    request.message.payload.clear();
+    request.message.clear_option(CoapOption::Block1);
+    request.message.clear_option(CoapOption::Size1);
+    while block.more {
+        if end != (block.num + 1) * block.size {
+            progress.finish();
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("server sent a short block {}", block.num),
+            ));
+        }
+        let next = Block {
+            num: block.num + 1,
+            more: false,
+            size: block.size,
+        };
+        set_block(&mut request, CoapOption::Block2, next);
+        response = match send_message(conn, &mut request).await {
+            Ok(response) => response,
+            Err(e) => {
+                progress.finish();
+                return Err(CoapCliError::at_block(e, next.num, started));
+            }
+        };
+        if matches!(response.message.header.code, MessageClass::Response(code) if code.is_error()) {
+            // e.g. 4.08 Request Entity Incomplete if the server lost track of the transfer
+            progress.finish();
+            return Ok(response);
+        }
+        let Some(received) = block_option(&response, CoapOption::Block2)? else {
+            progress.finish();
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("server stopped sending blocks after block {}", block.num),
+            ));
+        };
+        if received.num * received.size != next.num * next.size {
+            progress.finish();
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("asked for block {}, got block {}", next.num, received.num),
+            ));
+        }
+        if response.message.get_first_option(CoapOption::ETag) != etag.as_ref() {
+            progress.finish();
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "resource changed during the block-wise transfer",
+            ));
+        }
+        debug!(
+            "received block {} ({} bytes, more: {})",
+            received.num,
+            response.message.payload.len(),
+            received.more
+        );
+        if let Err(e) = sink(end, &response.message.payload) {
+            progress.finish();
+            return Err(e);
+        }
+        end += response.message.payload.len();
+        block = received;
+        progress.update(end);
+    }
+    progress.finish();
+    // the server need only give the size with the first block, but it is of the whole
+    if let (Some(size2), None) = (size2, response.message.get_first_option(CoapOption::Size2)) {
+        response.message.add_option(CoapOption::Size2, size2);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_decodes_blocks() {
+        for (value, num, more, size) in [
+            (vec![], 0, false, 16),
+            (vec![0x0e], 0, true, 1024),
+            (vec![0x1a], 1, true, 64),
+            (vec![0x12, 0x34], 0x123, false, 256),
+            (vec![0xff, 0xff, 0xf6], MAX_BLOCK_NUM, false, 1024),
+        ] {
+            let block = Block { num, more, size };
+            assert_eq!(Block::decode(&value).unwrap(), block);
+            assert_eq!(block.encode(), value);
+        }
+        // a leading zero byte makes no difference
+        assert_eq!(Block::decode(&[0x00, 0x1a]).unwrap(), Block::decode(&[0x1a]).unwrap());
+    }
+
+    #[test]
+    fn rejects_invalid_blocks() {
+        // too long, and BERT's size exponent
+        for value in [&[0, 0, 0, 0x1a][..], &[0x07], &[0x12, 0x3f]] {
+            assert_eq!(Block::decode(value).unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn parses_block_sizes() {
+        for size in [16, 32, 64, 128, 256, 512, 1024] {
+            assert_eq!(parse_block_size(&size.to_string()), Ok(size));
+        }
+        for s in ["0", "8", "100", "2048"] {
+            assert_eq!(
+                parse_block_size(s).unwrap_err(),
+                format!("block size must be a power of two from 16 to 1024: {}", s)
+            );
+        }
+        for s in ["", "-16", "1k", "64 "] {
+            assert_eq!(parse_block_size(s).unwrap_err(), format!("invalid block size: {}", s));
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use coap_lite::{CoapOption, Packet};
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::coap_helper::fnv1a;
+use crate::observe::max_age;
+
+/// A response held in the cache, and until when it is fresh.
+pub struct CachedResponse {
+    pub message: Packet,
+    expires: u64,
+}
+
+impl CachedResponse {
+    /// How much longer the response is fresh for, if it still is.
+    pub fn fresh_for(&self) -> Option<Duration> {
+        let remaining = self.expires.checked_sub(now())?;
+        (remaining > 0).then(|| Duration::from_secs(remaining))
+    }
+
+    pub fn etag(&self) -> Option<&Vec<u8>> {
+        self.message.get_first_option(CoapOption::ETag)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Keeps responses in a directory, one file per request, for as long as their Max-Age says they
+/// are fresh (RFC 7252 section 5.6.1), and with their ETag after that, so that the server can
+/// confirm that a stale one is still current (section 5.6.2).
+pub struct ResponseCache {
+    dir: PathBuf,
+}
+
+impl ResponseCache {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(ResponseCache { dir })
+    }
+
+    /// The file for the response to a request for the URL accepting the given content formats,
+    /// as a server may send a different representation for each.
+    fn path_for(&self, url: &str, accept: &[usize]) -> PathBuf {
+        let key: Vec<String> = std::iter::once(url.to_string())
+            .chain(accept.iter().map(|cf| cf.to_string()))
+            .collect();
+        self.dir.join(format!("{:016x}.response", fnv1a(key.join("\n").as_bytes())))
+    }
+
+    /// The response kept for a request, fresh or not, if any. One that can't be read is treated
+    /// as not kept.
+    pub fn load(&self, url: &str, accept: &[usize]) -> Result<Option<CachedResponse>> {
+        let bytes = match std::fs::read(self.path_for(url, accept)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let Some((expires, message)) = bytes.split_first_chunk::<8>() else {
+            return Ok(None);
+        };
+        Ok(Packet::from_bytes(message).ok().map(|message| CachedResponse {
+            message,
+            expires: u64::from_be_bytes(*expires),
+        }))
+    }
+
+    /// Keeps a response for as long as its Max-Age says. A response that's already stale is
+    /// kept only if it has an ETag to validate it with.
+    pub fn store(&self, url: &str, accept: &[usize], message: &Packet) -> Result<()> {
+        let path = self.path_for(url, accept);
+        let fresh_for = max_age(message).as_secs();
+        if fresh_for == 0 && message.get_first_option(CoapOption::ETag).is_none() {
+            return match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let mut bytes = (now() + fresh_for).to_be_bytes().to_vec();
+        let message = message
+            .to_bytes_unlimited()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        bytes.extend(message);
+        std::fs::write(path, bytes)
+    }
+
+    /// Freshens a kept response that the server has said is still current with a 2.03 Valid,
+    /// taking the Max-Age, and any other options, that the 2.03 gives (RFC 7252 section
+    /// 5.9.1.3), and returns it.
+    pub fn validate(
+        &self,
+        url: &str,
+        accept: &[usize],
+        mut cached: CachedResponse,
+        valid: &Packet,
+    ) -> Result<Packet> {
+        for (&number, values) in valid.options() {
+            let option = CoapOption::from(number);
+            cached.message.clear_option(option);
+            for value in values {
+                cached.message.add_option(option, value.clone());
+            }
+        }
+        if valid.get_first_option(CoapOption::MaxAge).is_none() {
+            cached.message.clear_option(CoapOption::MaxAge);
+        }
+        self.store(url, accept, &cached.message)?;
+        Ok(cached.message)
+    }
+}
@@ -0,0 +1,66 @@
+use log::info;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// The exit status after being interrupted, as a shell gives a process killed by SIGINT.
+pub const INTERRUPTED_STATUS: i32 = 130;
+
+/// Whether the command has been interrupted with Ctrl-C, shared by everything waiting on the
+/// network so that it can stop waiting and clean up rather than be killed part of the way through
+/// an exchange.
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl Cancellation {
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Completes once cancelled, at once if already.
+    pub async fn cancelled(&self) {
+        let notified = self.inner.notify.notified();
+        tokio::pin!(notified);
+        // registered before checking, so that a cancellation in between isn't missed
+        notified.as_mut().enable();
+        if self.is_cancelled() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// The error an exchange fails with when it is given up on because of Ctrl-C.
+pub fn interrupted() -> Error {
+    Error::new(ErrorKind::Interrupted, "interrupted")
+}
+
+/// Listens for Ctrl-C in the background. The first cancels `cancellation`, so that the command
+/// stops and cleans up, e.g. deregistering an observation; the second exits at once, in case
+/// cleaning up takes too long.
+pub fn listen(cancellation: Cancellation) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        info!("interrupted; press Ctrl-C again to quit at once");
+        cancellation.cancel();
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(INTERRUPTED_STATUS);
+        }
+    });
+}
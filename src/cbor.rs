@@ -0,0 +1,729 @@
+use base64::Engine;
+use serde_json::{Map, Number, Value};
+use std::fmt::Write;
+use std::io::{Error, ErrorKind, Result};
+
+use crate::message_dump::hex;
+
+/// Reads CBOR (RFC 8949) data items and renders them in diagnostic notation.
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+/// How deeply arrays, maps and tags may nest, beyond which data is refused rather than
+/// recursed into without bound.
+const MAX_DEPTH: usize = 256;
+
+fn truncated() -> Error {
+    Error::new(ErrorKind::InvalidData, "truncated CBOR data")
+}
+
+fn check_depth(depth: usize) -> Result<()> {
+    if depth > MAX_DEPTH {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("CBOR nested more than {} levels deep", MAX_DEPTH),
+        ));
+    }
+    Ok(())
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.bytes.len()).ok_or_else(truncated)?;
+        let taken = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads the argument following an initial byte, or `None` for an indefinite length.
+    fn argument(&mut self, info: u8) -> Result<Option<u64>> {
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |acc, b| (acc << 8) | u64::from(*b));
+        Ok(Some(match info {
+            0..=23 => u64::from(info),
+            24 => be(self.take(1)?),
+            25 => be(self.take(2)?),
+            26 => be(self.take(4)?),
+            27 => be(self.take(8)?),
+            31 => return Ok(None),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("reserved CBOR additional information: {}", info),
+                ));
+            }
+        }))
+    }
+
+    fn at_break(&mut self) -> Result<bool> {
+        if *self.bytes.get(self.pos).ok_or_else(truncated)? == 0xff {
+            self.pos += 1;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn length(&mut self, info: u8) -> Result<usize> {
+        let length = self.argument(info)?.ok_or_else(|| {
+            Error::new(ErrorKind::InvalidData, "indefinite length chunk in CBOR string")
+        })?;
+        usize::try_from(length).map_err(|_| truncated())
+    }
+
+    fn item(&mut self, out: &mut String, depth: usize) -> Result<()> {
+        check_depth(depth)?;
+        let initial = self.byte()?;
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        match major {
+            0 | 1 => {
+                let value = self.argument(info)?.ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, "indefinite length CBOR integer")
+                })?;
+                let _ = match major {
+                    0 => write!(out, "{}", value),
+                    _ => write!(out, "{}", -1 - i128::from(value)),
+                };
+            }
+            2 | 3 => match self.argument(info)? {
+                Some(length) => {
+                    let length = usize::try_from(length).map_err(|_| truncated())?;
+                    let bytes = self.take(length)?;
+                    string(out, major, bytes);
+                }
+                None => {
+                    out.push_str("(_ ");
+                    let mut first = true;
+                    while !self.at_break()? {
+                        let chunk = self.byte()?;
+                        if chunk >> 5 != major {
+                            return Err(Error::new(
+                                ErrorKind::InvalidData,
+                                "CBOR string chunk of the wrong type",
+                            ));
+                        }
+                        let length = self.length(chunk & 0x1f)?;
+                        if !first {
+                            out.push_str(", ");
+                        }
+                        first = false;
+                        let bytes = self.take(length)?;
+                        string(out, major, bytes);
+                    }
+                    out.push(')');
+                }
+            },
+            4 | 5 => {
+                let (open, close) = if major == 4 { ('[', ']') } else { ('{', '}') };
+                out.push(open);
+                let count = self.argument(info)?;
+                if count.is_none() {
+                    out.push_str("_ ");
+                }
+                let mut i = 0;
+                loop {
+                    match count {
+                        Some(count) if i == count => break,
+                        None if self.at_break()? => break,
+                        _ => {}
+                    }
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    self.item(out, depth + 1)?;
+                    if major == 5 {
+                        out.push_str(": ");
+                        self.item(out, depth + 1)?;
+                    }
+                    i += 1;
+                }
+                out.push(close);
+            }
+            6 => {
+                let tag = self.argument(info)?.ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, "indefinite length CBOR tag")
+                })?;
+                let _ = write!(out, "{}(", tag);
+                self.item(out, depth + 1)?;
+                out.push(')');
+            }
+            _ => match info {
+                20 => out.push_str("false"),
+                21 => out.push_str("true"),
+                22 => out.push_str("null"),
+                23 => out.push_str("undefined"),
+                24 => {
+                    let _ = write!(out, "simple({})", self.byte()?);
+                }
+                25 => {
+                    let bits = u16::from_be_bytes(self.take(2)?.try_into().unwrap());
+                    float(out, half_to_f64(bits));
+                }
+                26 => {
+                    let bits = u32::from_be_bytes(self.take(4)?.try_into().unwrap());
+                    float(out, f64::from(f32::from_bits(bits)));
+                }
+                27 => {
+                    let bits = u64::from_be_bytes(self.take(8)?.try_into().unwrap());
+                    float(out, f64::from_bits(bits));
+                }
+                31 => return Err(Error::new(ErrorKind::InvalidData, "unexpected CBOR break")),
+                _ => {
+                    let _ = write!(out, "simple({})", info);
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Reads a data item as the JSON value RFC 8949 section 6.1 converts it to: byte strings
+    /// become base64url text, tags are dropped, and values JSON can't hold become null.
+    fn value(&mut self, labels: Option<&Labels>, depth: usize) -> Result<Value> {
+        check_depth(depth)?;
+        let initial = self.byte()?;
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        Ok(match major {
+            0 | 1 => {
+                let value = self.argument(info)?.ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, "indefinite length CBOR integer")
+                })?;
+                match major {
+                    0 => Value::from(value),
+                    _ => i64::try_from(value)
+                        .map(|value| Value::from(-1 - value))
+                        .unwrap_or_else(|_| Value::from(-1.0 - value as f64)),
+                }
+            }
+            2 | 3 => {
+                let bytes = match self.argument(info)? {
+                    Some(length) => {
+                        let length = usize::try_from(length).map_err(|_| truncated())?;
+                        self.take(length)?.to_vec()
+                    }
+                    None => {
+                        let mut bytes = vec![];
+                        while !self.at_break()? {
+                            let chunk = self.byte()?;
+                            let length = self.length(chunk & 0x1f)?;
+                            bytes.extend_from_slice(self.take(length)?);
+                        }
+                        bytes
+                    }
+                };
+                match major {
+                    2 => Value::from(base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)),
+                    _ => Value::from(String::from_utf8(bytes).map_err(|_| {
+                        Error::new(ErrorKind::InvalidData, "CBOR text string isn't UTF-8")
+                    })?),
+                }
+            }
+            4 | 5 => {
+                let count = self.argument(info)?;
+                let mut items = vec![];
+                let mut object = Map::new();
+                let mut i = 0;
+                loop {
+                    match count {
+                        Some(count) if i == count => break,
+                        None if self.at_break()? => break,
+                        _ => {}
+                    }
+                    if major == 4 {
+                        items.push(self.value(labels, depth + 1)?);
+                    } else {
+                        let key = match self.value(None, depth + 1)? {
+                            Value::String(key) => key,
+                            key => labels
+                                .and_then(|labels| labels.name(&key))
+                                .map_or_else(|| key.to_string(), str::to_string),
+                        };
+                        object.insert(key, self.value(labels, depth + 1)?);
+                    }
+                    i += 1;
+                }
+                match major {
+                    4 => Value::Array(items),
+                    _ => Value::Object(object),
+                }
+            }
+            6 => {
+                self.argument(info)?;
+                self.value(labels, depth + 1)?
+            }
+            _ => match info {
+                20 => Value::Bool(false),
+                21 => Value::Bool(true),
+                25 => {
+                    let bits = u16::from_be_bytes(self.take(2)?.try_into().unwrap());
+                    float_value(half_to_f64(bits))
+                }
+                26 => {
+                    let bits = u32::from_be_bytes(self.take(4)?.try_into().unwrap());
+                    float_value(f64::from(f32::from_bits(bits)))
+                }
+                27 => {
+                    let bits = u64::from_be_bytes(self.take(8)?.try_into().unwrap());
+                    float_value(f64::from_bits(bits))
+                }
+                24 => {
+                    self.byte()?;
+                    Value::Null
+                }
+                31 => return Err(Error::new(ErrorKind::InvalidData, "unexpected CBOR break")),
+                _ => Value::Null,
+            },
+        })
+    }
+}
+
+fn float_value(value: f64) -> Value {
+    Number::from_f64(value).map_or(Value::Null, Value::Number)
+}
+
+fn string(out: &mut String, major: u8, bytes: &[u8]) {
+    match (major, std::str::from_utf8(bytes)) {
+        (3, Ok(text)) => {
+            let _ = write!(out, "{:?}", text);
+        }
+        _ => {
+            let _ = write!(out, "h'{}'", hex(bytes));
+        }
+    }
+}
+
+fn half_to_f64(bits: u16) -> f64 {
+    let exponent = i32::from((bits >> 10) & 0x1f);
+    let mantissa = f64::from(bits & 0x3ff);
+    let magnitude = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f64.powi(exponent - 15),
+    };
+    if bits & 0x8000 != 0 { -magnitude } else { magnitude }
+}
+
+fn float(out: &mut String, value: f64) {
+    match value {
+        v if v.is_nan() => out.push_str("NaN"),
+        v if v == f64::INFINITY => out.push_str("Infinity"),
+        v if v == f64::NEG_INFINITY => out.push_str("-Infinity"),
+        v => {
+            let _ = write!(out, "{:?}", v);
+        }
+    }
+}
+
+/// Renders a CBOR data item, or a CBOR sequence (RFC 8742) of them one per line, in diagnostic
+/// notation (RFC 8949 section 8), e.g. `{"temp": 21.5, "unit": "C"}`.
+pub fn diagnostic(bytes: &[u8]) -> Result<String> {
+    let mut decoder = Decoder { bytes, pos: 0 };
+    let mut out = String::new();
+    while decoder.pos < bytes.len() {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        decoder.item(&mut out, 0)?;
+    }
+    Ok(out)
+}
+
+/// Checks that bytes are a well-formed CBOR data item, or with `sequence` a CBOR sequence (RFC
+/// 8742) of any number of them, saying where they aren't.
+pub fn check(bytes: &[u8], sequence: bool) -> Result<()> {
+    let mut decoder = Decoder { bytes, pos: 0 };
+    let mut items = 0;
+    while decoder.pos < bytes.len() {
+        if items == 1 && !sequence {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("more than one CBOR data item, the second at byte {}", decoder.pos),
+            ));
+        }
+        let start = decoder.pos;
+        decoder.item(&mut String::new(), 0).map_err(|e| {
+            Error::new(e.kind(), format!("{} in the data item at byte {}", e, start))
+        })?;
+        items += 1;
+    }
+    if items == 0 && !sequence {
+        return Err(Error::new(ErrorKind::InvalidData, "no CBOR data item"));
+    }
+    Ok(())
+}
+
+/// The integer labels SenML (RFC 8428 section 6) gives its fields in CBOR.
+const SENML_LABELS: [(i64, &str); 15] = [
+    (-1, "bver"),
+    (-2, "bn"),
+    (-3, "bt"),
+    (-4, "bu"),
+    (-5, "bv"),
+    (-6, "bs"),
+    (0, "n"),
+    (1, "u"),
+    (2, "v"),
+    (3, "vs"),
+    (4, "vb"),
+    (5, "s"),
+    (6, "t"),
+    (7, "ut"),
+    (8, "vd"),
+];
+
+/// Names for the integer map keys of a CBOR format, such as SenML's.
+pub struct Labels(&'static [(i64, &'static str)]);
+
+pub const SENML: Labels = Labels(&SENML_LABELS);
+
+impl Labels {
+    fn name(&self, key: &Value) -> Option<&'static str> {
+        let key = key.as_i64()?;
+        self.0.iter().find(|(label, _)| *label == key).map(|(_, name)| *name)
+    }
+
+    fn label(&self, name: &str) -> Option<i64> {
+        self.0.iter().find(|(_, n)| *n == name).map(|(label, _)| *label)
+    }
+}
+
+/// Translates CBOR to JSON, naming integer map keys after `labels` if given.
+pub fn to_json(bytes: &[u8], labels: Option<&Labels>) -> Result<Value> {
+    let mut decoder = Decoder { bytes, pos: 0 };
+    let value = decoder.value(labels, 0)?;
+    if decoder.pos < bytes.len() {
+        return Err(Error::new(ErrorKind::InvalidData, "more than one CBOR data item"));
+    }
+    Ok(value)
+}
+
+fn head(out: &mut Vec<u8>, major: u8, argument: u64) {
+    let major = major << 5;
+    match argument {
+        0..=23 => out.push(major | argument as u8),
+        24..=0xff => out.extend([major | 24, argument as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend((argument as u16).to_be_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend((argument as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend(argument.to_be_bytes());
+        }
+    }
+}
+
+fn encode(out: &mut Vec<u8>, value: &Value, labels: Option<&Labels>) {
+    match value {
+        Value::Null => out.push(0xf6),
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::Number(number) => {
+            if let Some(value) = number.as_u64() {
+                head(out, 0, value);
+            } else if let Some(value) = number.as_i64() {
+                head(out, 1, (-1 - value) as u64);
+            } else {
+                let value = number.as_f64().unwrap_or(f64::NAN);
+                // in as few bytes as keep it exact
+                if f64::from(value as f32) == value {
+                    out.push(0xfa);
+                    out.extend((value as f32).to_be_bytes());
+                } else {
+                    out.push(0xfb);
+                    out.extend(value.to_be_bytes());
+                }
+            }
+        }
+        Value::String(text) => {
+            head(out, 3, text.len() as u64);
+            out.extend(text.as_bytes());
+        }
+        Value::Array(items) => {
+            head(out, 4, items.len() as u64);
+            for item in items {
+                encode(out, item, labels);
+            }
+        }
+        Value::Object(object) => {
+            head(out, 5, object.len() as u64);
+            for (key, item) in object {
+                match labels.and_then(|labels| labels.label(key)) {
+                    Some(label) => encode(out, &Value::from(label), None),
+                    None => encode(out, &Value::from(key.as_str()), None),
+                }
+                encode(out, item, labels);
+            }
+        }
+    }
+}
+
+/// Translates JSON to CBOR, giving map keys the integer labels of `labels` where they have one.
+pub fn from_json(value: &Value, labels: Option<&Labels>) -> Vec<u8> {
+    let mut out = vec![];
+    encode(&mut out, value, labels);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coap_helper::parse_hex;
+    use serde_json::json;
+
+    fn round_trip(value: Value) {
+        assert_eq!(to_json(&from_json(&value, None), None).unwrap(), value);
+    }
+
+    #[test]
+    fn round_trips_integers() {
+        for (value, encoded) in [
+            (0, "00"),
+            (23, "17"),
+            (24, "1818"),
+            (255, "18ff"),
+            (256, "190100"),
+            (65535, "19ffff"),
+            (65536, "1a00010000"),
+            (1 << 32, "1b0000000100000000"),
+            (-1, "20"),
+            (-24, "37"),
+            (-25, "3818"),
+            (-256, "38ff"),
+            (-257, "390100"),
+            (i64::MIN, "3b7fffffffffffffff"),
+        ] {
+            assert_eq!(hex(&from_json(&json!(value), None)), encoded, "{}", value);
+            round_trip(json!(value));
+        }
+        round_trip(json!(u64::MAX));
+    }
+
+    #[test]
+    fn round_trips_floats() {
+        assert_eq!(hex(&from_json(&json!(1.5), None)), "fa3fc00000");
+        assert_eq!(hex(&from_json(&json!(1.1), None)), "fb3ff199999999999a");
+        for value in [0.5, -4.1, 1.0e300, 5.960464477539063e-8, f64::MAX] {
+            round_trip(json!(value));
+        }
+    }
+
+    #[test]
+    fn round_trips_strings_and_containers() {
+        round_trip(json!(""));
+        round_trip(json!("IETF"));
+        round_trip(json!("水 \"quoted\""));
+        round_trip(json!("x".repeat(300)));
+        round_trip(json!([]));
+        round_trip(json!([1, [2, 3], [4, [5, {"a": null}]]]));
+        round_trip(json!({}));
+        round_trip(json!({"a": true, "b": [false, null], "c": {"d": {"e": -1.5}}}));
+        // byte strings come back as base64url text
+        assert_eq!(
+            to_json(&parse_hex("4401020304").unwrap(), None).unwrap(),
+            json!("AQIDBA")
+        );
+    }
+
+    /// The examples of RFC 8949 appendix A in diagnostic notation, but for floats being written as
+    /// Rust writes them.
+    #[test]
+    fn diagnoses_rfc_8949_examples() {
+        for (encoded, expected) in [
+            ("00", "0"),
+            ("01", "1"),
+            ("0a", "10"),
+            ("17", "23"),
+            ("1818", "24"),
+            ("1819", "25"),
+            ("1864", "100"),
+            ("1903e8", "1000"),
+            ("1a000f4240", "1000000"),
+            ("1b000000e8d4a51000", "1000000000000"),
+            ("1bffffffffffffffff", "18446744073709551615"),
+            ("c249010000000000000000", "2(h'010000000000000000')"),
+            ("3bffffffffffffffff", "-18446744073709551616"),
+            ("20", "-1"),
+            ("29", "-10"),
+            ("3863", "-100"),
+            ("3903e7", "-1000"),
+            ("f90000", "0.0"),
+            ("f98000", "-0.0"),
+            ("f93c00", "1.0"),
+            ("fb3ff199999999999a", "1.1"),
+            ("f93e00", "1.5"),
+            ("f97bff", "65504.0"),
+            ("fa47c35000", "100000.0"),
+            ("fa7f7fffff", "3.4028234663852886e38"),
+            ("fb7e37e43c8800759c", "1e300"),
+            ("f90001", "5.960464477539063e-8"),
+            ("f90400", "6.103515625e-5"),
+            ("f9c400", "-4.0"),
+            ("fbc010666666666666", "-4.1"),
+            ("f97c00", "Infinity"),
+            ("f97e00", "NaN"),
+            ("f9fc00", "-Infinity"),
+            ("fa7f800000", "Infinity"),
+            ("fa7fc00000", "NaN"),
+            ("faff800000", "-Infinity"),
+            ("fb7ff0000000000000", "Infinity"),
+            ("fb7ff8000000000000", "NaN"),
+            ("fbfff0000000000000", "-Infinity"),
+            ("f4", "false"),
+            ("f5", "true"),
+            ("f6", "null"),
+            ("f7", "undefined"),
+            ("f0", "simple(16)"),
+            ("f8ff", "simple(255)"),
+            (
+                "c074323031332d30332d32315432303a30343a30305a",
+                "0(\"2013-03-21T20:04:00Z\")",
+            ),
+            ("c11a514b67b0", "1(1363896240)"),
+            ("c1fb41d452d9ec200000", "1(1363896240.5)"),
+            ("d74401020304", "23(h'01020304')"),
+            ("d818456449455446", "24(h'6449455446')"),
+            (
+                "d82076687474703a2f2f7777772e6578616d706c652e636f6d",
+                "32(\"http://www.example.com\")",
+            ),
+            ("40", "h''"),
+            ("4401020304", "h'01020304'"),
+            ("60", "\"\""),
+            ("6161", "\"a\""),
+            ("6449455446", "\"IETF\""),
+            ("62225c", "\"\\\"\\\\\""),
+            ("62c3bc", "\"\u{fc}\""),
+            ("63e6b0b4", "\"\u{6c34}\""),
+            ("64f0908591", "\"\u{10151}\""),
+            ("80", "[]"),
+            ("83010203", "[1, 2, 3]"),
+            ("8301820203820405", "[1, [2, 3], [4, 5]]"),
+            (
+                "98190102030405060708090a0b0c0d0e0f101112131415161718181819",
+                "[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, \
+                 23, 24, 25]",
+            ),
+            ("a0", "{}"),
+            ("a201020304", "{1: 2, 3: 4}"),
+            ("a26161016162820203", "{\"a\": 1, \"b\": [2, 3]}"),
+            ("826161a161626163", "[\"a\", {\"b\": \"c\"}]"),
+            (
+                "a56161614161626142616361436164614461656145",
+                "{\"a\": \"A\", \"b\": \"B\", \"c\": \"C\", \"d\": \"D\", \"e\": \"E\"}",
+            ),
+            ("5f42010243030405ff", "(_ h'0102', h'030405')"),
+            ("7f657374726561646d696e67ff", "(_ \"strea\", \"ming\")"),
+            ("9fff", "[_ ]"),
+            ("9f018202039f0405ffff", "[_ 1, [2, 3], [_ 4, 5]]"),
+            ("9f01820203820405ff", "[_ 1, [2, 3], [4, 5]]"),
+            ("83018202039f0405ff", "[1, [2, 3], [_ 4, 5]]"),
+            ("83019f0203ff820405", "[1, [_ 2, 3], [4, 5]]"),
+            ("bf61610161629f0203ffff", "{_ \"a\": 1, \"b\": [_ 2, 3]}"),
+            ("826161bf61626163ff", "[\"a\", {_ \"b\": \"c\"}]"),
+            ("bf6346756ef563416d7421ff", "{_ \"Fun\": true, \"Amt\": -2}"),
+        ] {
+            assert_eq!(
+                diagnostic(&parse_hex(encoded).unwrap()).unwrap(),
+                expected,
+                "{}",
+                encoded
+            );
+        }
+    }
+
+    #[test]
+    fn diagnoses_sequences_and_malformed_data() {
+        assert_eq!(
+            diagnostic(&parse_hex("0161616283").unwrap())
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(
+            diagnostic(&parse_hex("016161").unwrap()).unwrap(),
+            "1\n\"a\""
+        );
+        for malformed in ["18", "1c", "62c3", "8301", "ff", "5f6161ff", "1f"] {
+            let bytes = parse_hex(malformed).unwrap();
+            assert_eq!(
+                diagnostic(&bytes).unwrap_err().kind(),
+                ErrorKind::InvalidData,
+                "{}",
+                malformed
+            );
+            assert!(check(&bytes, true).is_err(), "{}", malformed);
+        }
+        assert!(check(&parse_hex("0102").unwrap(), true).is_ok());
+        assert!(check(&parse_hex("0102").unwrap(), false).is_err());
+        assert!(check(&[], true).is_ok());
+        assert!(check(&[], false).is_err());
+    }
+
+    #[test]
+    fn translates_to_json() {
+        // tags are dropped, values JSON can't hold become null, and keys that aren't text are
+        // written as JSON, byte strings as base64url
+        let bytes = parse_hex("a461610101c11a514b67b002f97e0041f6f7").unwrap();
+        assert_eq!(
+            to_json(&bytes, None).unwrap(),
+            json!({"a": 1, "1": 1363896240, "2": null, "9g": null})
+        );
+        assert_eq!(
+            to_json(&parse_hex("3bffffffffffffffff").unwrap(), None).unwrap(),
+            json!(-18446744073709551616.0)
+        );
+        assert_eq!(
+            to_json(&parse_hex("7f657374726561646d696e67ff").unwrap(), None).unwrap(),
+            json!("streaming")
+        );
+        assert!(to_json(&parse_hex("0102").unwrap(), None).is_err());
+        assert!(to_json(&parse_hex("62c328").unwrap(), None).is_err());
+    }
+
+    #[test]
+    fn translates_senml_labels() {
+        let senml =
+            json!([{"bn": "urn:dev:ow:10e2073a01080063:", "n": "temp", "u": "Cel", "v": 23.5}]);
+        let bytes = from_json(&senml, Some(&SENML));
+        assert_eq!(
+            diagnostic(&bytes).unwrap(),
+            "[{-2: \"urn:dev:ow:10e2073a01080063:\", 0: \"temp\", 1: \"Cel\", 2: 23.5}]"
+        );
+        assert_eq!(to_json(&bytes, Some(&SENML)).unwrap(), senml);
+        // without the labels the keys stay numbers, and names without one stay text
+        assert_eq!(
+            to_json(&bytes, None).unwrap()[0]["-2"],
+            json!("urn:dev:ow:10e2073a01080063:")
+        );
+        let custom = from_json(&json!({"n": "x", "custom": 1}), Some(&SENML));
+        assert_eq!(diagnostic(&custom).unwrap(), "{\"custom\": 1, 0: \"x\"}");
+    }
+
+    fn nested_arrays(levels: usize) -> Vec<u8> {
+        let mut bytes = vec![0x81; levels];
+        bytes.push(0x00);
+        bytes
+    }
+
+    #[test]
+    fn refuses_deep_nesting() {
+        assert!(diagnostic(&nested_arrays(MAX_DEPTH)).is_ok());
+        assert!(to_json(&nested_arrays(MAX_DEPTH), None).is_ok());
+        for result in [
+            diagnostic(&nested_arrays(MAX_DEPTH + 1)).map(drop),
+            to_json(&nested_arrays(MAX_DEPTH + 1), None).map(drop),
+            check(&vec![0xc6; 100_000], false),
+        ] {
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+    }
+}
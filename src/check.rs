@@ -0,0 +1,133 @@
+use coap_lite::{CoapOption, MessageClass, Packet, RequestType};
+use log::info;
+use serde_json::json;
+use std::io::{Error, Result};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::blockwise::{self, Block};
+use crate::coap_helper::*;
+use crate::expect::ExpectationFailed;
+use crate::message_dump::{decode_uint, hex};
+use crate::observe::max_age;
+use crate::report::OutputFormat;
+use crate::transport::Connection;
+use crate::{before_deadline, prepare_request, request_deadline, Args, ERROR_RESPONSE_CLASS};
+
+/// The smallest block size there is, so that as little of the payload as possible comes along.
+const PROBE_BLOCK_SIZE: usize = 16;
+
+/// The size of the whole representation: as Size2 gives it, or the payload if it came whole.
+fn representation_size(message: &Packet) -> Result<Option<u64>> {
+    if let Some(size) = message.get_first_option(CoapOption::Size2) {
+        return Ok(Some(decode_uint(size)));
+    }
+    let block = message
+        .get_first_option(CoapOption::Block2)
+        .map(|value| Block::decode(value))
+        .transpose()?;
+    Ok(match block {
+        Some(block) if block.more => None,
+        _ => Some(message.payload.len() as u64),
+    })
+}
+
+fn code_name(message: &Packet) -> &'static str {
+    match message.header.code {
+        MessageClass::Response(code) => response_code_info(code).0,
+        _ => "",
+    }
+}
+
+/// Asks for a resource with as little of its payload as possible, the first block of the
+/// smallest size along with the size of the whole (RFC 7959 section 4), and reports what the
+/// response says about it: its code, content format, ETag, Max-Age, size and how long it stays
+/// fresh. With `freshness`, fails unless it stays fresh for at least that long.
+pub async fn coap_check(conn: &Connection, args: &Args, freshness: Option<Duration>) -> Result<()> {
+    info!("GET {}", args.url);
+    let mut request = build_coap_request_for_url(&args.url, RequestType::Get, None, None, None)?;
+    if args.dry_run {
+        return Ok(());
+    }
+    prepare_request(conn, args, &mut request)?;
+    if request.message.get_first_option(CoapOption::Size2).is_none() {
+        request.message.add_option(CoapOption::Size2, encode_uint(0));
+    }
+    let block = Block {
+        num: 0,
+        more: false,
+        size: PROBE_BLOCK_SIZE,
+    };
+    let response = before_deadline(
+        args,
+        request_deadline(args),
+        "check",
+        blockwise::request_block(conn, &mut request, block),
+    )
+    .await?;
+    let message = &response.message;
+
+    let content_format = message.get_content_format().map(usize::from);
+    let etag = message.get_first_option(CoapOption::ETag).map(|etag| hex(etag));
+    let explicit_max_age = message.get_first_option(CoapOption::MaxAge).is_some();
+    let max_age = max_age(message);
+    let size = representation_size(message)?;
+    let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+    let fresh_until = (now + max_age)
+        .replace_nanosecond(0)
+        .map_err(Error::other)?
+        .format(&Rfc3339)
+        .map_err(Error::other)?;
+
+    match args.output {
+        OutputFormat::Json => println!(
+            "{}",
+            json!({
+                "url": args.url,
+                "code": message.header.code.to_string(),
+                "name": code_name(message),
+                "content_format": content_format,
+                "etag": etag,
+                "max_age": max_age.as_secs(),
+                "max_age_default": !explicit_max_age,
+                "size": size,
+                "fresh_until": fresh_until,
+            })
+        ),
+        _ => {
+            println!("code: {} {}", message.header.code, code_name(message));
+            if let Some(content_format) = content_format {
+                println!("content-format: {}", content_format_name(content_format));
+            }
+            println!("etag: {}", etag.as_deref().unwrap_or("-"));
+            let default = if explicit_max_age { "" } else { " (default)" };
+            println!("max-age: {}s{}", max_age.as_secs(), default);
+            match size {
+                Some(size) => println!("size: {} bytes", size),
+                None => println!("size: unknown"),
+            }
+            println!("fresh until: {}", fresh_until);
+        }
+    }
+
+    if let MessageClass::Response(code) = message.header.code
+        && code.is_error()
+    {
+        ERROR_RESPONSE_CLASS.fetch_max(u8::from(message.header.code) >> 5, Ordering::Relaxed);
+        return Ok(());
+    }
+    if let Some(freshness) = freshness
+        && max_age < freshness
+    {
+        return Err(Error::other(ExpectationFailed {
+            failures: vec![format!(
+                "fresh for {}s, less than the {}s required",
+                max_age.as_secs(),
+                freshness.as_secs()
+            )],
+        }));
+    }
+    Ok(())
+}
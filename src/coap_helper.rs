@@ -1,60 +1,385 @@
+use clap::builder::{PossibleValue, StringValueParser, TypedValueParser};
+use clap::ValueEnum;
 use coap::request::RequestBuilder;
 use coap_lite::option_value::OptionValueU16;
-use coap_lite::{CoapOption, CoapRequest, ContentFormat, RequestType};
+use coap_lite::{
+    CoapOption, CoapRequest, ContentFormat, MessageClass, Packet, RequestType, ResponseType,
+};
+use percent_encoding::{AsciiSet, CONTROLS};
 use regex::Regex;
 use std::io::{Error, ErrorKind, Result};
 use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::time::Duration;
 use url::Url;
 
-pub fn parse_coap_url(url: &str) -> Result<(String, Option<u16>, String, Option<String>)> {
-    let url_params = match Url::parse(url) {
-        Ok(url_params) => url_params,
-        Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "url error")),
-    };
+use crate::resolve::split_zone;
 
-    let host = match url_params.host_str() {
-        Some("") => return Err(Error::new(ErrorKind::InvalidInput, "host error")),
-        Some(h) => h,
-        None => return Err(Error::new(ErrorKind::InvalidInput, "host error")),
-    };
-    let host = Regex::new(r"^\[(.*?)]$")
-        .unwrap()
-        .replace(&host, "$1")
+/// The schemes of CoAP URIs: over UDP and DTLS (RFC 7252 section 6), and over TCP, TLS and
+/// WebSockets (RFC 8323 section 8).
+pub const COAP_SCHEMES: [&str; 6] = ["coap", "coaps", "coap+tcp", "coaps+tcp", "coap+ws", "coaps+ws"];
+
+// an IPv6 address with a zone identifier, as a URI host: `[fe80::1%25eth0]` (RFC 6874), or
+// `[fe80::1%eth0]` as it is often written
+static ZONED_HOST: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[([0-9A-Fa-f:.]+)(%[^\]]+)\]").unwrap());
+
+/// A CoAP URI taken apart: its scheme, host, port, path and query, the latter two still
+/// percent-encoded as they were given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoapUri {
+    /// In lower case, e.g. `coaps`
+    pub scheme: String,
+    /// Percent-decoded and without the brackets of an IPv6 address, but with its zone, e.g.
+    /// `fe80::1%eth0`
+    pub host: String,
+    /// The port the URI gives, if it gives one
+    pub port: Option<u16>,
+    pub path: String,
+    pub query: Option<String>,
+}
+
+impl CoapUri {
+    /// Parses a CoAP URI, failing with what is wrong with it: a missing or unsupported scheme, a
+    /// missing host, a fragment (which CoAP URIs can't have) or anything else that keeps it from
+    /// being a URI.
+    pub fn parse(url: &str) -> Result<CoapUri> {
+        let uri = CoapUri::parse_any_scheme(url)?;
+        if !COAP_SCHEMES.contains(&uri.scheme.as_str()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "unsupported scheme '{}'; expected {}",
+                    uri.scheme,
+                    COAP_SCHEMES.join(", ")
+                ),
+            ));
+        }
+        if url.contains('#') {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("'{}' has a fragment, which a CoAP URI can't have", url),
+            ));
+        }
+        Ok(uri)
+    }
+
+    /// Parses a URI of any scheme, as that of a resource a forward proxy is asked for may be,
+    /// e.g. an `http` one.
+    pub fn parse_any_scheme(url: &str) -> Result<CoapUri> {
+        let invalid = |message: String| Error::new(ErrorKind::InvalidInput, message);
+        if !url.contains("://") {
+            return Err(invalid(format!(
+                "'{}' has no scheme; expected a URI such as coap://{}",
+                url, url
+            )));
+        }
+        // the url crate rejects IPv6 zone identifiers, so they're kept out of its way and given
+        // back as part of the host
+        let zone = ZONED_HOST.captures(url).map(|c| c[2].to_string());
+        let unzoned = ZONED_HOST.replace(url, "[$1]");
+        let parsed =
+            Url::parse(&unzoned).map_err(|e| invalid(format!("invalid URI '{}': {}", url, e)))?;
+
+        let host = match parsed.host_str() {
+            Some(host) if !host.is_empty() => host,
+            _ => return Err(invalid(format!("'{}' has no host", url))),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_string()
+            + zone.as_deref().unwrap_or("");
+        let host = percent_encoding::percent_decode_str(&host).decode_utf8_lossy().into_owned();
+
+        Ok(CoapUri {
+            scheme: parsed.scheme().to_string(),
+            host,
+            port: parsed.port(),
+            path: parsed.path().to_string(),
+            query: parsed.query().map(str::to_string),
+        })
+    }
+
+    /// The port of the scheme, for when the URI doesn't give one: 5683 for `coap` and
+    /// `coap+tcp`, 5684 for `coaps` and `coaps+tcp`, and those of HTTP for `coap+ws` and
+    /// `coaps+ws`.
+    pub fn default_port(&self) -> u16 {
+        match self.scheme.as_str() {
+            "coaps" | "coaps+tcp" => 5684,
+            "coap+ws" => 80,
+            "coaps+ws" => 443,
+            _ => 5683,
+        }
+    }
+
+    /// The port the URI gives, or that of its scheme.
+    pub fn port_or_default(&self) -> u16 {
+        self.port.unwrap_or_else(|| self.default_port())
+    }
+
+    /// The values of the Uri-Path options for the path, each segment percent-decoded.
+    pub fn path_segments(&self) -> Vec<Vec<u8>> {
+        uri_path_segments(&self.path)
+    }
+
+    /// The values of the Uri-Query options for the query, one for each argument, percent-decoded.
+    pub fn query_arguments(&self) -> Vec<Vec<u8>> {
+        match &self.query {
+            Some(query) => query.split('&').map(percent_decode).collect(),
+            None => vec![],
+        }
+    }
+
+    /// The query's arguments as names and values, percent-decoded, with an empty value for an
+    /// argument without `=`.
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        self.query_arguments()
+            .iter()
+            .map(|argument| {
+                let argument = String::from_utf8_lossy(argument);
+                match argument.split_once('=') {
+                    Some((name, value)) => (name.to_string(), value.to_string()),
+                    None => (argument.to_string(), String::new()),
+                }
+            })
+            .collect()
+    }
+}
+
+/// A request method, as chosen on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum RequestMethod {
+    Get,
+    Post,
+    Put,
+    Delete,
+    Fetch,
+    Patch,
+    Ipatch,
+}
+
+impl RequestMethod {
+    /// The method's name as RFC 7252 and RFC 8132 give it, e.g. `GET` or `iPATCH`.
+    pub fn name(self) -> &'static str {
+        match self {
+            RequestMethod::Get => "GET",
+            RequestMethod::Post => "POST",
+            RequestMethod::Put => "PUT",
+            RequestMethod::Delete => "DELETE",
+            RequestMethod::Fetch => "FETCH",
+            RequestMethod::Patch => "PATCH",
+            RequestMethod::Ipatch => "iPATCH",
+        }
+    }
+}
+
+impl From<RequestMethod> for RequestType {
+    fn from(method: RequestMethod) -> RequestType {
+        match method {
+            RequestMethod::Get => RequestType::Get,
+            RequestMethod::Post => RequestType::Post,
+            RequestMethod::Put => RequestType::Put,
+            RequestMethod::Delete => RequestType::Delete,
+            RequestMethod::Fetch => RequestType::Fetch,
+            RequestMethod::Patch => RequestType::Patch,
+            RequestMethod::Ipatch => RequestType::IPatch,
+        }
+    }
+}
+
+/// Rewrites a request built for a resource on `target`'s server so that a forward proxy can send
+/// it on: the target URI is given in Proxy-Uri or, with `proxy_scheme`, in the Uri-* options
+/// plus Proxy-Scheme.
+pub fn route_via_proxy(request: &mut Packet, target: &str, proxy_scheme: bool) -> Result<()> {
+    let mut uri =
+        Url::parse(target).map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+    let host = uri
+        .host_str()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("'{}' has no host", target)))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
         .to_string();
+    if proxy_scheme {
+        request.clear_option(CoapOption::UriHost);
+        request.add_option(CoapOption::UriHost, host.into_bytes());
+        if let Some(port) = uri.port() {
+            request.clear_option(CoapOption::UriPort);
+            request.add_option(CoapOption::UriPort, encode_uint(u32::from(port)));
+        }
+        request.add_option(CoapOption::ProxyScheme, uri.scheme().as_bytes().to_vec());
+        return Ok(());
+    }
 
-    let port = url_params.port();
+    let encoded = |values: Option<&std::collections::LinkedList<Vec<u8>>>| -> Vec<String> {
+        values.into_iter().flatten().map(|v| percent_encode(v)).collect()
+    };
+    let segments = encoded(request.get_option(CoapOption::UriPath));
+    let queries = encoded(request.get_option(CoapOption::UriQuery));
+    uri.set_path(&format!("/{}", segments.join("/")));
+    uri.set_query((!queries.is_empty()).then(|| queries.join("&")).as_deref());
+    for option in [
+        CoapOption::UriHost,
+        CoapOption::UriPort,
+        CoapOption::UriPath,
+        CoapOption::UriQuery,
+    ] {
+        request.clear_option(option);
+    }
+    request.add_option(CoapOption::ProxyUri, uri.to_string().into_bytes());
+    Ok(())
+}
 
-    let path = url_params.path().to_string();
+/// The registered content formats (the IANA CoAP Content-Formats registry), by media type and
+/// parameters as the registry gives them, and with `@` and the content coding for those that have
+/// one.
+pub const CONTENT_FORMATS: &[(&str, ContentFormat)] = &[
+    ("text/plain; charset=utf-8", ContentFormat::TextPlain),
+    ("application/cose; cose-type=\"cose-encrypt0\"", ContentFormat::ApplicationCoseEncrypt0),
+    ("application/cose; cose-type=\"cose-mac0\"", ContentFormat::ApplicationCoseMac0),
+    ("application/cose; cose-type=\"cose-sign1\"", ContentFormat::ApplicationCoseSign1),
+    ("application/ace+cbor", ContentFormat::ApplicationAceCbor),
+    ("image/gif", ContentFormat::ImageGif),
+    ("image/jpeg", ContentFormat::ImageJpeg),
+    ("image/png", ContentFormat::ImagePng),
+    ("application/link-format", ContentFormat::ApplicationLinkFormat),
+    ("application/xml", ContentFormat::ApplicationXML),
+    ("application/octet-stream", ContentFormat::ApplicationOctetStream),
+    ("application/exi", ContentFormat::ApplicationEXI),
+    ("application/json", ContentFormat::ApplicationJSON),
+    ("application/json-patch+json", ContentFormat::ApplicationJsonPatchJson),
+    ("application/merge-patch+json", ContentFormat::ApplicationMergePatchJson),
+    ("application/cbor", ContentFormat::ApplicationCBOR),
+    ("application/cwt", ContentFormat::ApplicationCWt),
+    ("application/multipart-core", ContentFormat::ApplicationMultipartCore),
+    ("application/cbor-seq", ContentFormat::ApplicationCborSeq),
+    ("application/cose; cose-type=\"cose-encrypt\"", ContentFormat::ApplicationCoseEncrypt),
+    ("application/cose; cose-type=\"cose-mac\"", ContentFormat::ApplicationCoseMac),
+    ("application/cose; cose-type=\"cose-sign\"", ContentFormat::ApplicationCoseSign),
+    ("application/cose-key", ContentFormat::ApplicationCoseKey),
+    ("application/cose-key-set", ContentFormat::ApplicationCoseKeySet),
+    ("application/senml+json", ContentFormat::ApplicationSenmlJSON),
+    ("application/sensml+json", ContentFormat::ApplicationSensmlJSON),
+    ("application/senml+cbor", ContentFormat::ApplicationSenmlCBOR),
+    ("application/sensml+cbor", ContentFormat::ApplicationSensmlCBOR),
+    ("application/senml-exi", ContentFormat::ApplicationSenmlExi),
+    ("application/sensml-exi", ContentFormat::ApplicationSensmlExi),
+    ("application/yang-data+cbor; id=sid", ContentFormat::ApplicationYangDataCborSid),
+    ("application/coap-group+json", ContentFormat::ApplicationCoapGroupJson),
+    ("application/dots+cbor", ContentFormat::ApplicationDotsCbor),
+    ("application/missing-blocks+cbor-seq", ContentFormat::ApplicationMissingBlocksCborSeq),
+    ("application/pkcs7-mime; smime-type=server-generated-key", ContentFormat::ApplicationPkcs7MimeServerGeneratedKey),
+    ("application/pkcs7-mime; smime-type=certs-only", ContentFormat::ApplicationPkcs7MimeCertsOnly),
+    ("application/pkcs8", ContentFormat::ApplicationPkcs8),
+    ("application/csrattrs", ContentFormat::ApplicationCsrattrs),
+    ("application/pkcs10", ContentFormat::ApplicationPkcs10),
+    ("application/pkix-cert", ContentFormat::ApplicationPkixCert),
+    ("application/aif+cbor", ContentFormat::ApplicationAifCbor),
+    ("application/aif+json", ContentFormat::ApplicationAifJson),
+    ("application/senml+xml", ContentFormat::ApplicationSenmlXML),
+    ("application/sensml+xml", ContentFormat::ApplicationSensmlXML),
+    ("application/senml-etch+json", ContentFormat::ApplicationSenmlEtchJson),
+    ("application/senml-etch+cbor", ContentFormat::ApplicationSenmlEtchCbor),
+    ("application/yang-data+cbor", ContentFormat::ApplicationYangDataCbor),
+    ("application/yang-data+cbor; id=name", ContentFormat::ApplicationYangDataCborName),
+    ("application/td+json", ContentFormat::ApplicationTdJson),
+    ("application/voucher+cose", ContentFormat::ApplicationVoucherCoseCbor),
+    ("application/vnd.ocf+cbor", ContentFormat::ApplicationVndOcfCbor),
+    ("application/oscore", ContentFormat::ApplicationOscore),
+    ("application/javascript", ContentFormat::ApplicationJavascript),
+    ("application/json@deflate", ContentFormat::ApplicationJsonDeflate),
+    ("application/cbor@deflate", ContentFormat::ApplicationCborDeflate),
+    ("application/vnd.oma.lwm2m+tlv", ContentFormat::ApplicationVndOmaLwm2mTlv),
+    ("application/vnd.oma.lwm2m+json", ContentFormat::ApplicationVndOmaLwm2mJson),
+    ("application/vnd.oma.lwm2m+cbor", ContentFormat::ApplicationVndOmaLwm2mCbor),
+    ("text/css", ContentFormat::TextCss),
+    ("image/svg+xml", ContentFormat::ImageSvgXml),
+];
 
-    let query = url_params.query().map(|q| q.to_string());
+/// A media type as compared when looking one up: in lower case, without spaces or quotes.
+fn normalize_media_type(s: &str) -> String {
+    s.chars()
+        .filter(|c| !c.is_whitespace() && *c != '"')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
 
-    return Ok((host, port, path, query));
+/// Finds the content format for a media type given in full, without its parameters when only one
+/// format has the media type without them, by its subtype alone (e.g. `senml+json`), or failing
+/// those, by its structured syntax suffix (e.g. `application/vnd.example+json` as JSON).
+fn find_content_format(s: &str) -> Option<ContentFormat> {
+    let wanted = normalize_media_type(s);
+    let entries = || {
+        CONTENT_FORMATS
+            .iter()
+            .map(|(name, cf)| (normalize_media_type(name), *cf))
+    };
+    if let Some((_, cf)) = entries().find(|(name, _)| *name == wanted) {
+        return Some(cf);
+    }
+    let matching: Vec<(String, ContentFormat)> = entries()
+        .filter(|(name, _)| !name.contains('@'))
+        .filter(|(name, _)| {
+            let media_type = name.split(';').next().unwrap_or(name);
+            let subtype = media_type.split_once('/').map(|(_, subtype)| subtype);
+            media_type == wanted || subtype == Some(wanted.as_str())
+        })
+        .collect();
+    let unparameterized: Vec<&(String, ContentFormat)> =
+        matching.iter().filter(|(name, _)| !name.contains(';')).collect();
+    match (matching.as_slice(), unparameterized.as_slice()) {
+        ([(_, cf)], _) | (_, [(_, cf)]) => return Some(*cf),
+        _ => {}
+    }
+    let (_, suffix) = wanted.split(';').next()?.rsplit_once('+')?;
+    find_content_format(&format!("application/{}", suffix))
 }
 
 pub fn parse_content_format(s: &str) -> Result<ContentFormat> {
     if let Ok(num) = s.parse::<usize>() {
-        ContentFormat::try_from(num).map_err(|_| {
+        return ContentFormat::try_from(num).map_err(|_| {
             Error::new(
                 ErrorKind::InvalidInput,
                 format!("invalid content format number: {}", s),
             )
-        })
-    } else {
-        match s {
-            "text/plain" => Ok(ContentFormat::TextPlain),
-            "application/json" => Ok(ContentFormat::ApplicationJSON),
-            "application/xml" => Ok(ContentFormat::ApplicationXML),
-            "application/cbor" => Ok(ContentFormat::ApplicationCBOR),
-            "application/octet-stream" => Ok(ContentFormat::ApplicationOctetStream),
-            // TODO: more content formats
-            _ => Err(Error::new(
-                ErrorKind::InvalidInput,
-                format!("unsupported content format string: {}", s),
-            )),
-        }
+        });
+    }
+    find_content_format(s).ok_or(Error::new(
+        ErrorKind::InvalidInput,
+        format!("unsupported content format string: {}", s),
+    ))
+}
+
+/// Takes a content format argument as given, offering the media types in [`CONTENT_FORMATS`]
+/// without parameters for shell completion; it is checked by `parse_content_format` when used.
+#[derive(Debug, Clone, Copy)]
+pub struct ContentFormatParser;
+
+impl TypedValueParser for ContentFormatParser {
+    type Value = String;
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        value: &std::ffi::OsStr,
+    ) -> std::result::Result<String, clap::Error> {
+        StringValueParser::new().parse_ref(cmd, arg, value)
+    }
+
+    fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
+        // those with parameters are left out, as the shells would have them quoted
+        let names = CONTENT_FORMATS.iter().map(|(name, _)| *name);
+        Some(Box::new(names.filter(|name| !name.contains(';')).map(PossibleValue::new)))
     }
 }
 
+/// Names a content format as `parse_content_format` would take it: by its media type if it has one
+/// of those, or else by its number.
+pub fn content_format_name(number: usize) -> String {
+    CONTENT_FORMATS
+        .iter()
+        .find(|(_, cf)| usize::from(*cf) == number)
+        .map_or_else(|| number.to_string(), |(name, _)| name.to_string())
+}
+
 fn content_format_as_u16(cf: ContentFormat) -> u16 {
     let num = usize::from(cf);
     let num: u16 = num.try_into().unwrap();
@@ -68,11 +393,12 @@ pub fn build_coap_request_for_url(
     content_format: Option<ContentFormat>,
     accept: Option<Vec<ContentFormat>>,
 ) -> Result<CoapRequest<SocketAddr>> {
-    let (host, _, path, query) = parse_coap_url(url)?;
-    let mut rb = RequestBuilder::new(&path, method);
-    rb = rb.domain(host);
-    if let Some(q) = query {
-        rb = rb.queries(vec![q.as_bytes().to_vec()]);
+    let uri = CoapUri::parse_any_scheme(url)?;
+    let mut rb = RequestBuilder::new("", method);
+    rb = rb.domain(split_zone(&uri.host).0.to_string());
+    if uri.query.is_some() {
+        // each argument of the query goes in its own Uri-Query option
+        rb = rb.queries(uri.query_arguments());
     }
     rb = rb.data(payload);
     let mut options = vec![];
@@ -91,5 +417,608 @@ pub fn build_coap_request_for_url(
         }
     }
     rb = rb.options(options);
-    Ok(rb.build())
+    let mut request = rb.build();
+    request.message.clear_option(CoapOption::UriPath);
+    for segment in uri.path_segments() {
+        request.message.add_option(CoapOption::UriPath, segment);
+    }
+    Ok(request)
+}
+
+fn percent_decode(s: &str) -> Vec<u8> {
+    percent_encoding::percent_decode_str(s).collect()
+}
+
+/// Splits the path of a URL into the values of its Uri-Path options, decoding each segment, as
+/// RFC 7252 section 6.4 describes: none for an empty path or `/`, and otherwise one for every
+/// segment, including empty ones, e.g. `a`, `b c` and `` for `/a/b%20c/`.
+fn uri_path_segments(path: &str) -> Vec<Vec<u8>> {
+    if path.is_empty() || path == "/" {
+        return vec![];
+    }
+    path.strip_prefix('/')
+        .unwrap_or(path)
+        .split('/')
+        .map(percent_decode)
+        .collect()
+}
+
+/// The characters to escape in a path segment or query argument rebuilt from options, beyond
+/// those URLs can't contain: those that would otherwise be read as delimiters.
+const URI_COMPONENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+fn percent_encode(value: &[u8]) -> String {
+    percent_encoding::percent_encode(value, URI_COMPONENT).to_string()
+}
+
+/// Appends path segments and query arguments to a URL, escaping each so that it becomes exactly
+/// one Uri-Path or Uri-Query option, whatever characters it contains. A trailing `/` on the URL's
+/// path, which would otherwise leave an empty segment before them, is dropped.
+pub fn append_to_url(url: &str, segments: &[String], query: &[String]) -> String {
+    let (url, existing) = match url.split_once('?') {
+        Some((url, existing)) => (url, Some(existing)),
+        None => (url, None),
+    };
+    let mut appended = url.to_string();
+    if !segments.is_empty() {
+        let authority_end = appended.find("://").map_or(0, |i| i + 3);
+        if appended.ends_with('/') && appended.len() > authority_end {
+            appended.pop();
+        }
+        for segment in segments {
+            appended.push('/');
+            appended.push_str(&percent_encode(segment.as_bytes()));
+        }
+    }
+    let arguments: Vec<String> = existing
+        .filter(|existing| !existing.is_empty())
+        .map(str::to_string)
+        .into_iter()
+        .chain(query.iter().map(|argument| percent_encode(argument.as_bytes())))
+        .collect();
+    if !arguments.is_empty() {
+        appended.push('?');
+        appended.push_str(&arguments.join("&"));
+    }
+    appended
+}
+
+/// The location a response gives for the resource it created, from its Location-Path and
+/// Location-Query options, as a path such as `/sensors/42?v=1`, or `None` if it gives none.
+pub fn response_location(message: &Packet) -> Option<String> {
+    let path = message.get_option(CoapOption::LocationPath);
+    let query = message.get_option(CoapOption::LocationQuery);
+    if path.is_none() && query.is_none() {
+        return None;
+    }
+    let mut location = String::new();
+    for segment in path.into_iter().flatten() {
+        location.push('/');
+        location.push_str(&percent_encode(segment));
+    }
+    if location.is_empty() {
+        location.push('/');
+    }
+    if let Some(query) = query {
+        let arguments: Vec<String> = query.iter().map(|argument| percent_encode(argument)).collect();
+        location.push('?');
+        location.push_str(&arguments.join("&"));
+    }
+    Some(location)
+}
+
+pub const DEFAULT_MTU: usize = 1280;
+
+/// Returns the number of bytes available to a CoAP message in a single datagram on a path with
+/// the given MTU, after the IP and UDP headers.
+pub fn datagram_limit(mtu: usize, peer_addr: &SocketAddr) -> usize {
+    let overhead = match peer_addr {
+        SocketAddr::V4(_) => 20 + 8,
+        SocketAddr::V6(_) => 40 + 8,
+    };
+    mtu.saturating_sub(overhead)
+}
+
+/// Picks the largest Block1 size (16 to 1024 bytes) which keeps every block of the request within
+/// `limit` bytes, or `None` if the request already fits in a single datagram.
+pub fn block1_size_for_limit(request: &CoapRequest<SocketAddr>, limit: usize) -> Result<Option<usize>> {
+    let encoded = request
+        .message
+        .to_bytes_unlimited()
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+    if encoded.len() <= limit {
+        return Ok(None);
+    }
+    // the Block1 option adds up to 4 bytes to each block
+    let overhead = encoded.len() - request.message.payload.len() + 4;
+    let room = limit.saturating_sub(overhead);
+    let mut size = 1024;
+    while size > 16 && size > room {
+        size /= 2;
+    }
+    Ok(Some(size))
+}
+
+pub const RECOMMENDED_MESSAGE_SIZE: usize = 1152;
+pub const MAX_UDP_MESSAGE_SIZE: usize = 65507;
+
+fn option_field_ext_size(n: usize) -> usize {
+    match n {
+        0..=12 => 0,
+        13..=268 => 1,
+        _ => 2,
+    }
+}
+
+/// Breaks down the encoded size of a message into header, token, each option and payload.
+pub fn message_size_breakdown(message: &Packet) -> Vec<(String, usize)> {
+    let mut components = vec![
+        ("header".to_string(), 4),
+        ("token".to_string(), message.get_token().len()),
+    ];
+    let mut last_number = 0;
+    for (number, values) in message.options() {
+        for value in values {
+            let delta = usize::from(*number - last_number);
+            let size = 1 + option_field_ext_size(delta) + option_field_ext_size(value.len()) + value.len();
+            components.push((format!("option {:?} ({})", CoapOption::from(*number), number), size));
+            last_number = *number;
+        }
+    }
+    if !message.payload.is_empty() {
+        components.push(("payload marker".to_string(), 1));
+        components.push(("payload".to_string(), message.payload.len()));
+    }
+    components
+}
+
+/// Returns warnings for a message whose encoded size approaches or exceeds CoAP size limits.
+pub fn message_size_warnings(size: usize) -> Vec<String> {
+    let mut warnings = vec![];
+    for (limit, name) in [
+        (RECOMMENDED_MESSAGE_SIZE, "recommended CoAP message size"),
+        (MAX_UDP_MESSAGE_SIZE, "maximum UDP datagram payload"),
+    ] {
+        if size > limit {
+            warnings.push(format!("message size {} exceeds the {} of {} bytes", size, name, limit));
+        } else if size * 10 > limit * 9 {
+            warnings.push(format!("message size {} is approaching the {} of {} bytes", size, name, limit));
+        }
+    }
+    warnings
+}
+
+/// Parses a response code written as `c.dd`, e.g. `2.05` or `4.04`.
+pub fn parse_response_code(s: &str) -> Result<ResponseType> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid response code: {}", s));
+    let (class, detail) = s.split_once('.').ok_or_else(invalid)?;
+    let class: u8 = class.parse().map_err(|_| invalid())?;
+    let detail: u8 = detail.parse().map_err(|_| invalid())?;
+    if class > 7 || detail > 31 {
+        return Err(invalid());
+    }
+    match MessageClass::from((class << 5) | detail) {
+        MessageClass::Response(code) => Ok(code),
+        _ => Err(invalid()),
+    }
+}
+
+/// A stable hash, so that file names derived from it survive toolchain upgrades.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Parses a string of hex digit pairs, e.g. `0a1b2c`.
+pub fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid hex string: {}", s));
+    if !s.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()).ok_or_else(invalid))
+        .collect()
+}
+
+/// Parses an ETag given as hex, which may be at most 8 bytes long.
+pub fn parse_etag(s: &str) -> Result<Vec<u8>> {
+    let etag = parse_hex(s)?;
+    if etag.len() > 8 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("ETag longer than 8 bytes: {}", s),
+        ));
+    }
+    Ok(etag)
+}
+
+/// Returns the registered name of a response code and a short description of what it means.
+pub fn response_code_info(code: ResponseType) -> (&'static str, &'static str) {
+    match code {
+        ResponseType::Created => ("Created", "the resource was created"),
+        ResponseType::Deleted => ("Deleted", "the resource was deleted"),
+        ResponseType::Valid => ("Valid", "the cached representation is still valid"),
+        ResponseType::Changed => ("Changed", "the resource was modified"),
+        ResponseType::Content => ("Content", "the response carries a representation of the resource"),
+        ResponseType::Continue => ("Continue", "the block was received; send the next one"),
+        ResponseType::BadRequest => ("Bad Request", "the server could not understand the request"),
+        ResponseType::Unauthorized => ("Unauthorized", "the client is not authorized to perform the request"),
+        ResponseType::BadOption => (
+            "Bad Option",
+            "the request contained a critical option the server did not understand",
+        ),
+        ResponseType::Forbidden => ("Forbidden", "the server refuses to perform the request"),
+        ResponseType::NotFound => ("Not Found", "the server has no resource at this URI"),
+        ResponseType::MethodNotAllowed => (
+            "Method Not Allowed",
+            "the resource does not support the request method",
+        ),
+        ResponseType::NotAcceptable => (
+            "Not Acceptable",
+            "the resource has no representation in the requested content format",
+        ),
+        ResponseType::Conflict => ("Conflict", "the request conflicts with the resource's current state"),
+        ResponseType::PreconditionFailed => (
+            "Precondition Failed",
+            "a condition given in the request (e.g. If-Match) was not met",
+        ),
+        ResponseType::RequestEntityTooLarge => (
+            "Request Entity Too Large",
+            "the payload is larger than the server accepts; try a block-wise transfer",
+        ),
+        ResponseType::UnsupportedContentFormat => (
+            "Unsupported Content-Format",
+            "the server does not support the payload's content format",
+        ),
+        ResponseType::RequestEntityIncomplete => (
+            "Request Entity Incomplete",
+            "the server did not receive all the blocks of the request",
+        ),
+        ResponseType::UnprocessableEntity => (
+            "Unprocessable Entity",
+            "the payload is well-formed but semantically invalid",
+        ),
+        ResponseType::TooManyRequests => ("Too Many Requests", "the client is being rate-limited; try again later"),
+        ResponseType::InternalServerError => (
+            "Internal Server Error",
+            "the server failed while handling the request",
+        ),
+        ResponseType::NotImplemented => ("Not Implemented", "the server does not support the request method"),
+        ResponseType::BadGateway => ("Bad Gateway", "the proxy received an invalid response from upstream"),
+        ResponseType::ServiceUnavailable => (
+            "Service Unavailable",
+            "the server is temporarily unable to handle the request",
+        ),
+        ResponseType::GatewayTimeout => ("Gateway Timeout", "the proxy did not get a timely response from upstream"),
+        ResponseType::ProxyingNotSupported => (
+            "Proxying Not Supported",
+            "the server cannot act as a proxy for the requested URI",
+        ),
+        ResponseType::HopLimitReached => (
+            "Hop Limit Reached",
+            "the request passed through too many proxies",
+        ),
+        ResponseType::UnKnown => ("Unknown", "the response code is not registered"),
+    }
+}
+
+/// Parses a duration with a unit, e.g. `250ms`, `1.5s` or `2m`.
+pub fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: f64 = value.parse().map_err(|_| format!("invalid duration: {}", s))?;
+    let seconds = match unit {
+        "ms" => value / 1000.0,
+        "s" => value,
+        "m" => value * 60.0,
+        "" => return Err(format!("duration needs a unit (ms, s or m): {}", s)),
+        _ => return Err(format!("invalid duration unit: {}", unit)),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("invalid duration: {}", s))
+}
+
+/// Parses an ACK_RANDOM_FACTOR, which must be at least 1.
+pub fn parse_random_factor(s: &str) -> std::result::Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(factor) if factor >= 1.0 => Ok(factor),
+        _ => Err(format!("random factor must be a number of at least 1: {}", s)),
+    }
+}
+
+/// The number of the Hop-Limit option (RFC 8768), which coap-lite doesn't know.
+pub const HOP_LIMIT: u16 = 16;
+
+/// The number of the Echo option (RFC 9175), which coap-lite doesn't know.
+pub const ECHO: u16 = 252;
+
+/// The number of the Request-Tag option (RFC 9175), which coap-lite doesn't know.
+pub const REQUEST_TAG: u16 = 292;
+
+// registered option names, as written in RFC 7252 and its extensions
+const OPTION_NAMES: [(CoapOption, &str); 24] = [
+    (CoapOption::IfMatch, "If-Match"),
+    (CoapOption::UriHost, "Uri-Host"),
+    (CoapOption::ETag, "ETag"),
+    (CoapOption::IfNoneMatch, "If-None-Match"),
+    (CoapOption::Observe, "Observe"),
+    (CoapOption::UriPort, "Uri-Port"),
+    (CoapOption::LocationPath, "Location-Path"),
+    (CoapOption::Oscore, "OSCORE"),
+    (CoapOption::UriPath, "Uri-Path"),
+    (CoapOption::ContentFormat, "Content-Format"),
+    (CoapOption::MaxAge, "Max-Age"),
+    (CoapOption::UriQuery, "Uri-Query"),
+    (CoapOption::Unknown(HOP_LIMIT), "Hop-Limit"),
+    (CoapOption::Accept, "Accept"),
+    (CoapOption::LocationQuery, "Location-Query"),
+    (CoapOption::Block2, "Block2"),
+    (CoapOption::Block1, "Block1"),
+    (CoapOption::ProxyUri, "Proxy-Uri"),
+    (CoapOption::ProxyScheme, "Proxy-Scheme"),
+    (CoapOption::Size1, "Size1"),
+    (CoapOption::Size2, "Size2"),
+    (CoapOption::Unknown(ECHO), "Echo"),
+    (CoapOption::NoResponse, "No-Response"),
+    (CoapOption::Unknown(REQUEST_TAG), "Request-Tag"),
+];
+
+/// The registered name of an option, e.g. `Location-Path`, or its number if it has none.
+pub fn option_name(option: CoapOption) -> String {
+    OPTION_NAMES
+        .iter()
+        .find(|(known, _)| *known == option)
+        .map_or_else(|| u16::from(option).to_string(), |(_, name)| name.to_string())
+}
+
+/// Parses an option given by its registered name (case-insensitively, e.g. `uri-host`) or number.
+pub fn parse_option_name(s: &str) -> Result<CoapOption> {
+    if let Ok(number) = s.parse::<u16>() {
+        return Ok(CoapOption::from(number));
+    }
+    OPTION_NAMES
+        .iter()
+        .find(|(_, name)| name.eq_ignore_ascii_case(s))
+        .map(|(option, _)| *option)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("unknown option: {}", s)))
+}
+
+/// Parses a Request-Tag given as hex, which may be empty and at most 8 bytes long, into the option
+/// to add to requests.
+pub fn parse_request_tag(s: &str) -> std::result::Result<CustomOption, String> {
+    let value = parse_hex(s).map_err(|e| e.to_string())?;
+    if value.len() > 8 {
+        return Err(format!("Request-Tag longer than 8 bytes: {}", s));
+    }
+    Ok(CustomOption {
+        option: CoapOption::Unknown(REQUEST_TAG),
+        value,
+    })
+}
+
+/// An option given with `--option`, added to requests exactly as given.
+#[derive(Debug, Clone)]
+pub struct CustomOption {
+    pub option: CoapOption,
+    pub value: Vec<u8>,
+}
+
+/// Parses an option written as `OPTION:VALUE`, where the option is given by name or number and
+/// the value is `empty` or prefixed with its encoding: `str:`, `hex:`, `uint:` (in as few bytes
+/// as possible) or `u8:`, `u16:`, `u32:` (in exactly that many bytes), e.g. `65000:hex:0a1b`.
+pub fn parse_custom_option(s: &str) -> std::result::Result<CustomOption, String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected OPTION:VALUE: {}", s))?;
+    let option = parse_option_name(name).map_err(|e| e.to_string())?;
+    let number = |digits: &str| -> std::result::Result<u32, String> {
+        digits.parse().map_err(|_| format!("invalid number: {}", digits))
+    };
+    let too_large = || format!("value too large for its encoding: {}", value);
+    let value = match value.split_once(':') {
+        _ if value == "empty" => vec![],
+        Some(("str", text)) => text.as_bytes().to_vec(),
+        Some(("hex", digits)) => parse_hex(digits).map_err(|e| e.to_string())?,
+        Some(("uint", digits)) => encode_uint(number(digits)?),
+        Some(("u8", digits)) => vec![u8::try_from(number(digits)?).map_err(|_| too_large())?],
+        Some(("u16", digits)) => u16::try_from(number(digits)?)
+            .map_err(|_| too_large())?
+            .to_be_bytes()
+            .to_vec(),
+        Some(("u32", digits)) => number(digits)?.to_be_bytes().to_vec(),
+        _ => {
+            return Err(format!(
+                "expected empty or a value prefixed with str:, hex:, uint:, u8:, u16: or u32: {}",
+                value
+            ));
+        }
+    };
+    Ok(CustomOption { option, value })
+}
+
+/// Parses a token written in hex, which may be at most 8 bytes long.
+pub fn parse_token(s: &str) -> std::result::Result<Vec<u8>, String> {
+    let token = parse_hex(s).map_err(|e| e.to_string())?;
+    if token.len() > 8 {
+        return Err(format!("token must be at most 8 bytes: {}", s));
+    }
+    Ok(token)
+}
+
+/// Parses a message ID, written in decimal or, prefixed with `0x`, in hex.
+pub fn parse_message_id(s: &str) -> std::result::Result<u16, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(digits) => u16::from_str_radix(digits, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("invalid message ID (0 to 65535, or 0x0000 to 0xffff): {}", s))
+}
+
+/// The No-Response (RFC 7967) value that suppresses responses of every class.
+pub const NO_RESPONSE_ALL: u8 = 2 | 8 | 16;
+
+/// Parses the response classes `--no-response` suppresses, a comma-separated list of `2xx`,
+/// `4xx` and `5xx`, or `all`, `none` or the option value as a number, into the option value.
+pub fn parse_no_response(s: &str) -> std::result::Result<u8, String> {
+    if let Ok(value) = s.parse::<u8>() {
+        return Ok(value);
+    }
+    s.split(',').try_fold(0, |value, class| {
+        Ok(value
+            | match class.trim() {
+                "2xx" => 2,
+                "4xx" => 8,
+                "5xx" => 16,
+                "all" => NO_RESPONSE_ALL,
+                "none" => 0,
+                other => {
+                    return Err(format!(
+                        "expected 2xx, 4xx, 5xx, all, none or a number: {}",
+                        other
+                    ));
+                }
+            })
+    })
+}
+
+/// Encodes a uint option value in as few bytes as possible, as RFC 7252 requires.
+pub fn encode_uint(number: u32) -> Vec<u8> {
+    let bytes = number.to_be_bytes();
+    let leading = bytes.iter().take_while(|b| **b == 0).count();
+    bytes[leading..].to_vec()
+}
+
+/// Encodes an option value given on the command line according to the option's registered
+/// format: a number for uint options, text for string options, and `0x`-prefixed hex or text
+/// for opaque ones.
+pub fn encode_option_value(option: CoapOption, value: &str) -> Result<Vec<u8>> {
+    match option {
+        CoapOption::Observe
+        | CoapOption::UriPort
+        | CoapOption::ContentFormat
+        | CoapOption::MaxAge
+        | CoapOption::Accept
+        | CoapOption::Block1
+        | CoapOption::Block2
+        | CoapOption::Size1
+        | CoapOption::Size2
+        | CoapOption::NoResponse => {
+            let number: u32 = value.parse().map_err(|_| {
+                Error::new(ErrorKind::InvalidInput, format!("invalid {:?} value: {}", option, value))
+            })?;
+            Ok(encode_uint(number))
+        }
+        CoapOption::UriHost
+        | CoapOption::LocationPath
+        | CoapOption::UriPath
+        | CoapOption::UriQuery
+        | CoapOption::LocationQuery
+        | CoapOption::ProxyUri
+        | CoapOption::ProxyScheme => Ok(value.as_bytes().to_vec()),
+        _ => match value.strip_prefix("0x") {
+            Some(digits) => parse_hex(digits),
+            None => Ok(value.as_bytes().to_vec()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(url: &str) -> String {
+        CoapUri::parse(url).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn rejects_unsupported_scheme() {
+        assert_eq!(
+            error("http://sensor.local/temp"),
+            "unsupported scheme 'http'; expected coap, coaps, coap+tcp, coaps+tcp, coap+ws, coaps+ws"
+        );
+        assert!(CoapUri::parse_any_scheme("http://sensor.local/temp").is_ok());
+    }
+
+    #[test]
+    fn default_ports() {
+        let ports = [
+            ("coap", 5683),
+            ("coaps", 5684),
+            ("coap+tcp", 5683),
+            ("coaps+tcp", 5684),
+            ("coap+ws", 80),
+            ("coaps+ws", 443),
+        ];
+        for (scheme, port) in ports {
+            let uri = CoapUri::parse(&format!("{}://sensor.local/temp", scheme)).unwrap();
+            assert_eq!(uri.port, None, "{}", scheme);
+            assert_eq!(uri.port_or_default(), port, "{}", scheme);
+        }
+        let uri = CoapUri::parse("coaps://sensor.local:6000/temp").unwrap();
+        assert_eq!(uri.port_or_default(), 6000);
+    }
+
+    #[test]
+    fn rejects_missing_host() {
+        assert_eq!(error("coap:///temp"), "'coap:///temp' has no host");
+        assert_eq!(
+            error("sensor.local/temp"),
+            "'sensor.local/temp' has no scheme; expected a URI such as coap://sensor.local/temp"
+        );
+    }
+
+    #[test]
+    fn rejects_fragment() {
+        assert_eq!(
+            error("coap://sensor.local/temp#now"),
+            "'coap://sensor.local/temp#now' has a fragment, which a CoAP URI can't have"
+        );
+    }
+
+    #[test]
+    fn ipv6_hosts_and_zones() {
+        let uri = CoapUri::parse("coap://[2001:db8::1]:5700/temp").unwrap();
+        assert_eq!(uri.host, "2001:db8::1");
+        assert_eq!(uri.port, Some(5700));
+
+        for url in ["coap://[fe80::1%25eth0]/temp", "coap://[fe80::1%eth0]/temp"] {
+            let uri = CoapUri::parse(url).unwrap();
+            assert_eq!(uri.host, "fe80::1%eth0", "{}", url);
+            assert_eq!(uri.path, "/temp", "{}", url);
+        }
+    }
+
+    #[test]
+    fn percent_decodes_host() {
+        let uri = CoapUri::parse("coap://sensor%2D1.local/temp").unwrap();
+        assert_eq!(uri.host, "sensor-1.local");
+    }
+
+    #[test]
+    fn percent_decodes_path_and_query() {
+        let uri = CoapUri::parse("coap://sensor.local/a%20b/c%2Fd?unit=%C2%B0C&raw&q=a%26b").unwrap();
+        assert_eq!(uri.path, "/a%20b/c%2Fd");
+        assert_eq!(uri.path_segments(), vec![b"a b".to_vec(), b"c/d".to_vec()]);
+        assert_eq!(
+            uri.query_pairs(),
+            vec![
+                ("unit".to_string(), "°C".to_string()),
+                ("raw".to_string(), String::new()),
+                ("q".to_string(), "a&b".to_string()),
+            ]
+        );
+    }
 }
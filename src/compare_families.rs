@@ -0,0 +1,91 @@
+use coap_lite::{CoapRequest, CoapResponse};
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::coap_helper::*;
+use crate::exchange;
+use crate::transport::{new_udp_connection_to_addr, Connection};
+use crate::{resolve, Args};
+
+struct FamilyResult {
+    peer_addr: SocketAddr,
+    response: Result<CoapResponse>,
+    rtt: Duration,
+}
+
+fn describe(result: &FamilyResult) -> (String, String, String) {
+    match &result.response {
+        Ok(response) => (
+            response.message.header.code.to_string(),
+            format!("{}ms", result.rtt.as_millis()),
+            format!("{} bytes", response.message.payload.len()),
+        ),
+        Err(e) => (format!("error: {}", e), "-".to_string(), "-".to_string()),
+    }
+}
+
+/// Sends the request to the host's first IPv4 and first IPv6 address, and prints the responses
+/// side by side. Fails if they differ in code or payload.
+pub async fn compare_families(args: &Args, request: CoapRequest<SocketAddr>) -> Result<()> {
+    let uri = CoapUri::parse(&args.url)?;
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((uri.host.as_str(), uri.port.unwrap_or(5683)))
+        .await?
+        .collect();
+    let (Some(v4), Some(v6)) = (
+        addresses.iter().find(|a| a.is_ipv4()),
+        addresses.iter().find(|a| a.is_ipv6()),
+    ) else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} does not have both IPv4 and IPv6 addresses", uri.host),
+        ));
+    };
+
+    let mut results = vec![];
+    for peer_addr in [*v4, *v6] {
+        let local_addr = resolve::local_addr(args, peer_addr)?;
+        let mut conn: Connection = new_udp_connection_to_addr(peer_addr, local_addr).await?;
+        conn.client.set_receive_timeout(Duration::new(args.timeout, 0));
+        let start = Instant::now();
+        let response = exchange(&conn, args, request.clone()).await;
+        results.push(FamilyResult {
+            peer_addr,
+            response,
+            rtt: start.elapsed(),
+        });
+    }
+
+    let columns: Vec<_> = results.iter().map(describe).collect();
+    let headings: Vec<_> = results.iter().map(|r| r.peer_addr.to_string()).collect();
+    let width = headings
+        .iter()
+        .chain(columns.iter().map(|(code, _, _)| code))
+        .map(String::len)
+        .max()
+        .unwrap_or(0);
+    println!("{:8}  {:width$}  {}", "", headings[0], headings[1], width = width);
+    println!("{:8}  {:width$}  {}", "code", columns[0].0, columns[1].0, width = width);
+    println!("{:8}  {:width$}  {}", "latency", columns[0].1, columns[1].1, width = width);
+    println!("{:8}  {:width$}  {}", "payload", columns[0].2, columns[1].2, width = width);
+
+    let (Ok(first), Ok(second)) = (&results[0].response, &results[1].response) else {
+        return Err(Error::other("the request failed over one of the address families"));
+    };
+    let mut differences = vec![];
+    if first.message.header.code != second.message.header.code {
+        differences.push("code".to_string());
+    }
+    let (a, b) = (&first.message.payload, &second.message.payload);
+    if a != b {
+        let offset = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+        differences.push(format!("payload (from byte {})", offset));
+    }
+    if differences.is_empty() {
+        println!("responses match");
+        Ok(())
+    } else {
+        println!("responses differ in {}", differences.join(", "));
+        Err(Error::other("responses differ between address families"))
+    }
+}
@@ -0,0 +1,215 @@
+use clap::ValueEnum;
+use coap_lite::{ContentFormat, Packet};
+use std::io::{Error, ErrorKind, Result};
+
+use crate::cbor;
+use crate::coap_helper::content_format_name;
+use crate::link_format::{links_table, parse_links};
+use crate::senml;
+
+/// Encodings a payload written as JSON can be sent in.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum PayloadEncoding {
+    /// CBOR, with SenML's integer labels for its fields if the content format is
+    /// application/senml+cbor
+    Cbor,
+}
+
+/// Formats response payloads can be translated to.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum DecodeFormat {
+    /// JSON, from CBOR
+    Json,
+    /// A table of the records of SenML, in JSON or CBOR, with their base fields applied
+    Senml,
+}
+
+/// How response payloads are printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rendering {
+    /// As they are
+    Verbatim,
+    /// As [`render_payload`] does
+    Readable,
+    /// As [`render_payload`] does, but with CBOR translated to JSON
+    Json,
+    /// As [`render_payload`] does, but with SenML laid out as a table of its resolved records
+    Senml,
+}
+
+/// Encodes a payload written as JSON, returning it with the content format to send it with.
+pub fn encode_payload(
+    data: &[u8],
+    encoding: PayloadEncoding,
+    content_format: Option<ContentFormat>,
+) -> Result<(Vec<u8>, ContentFormat)> {
+    let value: serde_json::Value = serde_json::from_slice(data)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("invalid JSON data: {}", e)))?;
+    match encoding {
+        PayloadEncoding::Cbor => {
+            let content_format = content_format.unwrap_or(ContentFormat::ApplicationCBOR);
+            let labels = is_senml_cbor(content_format).then_some(&cbor::SENML);
+            Ok((cbor::from_json(&value, labels), content_format))
+        }
+    }
+}
+
+fn is_senml_cbor(content_format: ContentFormat) -> bool {
+    matches!(
+        content_format,
+        ContentFormat::ApplicationSenmlCBOR | ContentFormat::ApplicationSensmlCBOR
+    )
+}
+
+/// Shows where a parse error is in a line of text: the line, and a caret under the column.
+fn point_at(text: &str, line: usize, column: usize) -> String {
+    let line = text.lines().nth(line.saturating_sub(1)).unwrap_or_default();
+    format!("\n  {}\n  {:>width$}", line, "^", width = column.max(1))
+}
+
+fn invalid(content_format: ContentFormat, problem: impl std::fmt::Display) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("payload is not valid {}: {}", content_format_name(usize::from(content_format)), problem),
+    )
+}
+
+fn check_json(data: &[u8], content_format: ContentFormat) -> Result<serde_json::Value> {
+    serde_json::from_slice(data).map_err(|e| {
+        let text = String::from_utf8_lossy(data);
+        invalid(content_format, format!("{}{}", e, point_at(&text, e.line(), e.column())))
+    })
+}
+
+/// Checks that a SenML pack is what RFC 8428 section 4 says it is: an array of records, each an
+/// object.
+fn check_senml(pack: &serde_json::Value, content_format: ContentFormat) -> Result<()> {
+    let records = pack
+        .as_array()
+        .ok_or_else(|| invalid(content_format, "a SenML pack must be an array of records"))?;
+    match records.iter().position(|record| !record.is_object()) {
+        Some(i) => Err(invalid(content_format, format!("record {} is not an object", i))),
+        None => Ok(()),
+    }
+}
+
+/// Checks, for `--validate`, that a payload is what its Content-Format says it is, so that a
+/// malformed one is caught before it is sent rather than answered with 4.00 Bad Request: that
+/// JSON parses, showing where it doesn't, that CBOR is well-formed, that a SenML pack is an array
+/// of records, that link format parses and that text is UTF-8. Payloads of other formats aren't
+/// checked.
+pub fn validate_payload(data: &[u8], content_format: Option<ContentFormat>) -> Result<()> {
+    let Some(content_format) = content_format else {
+        return Ok(());
+    };
+    match content_format {
+        ContentFormat::ApplicationSenmlJSON | ContentFormat::ApplicationSensmlJSON => {
+            check_senml(&check_json(data, content_format)?, content_format)
+        }
+        ContentFormat::ApplicationJSON
+        | ContentFormat::ApplicationMergePatchJson
+        | ContentFormat::ApplicationJsonPatchJson
+        | ContentFormat::ApplicationTdJson
+        | ContentFormat::ApplicationVndOmaLwm2mJson => check_json(data, content_format).map(drop),
+        ContentFormat::ApplicationSenmlCBOR | ContentFormat::ApplicationSensmlCBOR => {
+            cbor::check(data, false).map_err(|e| invalid(content_format, e))?;
+            let pack = cbor::to_json(data, Some(&cbor::SENML))
+                .map_err(|e| invalid(content_format, e))?;
+            check_senml(&pack, content_format)
+        }
+        ContentFormat::ApplicationCBOR | ContentFormat::ApplicationVndOmaLwm2mCbor => {
+            cbor::check(data, false).map_err(|e| invalid(content_format, e))
+        }
+        ContentFormat::ApplicationCborSeq => {
+            cbor::check(data, true).map_err(|e| invalid(content_format, e))
+        }
+        ContentFormat::ApplicationLinkFormat | ContentFormat::TextPlain => {
+            let text = std::str::from_utf8(data).map_err(|e| invalid(content_format, e))?;
+            if content_format == ContentFormat::ApplicationLinkFormat {
+                parse_links(text).map_err(|e| invalid(content_format, e))?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The payload as a JSON value, if its Content-Format is JSON or CBOR and it is what that claims;
+/// CBOR is translated, with SenML's integer labels named for SenML CBOR.
+pub fn payload_json(message: &Packet) -> Option<serde_json::Value> {
+    let payload = &message.payload;
+    match message.get_content_format()? {
+        ContentFormat::ApplicationJSON
+        | ContentFormat::ApplicationMergePatchJson
+        | ContentFormat::ApplicationJsonPatchJson
+        | ContentFormat::ApplicationSenmlJSON
+        | ContentFormat::ApplicationSensmlJSON
+        | ContentFormat::ApplicationTdJson
+        | ContentFormat::ApplicationVndOmaLwm2mJson => serde_json::from_slice(payload).ok(),
+        content_format @ (ContentFormat::ApplicationCBOR
+        | ContentFormat::ApplicationSenmlCBOR
+        | ContentFormat::ApplicationSensmlCBOR
+        | ContentFormat::ApplicationVndOmaLwm2mCbor) => {
+            let labels = is_senml_cbor(content_format).then_some(&cbor::SENML);
+            cbor::to_json(payload, labels).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Renders a payload for reading according to its Content-Format: JSON is pretty-printed, CBOR is
+/// shown in diagnostic notation (or as JSON if `rendering` asks for it), SenML as a table of its
+/// records if `rendering` asks for that, and link format as a table of the links. Anything else,
+/// or anything that isn't what its Content-Format claims, is shown as text.
+pub fn render_payload(message: &Packet, rendering: Rendering) -> String {
+    let payload = &message.payload;
+    if payload.is_empty() {
+        return String::new();
+    }
+    if rendering == Rendering::Senml
+        && let Some(records) = senml::message_records(message)
+    {
+        return senml::records_table(&records);
+    }
+    let rendered = match message.get_content_format() {
+        Some(
+            ContentFormat::ApplicationJSON
+            | ContentFormat::ApplicationMergePatchJson
+            | ContentFormat::ApplicationJsonPatchJson
+            | ContentFormat::ApplicationSenmlJSON
+            | ContentFormat::ApplicationSensmlJSON
+            | ContentFormat::ApplicationTdJson
+            | ContentFormat::ApplicationVndOmaLwm2mJson,
+        ) => serde_json::from_slice::<serde_json::Value>(payload)
+            .ok()
+            .and_then(|value| serde_json::to_string_pretty(&value).ok()),
+        Some(
+            ContentFormat::ApplicationCBOR
+            | ContentFormat::ApplicationCborSeq
+            | ContentFormat::ApplicationSenmlCBOR
+            | ContentFormat::ApplicationSensmlCBOR
+            | ContentFormat::ApplicationVndOmaLwm2mCbor,
+        ) if rendering == Rendering::Json => {
+            let labels = message
+                .get_content_format()
+                .is_some_and(is_senml_cbor)
+                .then_some(&cbor::SENML);
+            cbor::to_json(payload, labels)
+                .ok()
+                .and_then(|value| serde_json::to_string_pretty(&value).ok())
+        }
+        Some(
+            ContentFormat::ApplicationCBOR
+            | ContentFormat::ApplicationCborSeq
+            | ContentFormat::ApplicationSenmlCBOR
+            | ContentFormat::ApplicationSensmlCBOR
+            | ContentFormat::ApplicationVndOmaLwm2mCbor,
+        ) => cbor::diagnostic(payload).ok(),
+        Some(ContentFormat::ApplicationLinkFormat) => std::str::from_utf8(payload)
+            .ok()
+            .and_then(|text| parse_links(text).ok())
+            .map(|links| links_table(&links)),
+        _ => None,
+    };
+    rendered.unwrap_or_else(|| String::from_utf8_lossy(payload).into_owned())
+}
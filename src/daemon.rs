@@ -0,0 +1,263 @@
+use base64::Engine;
+use coap_lite::Packet;
+use futures::future;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::OnceCell;
+
+use crate::batch::parse_method;
+use crate::coap_helper::*;
+use crate::transport::Connection;
+use crate::{create_connection_to, exchange, report, Args};
+
+/// A request passed to the daemon, as a line of JSON, e.g.
+/// `{"url": "coaps://sensor.local/temp", "method": "GET", "accept": "application/cbor"}`. The
+/// content formats are given by name or number, and the payload as text or in base64.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DaemonRequest {
+    pub url: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    pub accept: Option<String>,
+    pub content_format: Option<String>,
+    pub payload: Option<String>,
+    pub payload_base64: Option<String>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+/// The server a connection is to, as the scheme, host and port it is reached with.
+type ServerKey = (String, String, u16);
+
+/// A connection to a server, set up by the first request to need it. Requests to the server
+/// that arrive while it is being set up wait for it, and those to other servers don't.
+type ConnectionCell = Arc<OnceCell<Arc<Connection>>>;
+
+/// Connections to the servers requests have been made of, kept open with their DTLS sessions
+/// and OSCORE contexts for the requests after.
+struct Daemon {
+    args: Args,
+    connections: Mutex<HashMap<ServerKey, ConnectionCell>>,
+}
+
+impl Daemon {
+    fn server_key(&self, url: &str) -> Result<ServerKey> {
+        // with a forward proxy, every request goes to it
+        let uri = CoapUri::parse(self.args.proxy.as_deref().unwrap_or(url))?;
+        let port = uri.port_or_default();
+        Ok((uri.scheme, uri.host, port))
+    }
+
+    /// The connection to the URL's server, set up if there isn't one yet.
+    async fn connection(&self, url: &str) -> Result<Arc<Connection>> {
+        let key = self.server_key(url)?;
+        let cell = self.connections.lock().unwrap().entry(key).or_default().clone();
+        let conn = cell
+            .get_or_try_init(|| async {
+                let conn = create_connection_to(&self.args, url).await?;
+                info!("connected to {}", conn.peer_addr);
+                Ok::<_, Error>(Arc::new(conn))
+            })
+            .await?;
+        Ok(conn.clone())
+    }
+
+    /// Forgets the connection to the URL's server, after it failed, so that the next request sets
+    /// up a new one, e.g. with a new DTLS session once the server has restarted.
+    async fn disconnect(&self, url: &str) {
+        if let Ok(key) = self.server_key(url) {
+            self.connections.lock().unwrap().remove(&key);
+        }
+    }
+
+    async fn handle(&self, request: &DaemonRequest) -> Result<Value> {
+        let invalid = |e: String| Error::new(ErrorKind::InvalidInput, e);
+        let method = parse_method(&request.method)?;
+        let payload = match (&request.payload, &request.payload_base64) {
+            (Some(text), _) => Some(text.as_bytes().to_vec()),
+            (None, Some(encoded)) => Some(
+                base64::engine::general_purpose::STANDARD
+                    .decode(encoded)
+                    .map_err(|e| invalid(format!("invalid payload_base64: {}", e)))?,
+            ),
+            (None, None) => None,
+        };
+        let content_format = request.content_format.as_deref().map(parse_content_format).transpose()?;
+        let accept = request.accept.as_deref().map(parse_content_format).transpose()?;
+        let mut coap_request = build_coap_request_for_url(
+            &request.url,
+            method,
+            payload,
+            content_format,
+            accept.map(|accept| vec![accept]),
+        )?;
+        // requests from several clients may be in flight together, and responses are routed by
+        // token
+        if !self.args.token_chosen() {
+            coap_request.message.set_token(rand::random::<[u8; 8]>().to_vec());
+        }
+
+        let conn = self.connection(&request.url).await?;
+        let args = Args {
+            url: request.url.clone(),
+            ..self.args.clone()
+        };
+        info!("{} {}", request.method.to_uppercase(), request.url);
+        let start = Instant::now();
+        let response = match exchange(&conn, &args, coap_request).await {
+            Ok(response) => response,
+            Err(e) => {
+                if e.kind() != ErrorKind::InvalidInput {
+                    self.disconnect(&request.url).await;
+                }
+                return Err(e);
+            }
+        };
+        let mut reply = report::message_json(&response.message);
+        reply["rtt_ms"] = Value::from(start.elapsed().as_secs_f64() * 1000.0);
+        let raw = response.message.to_bytes().map_err(|e| Error::other(format!("{:?}", e)))?;
+        reply["raw"] = Value::from(base64::engine::general_purpose::STANDARD.encode(raw));
+        Ok(reply)
+    }
+
+    /// Answers the requests a client sends, one line of JSON for each.
+    #[cfg(unix)]
+    async fn serve_client(&self, stream: tokio::net::UnixStream) -> Result<()> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let result = match serde_json::from_str::<DaemonRequest>(&line) {
+                Ok(request) => self.handle(&request).await,
+                Err(e) => Err(Error::new(ErrorKind::InvalidInput, format!("invalid request: {}", e))),
+            };
+            let reply = result.unwrap_or_else(|e| {
+                warn!("{}", e);
+                serde_json::json!({ "error": e.to_string(), "kind": kind_name(e.kind()) })
+            });
+            writer.write_all(format!("{}\n", reply).as_bytes()).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The names of the kinds of error passed on to clients, by which the exit status of `send`
+/// stays that of the failure.
+const ERROR_KINDS: [(ErrorKind, &str); 7] = [
+    (ErrorKind::TimedOut, "timed_out"),
+    (ErrorKind::ConnectionRefused, "connection_refused"),
+    (ErrorKind::ConnectionReset, "connection_reset"),
+    (ErrorKind::HostUnreachable, "host_unreachable"),
+    (ErrorKind::NetworkUnreachable, "network_unreachable"),
+    (ErrorKind::InvalidInput, "invalid_input"),
+    (ErrorKind::Unsupported, "unsupported"),
+];
+
+fn kind_name(kind: ErrorKind) -> &'static str {
+    ERROR_KINDS
+        .iter()
+        .find(|(k, _)| *k == kind)
+        .map_or("other", |(_, name)| name)
+}
+
+fn parse_kind(name: &str) -> ErrorKind {
+    ERROR_KINDS
+        .iter()
+        .find(|(_, n)| *n == name)
+        .map_or(ErrorKind::Other, |(kind, _)| *kind)
+}
+
+/// Listens on a Unix socket for requests, passed as lines of JSON such as those `send
+/// --via-daemon` passes, and makes them over connections it keeps open to their servers: the
+/// URL's and `devices`, connected to at the start, and any other on its first request. Each
+/// response goes back as a line of JSON, that of `--output json` plus the time it took and the
+/// whole message in base64, or an error. The socket is only accessible to its owner.
+#[cfg(unix)]
+pub async fn coap_daemon(args: &Args, socket: &Path, devices: &[String]) -> Result<()> {
+    if socket.exists() {
+        if tokio::net::UnixStream::connect(socket).await.is_ok() {
+            return Err(Error::new(
+                ErrorKind::AddrInUse,
+                format!("a daemon is already listening on {}", socket.display()),
+            ));
+        }
+        // left behind by a daemon that didn't get to clean up
+        std::fs::remove_file(socket)?;
+    }
+    let listener = tokio::net::UnixListener::bind(socket)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", socket.display(), e)))?;
+    let owner_only = <std::fs::Permissions as std::os::unix::fs::PermissionsExt>::from_mode(0o600);
+    std::fs::set_permissions(socket, owner_only)?;
+
+    let daemon = Arc::new(Daemon {
+        args: args.clone(),
+        connections: Mutex::new(HashMap::new()),
+    });
+    let urls = (!args.url.is_empty()).then_some(&args.url).into_iter().chain(devices);
+    future::try_join_all(urls.map(|url| daemon.connection(url))).await?;
+    info!("listening on {}", socket.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let daemon = daemon.clone();
+        tokio::spawn(async move {
+            if let Err(e) = daemon.serve_client(stream).await {
+                warn!("client: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn coap_daemon(_args: &Args, _socket: &Path, _devices: &[String]) -> Result<()> {
+    Err(Error::new(ErrorKind::Unsupported, "the daemon needs Unix domain sockets"))
+}
+
+/// Passes a request to the daemon listening on `socket` and returns the response it got, or the
+/// error it failed with.
+#[cfg(unix)]
+pub async fn send_via_daemon(socket: &Path, request: &DaemonRequest) -> Result<Packet> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let stream = tokio::net::UnixStream::connect(socket).await.map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("no daemon listening on {}: {}", socket.display(), e),
+        )
+    })?;
+    let (reader, mut writer) = stream.into_split();
+    let line = serde_json::to_string(request).map_err(Error::other)?;
+    writer.write_all(format!("{}\n", line).as_bytes()).await?;
+    let reply = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "the daemon closed the connection"))?;
+    let reply: Value = serde_json::from_str(&reply).map_err(Error::other)?;
+    if let Some(error) = reply["error"].as_str() {
+        let kind = parse_kind(reply["kind"].as_str().unwrap_or(""));
+        return Err(Error::new(kind, error.to_string()));
+    }
+    let raw = reply["raw"]
+        .as_str()
+        .and_then(|raw| base64::engine::general_purpose::STANDARD.decode(raw).ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "the daemon's reply has no message"))?;
+    Packet::from_bytes(&raw).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+#[cfg(not(unix))]
+pub async fn send_via_daemon(_socket: &Path, _request: &DaemonRequest) -> Result<Packet> {
+    Err(Error::new(ErrorKind::Unsupported, "the daemon needs Unix domain sockets"))
+}
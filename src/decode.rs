@@ -0,0 +1,57 @@
+use coap_lite::Packet;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::Path;
+
+use crate::coap_helper::parse_hex;
+use crate::content::{self, Rendering};
+use crate::message_dump::trace_message;
+use crate::Args;
+
+/// Parses a message written in hex as Wireshark or a serial console might show it: optionally
+/// prefixed with `0x`, with its bytes separated by spaces, colons or newlines, or not at all.
+fn parse_hex_dump(s: &str) -> Result<Vec<u8>> {
+    let s = s.trim();
+    let s = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+    let digits: String = s.chars().filter(|c| !c.is_whitespace() && *c != ':').collect();
+    parse_hex(&digits)
+}
+
+fn read_file(file: &Path) -> Result<Vec<u8>> {
+    if file.as_os_str() == "-" {
+        let mut bytes = vec![];
+        std::io::stdin().lock().read_to_end(&mut bytes)?;
+        return Ok(bytes);
+    }
+    std::fs::read(file).map_err(|e| Error::new(e.kind(), format!("{}: {}", file.display(), e)))
+}
+
+/// Prints a message field by field, followed by its payload rendered according to its
+/// Content-Format if it has one.
+fn print_decoded(bytes: &[u8], rendering: Rendering) -> Result<()> {
+    print!("{}", trace_message(bytes));
+    let message = Packet::from_bytes(bytes)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("could not decode message: {:?}", e)))?;
+    if message.get_content_format().is_some() && !message.payload.is_empty() {
+        println!("  decoded payload:");
+        for line in content::render_payload(&message, rendering).lines() {
+            println!("    {}", line);
+        }
+    }
+    Ok(())
+}
+
+/// Decodes CoAP messages given in hex on the command line, or as raw bytes in a file.
+pub fn coap_decode(args: &Args, messages: &[String], file: Option<&Path>) -> Result<()> {
+    let messages = match file {
+        Some(file) => vec![read_file(file)?],
+        None => messages.iter().map(|m| parse_hex_dump(m)).collect::<Result<_>>()?,
+    };
+    for (i, bytes) in messages.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("message of {} bytes:", bytes.len());
+        print_decoded(bytes, args.rendering())?;
+    }
+    Ok(())
+}
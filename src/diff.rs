@@ -0,0 +1,232 @@
+use coap_lite::{CoapResponse, MessageClass, Packet, RequestType};
+use log::info;
+use serde_json::{json, Value};
+use std::io::{Error, Result};
+use std::time::Duration;
+
+use crate::coap_helper::*;
+use crate::report::OutputFormat;
+use crate::transport::Connection;
+use crate::{content, create_connection_to, exchange, mirror, same_server, Args};
+
+/// A difference between two representations, at a place in them given as a JSON Pointer.
+pub enum Change {
+    Added { path: String, new: Value },
+    Removed { path: String, old: Value },
+    Changed { path: String, old: Value, new: Value },
+}
+
+/// A representation, decoded as far as its Content-Format allows for comparing.
+enum Representation {
+    Structured(Value),
+    Text(String),
+}
+
+fn decode(message: &Packet) -> Representation {
+    match content::payload_json(message) {
+        Some(value) => Representation::Structured(value),
+        None => Representation::Text(String::from_utf8_lossy(&message.payload).into_owned()),
+    }
+}
+
+/// Escapes a map key for a JSON Pointer (RFC 6901).
+fn pointer_segment(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// Collects the differences between two values: map entries by key, array elements by index.
+fn diff_values(path: &str, old: &Value, new: &Value, changes: &mut Vec<Change>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, old_value) in old {
+                let path = format!("{}/{}", path, pointer_segment(key));
+                match new.get(key) {
+                    Some(new_value) => diff_values(&path, old_value, new_value, changes),
+                    None => changes.push(Change::Removed {
+                        path,
+                        old: old_value.clone(),
+                    }),
+                }
+            }
+            for (key, new_value) in new {
+                if !old.contains_key(key) {
+                    changes.push(Change::Added {
+                        path: format!("{}/{}", path, pointer_segment(key)),
+                        new: new_value.clone(),
+                    });
+                }
+            }
+        }
+        (Value::Array(old), Value::Array(new)) => {
+            for i in 0..old.len().max(new.len()) {
+                let path = format!("{}/{}", path, i);
+                match (old.get(i), new.get(i)) {
+                    (Some(old), Some(new)) => diff_values(&path, old, new, changes),
+                    (Some(old), None) => changes.push(Change::Removed {
+                        path,
+                        old: old.clone(),
+                    }),
+                    (None, Some(new)) => changes.push(Change::Added {
+                        path,
+                        new: new.clone(),
+                    }),
+                    (None, None) => {}
+                }
+            }
+        }
+        _ if old == new => {}
+        _ => changes.push(Change::Changed {
+            path: path.to_string(),
+            old: old.clone(),
+            new: new.clone(),
+        }),
+    }
+}
+
+/// Collects the lines removed from and added to a text, in the order they appear, by way of the
+/// longest common subsequence of lines. Each is given as a change at its line number.
+fn diff_lines(old: &str, new: &str, changes: &mut Vec<Change>) {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    // common[i][j]: the length of the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = match old[i] == new[j] {
+                true => common[i + 1][j + 1] + 1,
+                false => common[i + 1][j].max(common[i][j + 1]),
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j == new.len() || i < old.len() && common[i + 1][j] >= common[i][j + 1] {
+            changes.push(Change::Removed {
+                path: format!("line {}", i + 1),
+                old: Value::from(old[i]),
+            });
+            i += 1;
+        } else {
+            changes.push(Change::Added {
+                path: format!("line {}", j + 1),
+                new: Value::from(new[j]),
+            });
+            j += 1;
+        }
+    }
+}
+
+/// How the second of two representations differs from the first.
+pub fn differences(old: &Packet, new: &Packet) -> Vec<Change> {
+    let mut changes = vec![];
+    match (decode(old), decode(new)) {
+        (Representation::Structured(old), Representation::Structured(new)) => {
+            diff_values("", &old, &new, &mut changes)
+        }
+        (Representation::Text(old), Representation::Text(new)) => {
+            diff_lines(&old, &new, &mut changes)
+        }
+        (old, new) => {
+            let value = |representation| match representation {
+                Representation::Structured(value) => value,
+                Representation::Text(text) => Value::from(text),
+            };
+            diff_values("", &value(old), &value(new), &mut changes)
+        }
+    }
+    changes
+}
+
+/// A change as a line of a diff, e.g. `~ /interval: 30 -> 60`.
+pub fn describe_change(change: &Change) -> String {
+    // the whole representation is at the empty pointer, which wouldn't show
+    let place = |path: &str| match path {
+        "" => "(whole)".to_string(),
+        path => path.to_string(),
+    };
+    match change {
+        Change::Added { path, new } => format!("+ {}: {}", place(path), new),
+        Change::Removed { path, old } => format!("- {}: {}", place(path), old),
+        Change::Changed { path, old, new } => format!("~ {}: {} -> {}", place(path), old, new),
+    }
+}
+
+fn change_json(change: &Change) -> Value {
+    match change {
+        Change::Added { path, new } => json!({ "op": "add", "path": path, "value": new }),
+        Change::Removed { path, old } => json!({ "op": "remove", "path": path, "old": old }),
+        Change::Changed { path, old, new } => {
+            json!({ "op": "replace", "path": path, "old": old, "value": new })
+        }
+    }
+}
+
+/// GETs a resource, failing unless the server responds with a representation of it.
+async fn get(conn: &Connection, args: &Args, url: &str) -> Result<CoapResponse> {
+    info!("GET {}", url);
+    let request = build_coap_request_for_url(url, RequestType::Get, None, None, None)?;
+    let response = exchange(conn, args, request).await?;
+    if let MessageClass::Response(code) = response.message.header.code
+        && code.is_error()
+    {
+        return Err(Error::other(format!("{}: {}", url, response.message.header.code)));
+    }
+    Ok(response)
+}
+
+/// GETs the resource and either `other`, which may be relative to the URL, or the resource again
+/// after `interval`, and prints how the second representation differs from the first. JSON and
+/// CBOR representations are compared as the structures they are, entry by entry, and reported at
+/// JSON Pointers into them; anything else is compared line by line. Fails if they differ.
+pub async fn coap_diff(
+    conn: &Connection,
+    args: &Args,
+    other: Option<&str>,
+    interval: Option<Duration>,
+) -> Result<()> {
+    let first = get(conn, args, &args.url).await?;
+    let (label, second) = match other {
+        Some(other) => {
+            let url = mirror::resource_url(&args.url, other)?;
+            let second = match args.proxy.is_some() || same_server(&url, &args.url)? {
+                true => get(conn, args, &url).await?,
+                false => get(&create_connection_to(args, &url).await?, args, &url).await?,
+            };
+            (url, second)
+        }
+        None => {
+            let interval = interval.unwrap_or_default();
+            tokio::time::sleep(interval).await;
+            let label = format!("{} after {}ms", args.url, interval.as_millis());
+            (label, get(conn, args, &args.url).await?)
+        }
+    };
+
+    if first.message.get_content_format() != second.message.get_content_format() {
+        info!("the representations are in different content formats");
+    }
+    let changes = differences(&first.message, &second.message);
+    match args.output {
+        OutputFormat::Json => {
+            for change in &changes {
+                println!("{}", change_json(change));
+            }
+        }
+        _ if changes.is_empty() => {}
+        _ => {
+            println!("--- {}", args.url);
+            println!("+++ {}", label);
+            for change in &changes {
+                println!("{}", describe_change(change));
+            }
+        }
+    }
+    info!("{} difference(s)", changes.len());
+    if !changes.is_empty() {
+        return Err(Error::other("the representations differ"));
+    }
+    Ok(())
+}
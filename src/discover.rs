@@ -0,0 +1,413 @@
+use clap::ValueEnum;
+use coap_lite::{MessageClass, RequestType};
+use log::info;
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::io::{Error, Result};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::coap_helper::*;
+use crate::dns::{self, RecordData};
+use crate::link_format::{links_table, parse_links, Link};
+use crate::mirror::resource_url;
+use crate::output;
+use crate::transport::Connection;
+use crate::multicast::multicast_exchange;
+use crate::report::{group_model, print_report};
+use crate::{create_connection_to, exchange, print_size_analysis, send_request, Args};
+
+/// The CoAP service types (RFC 7252 section 12.9), and the schemes of their URLs.
+const SERVICE_TYPES: [(&str, &str); 2] = [("_coap._udp", "coap"), ("_coaps._udp", "coaps")];
+
+/// The group of all CoAP nodes to send a multicast discovery request to.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum MulticastGroup {
+    /// 224.0.1.187
+    Ipv4,
+    /// ff02::fd, on the link
+    Ipv6,
+}
+
+impl MulticastGroup {
+    /// The All-CoAP-Nodes address (RFC 7252 section 12.8), as written in a URL.
+    fn host(self) -> &'static str {
+        match self {
+            MulticastGroup::Ipv4 => "224.0.1.187",
+            MulticastGroup::Ipv6 => "[ff02::fd]",
+        }
+    }
+}
+
+/// Which of the advertised resources to list, and how.
+pub struct DiscoverOptions<'a> {
+    pub rt: Option<&'a str>,
+    pub interface: Option<&'a str>,
+    pub json: bool,
+}
+
+/// Checks an attribute against a query filter (RFC 6690 section 4.1): the filter matches if it
+/// equals one of the attribute's space-separated values, or is a prefix of one when it ends in
+/// `*`.
+fn matches_filter(link: &Link, name: &str, filter: &str) -> bool {
+    let Some(value) = link.attr(name) else {
+        return false;
+    };
+    value.split_whitespace().any(|value| match filter.strip_suffix('*') {
+        Some(prefix) => value.starts_with(prefix),
+        None => value == filter,
+    })
+}
+
+fn list_values(link: &Link, name: &str) -> Vec<String> {
+    link.attr(name)
+        .map(|value| value.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+fn link_model(link: &Link) -> Value {
+    let attributes: serde_json::Map<String, Value> = link
+        .attributes
+        .iter()
+        .map(|(name, value)| (name.clone(), json!(value)))
+        .collect();
+    let ct: Vec<u64> = list_values(link, "ct").iter().filter_map(|ct| ct.parse().ok()).collect();
+    json!({
+        "href": link.target,
+        "rt": list_values(link, "rt"),
+        "if": list_values(link, "if"),
+        "ct": ct,
+        "sz": link.attr("sz").and_then(|sz| sz.parse::<u64>().ok()),
+        "obs": link.attr("obs").is_some(),
+        "attributes": attributes,
+    })
+}
+
+/// The address of `/.well-known/core` relative to `base`, with a query for the server to filter
+/// the links by.
+pub fn well_known_url(base: &str, options: &DiscoverOptions<'_>) -> Result<String> {
+    let url = resource_url(base, "/.well-known/core")?;
+    // servers accept a single filter, so any other is only applied to the links returned
+    Ok(match (options.rt, options.interface) {
+        (Some(rt), _) => format!("{}?rt={}", url, rt),
+        (None, Some(interface)) => format!("{}?if={}", url, interface),
+        (None, None) => url,
+    })
+}
+
+pub fn filter_links(payload: &[u8], options: &DiscoverOptions<'_>) -> Result<Vec<Link>> {
+    Ok(parse_links(&String::from_utf8_lossy(payload))?
+        .into_iter()
+        .filter(|link| options.rt.is_none_or(|rt| matches_filter(link, "rt", rt)))
+        .filter(|link| options.interface.is_none_or(|interface| matches_filter(link, "if", interface)))
+        .collect())
+}
+
+/// Lists the resources a server advertises in `/.well-known/core`, optionally only those with a
+/// given resource type or interface.
+pub async fn coap_discover(conn: &Connection, args: &Args, options: &DiscoverOptions<'_>) -> Result<()> {
+    let url = well_known_url(&args.url, options)?;
+    info!("GET {}", url);
+    let request = build_coap_request_for_url(&url, RequestType::Get, None, None, None)?;
+    let Some(response) = send_request(conn, args, request).await? else {
+        return Ok(());
+    };
+    output::print_code(&response.message, args);
+    if response.get_status().is_error() {
+        return Ok(());
+    }
+    let links = filter_links(&response.message.payload, options)?;
+    if options.json {
+        let links: Vec<Value> = links.iter().map(link_model).collect();
+        println!("{}", serde_json::to_string_pretty(&links).map_err(Error::other)?);
+    } else {
+        println!("{}", links_table(&links));
+        info!("{} resource(s)", links.len());
+    }
+    Ok(())
+}
+
+/// Asks every CoAP node on the local network for its resources, by sending the request for
+/// `/.well-known/core` to the All-CoAP-Nodes group, and lists the resources of each that answers
+/// within the timeout.
+pub async fn coap_discover_multicast(
+    args: &Args,
+    group: MulticastGroup,
+    options: &DiscoverOptions<'_>,
+) -> Result<()> {
+    let port = CoapUri::parse(&args.url)?.port.unwrap_or(5683);
+    let group_url = format!("coap://{}:{}", group.host(), port);
+    let url = well_known_url(&group_url, options)?;
+    info!("GET {}", url);
+    let request = build_coap_request_for_url(&url, RequestType::Get, None, None, None)?;
+    if args.dry_run || args.verbose > 0 {
+        print_size_analysis(&request.message);
+    }
+    if args.dry_run {
+        return Ok(());
+    }
+    let conn = create_connection_to(args, &url).await?;
+    let responses = multicast_exchange(&conn, args, request).await?;
+    if let Some(template) = &args.template {
+        return print_report(template, &group_model(&url, "GET", &responses));
+    }
+
+    let mut models = vec![];
+    for (source, response) in &responses {
+        let links = match response.header.code {
+            MessageClass::Response(code) if !code.is_error() => {
+                filter_links(&response.payload, options)?
+            }
+            _ => vec![],
+        };
+        if options.json {
+            models.push(json!({
+                "source": source.to_string(),
+                "code": response.header.code.to_string(),
+                "links": links.iter().map(link_model).collect::<Vec<_>>(),
+            }));
+        } else {
+            println!("{}  {}", source, response.header.code);
+            if !links.is_empty() {
+                println!("{}", links_table(&links));
+            }
+            println!();
+        }
+    }
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&models).map_err(Error::other)?);
+    }
+    info!("{} responder(s)", responses.len());
+    Ok(())
+}
+
+/// Browses the local network with multicast DNS for CoAP services, listing each instance found
+/// within the timeout, or with `json`, printing them as a JSON array once the timeout is up.
+pub async fn coap_discover_mdns(args: &Args, json: bool) -> Result<()> {
+    let daemon = ServiceDaemon::new().map_err(Error::other)?;
+    let mut receivers = vec![];
+    for (service_type, scheme) in SERVICE_TYPES {
+        let service_type = format!("{}.local.", service_type);
+        info!("browsing {}", service_type);
+        receivers.push((daemon.browse(&service_type).map_err(Error::other)?, scheme));
+    }
+
+    let mut seen = HashSet::new();
+    let mut models = vec![];
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(args.timeout);
+    loop {
+        let next = async {
+            tokio::select! {
+                event = receivers[0].0.recv_async() => (event, receivers[0].1),
+                event = receivers[1].0.recv_async() => (event, receivers[1].1),
+            }
+        };
+        let Ok((event, scheme)) = tokio::time::timeout_at(deadline, next).await else {
+            break;
+        };
+        let Ok(ServiceEvent::ServiceResolved(service)) = event else {
+            continue;
+        };
+        if !seen.insert(service.get_fullname().to_string()) {
+            continue;
+        }
+        let mut addresses: Vec<_> = service.get_addresses().iter().map(|a| a.to_ip_addr()).collect();
+        addresses.sort();
+        let urls: Vec<String> = addresses
+            .iter()
+            .map(|address| match address {
+                std::net::IpAddr::V4(a) => format!("{}://{}:{}", scheme, a, service.get_port()),
+                std::net::IpAddr::V6(a) => format!("{}://[{}]:{}", scheme, a, service.get_port()),
+            })
+            .collect();
+        if json {
+            let txt: serde_json::Map<String, Value> = service
+                .get_properties()
+                .iter()
+                .map(|property| (property.key().to_string(), json!(property.val_str())))
+                .collect();
+            models.push(json!({
+                "name": service.get_fullname(),
+                "host": service.get_hostname(),
+                "addresses": addresses,
+                "port": service.get_port(),
+                "urls": urls,
+                "txt": txt,
+            }));
+            continue;
+        }
+        println!("{}", service.get_fullname());
+        println!("  host: {}", service.get_hostname());
+        for url in urls {
+            println!("  url: {}", url);
+        }
+        for property in service.get_properties().iter() {
+            println!("  txt: {}={}", property.key(), property.val_str());
+        }
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&models).map_err(Error::other)?);
+    }
+    if seen.is_empty() {
+        info!("no services found");
+    }
+    let _ = daemon.shutdown();
+    Ok(())
+}
+
+/// A CoAP service advertised in DNS, from its SRV record.
+struct DnsService {
+    /// The name of the service instance, or of the service for a plain SRV record
+    name: String,
+    url: String,
+    priority: u16,
+    weight: u16,
+    txt: Vec<String>,
+}
+
+/// Looks up the services of a type in a domain: the instances that DNS-SD lists in PTR records
+/// for it, and the service itself, as a plain SRV record would give it, each with its SRV records.
+async fn dns_services(
+    server: SocketAddr,
+    service_type: &str,
+    scheme: &str,
+    domain: &str,
+    timeout: Duration,
+) -> Result<Vec<DnsService>> {
+    let service_name = format!("{}.{}", service_type, domain);
+    info!("looking up {}", service_name);
+    let mut names: Vec<String> = dns::query(server, &service_name, dns::PTR, timeout)
+        .await?
+        .into_iter()
+        .filter(|record| record.name.eq_ignore_ascii_case(&service_name))
+        .filter_map(|record| match record.data {
+            RecordData::Ptr(instance) => Some(instance),
+            _ => None,
+        })
+        .collect();
+    names.push(service_name.clone());
+
+    let mut services = vec![];
+    for name in names {
+        let txt: Vec<String> = match name == service_name {
+            true => vec![],
+            false => dns::query(server, &name, dns::TXT, timeout)
+                .await?
+                .into_iter()
+                .filter(|record| record.name.eq_ignore_ascii_case(&name))
+                .flat_map(|record| match record.data {
+                    RecordData::Txt(strings) => strings,
+                    _ => vec![],
+                })
+                .filter(|string| !string.is_empty())
+                .collect(),
+        };
+        for record in dns::query(server, &name, dns::SRV, timeout).await? {
+            let RecordData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } = record.data
+            else {
+                continue;
+            };
+            // a target of "." says there is no such service
+            if !record.name.eq_ignore_ascii_case(&name) || target.is_empty() {
+                continue;
+            }
+            services.push(DnsService {
+                name: name.clone(),
+                url: format!("{}://{}:{}", scheme, target, port),
+                priority,
+                weight,
+                txt: txt.clone(),
+            });
+        }
+    }
+    Ok(services)
+}
+
+/// Fetches the resources a service lists in `/.well-known/core`.
+async fn probe_service(args: &Args, url: &str, options: &DiscoverOptions<'_>) -> Result<Vec<Link>> {
+    let url = well_known_url(url, options)?;
+    info!("GET {}", url);
+    let request = build_coap_request_for_url(&url, RequestType::Get, None, None, None)?;
+    let response = exchange(&create_connection_to(args, &url).await?, args, request).await?;
+    match response.message.header.code {
+        MessageClass::Response(code) if code.is_error() => Err(Error::other(format!(
+            "{} {}",
+            response.message.header.code,
+            response_code_info(code).0
+        ))),
+        _ => filter_links(&response.message.payload, options),
+    }
+}
+
+/// Lists the CoAP services that the URL's domain advertises in DNS, looked up with DNS-SD and
+/// SRV records in the order their priorities and weights prefer, optionally fetching the
+/// resources of each from `/.well-known/core`.
+pub async fn coap_discover_dns(
+    args: &Args,
+    server: Option<SocketAddr>,
+    probe: bool,
+    options: &DiscoverOptions<'_>,
+) -> Result<()> {
+    let domain = CoapUri::parse(&args.url)?.host;
+    let server = match server {
+        Some(server) => server,
+        None => dns::system_name_server()?,
+    };
+    let timeout = Duration::from_secs(args.timeout);
+    let mut services = vec![];
+    for (service_type, scheme) in SERVICE_TYPES {
+        services.extend(dns_services(server, service_type, scheme, &domain, timeout).await?);
+    }
+    services.sort_by_key(|service| (service.priority, u16::MAX - service.weight));
+
+    let mut models = vec![];
+    for service in &services {
+        let probed = match probe {
+            true => Some(probe_service(args, &service.url, options).await),
+            false => None,
+        };
+        if options.json {
+            let mut model = json!({
+                "name": service.name,
+                "url": service.url,
+                "priority": service.priority,
+                "weight": service.weight,
+                "txt": service.txt,
+            });
+            match &probed {
+                Some(Ok(links)) => {
+                    model["links"] = links.iter().map(link_model).collect::<Vec<_>>().into()
+                }
+                Some(Err(e)) => model["error"] = e.to_string().into(),
+                None => {}
+            }
+            models.push(model);
+            continue;
+        }
+        println!("{}", service.name);
+        println!("  url: {}", service.url);
+        println!("  priority: {}, weight: {}", service.priority, service.weight);
+        for string in &service.txt {
+            println!("  txt: {}", string);
+        }
+        match &probed {
+            Some(Ok(links)) if !links.is_empty() => println!("{}", links_table(links)),
+            Some(Ok(_)) => {}
+            Some(Err(e)) => println!("  error: {}", e),
+            None => {}
+        }
+        println!();
+    }
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&models).map_err(Error::other)?);
+    }
+    info!("{} service(s)", services.len());
+    Ok(())
+}
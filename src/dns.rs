@@ -0,0 +1,241 @@
+use log::debug;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+// record types (RFC 1035 section 3.2.2, RFC 3596, RFC 2782)
+pub const A: u16 = 1;
+pub const PTR: u16 = 12;
+pub const TXT: u16 = 16;
+pub const AAAA: u16 = 28;
+pub const SRV: u16 = 33;
+
+const CLASS_IN: u16 = 1;
+
+/// How long to wait for an answer before asking again, until the timeout.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The data of a resource record of one of the types looked up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordData {
+    Address(IpAddr),
+    Ptr(String),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    Txt(Vec<String>),
+    Other(u16),
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub name: String,
+    pub data: RecordData,
+}
+
+fn malformed() -> Error {
+    Error::new(ErrorKind::InvalidData, "malformed DNS response")
+}
+
+/// The name server the system is configured to use: the first in `/etc/resolv.conf`.
+pub fn system_name_server() -> Result<SocketAddr> {
+    let config = std::fs::read_to_string("/etc/resolv.conf").map_err(|e| {
+        Error::new(e.kind(), format!("can't find the name server: /etc/resolv.conf: {}", e))
+    })?;
+    config
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        // e.g. fe80::1%eth0, whose zone can't be used here
+        .filter_map(|addr| addr.trim().split('%').next()?.parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "no name server in /etc/resolv.conf"))
+}
+
+/// Parses a name server given as an address, with or without a port, e.g. `192.168.1.1` or
+/// `[fd00::1]:5353`.
+pub fn parse_name_server(s: &str) -> std::result::Result<SocketAddr, String> {
+    if let Ok(addr) = s.parse() {
+        return Ok(addr);
+    }
+    s.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, 53))
+        .map_err(|_| format!("invalid name server address: {}", s))
+}
+
+fn encode_query(id: u16, name: &str, record_type: u16) -> Result<Vec<u8>> {
+    // a standard query, recursion desired
+    let mut query = vec![];
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            let message = format!("invalid domain name: {}", name);
+            return Err(Error::new(ErrorKind::InvalidInput, message));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+fn read_u16(message: &[u8], pos: usize) -> Result<u16> {
+    let bytes = message.get(pos..pos + 2).ok_or_else(malformed)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Reads a possibly compressed name (RFC 1035 section 4.1.4) at `pos`, returning it and the
+/// position after it.
+fn read_name(message: &[u8], mut pos: usize) -> Result<(String, usize)> {
+    let mut labels = vec![];
+    let mut end = None;
+    // each pointer must point back, so that they can't loop
+    let mut limit = pos;
+    loop {
+        let length = *message.get(pos).ok_or_else(malformed)?;
+        match length {
+            0 => break,
+            0xc0..=0xff => {
+                let target = usize::from(read_u16(message, pos)? & 0x3fff);
+                if target >= limit {
+                    return Err(malformed());
+                }
+                end.get_or_insert(pos + 2);
+                limit = target;
+                pos = target;
+            }
+            1..=63 => {
+                let label = message
+                    .get(pos + 1..pos + 1 + usize::from(length))
+                    .ok_or_else(malformed)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + usize::from(length);
+            }
+            _ => return Err(malformed()),
+        }
+    }
+    Ok((labels.join("."), end.unwrap_or(pos + 1)))
+}
+
+fn read_record_data(
+    message: &[u8],
+    record_type: u16,
+    data: std::ops::Range<usize>,
+) -> Result<RecordData> {
+    let bytes = message.get(data.clone()).ok_or_else(malformed)?;
+    Ok(match record_type {
+        A if bytes.len() == 4 => {
+            RecordData::Address(IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])))
+        }
+        AAAA if bytes.len() == 16 => {
+            let octets: [u8; 16] = bytes.try_into().map_err(|_| malformed())?;
+            RecordData::Address(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        PTR => RecordData::Ptr(read_name(message, data.start)?.0),
+        SRV if bytes.len() > 6 => RecordData::Srv {
+            priority: read_u16(message, data.start)?,
+            weight: read_u16(message, data.start + 2)?,
+            port: read_u16(message, data.start + 4)?,
+            target: read_name(message, data.start + 6)?.0,
+        },
+        TXT => {
+            let mut strings = vec![];
+            let mut rest = bytes;
+            while let Some((&length, after)) = rest.split_first() {
+                let string = after.get(..usize::from(length)).ok_or_else(malformed)?;
+                strings.push(String::from_utf8_lossy(string).into_owned());
+                rest = &after[usize::from(length)..];
+            }
+            RecordData::Txt(strings)
+        }
+        A | AAAA | SRV => return Err(malformed()),
+        other => RecordData::Other(other),
+    })
+}
+
+/// Reads the records of a response to the query with the given ID, from its answer and additional
+/// sections, where a server may add records it expects to be asked for next, e.g. the SRV and TXT
+/// records of the instances a PTR query finds. A name that doesn't exist has no records.
+fn parse_response(message: &[u8], id: u16) -> Result<Vec<Record>> {
+    if message.len() < 12 || read_u16(message, 0)? != id || message[2] & 0x80 == 0 {
+        return Err(malformed());
+    }
+    if message[2] & 0x02 != 0 {
+        debug!("DNS response truncated; using the records it holds");
+    }
+    match message[3] & 0x0f {
+        0 | 3 => {}
+        rcode => {
+            return Err(Error::other(format!("name server failed the query (RCODE {})", rcode)));
+        }
+    }
+    let questions = read_u16(message, 4)?;
+    let records = read_u16(message, 6)? as usize
+        + read_u16(message, 8)? as usize
+        + read_u16(message, 10)? as usize;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(message, pos)?.1 + 4;
+    }
+    let mut parsed = vec![];
+    for _ in 0..records {
+        let (name, after) = read_name(message, pos)?;
+        let record_type = read_u16(message, after)?;
+        let length = usize::from(read_u16(message, after + 8)?);
+        let data = after + 10..after + 10 + length;
+        parsed.push(Record {
+            name,
+            data: read_record_data(message, record_type, data.clone())?,
+        });
+        pos = data.end;
+    }
+    Ok(parsed)
+}
+
+/// Asks a name server for the records of a type at a name, asking again every second until the
+/// timeout if no answer comes. The records returned include any additional ones the server gave.
+pub async fn query(
+    server: SocketAddr,
+    name: &str,
+    record_type: u16,
+    timeout: Duration,
+) -> Result<Vec<Record>> {
+    let id = rand::random();
+    let query = encode_query(id, name, record_type)?;
+    let bind_addr: SocketAddr = match server {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(server).await?;
+    debug!("DNS query for {} (type {}) to {}", name, record_type, server);
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut buf = [0; 4096];
+    loop {
+        socket.send(&query).await?;
+        let retry = (tokio::time::Instant::now() + RETRY_INTERVAL).min(deadline);
+        while let Ok(received) = tokio::time::timeout_at(retry, socket.recv(&mut buf)).await {
+            match parse_response(&buf[..received?], id) {
+                Ok(records) => return Ok(records),
+                // e.g. a late answer to another query
+                Err(e) if e.kind() == ErrorKind::InvalidData => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if retry >= deadline {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("no answer from {} for {}", server, name),
+            ));
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use coap_lite::{CoapOption, MessageClass, MessageType, Packet, RequestType};
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+use crate::coap_helper::*;
+use crate::link_format::parse_links;
+use crate::mirror::resource_url;
+use crate::transport;
+use crate::{create_connection_to, exchange, resolve, Args};
+
+// the smallest block size, so that even short representations need more than one block
+const PROBE_BLOCK_SZX: u8 = 0;
+
+enum Outcome {
+    Ok(String),
+    Fail(String),
+    Skip(String),
+}
+
+fn report(name: &str, outcome: &Outcome) {
+    match outcome {
+        Outcome::Ok(detail) => println!("ok    {}: {}", name, detail),
+        Outcome::Fail(detail) => println!("FAIL  {}: {}", name, detail),
+        Outcome::Skip(detail) => println!("skip  {}: {}", name, detail),
+    }
+}
+
+/// Sends a message from its own socket and waits for the reply to it: a reset or piggybacked
+/// response with the same message ID, or a separate response with the same token.
+async fn raw_exchange(
+    peer: SocketAddr,
+    local_addr: Option<SocketAddr>,
+    message: &Packet,
+    timeout: Duration,
+) -> Result<Option<Packet>> {
+    let socket = UdpSocket::bind(transport::bind_addr(peer, local_addr)).await?;
+    // connecting lets an ICMP port unreachable be reported as a socket error
+    socket.connect(peer).await?;
+    socket
+        .send(&message.to_bytes().map_err(Error::other)?)
+        .await?;
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut buf = vec![0; 65535];
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+        let Ok(reply) = Packet::from_bytes(&buf[..received?]) else {
+            continue;
+        };
+        let same_exchange = reply.header.message_id == message.header.message_id;
+        let same_request =
+            !message.get_token().is_empty() && reply.get_token() == message.get_token();
+        if reply.header.code == MessageClass::Empty
+            && reply.header.get_type() == MessageType::Acknowledgement
+        {
+            // the response will follow separately
+            continue;
+        }
+        if same_exchange || same_request {
+            return Ok(Some(reply));
+        }
+    }
+    match socket.take_error()? {
+        Some(e) => Err(e),
+        None => Ok(None),
+    }
+}
+
+/// Checks each step of talking to the server in turn, stopping early if the server cannot be
+/// reached at all, and explains where communication breaks down.
+pub async fn coap_doctor(args: &Args) -> Result<()> {
+    let uri = CoapUri::parse(&args.url)?;
+    let (host, port) = (uri.host.as_str(), uri.port_or_default());
+    // the session a connection sets up before any request, for schemes other than plain UDP, and
+    // what to suggest if that fails
+    let session = match uri.scheme.as_str() {
+        "coaps" => Some((
+            "DTLS handshake",
+            "no DTLS session could be established; check the port, that the server is running and the security options",
+        )),
+        "coap+tcp" => Some((
+            "CSM exchange",
+            "no CoAP over TCP connection could be established; check the port and that the server supports RFC 8323",
+        )),
+        "coap+ws" => Some((
+            "WebSocket handshake and CSM exchange",
+            "no CoAP over WebSockets connection could be established; check the port and that the server serves /.well-known/coap",
+        )),
+        _ => None,
+    };
+    let timeout = Duration::from_secs(args.timeout);
+    let mut healthy = true;
+
+    let peer = match tokio::net::lookup_host((host, port)).await {
+        Ok(mut addresses) => addresses.next(),
+        Err(_) => None,
+    };
+    let Some(peer) = peer else {
+        report(
+            "DNS resolution",
+            &Outcome::Fail(format!("could not resolve {}", host)),
+        );
+        println!(
+            "diagnosis: the host name does not resolve; check its spelling and the DNS configuration"
+        );
+        return Err(Error::other("diagnosis failed"));
+    };
+    report(
+        "DNS resolution",
+        &Outcome::Ok(format!("{} is {}", host, peer.ip())),
+    );
+    let local_addr = resolve::local_addr(args, peer)?;
+
+    if let Some((step, _)) = session {
+        // a plain CoAP ping or probe datagram would not be understood outside the session
+        report(
+            "CoAP ping",
+            &Outcome::Skip(format!("only sent over plain UDP, before the {}", step)),
+        );
+    } else {
+        let mut ping = Packet::new();
+        ping.header.set_type(MessageType::Confirmable);
+        ping.header.code = MessageClass::Empty;
+        ping.header.message_id = rand::random();
+        let start = Instant::now();
+        match raw_exchange(peer, local_addr, &ping, timeout).await {
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                report(
+                    "UDP reachability",
+                    &Outcome::Fail(format!(
+                        "{} is not listening on UDP port {}",
+                        peer.ip(),
+                        port
+                    )),
+                );
+                println!(
+                    "diagnosis: the host is reachable but nothing is listening on the port; check the port and that the server is running"
+                );
+                return Err(Error::other("diagnosis failed"));
+            }
+            Err(e) => {
+                report("UDP reachability", &Outcome::Fail(e.to_string()));
+                println!(
+                    "diagnosis: datagrams cannot be sent to the host; check the network configuration"
+                );
+                return Err(Error::other("diagnosis failed"));
+            }
+            Ok(None) => {
+                report(
+                    "UDP reachability",
+                    &Outcome::Fail(format!("no reply within {}s", args.timeout)),
+                );
+                report("CoAP ping", &Outcome::Fail("no reset received".to_string()));
+                println!(
+                    "diagnosis: nothing came back from {}; a firewall may be dropping UDP, or the server is down",
+                    peer
+                );
+                return Err(Error::other("diagnosis failed"));
+            }
+            Ok(Some(reply)) => {
+                report(
+                    "UDP reachability",
+                    &Outcome::Ok(format!("{} replied", peer)),
+                );
+                if reply.header.get_type() == MessageType::Reset {
+                    report(
+                        "CoAP ping",
+                        &Outcome::Ok(format!("reset after {}ms", start.elapsed().as_millis())),
+                    );
+                } else {
+                    healthy = false;
+                    report(
+                        "CoAP ping",
+                        &Outcome::Fail(format!(
+                            "expected a reset, got {} {}",
+                            crate::message_dump::message_type_name(reply.header.get_type()),
+                            reply.header.code
+                        )),
+                    );
+                }
+            }
+        }
+    }
+
+    let conn = match create_connection_to(args, &args.url).await {
+        Ok(conn) => {
+            if let Some((step, _)) = session {
+                report(step, &Outcome::Ok(format!("established with {}", peer)));
+            }
+            conn
+        }
+        Err(e) => {
+            let Some((step, diagnosis)) = session else {
+                return Err(e);
+            };
+            report(step, &Outcome::Fail(e.to_string()));
+            println!("diagnosis: {}", diagnosis);
+            return Err(Error::other("diagnosis failed"));
+        }
+    };
+    let well_known = resource_url(&args.url, "/.well-known/core")?;
+    let request = build_coap_request_for_url(&well_known, RequestType::Get, None, None, None)?;
+    match exchange(&conn, args, request).await {
+        Ok(response) if response.get_status().is_error() => {
+            report(
+                "/.well-known/core",
+                &Outcome::Skip(format!("server answered {}", response.message.header.code)),
+            );
+        }
+        Ok(response) => {
+            let payload = String::from_utf8_lossy(&response.message.payload);
+            match parse_links(&payload) {
+                Ok(links) => report(
+                    "/.well-known/core",
+                    &Outcome::Ok(format!("{} resource(s) listed", links.len())),
+                ),
+                Err(e) => {
+                    healthy = false;
+                    report(
+                        "/.well-known/core",
+                        &Outcome::Fail(format!("invalid link format: {}", e)),
+                    );
+                }
+            }
+        }
+        Err(e) => {
+            healthy = false;
+            report("/.well-known/core", &Outcome::Fail(e.to_string()));
+        }
+    }
+
+    if session.is_some() {
+        report(
+            "block transfer",
+            &Outcome::Skip("only probed over plain UDP".to_string()),
+        );
+        return finish(healthy);
+    }
+
+    let mut probe =
+        build_coap_request_for_url(&args.url, RequestType::Get, None, None, None)?.message;
+    probe.header.message_id = rand::random();
+    probe.set_token(rand::random::<[u8; 4]>().to_vec());
+    probe.add_option(CoapOption::Block2, vec![PROBE_BLOCK_SZX]);
+    match raw_exchange(peer, local_addr, &probe, timeout).await {
+        Ok(Some(response)) => {
+            let block2 = response.get_first_option(CoapOption::Block2);
+            let outcome = match (block2, response.payload.len()) {
+                (Some(_), len) if len <= 16 => Outcome::Ok(format!(
+                    "{} answered with a 16-byte block",
+                    response.header.code
+                )),
+                (Some(_), len) => {
+                    Outcome::Fail(format!("asked for 16-byte blocks, got {} bytes", len))
+                }
+                (None, len) if len > 16 => {
+                    Outcome::Fail(format!("Block2 ignored; got {} bytes in one message", len))
+                }
+                (None, len) => Outcome::Ok(format!(
+                    "{} answered with {} bytes, too few to need blocks",
+                    response.header.code, len
+                )),
+            };
+            if matches!(outcome, Outcome::Fail(_)) {
+                healthy = false;
+            }
+            report("block transfer", &outcome);
+        }
+        Ok(None) => {
+            healthy = false;
+            report(
+                "block transfer",
+                &Outcome::Fail(format!("no response within {}s", args.timeout)),
+            );
+        }
+        Err(e) => {
+            healthy = false;
+            report("block transfer", &Outcome::Fail(e.to_string()));
+        }
+    }
+
+    finish(healthy)
+}
+
+fn finish(healthy: bool) -> Result<()> {
+    if healthy {
+        println!("diagnosis: no problems found");
+        Ok(())
+    } else {
+        println!("diagnosis: the server is reachable but does not handle some requests correctly");
+        Err(Error::other("diagnosis failed"))
+    }
+}
@@ -0,0 +1,56 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// Parses a line of a `.env` file into a variable, or `None` for a blank line or a comment.
+fn parse_line(line: &str) -> std::result::Result<Option<(&str, &str)>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+    let (name, value) = line.split_once('=').ok_or("expected NAME=VALUE")?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("invalid variable name: {}", name));
+    }
+    let value = value.trim();
+    let value = [('"', '"'), ('\'', '\'')]
+        .iter()
+        .find_map(|(open, close)| value.strip_prefix(*open)?.strip_suffix(*close))
+        .unwrap_or(value);
+    Ok(Some((name, value)))
+}
+
+/// Reads the variables in a `.env` file, if there is one, into the environment, where they serve
+/// as defaults for the options that can be given by environment variable, such as
+/// `COAP_PSK_KEY`. Variables already set in the environment are left as they are.
+///
+/// Each line is `NAME=VALUE`, optionally preceded by `export`, and the value may be quoted; blank
+/// lines and lines starting with `#` are ignored.
+///
+/// # Safety
+///
+/// Must be called before any other thread is started, as setting environment variables is only
+/// sound then.
+pub unsafe fn load(path: &Path) -> Result<()> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+    };
+    for (i, line) in contents.lines().enumerate() {
+        let variable = parse_line(line).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), i + 1, e),
+            )
+        })?;
+        if let Some((name, value)) = variable
+            && std::env::var_os(name).is_none()
+        {
+            // SAFETY: no other thread is running, as the caller guarantees
+            unsafe { std::env::set_var(name, value) };
+        }
+    }
+    Ok(())
+}
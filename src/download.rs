@@ -0,0 +1,186 @@
+use coap_lite::{CoapOption, CoapRequest, CoapResponse, MessageClass, RequestType};
+use log::{info, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::blockwise::{self, Block};
+use crate::coap_helper::*;
+use crate::output::{self, print_response};
+use crate::transport::Connection;
+use crate::{
+    before_deadline, prepare_request, print_transmission_report, request_deadline, Args,
+};
+
+const DEFAULT_BLOCK_SIZE: usize = 1024;
+
+/// The file to save a resource in when none is given: the last segment of its path.
+fn default_file(request: &CoapRequest<SocketAddr>) -> Result<PathBuf> {
+    let name = request
+        .message
+        .get_option(CoapOption::UriPath)
+        .and_then(|segments| segments.back())
+        .map(|segment| String::from_utf8_lossy(segment).into_owned())
+        .unwrap_or_default();
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "can't name a file after the URL's path; give one",
+        ));
+    }
+    Ok(PathBuf::from(name))
+}
+
+/// `<file>` with `suffix` appended, e.g. `data.bin.part` for `data.bin`.
+fn with_suffix(file: &Path, suffix: &str) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Where to pick up a partial download: the start of the block it ends in, so that whatever is
+/// left of that block is fetched again.
+fn resume_point(partial: usize, size: usize) -> Block {
+    Block {
+        num: partial / size,
+        more: false,
+        size,
+    }
+}
+
+fn is_error(response: &CoapResponse) -> bool {
+    matches!(response.message.header.code, MessageClass::Response(code) if code.is_error())
+}
+
+/// Writes each block received into the partial file at its place, keeping track of where the
+/// representation ends.
+fn write_block(part: &mut File, end: &mut usize, offset: usize, bytes: &[u8]) -> Result<()> {
+    part.seek(SeekFrom::Start(offset as u64))?;
+    part.write_all(bytes)?;
+    *end = offset + bytes.len();
+    Ok(())
+}
+
+/// Downloads a resource block by block into `<file>.part`, which is renamed to `file` once
+/// complete. The ETag of the first block is kept in `<file>.part.etag`, so that a download resumed
+/// with `resume` after being interrupted can tell whether the resource changed in the meantime,
+/// in which case it starts over.
+pub async fn coap_download(
+    conn: &Connection,
+    args: &Args,
+    file: Option<&Path>,
+    resume: bool,
+) -> Result<()> {
+    info!("GET {}", args.url);
+
+    let mut request = build_coap_request_for_url(&args.url, RequestType::Get, None, None, None)?;
+    let file = match file {
+        Some(file) => file.to_path_buf(),
+        None => default_file(&request)?,
+    };
+    if args.dry_run {
+        info!("would save to {}", file.display());
+        return Ok(());
+    }
+    let part_file = with_suffix(&file, ".part");
+    let etag_file = with_suffix(&file, ".part.etag");
+    let partial = match std::fs::metadata(&part_file) {
+        Ok(metadata) if resume => metadata.len() as usize,
+        Ok(_) => 0,
+        Err(e) if e.kind() == ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    let saved_etag = match std::fs::read(&etag_file) {
+        Ok(etag) if resume => Some(etag),
+        Ok(_) => None,
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    prepare_request(conn, args, &mut request)?;
+    // a Size2 of 0 asks for the size of the whole representation, for the progress bar
+    if request.message.get_first_option(CoapOption::Size2).is_none() {
+        request.message.add_option(CoapOption::Size2, encode_uint(0));
+    }
+    let size = args.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+    let mut block = resume_point(partial, size);
+    if block.num > 0 {
+        info!("resuming from byte {}", block.num * block.size);
+    }
+    let token = request.message.get_token().to_vec();
+    let log = conn.log.exchange(&token);
+    let deadline = request_deadline(args);
+    let mut response = before_deadline(
+        args,
+        deadline,
+        "download",
+        blockwise::request_block(conn, &mut request, block),
+    )
+    .await?;
+    if block.num > 0
+        && saved_etag.is_some()
+        && response.message.get_first_option(CoapOption::ETag) != saved_etag.as_ref()
+    {
+        warn!("resource changed since the download was interrupted; starting over");
+        block = resume_point(0, size);
+        response = before_deadline(
+            args,
+            deadline,
+            "download",
+            blockwise::request_block(conn, &mut request, block),
+        )
+        .await?;
+    }
+    if is_error(&response) {
+        return print_response(&response.message, args, None);
+    }
+    if block.num > 0 && response.message.get_first_option(CoapOption::Block2).is_none() {
+        warn!("server sent the whole resource rather than the rest of it");
+    }
+    match response.message.get_first_option(CoapOption::ETag) {
+        Some(etag) => std::fs::write(&etag_file, etag)?,
+        None if etag_file.exists() => std::fs::remove_file(&etag_file)?,
+        None => {}
+    }
+
+    let mut part = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&part_file)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", part_file.display(), e)))?;
+    let mut end = 0;
+    let mut sink = |offset, bytes: &[u8]| write_block(&mut part, &mut end, offset, bytes);
+    let result = before_deadline(
+        args,
+        deadline,
+        "download",
+        blockwise::receive_blocks(conn, request, response, &mut sink),
+    )
+    .await;
+    if args.verbose > 0 {
+        print_transmission_report(&token, &log);
+    }
+    let response = result.map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("{}; run again with --resume to continue the download", e),
+        )
+    })?;
+    if is_error(&response) {
+        print_response(&response.message, args, None)?;
+        warn!("download incomplete; run again with --resume to continue it");
+        return Ok(());
+    }
+    part.set_len(end as u64)?;
+    drop(part);
+    std::fs::rename(&part_file, &file)?;
+    if etag_file.exists() {
+        std::fs::remove_file(&etag_file)?;
+    }
+
+    output::print_status(&response.message, args);
+    info!("saved {} bytes to {}", end, file.display());
+    output::print_write_out(&response.message, args)
+}
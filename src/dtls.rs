@@ -0,0 +1,131 @@
+use rcgen::KeyPair;
+use std::io::{BufReader, Error, ErrorKind, Result, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use webrtc_dtls::cipher_suite::CipherSuiteId;
+use webrtc_dtls::config::{Config, ExtendedMasterSecretType};
+use webrtc_dtls::conn::DTLSConn;
+use webrtc_dtls::crypto::{Certificate, CryptoPrivateKey};
+use webrtc_dtls::state::State;
+
+use crate::coap_helper::*;
+use crate::Args;
+
+fn invalid(path: &Path, e: impl std::fmt::Display) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<rustls::Certificate>> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let certificates = rustls_pemfile::certs(&mut reader)
+        .map(|cert| cert.map(|cert| rustls::Certificate(cert.to_vec())))
+        .collect::<Result<Vec<_>>>()
+        .map_err(|e| invalid(path, e))?;
+    if certificates.is_empty() {
+        return Err(invalid(path, "no certificates found"));
+    }
+    Ok(certificates)
+}
+
+fn load_private_key(path: &Path) -> Result<CryptoPrivateKey> {
+    let pem = std::fs::read_to_string(path)?;
+    let key_pair = KeyPair::from_pem(&pem).map_err(|e| {
+        invalid(
+            path,
+            format!(
+                "{} (keys must be PKCS #8; convert with `openssl pkcs8 -topk8 -nocrypt`)",
+                e
+            ),
+        )
+    })?;
+    CryptoPrivateKey::from_key_pair(&key_pair).map_err(|e| invalid(path, e))
+}
+
+/// Builds the DTLS configuration for a `coaps` URL from the security options: a pre-shared key,
+/// or certificates to verify the server and optionally to identify the client.
+pub fn dtls_config(args: &Args, server_name: &str) -> Result<Config> {
+    if let (Some(identity), Some(key)) = (&args.psk_identity, &args.psk_key) {
+        let key = match key.strip_prefix("0x") {
+            Some(digits) => parse_hex(digits)?,
+            None => key.as_bytes().to_vec(),
+        };
+        return Ok(Config {
+            psk: Some(Arc::new(move |_hint: &[u8]| Ok(key.clone()))),
+            psk_identity_hint: Some(identity.as_bytes().to_vec()),
+            // RFC 7252 requires TLS_PSK_WITH_AES_128_CCM_8; the others are offered as fallbacks
+            cipher_suites: vec![
+                CipherSuiteId::Tls_Psk_With_Aes_128_Ccm_8,
+                CipherSuiteId::Tls_Psk_With_Aes_128_Ccm,
+                CipherSuiteId::Tls_Psk_With_Aes_128_Gcm_Sha256,
+            ],
+            server_name: server_name.to_string(),
+            ..Default::default()
+        });
+    }
+
+    let Some(ca_cert) = &args.ca_cert else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "coaps URLs need --psk-identity and --psk-key, or --ca-cert (with --cert and --key to \
+             authenticate the client)",
+        ));
+    };
+    let mut roots = rustls::RootCertStore::empty();
+    for certificate in load_certificates(ca_cert)? {
+        roots.add(&certificate).map_err(|e| invalid(ca_cert, e))?;
+    }
+    let certificates = match (&args.cert, &args.key) {
+        (Some(cert), Some(key)) => vec![Certificate {
+            certificate: load_certificates(cert)?,
+            private_key: load_private_key(key)?,
+        }],
+        _ => vec![],
+    };
+    Ok(Config {
+        certificates,
+        extended_master_secret: ExtendedMasterSecretType::Request,
+        roots_cas: roots,
+        server_name: server_name.to_string(),
+        ..Default::default()
+    })
+}
+
+/// The file in the `--session-cache` directory that keeps the session with a server, named after
+/// its address and the credentials the session is set up with, so that sessions set up with other
+/// credentials aren't resumed in their place.
+pub fn session_file(dir: &Path, peer_addr: SocketAddr, args: &Args) -> PathBuf {
+    let credentials = format!(
+        "{}|{:?}|{:?}|{:?}",
+        peer_addr, args.psk_identity, args.cert, args.ca_cert
+    );
+    dir.join(format!("{:016x}.session", fnv1a(credentials.as_bytes())))
+}
+
+/// Reads a session saved by [`save_session`]: the local port it was set up from, which the server
+/// knows it by, and its state.
+pub async fn load_session(path: &Path) -> Option<(u16, State)> {
+    let bytes = std::fs::read(path).ok()?;
+    let (port, state) = bytes.split_at_checked(2)?;
+    let mut session = State::default();
+    session.unmarshal_binary(state).await.ok()?;
+    Some((u16::from_be_bytes([port[0], port[1]]), session))
+}
+
+/// Saves the state of a session, keys included, for a later run to resume it from the same local
+/// port without a handshake. The file is readable only by its owner.
+pub async fn save_session(path: &Path, conn: &DTLSConn, local_port: u16) -> Result<()> {
+    let state = conn.connection_state().await.marshal_binary().await.map_err(Error::other)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    file.write_all(&local_port.to_be_bytes())?;
+    file.write_all(&state)
+}
@@ -0,0 +1,132 @@
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+
+/// How a request failed: where it had got to, how many times the message that went unanswered
+/// was retransmitted and how long it had been going, along with the error itself. It travels
+/// inside an [`Error`] of the same kind as that error, so that exit statuses stay the same.
+#[derive(Debug)]
+pub enum CoapCliError {
+    /// Setting up a DTLS, TCP or WebSocket connection failed
+    Handshake { elapsed: Duration, source: Error },
+    /// A request that isn't sent or received in blocks failed
+    Request {
+        retransmissions: usize,
+        elapsed: Duration,
+        source: Error,
+    },
+    /// A block of a block-wise transfer failed, block 0 being the first
+    Block {
+        num: usize,
+        retransmissions: usize,
+        elapsed: Duration,
+        source: Error,
+    },
+}
+
+impl CoapCliError {
+    /// Puts a failure of a request into context. An interruption is left as it is, as the user
+    /// knows where it happened.
+    pub fn request(source: Error, retransmissions: usize, started: Instant) -> Error {
+        if source.kind() == ErrorKind::Interrupted {
+            return source;
+        }
+        CoapCliError::Request {
+            retransmissions,
+            elapsed: started.elapsed(),
+            source,
+        }
+        .into()
+    }
+
+    /// Puts a failure of a connection's setup into context.
+    pub fn handshake(source: Error, started: Instant) -> Error {
+        CoapCliError::Handshake {
+            elapsed: started.elapsed(),
+            source,
+        }
+        .into()
+    }
+
+    /// Gives the failure of a request the block of a block-wise transfer it happened at, timing
+    /// it from the start of the transfer. Any other error is left as it is.
+    pub fn at_block(err: Error, num: usize, started: Instant) -> Error {
+        match err.downcast::<CoapCliError>() {
+            Ok(CoapCliError::Request {
+                retransmissions,
+                source,
+                ..
+            }) => CoapCliError::Block {
+                num,
+                retransmissions,
+                elapsed: started.elapsed(),
+                source,
+            }
+            .into(),
+            Ok(e) => e.into(),
+            Err(err) => err,
+        }
+    }
+
+    fn source_error(&self) -> &Error {
+        match self {
+            CoapCliError::Handshake { source, .. }
+            | CoapCliError::Request { source, .. }
+            | CoapCliError::Block { source, .. } => source,
+        }
+    }
+}
+
+impl std::fmt::Display for CoapCliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CoapCliError::Handshake { elapsed, source } => write!(
+                f,
+                "{} (during the handshake, after {:.1}s)",
+                source,
+                elapsed.as_secs_f64()
+            ),
+            CoapCliError::Request {
+                retransmissions,
+                elapsed,
+                source,
+            } => write!(
+                f,
+                "{} ({} retransmission(s), after {:.1}s)",
+                source,
+                retransmissions,
+                elapsed.as_secs_f64()
+            ),
+            CoapCliError::Block {
+                num,
+                retransmissions,
+                elapsed,
+                source,
+            } => {
+                let block = match num {
+                    0 => "the first block".to_string(),
+                    num => format!("block {}", num),
+                };
+                write!(
+                    f,
+                    "{} (at {}, {} retransmission(s), after {:.1}s)",
+                    source,
+                    block,
+                    retransmissions,
+                    elapsed.as_secs_f64()
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CoapCliError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source_error())
+    }
+}
+
+impl From<CoapCliError> for Error {
+    fn from(err: CoapCliError) -> Error {
+        Error::new(err.source_error().kind(), err)
+    }
+}
@@ -0,0 +1,32 @@
+use std::io::{ErrorKind, Result};
+use std::path::PathBuf;
+
+use crate::coap_helper::fnv1a;
+
+/// Remembers the last ETag seen for each URL in a directory, one file per URL.
+pub struct EtagStore {
+    dir: PathBuf,
+}
+
+impl EtagStore {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(EtagStore { dir })
+    }
+
+    fn path_for(&self, url: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}.etag", fnv1a(url.as_bytes())))
+    }
+
+    pub fn load(&self, url: &str) -> Result<Option<Vec<u8>>> {
+        match std::fs::read(self.path_for(url)) {
+            Ok(etag) => Ok(Some(etag)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, url: &str, etag: &[u8]) -> Result<()> {
+        std::fs::write(self.path_for(url), etag)
+    }
+}
@@ -0,0 +1,169 @@
+use coap_lite::{ContentFormat, MessageClass, Packet};
+use regex::Regex;
+use serde_json::Value;
+
+use crate::coap_helper::*;
+use crate::content;
+
+/// The exit status when a response doesn't meet an `--expect-*` expectation.
+pub const EXPECTATION_FAILED_STATUS: i32 = 7;
+
+/// A response code given with `--expect-code`: a particular one, e.g. 2.05, or any of a class,
+/// e.g. 2.xx.
+#[derive(Debug, Clone, Copy)]
+pub enum ExpectedCode {
+    Code(u8),
+    Class(u8),
+}
+
+pub fn parse_expected_code(s: &str) -> std::result::Result<ExpectedCode, String> {
+    if let Some(class) = s.strip_suffix(".xx").or_else(|| s.strip_suffix("xx")) {
+        return match class.parse::<u8>() {
+            Ok(class @ 2..=5) => Ok(ExpectedCode::Class(class)),
+            _ => Err(format!("invalid response class: {}", s)),
+        };
+    }
+    let code = parse_response_code(s).map_err(|e| e.to_string())?;
+    Ok(ExpectedCode::Code(u8::from(MessageClass::Response(code))))
+}
+
+pub fn parse_expected_content_format(s: &str) -> std::result::Result<ContentFormat, String> {
+    parse_content_format(s).map_err(|e| e.to_string())
+}
+
+/// What `--expect-body` requires of a payload: that a regular expression matches it as text, or
+/// that the value at a place in it, decoded from JSON or CBOR, is a given one.
+#[derive(Debug, Clone)]
+pub enum ExpectedBody {
+    Matches(Regex),
+    Value { path: String, pointer: String, value: Value },
+}
+
+/// Converts a JSONPath of names and indexes, e.g. `$.sensors[0].value`, to a JSON Pointer.
+fn json_path_pointer(path: &str) -> Option<String> {
+    let mut pointer = String::new();
+    let mut rest = path.strip_prefix('$')?;
+    while !rest.is_empty() {
+        let (segment, after) = if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let (index, after) = after.split_once(']')?;
+            (index.trim_matches(['\'', '"']), after)
+        } else {
+            return None;
+        };
+        if segment.is_empty() {
+            return None;
+        }
+        pointer.push('/');
+        pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+        rest = after;
+    }
+    Some(pointer)
+}
+
+/// Parses an expectation of the body: `PATH=VALUE`, where the path is a JSON Pointer, e.g.
+/// `/temp=21.5`, or a JSONPath, e.g. `$.temp=21.5`, and the value is JSON or else a string; or
+/// otherwise a regular expression.
+pub fn parse_expected_body(s: &str) -> std::result::Result<ExpectedBody, String> {
+    if s.starts_with(['/', '$'])
+        && let Some((path, value)) = s.split_once('=')
+    {
+        let pointer = match path.starts_with('$') {
+            true => json_path_pointer(path).ok_or_else(|| format!("invalid JSONPath: {}", path))?,
+            false => path.to_string(),
+        };
+        let value = serde_json::from_str(value).unwrap_or_else(|_| Value::from(value));
+        return Ok(ExpectedBody::Value {
+            path: path.to_string(),
+            pointer,
+            value,
+        });
+    }
+    Regex::new(s)
+        .map(ExpectedBody::Matches)
+        .map_err(|e| format!("invalid regular expression: {}", e))
+}
+
+/// The expectations of a response given with `--expect-code`, `--expect-content-format` and
+/// `--expect-body`.
+pub struct Expectations<'a> {
+    pub code: Option<ExpectedCode>,
+    pub content_format: Option<ContentFormat>,
+    pub body: &'a [ExpectedBody],
+}
+
+fn check_body(message: &Packet, expected: &ExpectedBody) -> Option<String> {
+    match expected {
+        ExpectedBody::Matches(regex) => {
+            let text = String::from_utf8_lossy(&message.payload);
+            (!regex.is_match(&text)).then(|| format!("body doesn't match {}", regex))
+        }
+        ExpectedBody::Value {
+            path,
+            pointer,
+            value,
+        } => {
+            let Some(body) = content::payload_json(message) else {
+                return Some(format!("body isn't JSON or CBOR, so has no {}", path));
+            };
+            match body.pointer(pointer) {
+                Some(actual) if actual == value => None,
+                Some(actual) => Some(format!("{}: expected {}, got {}", path, value, actual)),
+                None => Some(format!("{}: expected {}, but there's nothing there", path, value)),
+            }
+        }
+    }
+}
+
+/// How a response falls short of the expectations, if it does, one diagnostic per expectation it
+/// doesn't meet.
+pub fn check(message: &Packet, expectations: &Expectations<'_>) -> Vec<String> {
+    let mut failures = vec![];
+    let code = u8::from(message.header.code);
+    let describe = || match message.header.code {
+        MessageClass::Response(response) => {
+            format!("{} {}", message.header.code, response_code_info(response).0)
+        }
+        other => other.to_string(),
+    };
+    match expectations.code {
+        Some(ExpectedCode::Code(expected)) if expected != code => failures.push(format!(
+            "expected {}, got {}",
+            MessageClass::from(expected),
+            describe()
+        )),
+        Some(ExpectedCode::Class(class)) if class != code >> 5 => {
+            failures.push(format!("expected {}.xx, got {}", class, describe()))
+        }
+        _ => {}
+    }
+    if let Some(expected) = expectations.content_format {
+        let expected = usize::from(expected);
+        let actual = message.get_content_format().map(usize::from);
+        if actual != Some(expected) {
+            failures.push(format!(
+                "expected content format {}, got {}",
+                content_format_name(expected),
+                actual.map_or("none".to_string(), content_format_name)
+            ));
+        }
+    }
+    failures.extend(expectations.body.iter().filter_map(|body| check_body(message, body)));
+    failures
+}
+
+/// The failure reported when a response doesn't meet its expectations.
+#[derive(Debug)]
+pub struct ExpectationFailed {
+    pub failures: Vec<String>,
+}
+
+impl std::fmt::Display for ExpectationFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "response not as expected: {}", self.failures.join("; "))
+    }
+}
+
+impl std::error::Error for ExpectationFailed {}
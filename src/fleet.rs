@@ -0,0 +1,217 @@
+use coap_lite::{MessageClass, Packet};
+use futures::stream::{self, StreamExt};
+use log::info;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use crate::report::{self, OutputFormat};
+use crate::{create_connection, run_requests, Args, ERROR_RESPONSE_CLASS};
+
+const DEFAULT_CONCURRENCY: usize = 16;
+
+/// A response to one of the requests made of a target.
+struct Exchange {
+    url: String,
+    method: String,
+    response: Packet,
+    rtt: Duration,
+}
+
+tokio::task_local! {
+    /// Where the responses to the requests made of a target are kept, rather than printed, so
+    /// that those of all the targets can be reported together.
+    static EXCHANGES: RefCell<Vec<Exchange>>;
+}
+
+/// Keeps the response to a request made of a target with `--targets`, if that is what is being
+/// done, returning whether it was kept.
+pub fn collect(url: &str, method: &str, response: &Packet, rtt: Duration) -> bool {
+    EXCHANGES
+        .try_with(|exchanges| {
+            exchanges.borrow_mut().push(Exchange {
+                url: url.to_string(),
+                method: method.to_string(),
+                response: response.clone(),
+                rtt,
+            })
+        })
+        .is_ok()
+}
+
+/// Reads the targets listed in a file, one to a line: a host, a host and port, or a URL giving
+/// the scheme too, e.g. `10.0.4.17`, `sensor-12.local:5683` or `coaps://gw-3.example.net`.
+/// Blank lines and lines starting with `#` are ignored.
+fn load_targets(path: &Path) -> Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    let targets: Vec<String> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    if targets.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{}: no targets", path.display()),
+        ));
+    }
+    Ok(targets)
+}
+
+/// The URL with its server replaced by the target, keeping the path and query. The URL may also
+/// be just a path, e.g. `/3/0/3`, to be requested of every target with `coap`, unless a target
+/// gives another scheme.
+pub fn target_url(url: &str, target: &str) -> String {
+    let (scheme, path) = match url.split_once("://") {
+        Some((scheme, rest)) => (scheme, &rest[rest.find(['/', '?']).unwrap_or(rest.len())..]),
+        None => ("coap", url),
+    };
+    let (scheme, authority) = match target.split_once("://") {
+        Some((scheme, authority)) => (scheme, authority.trim_end_matches('/')),
+        None => (scheme, target),
+    };
+    let separator = if path.is_empty() || path.starts_with(['/', '?']) { "" } else { "/" };
+    format!("{}://{}{}{}", scheme, authority, separator, path)
+}
+
+/// Makes the command's requests of a target over a connection of its own, keeping the responses.
+async fn run_target(args: &Args, url: String) -> Result<Vec<Exchange>> {
+    let args = Args {
+        url,
+        targets: None,
+        ..args.clone()
+    };
+    EXCHANGES
+        .scope(RefCell::new(vec![]), async {
+            let conn = create_connection(&args).await?;
+            run_requests(&conn, &args).await?;
+            Ok(EXCHANGES.with(|exchanges| exchanges.take()))
+        })
+        .await
+}
+
+fn is_error(response: &Packet) -> bool {
+    matches!(response.header.code, MessageClass::Response(code) if code.is_error())
+}
+
+fn print_results(results: &[(String, Result<Vec<Exchange>>)]) {
+    let width = results
+        .iter()
+        .map(|(target, _)| target.len())
+        .max()
+        .unwrap_or(0)
+        .max("TARGET".len());
+    println!("{:width$}  CODE   TIME     PAYLOAD", "TARGET", width = width);
+    for (target, result) in results {
+        match result {
+            Ok(exchanges) => {
+                for exchange in exchanges {
+                    let payload = String::from_utf8_lossy(&exchange.response.payload);
+                    let payload = payload.trim_end().replace('\n', " ");
+                    println!(
+                        "{:width$}  {:5}  {:>5}ms  {}",
+                        target,
+                        exchange.response.header.code.to_string(),
+                        exchange.rtt.as_millis(),
+                        payload,
+                        width = width
+                    );
+                }
+            }
+            Err(e) => println!("{:width$}  error: {}", target, e, width = width),
+        }
+    }
+}
+
+/// A JSON object per request made of a target, as `exchange_model` describes it plus the
+/// target, or the error that kept the target from being reached.
+fn results_json(results: &[(String, Result<Vec<Exchange>>)]) -> Vec<Value> {
+    let mut objects = vec![];
+    for (target, result) in results {
+        match result {
+            Ok(exchanges) => {
+                for exchange in exchanges {
+                    let mut model = report::exchange_model(
+                        &exchange.url,
+                        &exchange.method,
+                        &exchange.response,
+                        exchange.rtt,
+                    );
+                    model["target"] = Value::from(target.as_str());
+                    objects.push(model);
+                }
+            }
+            Err(e) => objects.push(serde_json::json!({
+                "target": target,
+                "error": e.to_string(),
+            })),
+        }
+    }
+    objects
+}
+
+/// Runs the command against every target listed in the file, up to `--concurrency` of them at
+/// once, each over its own connection, and prints the responses of all of them, in the order the
+/// targets are listed, once they are all done. Fails if any target couldn't be reached.
+pub async fn coap_fleet(args: &Args, file: &Path) -> Result<()> {
+    if !args.command.is_request() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--targets can only be used with get, post, put, delete, fetch, patch and ipatch",
+        ));
+    }
+    let targets = load_targets(file)?;
+    let concurrency = args.concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1);
+    info!("{} target(s), {} at a time", targets.len(), concurrency);
+
+    let mut results: Vec<(usize, String, Result<Vec<Exchange>>)> =
+        stream::iter(targets.into_iter().enumerate())
+            .map(|(index, target)| async move {
+                let result = run_target(args, target_url(&args.url, &target)).await;
+                (index, target, result)
+            })
+            .buffer_unordered(concurrency)
+            .collect()
+            .await;
+    results.sort_by_key(|(index, _, _)| *index);
+    let results: Vec<_> = results.into_iter().map(|(_, target, result)| (target, result)).collect();
+
+    match args.output {
+        OutputFormat::Json => {
+            for object in results_json(&results) {
+                println!("{}", object);
+            }
+        }
+        _ => print_results(&results),
+    }
+
+    let responses = results.iter().filter_map(|(_, result)| result.as_ref().ok()).flatten();
+    let mut errors = 0;
+    for exchange in responses {
+        if is_error(&exchange.response) {
+            errors += 1;
+            let class = u8::from(exchange.response.header.code) >> 5;
+            ERROR_RESPONSE_CLASS.fetch_max(class, Ordering::Relaxed);
+        }
+    }
+    let unreachable = results.iter().filter(|(_, result)| result.is_err()).count();
+    info!(
+        "{} target(s): {} error response(s), {} unreachable",
+        results.len(),
+        errors,
+        unreachable
+    );
+    if unreachable > 0 {
+        return Err(Error::other(format!(
+            "{} of {} target(s) could not be reached",
+            unreachable,
+            results.len()
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,397 @@
+use coap_lite::{CoapOption, ContentFormat, MessageClass, Packet, RequestType, ResponseType};
+use log::{info, warn};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::coap_helper::*;
+use crate::message_dump::hex;
+use crate::observe::max_age;
+use crate::transport::Connection;
+use crate::{exchange, request_url, Args};
+
+// the most a request's headers, and its body, may take up
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// An HTTP request, as much of it as the gateway makes use of.
+struct HttpRequest {
+    method: String,
+    target: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// An HTTP response, to be written out with `write_response`.
+struct HttpResponse {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// A response carrying a short explanation of why the request couldn't be passed on.
+    fn error(status: u16, message: &str) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: vec![("Content-Type", "text/plain; charset=utf-8".to_string())],
+            body: format!("{}\n", message).into_bytes(),
+        }
+    }
+}
+
+/// Reads a request from the stream, or returns `None` if the client closed the connection
+/// between requests.
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Result<Option<HttpRequest>> {
+    let invalid = |e: &str| Error::new(ErrorKind::InvalidData, e.to_string());
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(invalid("request headers too long"));
+        }
+        match stream.read_u8().await {
+            Ok(byte) => head.push(byte),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && head.is_empty() => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.lines();
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid("malformed request line"));
+    };
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let mut request = HttpRequest {
+        method: method.to_string(),
+        target: target.to_string(),
+        headers,
+        body: vec![],
+        keep_alive: version != "HTTP/1.0",
+    };
+    if let Some(connection) = request.header("Connection") {
+        request.keep_alive = match connection.to_ascii_lowercase().as_str() {
+            "close" => false,
+            "keep-alive" => true,
+            _ => request.keep_alive,
+        };
+    }
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(Error::new(ErrorKind::Unsupported, "chunked request bodies aren't supported"));
+    }
+    let length: usize = match request.header("Content-Length") {
+        Some(length) => length.parse().map_err(|_| invalid("invalid Content-Length"))?,
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(Error::new(ErrorKind::FileTooLarge, "request body too large"));
+    }
+    request.body = vec![0; length];
+    stream.read_exact(&mut request.body).await?;
+    Ok(Some(request))
+}
+
+async fn write_response(
+    stream: &mut BufReader<TcpStream>,
+    response: &HttpResponse,
+    head_only: bool,
+    keep_alive: bool,
+) -> Result<()> {
+    let mut out = format!("HTTP/1.1 {} {}\r\n", response.status, reason_phrase(response.status));
+    for (name, value) in &response.headers {
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    out.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    if !keep_alive {
+        out.push_str("Connection: close\r\n");
+    }
+    out.push_str("\r\n");
+    let mut out = out.into_bytes();
+    if !head_only {
+        out.extend(&response.body);
+    }
+    stream.get_mut().write_all(&out).await
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        406 => "Not Acceptable",
+        409 => "Conflict",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+/// The CoAP method an HTTP method maps to (RFC 8075 section 7.1), FETCH and iPATCH included for
+/// clients that can send them.
+fn coap_method(method: &str) -> Option<RequestType> {
+    match method {
+        "GET" | "HEAD" => Some(RequestType::Get),
+        "POST" => Some(RequestType::Post),
+        "PUT" => Some(RequestType::Put),
+        "DELETE" => Some(RequestType::Delete),
+        "FETCH" => Some(RequestType::Fetch),
+        "PATCH" => Some(RequestType::Patch),
+        "IPATCH" | "iPATCH" => Some(RequestType::IPatch),
+        _ => None,
+    }
+}
+
+/// The HTTP status a CoAP response code maps to (RFC 8075 section 7, table 2), given whether the
+/// response has a payload, for 2.02 and 2.04, and whether the request was conditional, for 2.03.
+fn http_status(code: ResponseType, has_payload: bool, conditional: bool) -> u16 {
+    match code {
+        ResponseType::Created => 201,
+        ResponseType::Deleted | ResponseType::Changed if !has_payload => 204,
+        ResponseType::Deleted | ResponseType::Changed => 200,
+        ResponseType::Valid if conditional => 304,
+        ResponseType::Valid | ResponseType::Content => 200,
+        // 4.02 and 4.05 are about options and methods the client didn't choose in HTTP terms
+        ResponseType::BadRequest | ResponseType::BadOption | ResponseType::MethodNotAllowed => 400,
+        ResponseType::Unauthorized | ResponseType::Forbidden => 403,
+        ResponseType::NotFound => 404,
+        ResponseType::NotAcceptable => 406,
+        ResponseType::Conflict => 409,
+        ResponseType::PreconditionFailed => 412,
+        ResponseType::RequestEntityTooLarge => 413,
+        ResponseType::UnsupportedContentFormat => 415,
+        ResponseType::UnprocessableEntity => 422,
+        ResponseType::TooManyRequests => 429,
+        ResponseType::InternalServerError => 500,
+        ResponseType::NotImplemented => 501,
+        ResponseType::BadGateway | ResponseType::ProxyingNotSupported => 502,
+        ResponseType::ServiceUnavailable => 503,
+        ResponseType::GatewayTimeout => 504,
+        // 2.31 and 4.08 only make sense mid block-wise transfer, which the gateway finishes, and
+        // the rest have no HTTP equivalent
+        _ => 502,
+    }
+}
+
+/// The Content-Format of an HTTP Content-Type (RFC 8075 section 6.2).
+fn content_format(content_type: &str) -> Option<ContentFormat> {
+    parse_content_format(content_type).ok()
+}
+
+/// The Content-Formats a client's Accept header asks for, in the order given, leaving out those
+/// without one. None if it accepts anything, so that the server chooses.
+fn accepted_formats(accept: &str) -> Option<Vec<ContentFormat>> {
+    let types: Vec<&str> = accept.split(',').map(|t| t.split(";q=").next().unwrap_or(t).trim()).collect();
+    if types.contains(&"*/*") {
+        return None;
+    }
+    let formats: Vec<ContentFormat> = types.iter().filter_map(|t| content_format(t)).collect();
+    (!formats.is_empty()).then_some(formats)
+}
+
+/// An HTTP entity tag of a CoAP ETag, and the other way around.
+fn entity_tag(etag: &[u8]) -> String {
+    format!("\"{}\"", hex(etag))
+}
+
+fn parse_entity_tag(tag: &str) -> Option<Vec<u8>> {
+    let tag = tag.trim().trim_start_matches("W/").trim_matches('"');
+    parse_etag(tag).ok()
+}
+
+/// Translates an HTTP request into a CoAP request for the same resource on the device, makes it,
+/// and translates the response back.
+async fn forward(conn: &Connection, args: &Args, request: &HttpRequest) -> HttpResponse {
+    let Some(method) = coap_method(&request.method) else {
+        return HttpResponse::error(501, &format!("{} has no CoAP equivalent", request.method));
+    };
+    // a proxy's absolute-form target, or the path and query of the resource on the device
+    let target = match request.target.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |slash| &rest[slash..]),
+        None => request.target.as_str(),
+    };
+    let url = match request_url(args, target.trim_start_matches('/')) {
+        Ok(url) => url,
+        Err(e) => return HttpResponse::error(400, &e.to_string()),
+    };
+    let content_format = match request.header("Content-Type") {
+        Some(content_type) if !request.body.is_empty() => match content_format(content_type) {
+            Some(cf) => Some(cf),
+            None => return HttpResponse::error(415, &format!("no Content-Format for {}", content_type)),
+        },
+        _ => None,
+    };
+    let accept = request.header("Accept").and_then(accepted_formats);
+    let payload = (!request.body.is_empty()).then(|| request.body.clone());
+    let mut coap_request = match build_coap_request_for_url(&url, method, payload, content_format, accept) {
+        Ok(coap_request) => coap_request,
+        Err(e) => return HttpResponse::error(400, &e.to_string()),
+    };
+    let message = &mut coap_request.message;
+    if let Some(tags) = request.header("If-Match") {
+        for etag in tags.split(',').filter_map(parse_entity_tag) {
+            message.add_option(CoapOption::IfMatch, etag);
+        }
+    }
+    // If-None-Match: * asks the device not to replace an existing resource, and entity tags on a
+    // GET ask it to validate them
+    let mut conditional = false;
+    match request.header("If-None-Match") {
+        Some("*") => message.add_option(CoapOption::IfNoneMatch, vec![]),
+        Some(tags) if method == RequestType::Get => {
+            for etag in tags.split(',').filter_map(parse_entity_tag) {
+                message.add_option(CoapOption::ETag, etag);
+                conditional = true;
+            }
+        }
+        _ => {}
+    }
+    // requests from several clients may be in flight together, and responses are routed by token
+    if !args.token_chosen() {
+        message.set_token(rand::random::<[u8; 8]>().to_vec());
+    }
+
+    let args = Args {
+        url: url.clone(),
+        ..args.clone()
+    };
+    match exchange(conn, &args, coap_request).await {
+        Ok(response) => {
+            let response = http_response(&response.message, conditional);
+            info!("{} {} -> {}", request.method, url, response.status);
+            response
+        }
+        Err(e) => {
+            warn!("{} {}: {}", request.method, url, e);
+            let status = if e.kind() == ErrorKind::TimedOut { 504 } else { 502 };
+            HttpResponse::error(status, &e.to_string())
+        }
+    }
+}
+
+/// Translates a CoAP response into HTTP (RFC 8075 sections 6.3 and 7).
+fn http_response(message: &Packet, conditional: bool) -> HttpResponse {
+    let code = match message.header.code {
+        MessageClass::Response(code) => code,
+        _ => ResponseType::UnKnown,
+    };
+    let status = http_status(code, !message.payload.is_empty(), conditional);
+    let mut headers = vec![];
+    if !message.payload.is_empty() {
+        // a Content-Format without a media type leaves the client to treat it as opaque
+        let content_type = match message.get_content_format() {
+            Some(cf) => content_format_name(usize::from(cf)),
+            None if code.is_error() => "text/plain; charset=utf-8".to_string(),
+            None => "application/octet-stream".to_string(),
+        };
+        let content_type = match content_type.parse::<usize>() {
+            Ok(_) => "application/octet-stream".to_string(),
+            Err(_) => content_type,
+        };
+        headers.push(("Content-Type", content_type));
+    }
+    if let Some(etag) = message.get_first_option(CoapOption::ETag) {
+        headers.push(("ETag", entity_tag(etag)));
+    }
+    match code {
+        ResponseType::Content | ResponseType::Valid => {
+            headers.push(("Cache-Control", format!("max-age={}", max_age(message).as_secs())))
+        }
+        ResponseType::ServiceUnavailable if message.get_first_option(CoapOption::MaxAge).is_some() => {
+            headers.push(("Retry-After", max_age(message).as_secs().to_string()))
+        }
+        _ => {}
+    }
+    if let Some(segments) = message.get_option(CoapOption::LocationPath) {
+        let mut location: String = segments
+            .iter()
+            .map(|segment| format!("/{}", String::from_utf8_lossy(segment)))
+            .collect();
+        if let Some(arguments) = message.get_option(CoapOption::LocationQuery) {
+            let arguments: Vec<String> =
+                arguments.iter().map(|a| String::from_utf8_lossy(a).into_owned()).collect();
+            location.push_str(&format!("?{}", arguments.join("&")));
+        }
+        headers.push(("Location", location));
+    }
+    HttpResponse {
+        status,
+        headers,
+        body: if status == 304 { vec![] } else { message.payload.clone() },
+    }
+}
+
+/// Answers the requests a client sends over one HTTP connection, until it closes it.
+async fn serve_client(conn: &Connection, args: &Args, stream: TcpStream) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    loop {
+        let request = match read_request(&mut stream).await {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                let status = match e.kind() {
+                    ErrorKind::Unsupported => 501,
+                    ErrorKind::FileTooLarge => 413,
+                    ErrorKind::InvalidData => 400,
+                    _ => return Err(e),
+                };
+                let response = HttpResponse::error(status, &e.to_string());
+                return write_response(&mut stream, &response, false, false).await;
+            }
+        };
+        let response = forward(conn, args, &request).await;
+        write_response(&mut stream, &response, request.method == "HEAD", request.keep_alive).await?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Listens for HTTP requests on `listen` and makes each of the device at the URL, translating
+/// methods, media types, conditional headers and status codes as an HTTP-to-CoAP proxy does (RFC
+/// 8075), so that HTTP clients can reach it. The request's path and query are those of the
+/// resource on the device.
+pub async fn coap_gateway(conn: Connection, args: &Args, listen: &str) -> Result<()> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", listen, e)))?;
+    info!("listening on http://{}, forwarding to {}", listener.local_addr()?, args.url);
+    let conn = Arc::new(conn);
+    loop {
+        let (stream, client) = listener.accept().await?;
+        let conn = conn.clone();
+        let args = args.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_client(&conn, &args, stream).await {
+                warn!("{}: {}", client, e);
+            }
+        });
+    }
+}
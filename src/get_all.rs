@@ -0,0 +1,186 @@
+use coap_lite::{CoapResponse, ContentFormat, MessageClass, RequestType, ResponseType};
+use futures::stream::{self, StreamExt};
+use log::info;
+use serde_json::Value;
+use std::io::{Error, Result};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use crate::coap_helper::*;
+use crate::content;
+use crate::discover::{filter_links, well_known_url, DiscoverOptions};
+use crate::mirror::resource_url;
+use crate::report::{self, OutputFormat};
+use crate::transport::Connection;
+use crate::{exchange, exchange_negotiating, Args, ERROR_RESPONSE_CLASS};
+
+/// How many resources to get at once unless `--concurrency` says otherwise: few enough not to
+/// swamp a constrained server.
+const DEFAULT_CONCURRENCY: usize = 4;
+
+/// The response to the GET of one of the resources found, or the error that kept it from coming.
+struct Snapshot {
+    href: String,
+    url: String,
+    result: Result<(CoapResponse, Duration)>,
+}
+
+/// The local resources the server lists in /.well-known/core, optionally only those of a
+/// resource type.
+async fn discover(conn: &Connection, args: &Args, rt: Option<&str>) -> Result<Vec<String>> {
+    let options = DiscoverOptions {
+        rt,
+        interface: None,
+        json: false,
+    };
+    let url = well_known_url(&args.url, &options)?;
+    info!("GET {}", url);
+    let request = build_coap_request_for_url(&url, RequestType::Get, None, None, None)?;
+    let response = exchange(conn, args, request).await?;
+    if *response.get_status() != ResponseType::Content {
+        return Err(Error::other(format!(
+            "resource discovery failed: {}",
+            response.message.header.code
+        )));
+    }
+    Ok(filter_links(&response.message.payload, &options)?
+        .into_iter()
+        .map(|link| link.target)
+        .filter(|target| target.starts_with('/') && target != "/.well-known/core")
+        .collect())
+}
+
+async fn get(
+    conn: &Connection,
+    args: &Args,
+    href: String,
+    accept: &[ContentFormat],
+    concurrency: usize,
+) -> Snapshot {
+    let url = match resource_url(&args.url, &href) {
+        Ok(url) => url,
+        Err(e) => {
+            return Snapshot {
+                url: href.clone(),
+                href,
+                result: Err(e),
+            };
+        }
+    };
+    let start = Instant::now();
+    let result = async {
+        let accept = Some(accept.to_vec());
+        let mut request = build_coap_request_for_url(&url, RequestType::Get, None, None, accept)?;
+        // responses are routed by token, so requests in flight together need distinct ones
+        if concurrency > 1 && !args.token_chosen() {
+            request.message.set_token(rand::random::<[u8; 8]>().to_vec());
+        }
+        let response = exchange_negotiating(conn, args, request).await?;
+        Ok((response, start.elapsed()))
+    }
+    .await;
+    Snapshot { href, url, result }
+}
+
+fn code_name(response: &CoapResponse) -> String {
+    match response.message.header.code {
+        MessageClass::Response(status) => {
+            format!("{} {}", response.message.header.code, response_code_info(status).0)
+        }
+        code => code.to_string(),
+    }
+}
+
+fn print_snapshots(args: &Args, snapshots: &[Snapshot]) {
+    for (i, snapshot) in snapshots.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        match &snapshot.result {
+            Ok((response, rtt)) => {
+                println!("{}  {}  {}ms", snapshot.href, code_name(response), rtt.as_millis());
+                for line in content::render_payload(&response.message, args.rendering()).lines() {
+                    println!("    {}", line);
+                }
+            }
+            Err(e) => println!("{}  error: {}", snapshot.href, e),
+        }
+    }
+}
+
+/// A JSON object per resource, as `exchange_model` describes its GET plus its path and, for a
+/// JSON or CBOR payload, the value it holds, or the error that kept the response from coming.
+fn snapshots_json(snapshots: &[Snapshot]) -> Vec<Value> {
+    snapshots
+        .iter()
+        .map(|snapshot| match &snapshot.result {
+            Ok((response, rtt)) => {
+                let mut model = report::exchange_model(&snapshot.url, "GET", &response.message, *rtt);
+                model["href"] = Value::from(snapshot.href.as_str());
+                model["value"] = content::payload_json(&response.message).unwrap_or(Value::Null);
+                model
+            }
+            Err(e) => serde_json::json!({
+                "href": snapshot.href,
+                "url": snapshot.url,
+                "error": e.to_string(),
+            }),
+        })
+        .collect()
+}
+
+/// Discovers the resources the server lists in /.well-known/core, optionally only those of a
+/// resource type, then gets them all, up to `--concurrency` at once, and prints their responses
+/// together, in the order listed, once they are all in. Fails if any of them couldn't be got.
+pub async fn coap_get_all(
+    conn: &Connection,
+    args: &Args,
+    rt: Option<&str>,
+    accept: &[String],
+) -> Result<()> {
+    let accept = accept.iter().map(|a| parse_content_format(a)).collect::<Result<Vec<_>>>()?;
+    let hrefs = discover(conn, args, rt).await?;
+    let concurrency = args.concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1);
+    info!("{} resource(s), {} at a time", hrefs.len(), concurrency);
+
+    let snapshots: Vec<Snapshot> = stream::iter(hrefs)
+        .map(|href| get(conn, args, href, &accept, concurrency))
+        .buffered(concurrency)
+        .collect()
+        .await;
+
+    match args.output {
+        OutputFormat::Json => {
+            let snapshots = Value::Array(snapshots_json(&snapshots));
+            println!("{}", serde_json::to_string_pretty(&snapshots).map_err(Error::other)?);
+        }
+        _ => print_snapshots(args, &snapshots),
+    }
+
+    let responses = snapshots.iter().filter_map(|s| s.result.as_ref().ok());
+    let mut errors = 0;
+    for (response, _) in responses {
+        if let MessageClass::Response(status) = response.message.header.code
+            && status.is_error()
+        {
+            errors += 1;
+            let class = u8::from(response.message.header.code) >> 5;
+            ERROR_RESPONSE_CLASS.fetch_max(class, Ordering::Relaxed);
+        }
+    }
+    let failed = snapshots.iter().filter(|s| s.result.is_err()).count();
+    info!(
+        "{} resource(s): {} error response(s), {} failed",
+        snapshots.len(),
+        errors,
+        failed
+    );
+    if failed > 0 {
+        return Err(Error::other(format!(
+            "{} of {} resource(s) could not be got",
+            failed,
+            snapshots.len()
+        )));
+    }
+    Ok(())
+}
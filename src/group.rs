@@ -0,0 +1,300 @@
+use coap_lite::{CoapRequest, ContentFormat, MessageClass, Packet, RequestType};
+use futures::future;
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::coap_helper::*;
+use crate::fleet::target_url;
+use crate::multicast::multicast_exchange;
+use crate::report::{self, OutputFormat};
+use crate::resolve::resolve_peer;
+use crate::{create_connection, create_connection_to, exchange, Args};
+
+/// How many of the members must succeed for the request to the group to succeed, given with
+/// `--threshold` as a number of members, e.g. `3`, or a share of them, e.g. `75%`.
+#[derive(Debug, Clone, Copy)]
+pub enum Threshold {
+    Count(usize),
+    Share(f64),
+}
+
+impl Threshold {
+    /// The number of a group's members that must succeed.
+    fn required(&self, members: usize) -> usize {
+        match *self {
+            Threshold::Count(count) => count,
+            Threshold::Share(share) => (share * members as f64).ceil() as usize,
+        }
+    }
+}
+
+pub fn parse_threshold(s: &str) -> std::result::Result<Threshold, String> {
+    if s.ends_with('%') {
+        let share = crate::bench::parse_percentage(s)?;
+        if share > 1.0 {
+            return Err(format!("invalid threshold: {}", s));
+        }
+        return Ok(Threshold::Share(share));
+    }
+    s.parse()
+        .map(Threshold::Count)
+        .map_err(|_| format!("invalid threshold: {}", s))
+}
+
+/// The request to make of every member of the group.
+pub struct GroupRequest<'a> {
+    pub method: RequestMethod,
+    pub content_format: Option<&'a str>,
+    pub data: Option<&'a str>,
+}
+
+impl GroupRequest<'_> {
+    fn build(
+        &self,
+        url: &str,
+        content_format: Option<ContentFormat>,
+    ) -> Result<CoapRequest<SocketAddr>> {
+        let payload = self.data.map(|data| data.as_bytes().to_vec());
+        let method = RequestType::from(self.method);
+        build_coap_request_for_url(url, method, payload, content_format, None)
+    }
+}
+
+/// What came of the request made of a member: its response and how long it took, the latter
+/// unknown for a response to a multicast request, or why there wasn't one.
+struct MemberResult {
+    member: String,
+    url: String,
+    outcome: Result<(Packet, Option<Duration>)>,
+}
+
+impl MemberResult {
+    fn succeeded(&self) -> bool {
+        match &self.outcome {
+            Ok((response, _)) => {
+                matches!(response.header.code, MessageClass::Response(code) if !code.is_error())
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// The URL of the resource at the URL's path on a member or group, given as a host, a host and
+/// port, an IPv6 address, e.g. `ff05::fd`, or a URL.
+fn member_url(url: &str, member: &str) -> String {
+    match member.parse::<std::net::Ipv6Addr>() {
+        Ok(addr) => target_url(url, &format!("[{}]", addr)),
+        Err(_) => target_url(url, member),
+    }
+}
+
+/// Makes the request of a member over a connection of its own.
+async fn request_member(
+    args: &Args,
+    url: &str,
+    request: &GroupRequest<'_>,
+    content_format: Option<ContentFormat>,
+) -> Result<(Packet, Option<Duration>)> {
+    let args = Args {
+        url: url.to_string(),
+        targets: None,
+        ..args.clone()
+    };
+    let conn = create_connection(&args).await?;
+    let mut coap_request = request.build(url, content_format)?;
+    // members may share a server, e.g. behind a proxy, and responses are routed by token
+    if !args.token_chosen() {
+        coap_request.message.set_token(rand::random::<[u8; 8]>().to_vec());
+    }
+    let start = Instant::now();
+    let response = exchange(&conn, &args, coap_request).await?;
+    Ok((response.message, Some(start.elapsed())))
+}
+
+/// Sends the request to the multicast group once, and matches the responses to the members by
+/// their addresses. A member that doesn't answer within the timeout has failed.
+async fn request_by_multicast(
+    args: &Args,
+    group: &str,
+    members: &[(String, String)],
+    request: &GroupRequest<'_>,
+    content_format: Option<ContentFormat>,
+) -> Result<Vec<MemberResult>> {
+    let group_url = member_url(&args.url, group);
+    let conn = create_connection_to(args, &group_url).await?;
+    if !conn.peer_addr.ip().is_multicast() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not a multicast address", group),
+        ));
+    }
+    let mut addresses = vec![];
+    for (_, url) in members {
+        let uri = CoapUri::parse(url)?;
+        addresses.push(resolve_peer(args, &uri.host, uri.port_or_default()).await?);
+    }
+    let coap_request = request.build(&group_url, content_format)?;
+    let responses = multicast_exchange(&conn, args, coap_request).await?;
+
+    let strangers = responses.iter().filter(|(source, _)| !addresses.contains(source)).count();
+    if strangers > 0 {
+        warn!("ignored {} response(s) from outside the member list", strangers);
+    }
+    let results = members
+        .iter()
+        .zip(addresses)
+        .map(|((member, url), address)| {
+            let response = responses.iter().find(|(source, _)| *source == address);
+            MemberResult {
+                member: member.clone(),
+                url: url.clone(),
+                outcome: match response {
+                    Some((_, response)) => Ok((response.clone(), None)),
+                    None => Err(Error::new(
+                        ErrorKind::TimedOut,
+                        format!("no response from {} within {}s", address, args.timeout),
+                    )),
+                },
+            }
+        })
+        .collect();
+    Ok(results)
+}
+
+fn print_results(results: &[MemberResult]) {
+    let width = results
+        .iter()
+        .map(|result| result.member.len())
+        .max()
+        .unwrap_or(0)
+        .max("MEMBER".len());
+    println!("{:width$}  CODE   TIME     PAYLOAD", "MEMBER", width = width);
+    for result in results {
+        match &result.outcome {
+            Ok((response, rtt)) => {
+                let payload = String::from_utf8_lossy(&response.payload);
+                let payload = payload.trim_end().replace('\n', " ");
+                let rtt = rtt.map_or("-".to_string(), |rtt| format!("{}ms", rtt.as_millis()));
+                println!(
+                    "{:width$}  {:5}  {:>7}  {}",
+                    result.member,
+                    response.header.code.to_string(),
+                    rtt,
+                    payload,
+                    width = width
+                );
+            }
+            Err(e) => println!("{:width$}  error: {}", result.member, e, width = width),
+        }
+    }
+}
+
+/// A JSON object per member: the response it gave, as `response_model` describes it, or the
+/// error that kept it from giving one, and whether it succeeded.
+fn result_json(result: &MemberResult, method: &str) -> Value {
+    let mut object = json!({
+        "member": result.member,
+        "url": result.url,
+        "method": method,
+        "ok": result.succeeded(),
+    });
+    match &result.outcome {
+        Ok((response, rtt)) => {
+            object["rtt_ms"] = json!(rtt.map(|rtt| rtt.as_secs_f64() * 1000.0));
+            object["response"] = report::response_model(response);
+        }
+        Err(e) => object["error"] = json!(e.to_string()),
+    }
+    object
+}
+
+/// Makes the same request of every member of a group, given as hosts or URLs and requested at the
+/// URL's path: one after the other with `serial`, all at once otherwise, or by sending it once to
+/// a multicast `group` they belong to. Reports what each member answered, and fails unless at
+/// least `threshold` of them succeeded, i.e. gave a 2.xx response.
+pub async fn coap_group(
+    args: &Args,
+    members: &[String],
+    group: Option<&str>,
+    serial: bool,
+    threshold: Threshold,
+    request: &GroupRequest<'_>,
+) -> Result<()> {
+    let members: Vec<(String, String)> = members
+        .iter()
+        .map(|member| (member.clone(), member_url(&args.url, member)))
+        .collect();
+    let required = threshold.required(members.len());
+    if required > members.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("threshold of {} is more than the {} member(s)", required, members.len()),
+        ));
+    }
+    let content_format = request.content_format.map(parse_content_format).transpose()?;
+    let method = request.method.name();
+    let how = match group {
+        Some(group) => format!("by multicast to {}", group),
+        None if serial => "one at a time".to_string(),
+        None => "at once".to_string(),
+    };
+    info!("{} {} of {} member(s), {}", method, args.url, members.len(), how);
+    if args.dry_run {
+        for (_, url) in &members {
+            info!("{} {}", method, url);
+        }
+        return Ok(());
+    }
+
+    let results = match group {
+        Some(group) => {
+            request_by_multicast(args, group, &members, request, content_format).await?
+        }
+        None if serial => {
+            let mut results = vec![];
+            for (member, url) in &members {
+                let outcome = request_member(args, url, request, content_format).await;
+                results.push(MemberResult {
+                    member: member.clone(),
+                    url: url.clone(),
+                    outcome,
+                });
+            }
+            results
+        }
+        None => {
+            future::join_all(members.iter().map(|(member, url)| async move {
+                MemberResult {
+                    member: member.clone(),
+                    url: url.clone(),
+                    outcome: request_member(args, url, request, content_format).await,
+                }
+            }))
+            .await
+        }
+    };
+
+    match args.output {
+        OutputFormat::Json => {
+            for result in &results {
+                println!("{}", result_json(result, method));
+            }
+        }
+        _ => print_results(&results),
+    }
+
+    let succeeded = results.iter().filter(|result| result.succeeded()).count();
+    info!("{} of {} member(s) succeeded, {} needed", succeeded, results.len(), required);
+    if succeeded < required {
+        return Err(Error::other(format!(
+            "only {} of {} member(s) succeeded, {} needed",
+            succeeded,
+            results.len(),
+            required
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use async_trait::async_trait;
+use coap_lite::{CoapRequest, ContentFormat, ResponseType};
+use log::info;
+use std::fs::File;
+use std::io::{Result, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use time::format_description::well_known::Iso8601;
+use time::OffsetDateTime;
+
+use crate::coap_helper::*;
+use crate::message_dump::dump_message;
+use crate::server::{set_response, RequestHandler, Server};
+use crate::Args;
+
+/// Logs every request it receives and answers all of them with the same canned response.
+struct InspectHandler {
+    code: ResponseType,
+    content_format: Option<ContentFormat>,
+    payload: Vec<u8>,
+    log: Option<Mutex<File>>,
+}
+
+#[async_trait]
+impl RequestHandler for InspectHandler {
+    async fn handle(&self, request: &mut CoapRequest<SocketAddr>) {
+        let now = OffsetDateTime::now_local()
+            .unwrap_or_else(|_| OffsetDateTime::now_utc())
+            .format(&Iso8601::DEFAULT)
+            .unwrap();
+        let entry = format!(
+            "{} from {}\n{}",
+            now,
+            request.source.unwrap(),
+            dump_message(&request.message)
+        );
+        println!("{}", entry);
+        if let Some(log) = &self.log {
+            let _ = writeln!(log.lock().unwrap(), "{}", entry);
+        }
+
+        set_response(request, self.code, self.payload.clone());
+        if let (Some(cf), Some(response)) = (self.content_format, request.response.as_mut()) {
+            response.message.set_content_format(cf);
+        }
+    }
+}
+
+pub async fn coap_inspect(
+    args: &Args,
+    code: &str,
+    content_format: Option<&str>,
+    payload: Option<&str>,
+    log: Option<&Path>,
+) -> Result<()> {
+    let CoapUri { host, port, .. } = CoapUri::parse(&args.url)?;
+    let log = log
+        .map(|path| File::options().create(true).append(true).open(path))
+        .transpose()?
+        .map(Mutex::new);
+    let handler = Arc::new(InspectHandler {
+        code: parse_response_code(code)?,
+        content_format: content_format.map(parse_content_format).transpose()?,
+        payload: payload.unwrap_or_default().as_bytes().to_vec(),
+        log,
+    });
+
+    let server = Server::bind((&host, port.unwrap_or(5683))).await?;
+    info!("listening on {}", server.local_addr()?);
+    server.run(handler).await
+}
@@ -0,0 +1,55 @@
+use coap_lite::{CoapOption, RequestType};
+use std::io::Result;
+use std::net::IpAddr;
+
+use crate::coap_helper::*;
+use crate::message_dump::decode_option_value;
+use crate::Args;
+
+/// Prints how the URL is turned into a destination address and request options, without sending
+/// anything.
+pub async fn coap_inspect_url(args: &Args) -> Result<()> {
+    let uri = CoapUri::parse(&args.url)?;
+    let host = uri.host.as_str();
+    println!("scheme: {}", uri.scheme);
+    println!("host: {}", host);
+    match uri.port {
+        Some(port) => println!("port: {}", port),
+        None => println!("port: {} (default)", uri.default_port()),
+    }
+    println!("path: {}", uri.path);
+    for (name, value) in uri.query_pairs() {
+        match value.as_str() {
+            "" => println!("query: {}", name),
+            value => println!("query: {} = {}", name, value),
+        }
+    }
+
+    match tokio::net::lookup_host((host, uri.port_or_default())).await {
+        Ok(addresses) => {
+            let addresses: Vec<_> = addresses.collect();
+            match addresses.first() {
+                Some(first) => println!("destination: {}", first),
+                None => println!("destination: none (the host has no addresses)"),
+            }
+            for other in addresses.iter().skip(1) {
+                println!("  also resolved: {} (not used)", other);
+            }
+        }
+        Err(e) => println!("destination: could not resolve {}: {}", host, e),
+    }
+
+    let request = build_coap_request_for_url(&args.url, RequestType::Get, None, None, None)?;
+    println!("options:");
+    for (number, values) in request.message.options() {
+        let option = CoapOption::from(*number);
+        for value in values {
+            println!("  {:?} ({}): {}", option, number, decode_option_value(option, value));
+        }
+    }
+    if host.parse::<IpAddr>().is_ok() {
+        println!("note: no Uri-Host option is sent, as the host is an IP address");
+    }
+    println!("note: no Uri-Port option is sent; the port only selects the destination");
+    Ok(())
+}
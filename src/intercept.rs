@@ -0,0 +1,183 @@
+use clap::ValueEnum;
+use coap_lite::{CoapOption, Packet};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use time::format_description::well_known::Iso8601;
+use time::OffsetDateTime;
+use tokio::net::UdpSocket;
+
+use crate::coap_helper::*;
+use crate::message_dump::dump_message;
+use crate::Args;
+
+/// Which messages passing through the proxy are tampered with.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Direction {
+    /// Messages from the client to the upstream device
+    Upstream,
+    /// Messages from the upstream device to the client
+    Downstream,
+    Both,
+}
+
+/// Replaces an option in the messages passing through, or removes it when there is no value.
+#[derive(Debug, Clone)]
+pub struct Rewrite {
+    option: CoapOption,
+    value: Option<Vec<u8>>,
+}
+
+/// Parses a rewrite written as `OPTION=VALUE`, e.g. `Uri-Host=device.local` or `Accept=50`.
+pub fn parse_rewrite(s: &str) -> std::result::Result<Rewrite, String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected OPTION=VALUE: {}", s))?;
+    let option = parse_option_name(name).map_err(|e| e.to_string())?;
+    let value = match value {
+        "" => None,
+        value => Some(encode_option_value(option, value).map_err(|e| e.to_string())?),
+    };
+    Ok(Rewrite { option, value })
+}
+
+#[derive(Debug, Clone)]
+pub struct Tampering {
+    pub delay: Option<Duration>,
+    /// Fraction of the messages to drop
+    pub drop: Option<f64>,
+    pub rewrites: Vec<Rewrite>,
+    pub direction: Direction,
+}
+
+impl Tampering {
+    fn applies_to(&self, direction: Direction) -> bool {
+        self.direction == Direction::Both || self.direction == direction
+    }
+}
+
+/// Logs a message and, if it is to be tampered with, drops, rewrites, or delays it before sending
+/// it on to `to` through `socket`.
+async fn relay(
+    socket: Arc<UdpSocket>,
+    bytes: Vec<u8>,
+    from: SocketAddr,
+    to: SocketAddr,
+    direction: Direction,
+    tampering: &Tampering,
+) -> Result<()> {
+    let now = OffsetDateTime::now_local()
+        .unwrap_or_else(|_| OffsetDateTime::now_utc())
+        .format(&Iso8601::DEFAULT)
+        .unwrap();
+    let parsed = Packet::from_bytes(&bytes);
+    match &parsed {
+        Ok(packet) => println!("{} {} -> {}\n{}", now, from, to, dump_message(packet)),
+        Err(_) => println!("{} {} -> {}\n  not a CoAP message ({} bytes)\n", now, from, to, bytes.len()),
+    }
+    if !tampering.applies_to(direction) {
+        socket.send_to(&bytes, to).await?;
+        return Ok(());
+    }
+
+    if let Some(drop) = tampering.drop
+        && rand::random::<f64>() < drop
+    {
+        println!("  dropped\n");
+        return Ok(());
+    }
+    let bytes = match parsed {
+        Ok(mut packet) if !tampering.rewrites.is_empty() => {
+            for rewrite in &tampering.rewrites {
+                packet.clear_option(rewrite.option);
+                if let Some(value) = &rewrite.value {
+                    packet.add_option(rewrite.option, value.clone());
+                }
+            }
+            println!("  rewritten to\n{}", dump_message(&packet));
+            packet.to_bytes_unlimited().map_err(Error::other)?
+        }
+        _ => bytes,
+    };
+    match tampering.delay {
+        Some(delay) => {
+            // delay in the background so that later messages aren't held up behind this one
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let _ = socket.send_to(&bytes, to).await;
+            });
+        }
+        None => {
+            socket.send_to(&bytes, to).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Relays messages between clients and an upstream device, giving each client its own socket
+/// towards the device so that the device's responses can be routed back to it.
+pub async fn coap_intercept(args: &Args, upstream: &str, tampering: Tampering) -> Result<()> {
+    let CoapUri { host, port, .. } = CoapUri::parse(&args.url)?;
+    let listener = Arc::new(UdpSocket::bind((host.as_str(), port.unwrap_or(5683))).await?);
+    let CoapUri {
+        host: upstream_host,
+        port: upstream_port,
+        ..
+    } = CoapUri::parse(upstream)?;
+    let upstream_addr = tokio::net::lookup_host((upstream_host.as_str(), upstream_port.unwrap_or(5683)))
+        .await?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("could not resolve {}", upstream_host)))?;
+    info!("listening on {}, forwarding to {}", listener.local_addr()?, upstream_addr);
+
+    let tampering = Arc::new(tampering);
+    let mut sessions: HashMap<SocketAddr, Arc<UdpSocket>> = HashMap::new();
+    let mut buf = vec![0; 65535];
+    loop {
+        let (len, client) = listener.recv_from(&mut buf).await?;
+        let session = match sessions.get(&client) {
+            Some(session) => session.clone(),
+            None => {
+                let bind_addr = match upstream_addr {
+                    SocketAddr::V4(_) => "0.0.0.0:0",
+                    SocketAddr::V6(_) => "[::]:0",
+                };
+                let session = Arc::new(UdpSocket::bind(bind_addr).await?);
+                let (session_rx, listener, tampering) =
+                    (session.clone(), listener.clone(), tampering.clone());
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 65535];
+                    while let Ok((len, from)) = session_rx.recv_from(&mut buf).await {
+                        if from != upstream_addr {
+                            continue;
+                        }
+                        let bytes = buf[..len].to_vec();
+                        let relayed = relay(
+                            listener.clone(),
+                            bytes,
+                            upstream_addr,
+                            client,
+                            Direction::Downstream,
+                            &tampering,
+                        )
+                        .await;
+                        if let Err(e) = relayed {
+                            warn!("could not relay to {}: {}", client, e);
+                        }
+                    }
+                });
+                sessions.insert(client, session.clone());
+                session
+            }
+        };
+        let bytes = buf[..len].to_vec();
+        if let Err(e) =
+            relay(session, bytes, client, upstream_addr, Direction::Upstream, &tampering).await
+        {
+            warn!("could not relay to {}: {}", upstream_addr, e);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use coap_lite::link_format::LinkFormatParser;
+use std::io::{Error, ErrorKind, Result};
+
+#[derive(Debug, Clone)]
+pub struct Link {
+    pub target: String,
+    pub attributes: Vec<(String, String)>,
+}
+
+impl Link {
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Parses a CoRE Link Format (RFC 6690) document.
+pub fn parse_links(s: &str) -> Result<Vec<Link>> {
+    LinkFormatParser::new(s)
+        .map(|link| {
+            let (target, attrs) = link.map_err(|_| {
+                Error::new(ErrorKind::InvalidData, "invalid link format")
+            })?;
+            Ok(Link {
+                target: target.to_string(),
+                attributes: attrs.map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            })
+        })
+        .collect()
+}
+
+/// Lays out links as a table of their target and common attributes, one line per link.
+pub fn links_table(links: &[Link]) -> String {
+    let rows: Vec<[String; 5]> = links
+        .iter()
+        .map(|link| {
+            let column = |name| link.attr(name).unwrap_or("-").to_string();
+            [link.target.clone(), column("rt"), column("if"), column("ct"), column("sz")]
+        })
+        .collect();
+    let headings = ["RESOURCE", "RT", "IF", "CT", "SZ"];
+    let widths: Vec<usize> = (0..headings.len())
+        .map(|i| rows.iter().map(|row| row[i].len()).chain([headings[i].len()]).max().unwrap())
+        .collect();
+    let line = |cells: [&str; 5]| {
+        let padded: Vec<String> =
+            cells.iter().zip(&widths).map(|(cell, width)| format!("{:width$}", cell)).collect();
+        padded.join("  ").trim_end().to_string()
+    };
+    let mut lines = vec![line(headings)];
+    for row in &rows {
+        lines.push(line([&row[0], &row[1], &row[2], &row[3], &row[4]]));
+    }
+    lines.join("\n")
+}
@@ -0,0 +1,99 @@
+use clap::ValueEnum;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::io::Write;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// The least severe messages logged.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum LogLevel {
+    Off,
+    /// Failures
+    Error,
+    /// Problems worked around, and failures of a part of a command that carries on
+    Warn,
+    /// What a command is doing, e.g. each request it sends
+    Info,
+    /// Transport events, such as retransmissions, timeouts and blocks
+    Debug,
+    /// Every datagram sent and received
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> LevelFilter {
+        match level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+/// How log messages are written to standard error.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    /// Plain lines, warnings and errors marked as such
+    Text,
+    /// One JSON object per line, with the timestamp, level, target and message
+    Json,
+}
+
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // what the libraries underneath have to say is only of interest when debugging, and their
+        // tracing is too fine-grained even then
+        let ours = metadata.target().starts_with(env!("CARGO_CRATE_NAME"));
+        let level = match (ours, self.level) {
+            (true, level) => level,
+            (false, LevelFilter::Debug | LevelFilter::Trace) => LevelFilter::Debug,
+            (false, level) => level.min(LevelFilter::Warn),
+        };
+        metadata.level() <= level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = match self.format {
+            LogFormat::Text => match record.level() {
+                Level::Error => format!("ERROR: {}", record.args()),
+                Level::Warn => format!("warning: {}", record.args()),
+                Level::Info => record.args().to_string(),
+                level => format!(
+                    "{}: {}: {}",
+                    level.as_str().to_lowercase(),
+                    record.target(),
+                    record.args()
+                ),
+            },
+            LogFormat::Json => serde_json::json!({
+                "timestamp": OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+                "level": record.level().as_str().to_lowercase(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            })
+            .to_string(),
+        };
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Sends log messages of the given level and more severe to standard error in the given format.
+pub fn init(level: LogLevel, format: LogFormat) {
+    let level = LevelFilter::from(level);
+    if log::set_boxed_logger(Box::new(Logger { level, format })).is_ok() {
+        log::set_max_level(level);
+    }
+}
@@ -0,0 +1,952 @@
+use base64::Engine;
+use clap::{Subcommand, ValueEnum};
+use coap_lite::{ContentFormat, MessageClass, Packet, RequestType, ResponseType};
+use log::info;
+use serde_json::json;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::cbor;
+use crate::coap_helper::*;
+use crate::link_format::parse_links;
+use crate::message_dump::hex;
+use crate::mirror::resource_url;
+use crate::output::{self, print_response};
+use crate::report::OutputFormat;
+use crate::transport::Connection;
+use crate::{send_request, Args};
+
+/// Operations of the LwM2M Device Management interface on a LwM2M client at the URL's server,
+/// addressing its objects by path: object, instance, resource and resource instance, e.g. 3/0/0.
+#[derive(Debug, Clone, Subcommand)]
+pub enum Lwm2mCommand {
+    /// Reads an object, object instance, resource or resource instance, listing each resource
+    /// read with its name
+    Read {
+        /// What to read, e.g. 3 (all instances of the Device object), 3/0 or 3/0/0
+        #[arg(value_parser = parse_path)]
+        path: Path,
+        /// Format to ask for, rather than SenML CBOR falling back on TLV
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+    },
+
+    /// Writes a resource or resource instance, or resources of an object instance given as
+    /// ID=VALUE, e.g. `write 3/0/14 +02` or `write 1/0 1=300 3=600`
+    Write {
+        /// What to write: an object instance, resource or resource instance
+        #[arg(value_parser = parse_path)]
+        path: Path,
+        /// Value to write, or ID=VALUE for each resource of an object instance; opaque values
+        /// are given in hex
+        #[arg(required = true)]
+        values: Vec<String>,
+        /// Format to write in, rather than SenML CBOR falling back on TLV
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+        /// Replace the object instance with the resources given, rather than update just those
+        #[arg(long)]
+        replace: bool,
+    },
+
+    /// Lists the object instances and resources under a path, with their attributes
+    Discover {
+        /// What to discover, e.g. 3 or 3/0
+        #[arg(value_parser = parse_path)]
+        path: Path,
+    },
+}
+
+/// The content formats LwM2M values can be read and written in.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Format {
+    /// application/senml+cbor (LwM2M 1.1)
+    SenmlCbor,
+    /// application/vnd.oma.lwm2m+tlv (LwM2M 1.0)
+    Tlv,
+    /// text/plain, for a single resource
+    Text,
+}
+
+impl Format {
+    fn content_format(self) -> ContentFormat {
+        match self {
+            Format::SenmlCbor => ContentFormat::ApplicationSenmlCBOR,
+            Format::Tlv => ContentFormat::ApplicationVndOmaLwm2mTlv,
+            Format::Text => ContentFormat::TextPlain,
+        }
+    }
+}
+
+/// A path to an object, object instance, resource or resource instance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path(Vec<u16>);
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for id in &self.0 {
+            write!(f, "/{}", id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a path of one to four numeric IDs separated by `/`, e.g. `3/0/0` or `/3/0/0`.
+fn parse_path(s: &str) -> std::result::Result<Path, String> {
+    let ids = s
+        .trim_matches('/')
+        .split('/')
+        .map(|id| id.parse::<u16>().map_err(|_| format!("invalid LwM2M path: {}", s)))
+        .collect::<std::result::Result<Vec<u16>, String>>()?;
+    if ids.len() > 4 {
+        return Err(format!("invalid LwM2M path: {}", s));
+    }
+    Ok(Path(ids))
+}
+
+/// The data types of LwM2M resources, which TLV doesn't carry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    String,
+    Integer,
+    Float,
+    Boolean,
+    Opaque,
+    Time,
+    Objlnk,
+    /// Executed rather than read or written
+    None,
+}
+
+/// The objects registered with OMNA that most clients implement.
+const OBJECTS: &[(u16, &str)] = &[
+    (0, "LwM2M Security"),
+    (1, "LwM2M Server"),
+    (2, "Access Control"),
+    (3, "Device"),
+    (4, "Connectivity Monitoring"),
+    (5, "Firmware Update"),
+    (6, "Location"),
+    (7, "Connectivity Statistics"),
+];
+
+/// The resources of the objects in [`OBJECTS`], as object, resource, name and type.
+const RESOURCES: &[(u16, u16, &str, Kind)] = &[
+    (0, 0, "LwM2M Server URI", Kind::String),
+    (0, 1, "Bootstrap-Server", Kind::Boolean),
+    (0, 2, "Security Mode", Kind::Integer),
+    (0, 3, "Public Key or Identity", Kind::Opaque),
+    (0, 4, "Server Public Key", Kind::Opaque),
+    (0, 5, "Secret Key", Kind::Opaque),
+    (0, 10, "Short Server ID", Kind::Integer),
+    (0, 11, "Client Hold Off Time", Kind::Integer),
+    (1, 0, "Short Server ID", Kind::Integer),
+    (1, 1, "Lifetime", Kind::Integer),
+    (1, 2, "Default Minimum Period", Kind::Integer),
+    (1, 3, "Default Maximum Period", Kind::Integer),
+    (1, 4, "Disable", Kind::None),
+    (1, 5, "Disable Timeout", Kind::Integer),
+    (1, 6, "Notification Storing When Disabled or Offline", Kind::Boolean),
+    (1, 7, "Binding", Kind::String),
+    (1, 8, "Registration Update Trigger", Kind::None),
+    (2, 0, "Object ID", Kind::Integer),
+    (2, 1, "Object Instance ID", Kind::Integer),
+    (2, 2, "ACL", Kind::Integer),
+    (2, 3, "Access Control Owner", Kind::Integer),
+    (3, 0, "Manufacturer", Kind::String),
+    (3, 1, "Model Number", Kind::String),
+    (3, 2, "Serial Number", Kind::String),
+    (3, 3, "Firmware Version", Kind::String),
+    (3, 4, "Reboot", Kind::None),
+    (3, 5, "Factory Reset", Kind::None),
+    (3, 6, "Available Power Sources", Kind::Integer),
+    (3, 7, "Power Source Voltage", Kind::Integer),
+    (3, 8, "Power Source Current", Kind::Integer),
+    (3, 9, "Battery Level", Kind::Integer),
+    (3, 10, "Memory Free", Kind::Integer),
+    (3, 11, "Error Code", Kind::Integer),
+    (3, 12, "Reset Error Code", Kind::None),
+    (3, 13, "Current Time", Kind::Time),
+    (3, 14, "UTC Offset", Kind::String),
+    (3, 15, "Timezone", Kind::String),
+    (3, 16, "Supported Binding and Modes", Kind::String),
+    (3, 17, "Device Type", Kind::String),
+    (3, 18, "Hardware Version", Kind::String),
+    (3, 19, "Software Version", Kind::String),
+    (3, 20, "Battery Status", Kind::Integer),
+    (3, 21, "Memory Total", Kind::Integer),
+    (3, 22, "ExtDevInfo", Kind::Objlnk),
+    (4, 0, "Network Bearer", Kind::Integer),
+    (4, 1, "Available Network Bearer", Kind::Integer),
+    (4, 2, "Radio Signal Strength", Kind::Integer),
+    (4, 3, "Link Quality", Kind::Integer),
+    (4, 4, "IP Addresses", Kind::String),
+    (4, 5, "Router IP Addresses", Kind::String),
+    (4, 6, "Link Utilization", Kind::Integer),
+    (4, 7, "APN", Kind::String),
+    (4, 8, "Cell ID", Kind::Integer),
+    (4, 9, "SMNC", Kind::Integer),
+    (4, 10, "SMCC", Kind::Integer),
+    (5, 0, "Package", Kind::Opaque),
+    (5, 1, "Package URI", Kind::String),
+    (5, 2, "Update", Kind::None),
+    (5, 3, "State", Kind::Integer),
+    (5, 5, "Update Result", Kind::Integer),
+    (5, 6, "PkgName", Kind::String),
+    (5, 7, "PkgVersion", Kind::String),
+    (5, 8, "Firmware Update Protocol Support", Kind::Integer),
+    (5, 9, "Firmware Update Delivery Method", Kind::Integer),
+    (6, 0, "Latitude", Kind::Float),
+    (6, 1, "Longitude", Kind::Float),
+    (6, 2, "Altitude", Kind::Float),
+    (6, 3, "Radius", Kind::Float),
+    (6, 4, "Velocity", Kind::Opaque),
+    (6, 5, "Timestamp", Kind::Time),
+    (6, 6, "Speed", Kind::Float),
+    (7, 0, "SMS Tx Counter", Kind::Integer),
+    (7, 1, "SMS Rx Counter", Kind::Integer),
+    (7, 2, "Tx Data", Kind::Integer),
+    (7, 3, "Rx Data", Kind::Integer),
+    (7, 4, "Max Message Size", Kind::Integer),
+    (7, 5, "Average Message Size", Kind::Integer),
+    (7, 6, "Start", Kind::None),
+    (7, 7, "Stop", Kind::None),
+    (7, 8, "Collection Period", Kind::Integer),
+];
+
+fn resource(ids: &[u16]) -> Option<(&'static str, Kind)> {
+    let [object, _, id, ..] = ids else {
+        return None;
+    };
+    RESOURCES
+        .iter()
+        .find(|(o, r, _, _)| o == object && r == id)
+        .map(|(_, _, name, kind)| (*name, *kind))
+}
+
+/// The name of the object or resource a path leads to, if it is a known one.
+fn name(ids: &[u16]) -> Option<&'static str> {
+    match ids {
+        [object] => OBJECTS.iter().find(|(o, _)| o == object).map(|(_, name)| *name),
+        [_, _, ..] => resource(ids).map(|(name, _)| name),
+        _ => None,
+    }
+}
+
+/// A resource value as read or to be written.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Opaque(Vec<u8>),
+    /// Seconds since the Unix epoch
+    Time(i64),
+    Objlnk(u16, u16),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{}", s),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Opaque(bytes) => write!(f, "{}", hex(bytes)),
+            Value::Time(t) => match OffsetDateTime::from_unix_timestamp(*t)
+                .ok()
+                .and_then(|time| time.format(&Rfc3339).ok())
+            {
+                Some(time) => write!(f, "{} ({})", t, time),
+                None => write!(f, "{}", t),
+            },
+            Value::Objlnk(object, instance) => write!(f, "{}:{}", object, instance),
+        }
+    }
+}
+
+impl Value {
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::String(s) => json!(s),
+            Value::Integer(n) | Value::Time(n) => json!(n),
+            Value::Float(x) => json!(x),
+            Value::Boolean(b) => json!(b),
+            Value::Opaque(bytes) => json!(hex(bytes)),
+            Value::Objlnk(..) => json!(self.to_string()),
+        }
+    }
+
+    /// Decodes a value as TLV carries it, guessing at the type of resources not in
+    /// [`RESOURCES`]: text if it is printable, an integer if it is as long as one, and otherwise
+    /// opaque.
+    fn from_tlv(bytes: &[u8], kind: Option<Kind>) -> Result<Value> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "invalid TLV value");
+        let integer = || match bytes.len() {
+            1 => Ok(i64::from(bytes[0] as i8)),
+            2 => Ok(i64::from(i16::from_be_bytes(bytes.try_into().unwrap()))),
+            4 => Ok(i64::from(i32::from_be_bytes(bytes.try_into().unwrap()))),
+            8 => Ok(i64::from_be_bytes(bytes.try_into().unwrap())),
+            _ => Err(invalid()),
+        };
+        let kind = kind.unwrap_or_else(|| match std::str::from_utf8(bytes) {
+            Ok(text) if !text.is_empty() && !text.contains(char::is_control) => Kind::String,
+            _ if matches!(bytes.len(), 1 | 2 | 4 | 8) => Kind::Integer,
+            _ => Kind::Opaque,
+        });
+        Ok(match kind {
+            Kind::String => Value::String(String::from_utf8_lossy(bytes).into_owned()),
+            Kind::Integer => Value::Integer(integer()?),
+            Kind::Time => Value::Time(integer()?),
+            Kind::Float => match bytes.len() {
+                4 => Value::Float(f64::from(f32::from_be_bytes(bytes.try_into().unwrap()))),
+                8 => Value::Float(f64::from_be_bytes(bytes.try_into().unwrap())),
+                _ => return Err(invalid()),
+            },
+            Kind::Boolean => match bytes {
+                [0] => Value::Boolean(false),
+                [1] => Value::Boolean(true),
+                _ => return Err(invalid()),
+            },
+            Kind::Objlnk => match bytes {
+                [o1, o2, i1, i2] => Value::Objlnk(
+                    u16::from_be_bytes([*o1, *o2]),
+                    u16::from_be_bytes([*i1, *i2]),
+                ),
+                _ => return Err(invalid()),
+            },
+            Kind::Opaque | Kind::None => Value::Opaque(bytes.to_vec()),
+        })
+    }
+
+    fn to_tlv(&self) -> Vec<u8> {
+        match self {
+            Value::String(s) => s.as_bytes().to_vec(),
+            Value::Integer(n) | Value::Time(n) => {
+                if let Ok(n) = i8::try_from(*n) {
+                    n.to_be_bytes().to_vec()
+                } else if let Ok(n) = i16::try_from(*n) {
+                    n.to_be_bytes().to_vec()
+                } else if let Ok(n) = i32::try_from(*n) {
+                    n.to_be_bytes().to_vec()
+                } else {
+                    n.to_be_bytes().to_vec()
+                }
+            }
+            Value::Float(x) => x.to_be_bytes().to_vec(),
+            Value::Boolean(b) => vec![u8::from(*b)],
+            Value::Opaque(bytes) => bytes.clone(),
+            Value::Objlnk(object, instance) => {
+                [object.to_be_bytes(), instance.to_be_bytes()].concat()
+            }
+        }
+    }
+
+    /// The SenML record that writes this value to the resource at `path`.
+    fn to_senml(&self, path: &[u16]) -> Result<serde_json::Value> {
+        let n = Path(path.to_vec()).to_string();
+        Ok(match self {
+            Value::String(s) => json!({ "n": n, "vs": s }),
+            Value::Integer(v) | Value::Time(v) => json!({ "n": n, "v": v }),
+            Value::Float(v) => json!({ "n": n, "v": v }),
+            Value::Boolean(b) => json!({ "n": n, "vb": b }),
+            Value::Objlnk(..) => json!({ "n": n, "vlo": self.to_string() }),
+            Value::Opaque(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "opaque values can only be written as TLV; use --format tlv",
+                ));
+            }
+        })
+    }
+}
+
+/// Resource values by path, as read or to be written.
+type Resources = Vec<(Vec<u16>, Value)>;
+
+/// Parses an object link, written `OBJECT:INSTANCE`.
+fn parse_objlnk(s: &str) -> Option<Value> {
+    let (object, instance) = s.split_once(':')?;
+    Some(Value::Objlnk(object.parse().ok()?, instance.parse().ok()?))
+}
+
+/// Parses a value to write to the resource at `path` as the type it is known to have, or, for
+/// resources not in [`RESOURCES`], as a boolean, integer or float if it is one and as text if not.
+fn parse_value(path: &[u16], s: &str) -> Result<Value> {
+    let invalid = |kind: &str| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("{}: expected {} for {}", s, kind, Path(path.to_vec())),
+        )
+    };
+    Ok(match resource(path).map(|(_, kind)| kind) {
+        Some(Kind::String) => Value::String(s.to_string()),
+        Some(Kind::Integer) => Value::Integer(s.parse().map_err(|_| invalid("an integer"))?),
+        Some(Kind::Float) => Value::Float(s.parse().map_err(|_| invalid("a number"))?),
+        Some(Kind::Boolean) => match s {
+            "true" | "1" => Value::Boolean(true),
+            "false" | "0" => Value::Boolean(false),
+            _ => return Err(invalid("true or false")),
+        },
+        Some(Kind::Opaque) => Value::Opaque(parse_hex(s)?),
+        Some(Kind::Time) => match s.parse() {
+            Ok(t) => Value::Time(t),
+            Err(_) => OffsetDateTime::parse(s, &Rfc3339)
+                .map(|time| Value::Time(time.unix_timestamp()))
+                .map_err(|_| invalid("seconds since the epoch or an RFC 3339 time"))?,
+        },
+        Some(Kind::Objlnk) => parse_objlnk(s).ok_or_else(|| invalid("OBJECT:INSTANCE"))?,
+        Some(Kind::None) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is executed, not written", Path(path.to_vec())),
+            ));
+        }
+        None => match s {
+            "true" => Value::Boolean(true),
+            "false" => Value::Boolean(false),
+            _ => match (s.parse::<i64>(), s.parse::<f64>()) {
+                (Ok(n), _) => Value::Integer(n),
+                (_, Ok(x)) => Value::Float(x),
+                _ => Value::String(s.to_string()),
+            },
+        },
+    })
+}
+
+/// Reads the TLV entries in `bytes` (OMA LwM2M TS Core section 7.4.3), as their type, ID and
+/// value.
+fn tlv_entries(mut bytes: &[u8]) -> Result<Vec<(u8, u16, &[u8])>> {
+    let truncated = || Error::new(ErrorKind::InvalidData, "truncated TLV");
+    let mut entries = vec![];
+    while let [type_byte, rest @ ..] = bytes {
+        let id_length = if type_byte & 0x20 != 0 { 2 } else { 1 };
+        let length_length = usize::from((type_byte >> 3) & 0x03);
+        let (id, rest) = rest.split_at_checked(id_length).ok_or_else(truncated)?;
+        let id = id.iter().fold(0u16, |acc, b| (acc << 8) | u16::from(*b));
+        let (length, rest) = rest.split_at_checked(length_length).ok_or_else(truncated)?;
+        let length = match length_length {
+            0 => usize::from(type_byte & 0x07),
+            _ => length.iter().fold(0, |acc, b| (acc << 8) | usize::from(*b)),
+        };
+        let (value, rest) = rest.split_at_checked(length).ok_or_else(truncated)?;
+        entries.push((type_byte >> 6, id, value));
+        bytes = rest;
+    }
+    Ok(entries)
+}
+
+/// Decodes TLV into the resources it holds, by path. `parent` is the path of the object,
+/// instance or resource the entries belong to.
+fn decode_tlv(parent: &[u16], bytes: &[u8], resources: &mut Resources) -> Result<()> {
+    for (kind, id, value) in tlv_entries(bytes)? {
+        let path = [parent, &[id]].concat();
+        match kind {
+            // object instance or multiple resource
+            0 | 2 => decode_tlv(&path, value, resources)?,
+            _ => {
+                let kind = resource(&path).map(|(_, kind)| kind);
+                resources.push((path, Value::from_tlv(value, kind)?));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The number of IDs a path to the entries at the top of a TLV payload has, from the type of the
+/// first: object instances, resources or resource instances.
+fn tlv_depth(bytes: &[u8]) -> usize {
+    match bytes.first().map(|b| b >> 6) {
+        Some(0) => 1,
+        Some(1) => 3,
+        _ => 2,
+    }
+}
+
+fn encode_tlv_entry(out: &mut Vec<u8>, kind: u8, id: u16, value: &[u8]) {
+    let mut type_byte = kind << 6;
+    if id > 0xff {
+        type_byte |= 0x20;
+    }
+    let length = value.len();
+    let length_bytes: Vec<u8> = match length {
+        0..=7 => {
+            type_byte |= length as u8;
+            vec![]
+        }
+        8..=0xff => {
+            type_byte |= 0x08;
+            vec![length as u8]
+        }
+        0x100..=0xffff => {
+            type_byte |= 0x10;
+            (length as u16).to_be_bytes().to_vec()
+        }
+        _ => {
+            type_byte |= 0x18;
+            (length as u32).to_be_bytes()[1..].to_vec()
+        }
+    };
+    out.push(type_byte);
+    if id > 0xff {
+        out.extend(id.to_be_bytes());
+    } else {
+        out.push(id as u8);
+    }
+    out.extend(length_bytes);
+    out.extend(value);
+}
+
+/// Decodes SenML into the resources it holds, by path, resolving each record's name against the
+/// base name in effect.
+fn decode_senml(records: &serde_json::Value) -> Result<Resources> {
+    let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+    let records = records.as_array().ok_or_else(|| invalid("SenML isn't an array"))?;
+    let mut base = String::new();
+    let mut resources = vec![];
+    for record in records {
+        if let Some(bn) = record.get("bn").and_then(|bn| bn.as_str()) {
+            base = bn.to_string();
+        }
+        let name = format!("{}{}", base, record.get("n").and_then(|n| n.as_str()).unwrap_or(""));
+        let path = parse_path(&name).map_err(|e| invalid(&e))?.0;
+        let kind = resource(&path).map(|(_, kind)| kind);
+        let value = if let Some(v) = record.get("v") {
+            match (v.as_i64(), kind) {
+                (Some(t), Some(Kind::Time)) => Value::Time(t),
+                (Some(n), _) => Value::Integer(n),
+                (None, _) => Value::Float(v.as_f64().ok_or_else(|| invalid("invalid SenML v"))?),
+            }
+        } else if let Some(vs) = record.get("vs").and_then(|vs| vs.as_str()) {
+            Value::String(vs.to_string())
+        } else if let Some(vb) = record.get("vb").and_then(|vb| vb.as_bool()) {
+            Value::Boolean(vb)
+        } else if let Some(vd) = record.get("vd").and_then(|vd| vd.as_str()) {
+            Value::Opaque(
+                base64::engine::general_purpose::URL_SAFE_NO_PAD
+                    .decode(vd.trim_end_matches('='))
+                    .map_err(|_| invalid("invalid SenML vd"))?,
+            )
+        } else if let Some(vlo) = record.get("vlo").and_then(|vlo| vlo.as_str()) {
+            parse_objlnk(vlo).ok_or_else(|| invalid("invalid SenML vlo"))?
+        } else {
+            continue;
+        };
+        resources.push((path, value));
+    }
+    Ok(resources)
+}
+
+/// The resources a response to a Read holds, or `None` if it isn't in a format they can be read
+/// from.
+fn decode_response(path: &Path, message: &Packet) -> Result<Option<Resources>> {
+    let payload = &message.payload;
+    let resources = match message.get_content_format() {
+        Some(ContentFormat::ApplicationVndOmaLwm2mTlv) => {
+            let parent = &path.0[..tlv_depth(payload).min(path.0.len())];
+            let mut resources = vec![];
+            decode_tlv(parent, payload, &mut resources)?;
+            resources
+        }
+        Some(ContentFormat::ApplicationSenmlCBOR) => {
+            decode_senml(&cbor::to_json(payload, Some(&cbor::SENML))?)?
+        }
+        Some(ContentFormat::TextPlain) | None if path.0.len() >= 3 => {
+            let text = String::from_utf8_lossy(payload);
+            let value = match resource(&path.0).map(|(_, kind)| kind) {
+                Some(Kind::String) => Value::String(text.into_owned()),
+                _ => parse_value(&path.0, &text).unwrap_or(Value::String(text.into_owned())),
+            };
+            vec![(path.0.clone(), value)]
+        }
+        Some(ContentFormat::ApplicationOctetStream) if path.0.len() >= 3 => {
+            vec![(path.0.clone(), Value::Opaque(payload.clone()))]
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(resources))
+}
+
+fn print_resources(args: &Args, resources: &[(Vec<u16>, Value)]) {
+    if args.output == OutputFormat::Json {
+        let resources: Vec<serde_json::Value> = resources
+            .iter()
+            .map(|(path, value)| {
+                json!({
+                    "path": Path(path.clone()).to_string(),
+                    "name": name(path),
+                    "value": value.to_json(),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::Value::Array(resources));
+        return;
+    }
+    let rows: Vec<(String, &str, String)> = resources
+        .iter()
+        .map(|(path, value)| {
+            (Path(path.clone()).to_string(), name(path).unwrap_or(""), value.to_string())
+        })
+        .collect();
+    let path_width = rows.iter().map(|(path, _, _)| path.len()).max().unwrap_or(0);
+    let name_width = rows.iter().map(|(_, name, _)| name.len()).max().unwrap_or(0);
+    for (path, name, value) in rows {
+        let line = format!("{:path_width$}  {:name_width$}  {}", path, name, value);
+        println!("{}", line.trim_end());
+    }
+}
+
+fn is_error(message: &Packet) -> bool {
+    matches!(message.header.code, MessageClass::Response(code) if code.is_error())
+}
+
+/// The payload writing `values` to `path` in `format`.
+fn write_payload(path: &Path, values: &[String], format: Format) -> Result<Vec<u8>> {
+    let resources: Resources = match path.0.len() {
+        2 => values
+            .iter()
+            .map(|value| {
+                let (id, value) = value
+                    .split_once('=')
+                    .and_then(|(id, value)| Some((id.parse::<u16>().ok()?, value)))
+                    .ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("expected ID=VALUE for an object instance: {}", value),
+                        )
+                    })?;
+                let path = [path.0.as_slice(), &[id]].concat();
+                let value = parse_value(&path, value)?;
+                Ok((path, value))
+            })
+            .collect::<Result<_>>()?,
+        3 | 4 => match values {
+            [value] => vec![(path.0.clone(), parse_value(&path.0, value)?)],
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "a resource is written with a single value",
+                ));
+            }
+        },
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "only an object instance, resource or resource instance can be written",
+            ));
+        }
+    };
+    match format {
+        Format::SenmlCbor => {
+            let records = resources
+                .iter()
+                .map(|(path, value)| value.to_senml(path))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(cbor::from_json(&serde_json::Value::Array(records), Some(&cbor::SENML)))
+        }
+        Format::Tlv => {
+            let mut out = vec![];
+            for (path, value) in &resources {
+                // resources, or a resource instance on its own
+                let kind = if path.len() == 4 { 1 } else { 3 };
+                encode_tlv_entry(&mut out, kind, *path.last().unwrap(), &value.to_tlv());
+            }
+            Ok(out)
+        }
+        Format::Text => match resources.as_slice() {
+            [(_, Value::Opaque(_))] | [] | [_, _, ..] => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "only a single resource that isn't opaque can be written as text",
+            )),
+            [(_, Value::Time(t))] => Ok(t.to_string().into_bytes()),
+            [(_, value)] => Ok(value.to_string().into_bytes()),
+        },
+    }
+}
+
+/// Lists the links a Discover responds with, with the names of the objects and resources they
+/// lead to.
+fn print_discovered(args: &Args, message: &Packet) -> Result<()> {
+    let links = parse_links(&String::from_utf8_lossy(&message.payload))?;
+    let rows: Vec<(String, &str, String)> = links
+        .iter()
+        .map(|link| {
+            let ids = parse_path(&link.target).map(|path| path.0).unwrap_or_default();
+            let attributes: Vec<String> = link
+                .attributes
+                .iter()
+                .map(|(name, value)| match value.as_str() {
+                    "" => name.clone(),
+                    _ => format!("{}={}", name, value),
+                })
+                .collect();
+            (link.target.clone(), name(&ids).unwrap_or(""), attributes.join(";"))
+        })
+        .collect();
+    if args.output == OutputFormat::Json {
+        let links: Vec<serde_json::Value> = links
+            .iter()
+            .zip(&rows)
+            .map(|(link, (path, name, _))| {
+                let attributes: serde_json::Map<String, serde_json::Value> = link
+                    .attributes
+                    .iter()
+                    .map(|(name, value)| (name.clone(), json!(value)))
+                    .collect();
+                json!({
+                    "path": path,
+                    "name": (!name.is_empty()).then_some(name),
+                    "attributes": attributes,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::Value::Array(links));
+        return Ok(());
+    }
+    let path_width = rows.iter().map(|(path, _, _)| path.len()).max().unwrap_or(0);
+    let name_width = rows.iter().map(|(_, name, _)| name.len()).max().unwrap_or(0);
+    for (path, name, attributes) in rows {
+        let line = format!("{:path_width$}  {:name_width$}  {}", path, name, attributes);
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
+
+pub async fn coap_lwm2m(conn: &Connection, args: &Args, command: &Lwm2mCommand) -> Result<()> {
+    match command {
+        Lwm2mCommand::Read { path, format } => {
+            let url = resource_url(&args.url, &path.to_string())?;
+            info!("GET {}", url);
+            let accept = match format {
+                Some(format) => vec![format.content_format()],
+                None => vec![
+                    ContentFormat::ApplicationSenmlCBOR,
+                    ContentFormat::ApplicationVndOmaLwm2mTlv,
+                ],
+            };
+            let request =
+                build_coap_request_for_url(&url, RequestType::Get, None, None, Some(accept))?;
+            let Some(response) = send_request(conn, args, request).await? else {
+                return Ok(());
+            };
+            if is_error(&response.message) {
+                return print_response(&response.message, args, None);
+            }
+            match decode_response(path, &response.message)? {
+                Some(resources) => {
+                    output::print_code(&response.message, args);
+                    print_resources(args, &resources);
+                    output::print_write_out(&response.message, args)
+                }
+                None => print_response(&response.message, args, args.output_file.as_deref()),
+            }
+        }
+        Lwm2mCommand::Write {
+            path,
+            values,
+            format,
+            replace,
+        } => {
+            let url = resource_url(&args.url, &path.to_string())?;
+            // a Write to an object instance updates the resources given unless it replaces it
+            let method = match path.0.len() {
+                2 if !replace => RequestType::Post,
+                _ => RequestType::Put,
+            };
+            let fallback = format.is_none();
+            let mut format = format.unwrap_or(Format::SenmlCbor);
+            loop {
+                let payload = write_payload(path, values, format)?;
+                info!("{} {}", format!("{:?}", method).to_uppercase(), url);
+                let request = build_coap_request_for_url(
+                    &url,
+                    method,
+                    Some(payload),
+                    Some(format.content_format()),
+                    None,
+                )?;
+                let Some(response) = send_request(conn, args, request).await? else {
+                    return Ok(());
+                };
+                // a LwM2M 1.0 client knows only TLV
+                if *response.get_status() == ResponseType::UnsupportedContentFormat
+                    && format == Format::SenmlCbor
+                    && fallback
+                {
+                    info!("{} Unsupported Content-Format; trying TLV", response.message.header.code);
+                    format = Format::Tlv;
+                    continue;
+                }
+                return print_response(&response.message, args, args.output_file.as_deref());
+            }
+        }
+        Lwm2mCommand::Discover { path } => {
+            let url = resource_url(&args.url, &path.to_string())?;
+            info!("GET {}", url);
+            let request = build_coap_request_for_url(
+                &url,
+                RequestType::Get,
+                None,
+                None,
+                Some(vec![ContentFormat::ApplicationLinkFormat]),
+            )?;
+            let Some(response) = send_request(conn, args, request).await? else {
+                return Ok(());
+            };
+            if is_error(&response.message) {
+                return print_response(&response.message, args, None);
+            }
+            output::print_code(&response.message, args);
+            print_discovered(args, &response.message)?;
+            output::print_write_out(&response.message, args)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tlv(kind: u8, id: u16, value: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        encode_tlv_entry(&mut out, kind, id, value);
+        out
+    }
+
+    #[test]
+    fn encodes_tlv_ids_and_lengths() {
+        for (id, length, header) in [
+            (5, 0, "c005"),
+            (5, 7, "c705"),
+            (255, 8, "c8ff08"),
+            (256, 8, "e8010008"),
+            (65535, 255, "e8ffffff"),
+            (5, 256, "d0050100"),
+            (300, 65535, "f0012cffff"),
+            (5, 65536, "d805010000"),
+            (5, 0xff_ffff, "d805ffffff"),
+        ] {
+            let value: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            let encoded = tlv(3, id, &value);
+            assert_eq!(hex(&encoded[..encoded.len() - length]), header, "{} {}", id, length);
+            assert_eq!(tlv_entries(&encoded).unwrap(), [(3, id, value.as_slice())]);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_tlv() {
+        let truncated = ["c8", "c800", "c80005", "e800", "e80001", "d00001", "d8000100", "c30001"];
+        for truncated in truncated {
+            assert_eq!(
+                tlv_entries(&parse_hex(truncated).unwrap()).unwrap_err().kind(),
+                ErrorKind::InvalidData,
+                "{}",
+                truncated
+            );
+        }
+        assert!(tlv_entries(&[]).unwrap().is_empty());
+    }
+
+    /// The Device object instance of the example in OMA LwM2M TS Core section 7.4.3, read as
+    /// the instances of the object.
+    #[test]
+    fn decodes_object_instances() {
+        let instance = parse_hex(concat!(
+            "c800144f70656e204d6f62696c6520416c6c69616e6365",
+            "c801164c69676874776569676874204d324d20436c69656e74",
+            "c80209333435303030313233",
+            "c303312e30",
+            "8606410001410105",
+            "88070842000ed842011388",
+            "870841007d42010384",
+            "c10964",
+            "c10a0f",
+            "830b410000",
+            "c40d5182428f",
+            "c60e2b30323a3030",
+            "c11055",
+        ))
+        .unwrap();
+        let payload = [tlv(0, 0, &instance), tlv(0, 1, &tlv(3, 0, b"Other"))].concat();
+        let mut message = Packet::new();
+        message.set_content_format(ContentFormat::ApplicationVndOmaLwm2mTlv);
+        message.payload = payload;
+        let resources = decode_response(&Path(vec![3]), &message).unwrap().unwrap();
+        let expected = [
+            (vec![3, 0, 0], Value::String("Open Mobile Alliance".to_string())),
+            (vec![3, 0, 1], Value::String("Lightweight M2M Client".to_string())),
+            (vec![3, 0, 2], Value::String("345000123".to_string())),
+            (vec![3, 0, 3], Value::String("1.0".to_string())),
+            (vec![3, 0, 6, 0], Value::Integer(1)),
+            (vec![3, 0, 6, 1], Value::Integer(5)),
+            (vec![3, 0, 7, 0], Value::Integer(3800)),
+            (vec![3, 0, 7, 1], Value::Integer(5000)),
+            (vec![3, 0, 8, 0], Value::Integer(125)),
+            (vec![3, 0, 8, 1], Value::Integer(900)),
+            (vec![3, 0, 9], Value::Integer(100)),
+            (vec![3, 0, 10], Value::Integer(15)),
+            (vec![3, 0, 11, 0], Value::Integer(0)),
+            (vec![3, 0, 13], Value::Time(1367491215)),
+            (vec![3, 0, 14], Value::String("+02:00".to_string())),
+            (vec![3, 0, 16], Value::String("U".to_string())),
+            (vec![3, 1, 0], Value::String("Other".to_string())),
+        ];
+        assert_eq!(resources, expected);
+
+        // the same instance read on its own, and a resource of it
+        message.payload = instance;
+        let resources = decode_response(&Path(vec![3, 0]), &message).unwrap().unwrap();
+        assert_eq!(resources, expected[..16]);
+        message.payload = tlv(1, 1, &[0x0e, 0xd8]);
+        let resources = decode_response(&Path(vec![3, 0, 7, 1]), &message).unwrap().unwrap();
+        assert_eq!(resources, [(vec![3, 0, 7, 1], Value::Integer(3800))]);
+    }
+
+    #[test]
+    fn rejects_values_of_the_wrong_type() {
+        let mut resources = vec![];
+        // Notification Storing is a boolean, Current Time an integer
+        for (parent, payload) in [
+            (vec![1, 0], tlv(3, 6, &[2])),
+            (vec![3, 0], tlv(3, 13, &[1, 2, 3])),
+        ] {
+            let e = decode_tlv(&parent, &payload, &mut resources).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn writes_tlv() {
+        let values = ["1=300".to_string(), "6=true".to_string(), "7=UQ".to_string()];
+        let payload = write_payload(&Path(vec![1, 0]), &values, Format::Tlv).unwrap();
+        assert_eq!(hex(&payload), "c201012cc10601c2075551");
+        let payload = write_payload(&Path(vec![3, 0, 14]), &["+02".to_string()], Format::Tlv);
+        assert_eq!(hex(&payload.unwrap()), "c30e2b3032");
+        let payload = write_payload(&Path(vec![3, 0, 6, 300]), &["7".to_string()], Format::Tlv);
+        assert_eq!(hex(&payload.unwrap()), "61012c07");
+        let named = ["lifetime=300".to_string()];
+        assert!(write_payload(&Path(vec![1, 0]), &named, Format::Tlv).is_err());
+        assert!(write_payload(&Path(vec![1, 0, 1]), &["x".to_string()], Format::Tlv).is_err());
+        assert!(write_payload(&Path(vec![1]), &["1=300".to_string()], Format::Tlv).is_err());
+    }
+
+    #[test]
+    fn parses_paths() {
+        for (s, ids) in [
+            ("3", vec![3]),
+            ("3/0", vec![3, 0]),
+            ("/3/0/0", vec![3, 0, 0]),
+            ("3/0/6/1/", vec![3, 0, 6, 1]),
+            ("65535/65535", vec![65535, 65535]),
+        ] {
+            assert_eq!(parse_path(s).unwrap(), Path(ids));
+        }
+        assert_eq!(Path(vec![3, 0, 0]).to_string(), "/3/0/0");
+        for s in ["", "/", "x", "3/x", "3//0", "3/0/0/0/0", "65536", "-1", "3/ 0", "3.0"] {
+            assert_eq!(parse_path(s).unwrap_err(), format!("invalid LwM2M path: {}", s));
+        }
+    }
+}
@@ -1,263 +1,2924 @@
+mod batch;
+mod bench;
+mod blockwise;
+mod cache;
+mod cancel;
+mod cbor;
+mod check;
 mod coap_helper;
+mod compare_families;
+mod content;
+mod daemon;
+mod decode;
+mod diff;
+mod discover;
+mod dns;
+mod doctor;
+mod dotenv;
+mod download;
+mod dtls;
+mod error;
+mod etag_store;
+mod expect;
+mod fleet;
+mod gateway;
+mod get_all;
+mod group;
+mod inspect;
+mod inspect_url;
+mod intercept;
+mod link_format;
+mod logging;
+mod lwm2m;
+mod message_dump;
+mod mirror;
+mod mock;
+mod multicast;
+mod observe;
+mod oscore;
+mod output;
+mod ping;
+mod poll;
+mod prefetch;
+mod profile;
+mod proxy;
+mod rd;
+mod record;
+mod report;
+mod resolve;
+mod script;
+mod selftest;
+mod senml;
+mod serve;
+mod server;
+mod sqlite_log;
+mod tcp;
+mod timestamp;
+mod transport;
+mod upload;
+mod websocket;
 
-use clap::{Parser, Subcommand};
-use coap::client::ObserveMessage;
-use coap::UdpCoAPClient;
-use coap_lite::{Packet, RequestType};
-use time::format_description::well_known::Iso8601;
-use time::OffsetDateTime;
-use std::io::{Error, ErrorKind, Result};
-use std::path::PathBuf;
-use std::time::Duration;
-use tokio::signal::ctrl_c;
+use base64::Engine;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand};
+use coap_lite::{
+    CoapOption, CoapRequest, CoapResponse, ContentFormat, MessageClass, MessageType, Packet,
+    RequestType, ResponseType,
+};
+use log::{error, info, warn};
+use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 
 use coap_helper::*;
+use cache::ResponseCache;
+use etag_store::EtagStore;
+use report::OutputFormat;
+use timestamp::TimestampFormat;
+use transport::*;
 
 const DEFAULT_RECEIVE_TIMEOUT: u64 = 1;
 
-#[derive(Debug, Parser)]
-#[command(author, version, about)]
+// set when any response arrives later than --max-rtt allows, so that the command fails once it
+// has finished
+static RTT_BUDGET_EXCEEDED: AtomicBool = AtomicBool::new(false);
+
+// the highest class (4 or 5) of the error responses printed, so that --fail can fail the command
+// once it has finished
+static ERROR_RESPONSE_CLASS: AtomicU8 = AtomicU8::new(0);
+
+const EXIT_STATUS_HELP: &str = "Exit status:
+  0    success
+  1    any other error
+  2    invalid command line
+  3    no response within the timeout, or a request not complete by its deadline
+  4    a 4.xx client error response (with --fail)
+  5    a 5.xx server error response (with --fail)
+  6    network error, e.g. connection refused or host unreachable
+  7    a response not as expected by --expect-code, --expect-content-format or --expect-body
+  130  interrupted with Ctrl-C";
+
+#[derive(Debug, Clone, Parser)]
+#[command(author, version, about, after_help = EXIT_STATUS_HELP)]
 struct Args {
-    /// COAP resource URL
+    /// COAP resource URL, which may be relative to --base or the profile's base URL
+    // required unless given by --base, and by every command but those that send nothing, as
+    // checked in main
+    #[arg(default_value = "", hide_default_value = true)]
     url: String,
 
-    /// Receive timeout in seconds
+    /// Base URL that the URL, and those given to a request command, may be relative to
+    #[arg(global = true, long, env = "COAP_BASE_URL")]
+    base: Option<String>,
+
+    /// Profile in the configuration file (~/.config/coap-cli/config.toml) to take defaults from
+    #[arg(global = true, long)]
+    profile: Option<String>,
+
+    /// Path segment to append to the URL, escaped as needed to be sent as one Uri-Path option
+    /// (repeatable)
+    #[arg(global = true, long = "path-segment", visible_alias = "path", value_name = "SEGMENT")]
+    path_segments: Vec<String>,
+
+    /// Query argument, e.g. key=value, to append to the URL, escaped as needed to be sent as one
+    /// Uri-Query option (repeatable)
+    #[arg(global = true, long = "query", value_name = "KEY=VALUE")]
+    query: Vec<String>,
+
+    /// Receive timeout in seconds (for requests to a multicast group, how long to collect
+    /// responses)
     #[arg(global = true, long, default_value_t = DEFAULT_RECEIVE_TIMEOUT)]
     timeout: u64,
 
+    /// Path MTU in bytes; larger requests are sent block-wise [default: 1280]
+    #[arg(global = true, long)]
+    mtu: Option<usize>,
+
+    /// Block size in bytes (16 to 1024) to send larger request payloads in, and to ask the server
+    /// to send responses in [default: chosen from the MTU]
+    #[arg(global = true, long, value_parser = blockwise::parse_block_size)]
+    block_size: Option<usize>,
+
+    /// Print the size of the request that would be sent, without sending it (for sync, report
+    /// the differences without applying them)
+    #[arg(global = true, long)]
+    dry_run: bool,
+
+    /// Shell command to pipe the payload of each request through before it is sent, e.g. a
+    /// vendor codec or a signer; the payload is sent as the command writes it
+    #[arg(global = true, long, value_name = "COMMAND")]
+    pre_cmd: Option<String>,
+
+    /// Shell command to pipe the payload of each response and notification through before it is
+    /// printed or used, e.g. "gunzip"; error responses are left as they are
+    #[arg(global = true, long, value_name = "COMMAND")]
+    post_cmd: Option<String>,
+
+    /// Check that the payload is what its content format says it is before sending it, e.g. that
+    /// JSON parses and CBOR is well-formed, rather than leaving the server to answer 4.00
+    #[arg(global = true, long)]
+    validate: bool,
+
+    /// Identity to present to the server when using DTLS with a pre-shared key
+    // as with --psk-key, whether it is given with the key rather than a certificate is checked in
+    // main, taking into account where each came from
+    #[arg(global = true, long, env = "COAP_PSK_IDENTITY")]
+    psk_identity: Option<String>,
+
+    /// Pre-shared key for DTLS, as text or as hex prefixed with 0x; best given by environment
+    /// variable or .env file, to keep it out of the shell's history and the process list
+    #[arg(global = true, long, env = "COAP_PSK_KEY", hide_env_values = true)]
+    psk_key: Option<String>,
+
+    /// Directory to keep DTLS sessions in, so that the next run against the same server resumes
+    /// the session rather than handshaking again, e.g. to spare a battery-powered device. The
+    /// session is resumed from the same local port, which the server knows it by (the DTLS
+    /// implementation has no Connection IDs, RFC 9146, to do without one), and only if a CoAP ping
+    /// shows the server still has it
+    #[arg(global = true, long, value_name = "DIR")]
+    session_cache: Option<PathBuf>,
+
+    /// Client certificate (PEM) to authenticate with when using DTLS
+    #[arg(global = true, long, requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// Private key (PKCS #8 PEM) for the client certificate
+    #[arg(global = true, long, requires = "cert")]
+    key: Option<PathBuf>,
+
+    /// CA certificates (PEM) to verify the server's DTLS certificate against
+    #[arg(global = true, long)]
+    ca_cert: Option<PathBuf>,
+
+    /// Send requests as non-confirmable messages, which are neither acknowledged nor
+    /// retransmitted; a request that gets no response within the timeout is reported rather than
+    /// failing the command
+    #[arg(global = true, long)]
+    non: bool,
+
+    /// Add an option to each request, as OPTION:VALUE with the option given by name or number and
+    /// the value as empty, str:TEXT, hex:DIGITS, uint:N, or u8:N, u16:N or u32:N for a fixed size,
+    /// e.g. 65000:hex:0a1b (may be repeated)
+    #[arg(global = true, long = "option", value_name = "OPTION:VALUE", value_parser = parse_custom_option)]
+    options: Vec<CustomOption>,
+
+    /// Ask the server not to respond to requests with responses of these classes, as a
+    /// comma-separated list of 2xx, 4xx and 5xx, or all; with --non and all, requests are sent
+    /// without waiting for anything, and otherwise a missing response is reported rather than
+    /// failing the command
+    #[arg(global = true, long, value_name = "CLASSES", value_parser = parse_no_response)]
+    no_response: Option<u8>,
+
+    /// Ask the server to say how large the representation is (Size2), e.g. to check the size of a
+    /// firmware image from its first block before downloading the rest
+    #[arg(global = true, long)]
+    request_size2: bool,
+
+    /// Forward proxy to send requests through, with the URL given in the Proxy-Uri option
+    #[arg(global = true, long)]
+    proxy: Option<String>,
+
+    /// Give the URL to the proxy in the Uri-Host, Uri-Port, Uri-Path and Uri-Query options plus
+    /// Proxy-Scheme, rather than in Proxy-Uri (always done with OSCORE, which can't protect
+    /// Proxy-Uri)
+    #[arg(global = true, long, requires = "proxy")]
+    proxy_scheme: bool,
+
+    /// Send requests to this host and port rather than the URL's, as HOST:PORT, e.g.
+    /// [fd00::17]:5683, while they still name the URL's host in Uri-Host; e.g. to reach one
+    /// virtual host of a server, or a server behind a reverse proxy, directly
+    #[arg(
+        global = true,
+        long,
+        value_name = "HOST:PORT",
+        value_parser = resolve::parse_connect_to,
+        conflicts_with = "proxy"
+    )]
+    connect_to: Option<resolve::ConnectTo>,
+
+    /// Name this host in the Uri-Host option of requests rather than the URL's host, whatever
+    /// they are sent to, or "" to leave the option out
+    #[arg(global = true, long, value_name = "HOST", conflicts_with = "proxy")]
+    uri_host: Option<String>,
+
+    /// Name this port in the Uri-Port option of requests, whatever they are sent to
+    #[arg(global = true, long, value_name = "PORT", conflicts_with = "proxy")]
+    uri_port: Option<u16>,
+
+    /// Send a Hop-Limit option (RFC 8768) with this value, 1 to 255, so that a chain of proxies
+    /// answers 5.08 Hop Limit Reached rather than forwarding the request more than this many
+    /// times, e.g. to find where a request loops
+    #[arg(global = true, long, value_parser = clap::value_parser!(u8).range(1..))]
+    hop_limit: Option<u8>,
+
+    /// Send a Request-Tag option (RFC 9175) with this value, as hex of up to 8 bytes or "" for an
+    /// empty one, in every block of a request, so that the server can tell its block-wise
+    /// transfer apart from any other of the same resource
+    #[arg(global = true, long, value_name = "HEX", value_parser = parse_request_tag)]
+    request_tag: Option<CustomOption>,
+
+    /// Maximum number of times to retransmit a confirmable request [default: 4]
+    #[arg(global = true, long)]
+    max_retransmit: Option<u32>,
+
+    /// Initial time to wait for the acknowledgement of a confirmable request before retransmitting
+    /// it, doubling after each retransmission, e.g. 2s [default: 2s]
+    #[arg(global = true, long, value_parser = parse_duration)]
+    ack_timeout: Option<Duration>,
+
+    /// Factor of at least 1 by which the initial acknowledgement timeout is randomly stretched
+    /// [default: 1.5]
+    #[arg(global = true, long, value_parser = parse_random_factor)]
+    ack_random_factor: Option<f64>,
+
+    /// When the server responds 5.03 Service Unavailable, e.g. while it boots, wait as long as
+    /// the response's Max-Age says (60s if it doesn't) and send the request again
+    #[arg(global = true, long)]
+    retry_after: bool,
+
+    /// Maximum number of times to send a request again with --retry-after
+    #[arg(global = true, long, default_value_t = 3)]
+    max_retries: u32,
+
+    /// Maximum number of requests awaiting a response at any one time [default: unlimited]
+    #[arg(global = true, long, value_parser = clap::value_parser!(u16).range(1..))]
+    nstart: Option<u16>,
+
+    /// OSCORE security context (TOML with hex master_secret, master_salt, sender_id, recipient_id
+    /// and optionally id_context) to protect requests and responses with end to end; the sender
+    /// sequence number is kept next to it, in a file with the extension .seq; the master secret
+    /// may be left out of the file and given by the COAP_OSCORE_MASTER_SECRET environment variable
+    #[arg(global = true, long, env = "COAP_OSCORE_CONTEXT")]
+    oscore_context: Option<PathBuf>,
+
+    /// Fail if a response takes longer than this to arrive, e.g. 500ms or 2s
+    #[arg(global = true, long, value_parser = parse_duration)]
+    max_rtt: Option<Duration>,
+
+    /// Give up on a request that isn't complete within this long, e.g. 10s, counting every
+    /// retransmission and block of it; unlike --timeout, which bounds each wait for a message,
+    /// this bounds the whole. Setting up a DTLS, TCP or WebSocket connection, handshake included,
+    /// is given as long too
+    #[arg(global = true, long, value_parser = parse_duration)]
+    deadline: Option<Duration>,
+
+    /// Length in bytes of the random token given to each request [default: no token]
+    #[arg(global = true, long, value_parser = clap::value_parser!(u8).range(0..=8))]
+    token_length: Option<u8>,
+
+    /// Give every request on a connection the same token instead of a fresh one, to test how
+    /// servers handle token collisions
+    #[arg(global = true, long, requires = "token_length")]
+    reuse_token: bool,
+
+    /// Token, in hex, to give every request instead of a random one, e.g. to reproduce an
+    /// exchange exactly
+    // spelled out so that clap takes the token as a single value rather than a list of bytes
+    #[arg(global = true, long, value_parser = parse_token, conflicts_with = "token_length")]
+    token: Option<::std::vec::Vec<u8>>,
+
+    /// Give requests a zero-length token, even where one would otherwise be chosen for them,
+    /// e.g. for bench --window or a multicast request
+    #[arg(global = true, long, conflicts_with_all = ["token_length", "token"])]
+    empty_token: bool,
+
+    /// Message ID, in decimal or 0x-prefixed hex, to give the first message of every request
+    /// instead of the next in sequence, e.g. to test how a server deduplicates
+    #[arg(global = true, long, value_parser = parse_message_id)]
+    message_id: Option<u16>,
+
+    /// Fail unless the SHA-256 digest of the response payload, after reassembling any blocks,
+    /// matches this hex string
+    #[arg(global = true, long)]
+    verify_sha256: Option<String>,
+
+    /// Print the SHA-256 digest of the response payload
+    #[arg(global = true, long)]
+    print_digest: bool,
+
+    /// Fail unless the response has this code, e.g. 2.05, or one of this class, e.g. 2.xx
+    #[arg(global = true, long, value_name = "CODE", value_parser = expect::parse_expected_code)]
+    expect_code: Option<expect::ExpectedCode>,
+
+    /// Fail unless the response has this content format
+    #[arg(
+        global = true,
+        long,
+        value_name = "FORMAT",
+        value_parser = expect::parse_expected_content_format
+    )]
+    expect_content_format: Option<ContentFormat>,
+
+    /// Fail unless the response body matches this regular expression, or with PATH=VALUE, holds
+    /// this JSON value, or else string, at a JSON Pointer or JSONPath into the body decoded from
+    /// JSON or CBOR, e.g. /temp=21.5 or '$.sensors[0].state=on' (may be repeated)
+    #[arg(global = true, long, value_name = "REGEX|PATH=VALUE", value_parser = expect::parse_expected_body)]
+    expect_body: Vec<expect::ExpectedBody>,
+
+    /// Send the request to both the IPv4 and the IPv6 address of the host and compare the
+    /// responses
+    #[arg(global = true, long)]
+    compare_families: bool,
+
+    /// Make the request of every target listed in this file, one to a line as a host, HOST:PORT
+    /// or a URL giving the scheme too, in place of the URL's server, and report the responses of
+    /// all of them together (the URL may then be just a path)
+    #[arg(global = true, long, value_name = "FILE", conflicts_with = "compare_families")]
+    targets: Option<PathBuf>,
+
+    /// Maximum number of requests in flight at once: with --targets, the number of targets talked
+    /// to at once [default: 16]; for batch and bench, over all their requests [default: 1 for
+    /// batch, as many as the window allows for bench]; for get-all, the number of resources got
+    /// at once [default: 4]
+    #[arg(global = true, long)]
+    concurrency: Option<usize>,
+
+    /// Use this address for a host and port rather than looking it up, as HOST:PORT:ADDR, e.g.
+    /// device.local:5683:192.168.1.17 (may be repeated)
+    #[arg(global = true, long, value_name = "HOST:PORT:ADDR", value_parser = resolve::parse_resolve)]
+    resolve: Vec<resolve::ResolveOverride>,
+
+    /// Only connect to IPv4 addresses
+    #[arg(global = true, short = '4', long, conflicts_with = "ipv6")]
+    ipv4: bool,
+
+    /// Only connect to IPv6 addresses
+    #[arg(global = true, short = '6', long)]
+    ipv6: bool,
+
+    /// Send from this local address and port, as ADDR:PORT or just ADDR, e.g. 192.168.1.10:0 to
+    /// use whichever port is free, so as to pick the network a request goes out on when there is
+    /// more than one
+    #[arg(
+        global = true,
+        long,
+        value_name = "ADDR:PORT",
+        value_parser = resolve::parse_local_addr,
+        conflicts_with = "interface"
+    )]
+    local_addr: Option<SocketAddr>,
+
+    /// Send from this network interface, e.g. wlan0, using its address of the server's family
+    #[arg(global = true, long, value_name = "NAME")]
+    interface: Option<String>,
+
+    /// How to print responses: the code on stderr and the payload on stdout as text, or written
+    /// verbatim (raw), or a JSON object per response on stdout describing the whole message
+    #[arg(global = true, long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Write response payloads verbatim to this file instead of stdout (when observing, the file
+    /// holds the latest notification)
+    #[arg(global = true, long)]
+    output_file: Option<PathBuf>,
+
+    /// Print every option of each response on stderr, by its registered name and with its value
+    /// decoded, e.g. Max-Age in seconds and Content-Format as a media type (the JSON output always
+    /// includes them)
+    #[arg(global = true, long)]
+    show_options: bool,
+
+    /// Print only the payload of a response, leaving out its code and options and the progress
+    /// of the command (error responses are still reported on stderr)
+    #[arg(global = true, short, long, conflicts_with = "include")]
+    quiet: bool,
+
+    /// Print the code and every option of a response on stdout ahead of its payload, separated
+    /// from it by a blank line, as curl's -i does
+    #[arg(global = true, long)]
+    include: bool,
+
+    /// Print this on stdout after each response, with variables such as %{code}, %{size} (of the
+    /// payload, in bytes) and %{time_total} (in seconds) filled in, and \n for a newline; the
+    /// others are %{code_name}, %{content_format}, %{etag} and %{location}
+    #[arg(global = true, long, value_name = "FORMAT", value_parser = output::parse_write_out)]
+    write_out: Option<String>,
+
+    /// Exit with a non-zero status if the server responds with an error (4.xx or 5.xx)
+    #[arg(global = true, long)]
+    fail: bool,
+
+    /// Print response payloads as they are, rather than pretty-printing JSON, showing CBOR in
+    /// diagnostic notation and link format as a table
+    #[arg(global = true, long)]
+    no_decode: bool,
+
+    /// Translate response payloads: CBOR to JSON rather than showing it in diagnostic notation
+    /// (naming SenML's fields if the content format is application/senml+cbor), or SenML, in JSON
+    /// or CBOR, to a table of its records' names, values, units and times, with base fields
+    /// applied and relative times made absolute (with --output json, to the resolved records)
+    #[arg(global = true, long, value_enum, conflicts_with = "no_decode")]
+    decode: Option<content::DecodeFormat>,
+
+    /// Handlebars template to render the result of the run with, in place of the usual output
+    #[arg(global = true, long)]
+    template: Option<PathBuf>,
+
+    /// Append every request sent and the response to it to this file, one JSON object to a line
+    /// with the time, the URL and both messages, to be sent again with replay
+    #[arg(global = true, long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Print timing and size statistics after each request: how long resolving the host, any
+    /// handshake, the first reply and the whole exchange took, the retransmissions and messages
+    /// sent, and the size of the payloads
+    #[arg(global = true, long)]
+    stats: bool,
+
+    /// Increase output verbosity (reports retransmissions and timing; given twice, also prints
+    /// every message sent and received in full)
+    #[arg(global = true, short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Least severe messages to log: each request a command makes and other progress is info,
+    /// transport events such as retransmissions, timeouts and blocks are debug, and every
+    /// datagram is trace
+    #[arg(global = true, long, value_enum, default_value_t = logging::LogLevel::Info)]
+    log_level: logging::LogLevel,
+
+    /// How to write log messages to standard error: as text, or as one JSON object per line for
+    /// test harnesses and log collectors
+    #[arg(global = true, long, value_enum, default_value_t = logging::LogFormat::Text)]
+    log_format: logging::LogFormat,
+
     #[command(subcommand)]
     command: Commands,
+
+    // set by Ctrl-C, for the command to stop and clean up
+    #[arg(skip)]
+    cancel: cancel::Cancellation,
+}
+
+impl Args {
+    /// How to print response payloads, as asked for by `--no-decode` and `--decode`.
+    fn rendering(&self) -> content::Rendering {
+        match (self.no_decode, self.decode) {
+            (true, _) => content::Rendering::Verbatim,
+            (_, Some(content::DecodeFormat::Json)) => content::Rendering::Json,
+            (_, Some(content::DecodeFormat::Senml)) => content::Rendering::Senml,
+            _ => content::Rendering::Readable,
+        }
+    }
+
+    /// Whether the tokens requests get were chosen with `--token-length`, `--token` or
+    /// `--empty-token`, rather than left to the command.
+    fn token_chosen(&self) -> bool {
+        self.token_length.is_some() || self.token.is_some() || self.empty_token
+    }
 }
 
-#[derive(Debug, Subcommand)]
+#[derive(Debug, Clone, Subcommand)]
 enum Commands {
     /// Retrieves a representation of a resource
     Get {
-        /// Acceptable content formats (comma-separated) for the response
-        #[arg(long, value_delimiter = ',')]
+        /// URLs to request in turn over one connection, relative to the URL [default: the URL]
+        urls: Vec<String>,
+        /// Acceptable content formats (comma-separated) for the response, in order of preference
+        #[arg(
+            long,
+            value_delimiter = ',',
+            value_parser = ContentFormatParser,
+            hide_possible_values = true
+        )]
         accept: Vec<String>,
+        /// If the server finds none of the --accept formats acceptable, ask again without Accept
+        /// and report the content format it chooses
+        #[arg(long)]
+        accept_any: bool,
+        /// Directory in which to remember ETags between runs, for conditional requests
+        #[arg(long)]
+        etag_store: Option<PathBuf>,
+        /// ETag (hex) of a representation already held, which the server may confirm is still
+        /// current with 2.03 Valid instead of sending it again (may be repeated)
+        #[arg(long)]
+        etag: Vec<String>,
+        /// Directory in which to keep responses between runs: one that is still fresh, by its
+        /// Max-Age, is used without asking the server, and a stale one with an ETag is used if
+        /// the server confirms it is still current with 2.03 Valid, rather than fetching it again
+        #[arg(long, conflicts_with_all = ["etag_store", "etag"])]
+        cache_dir: Option<PathBuf>,
+        /// Poll the resource this many times, --interval apart, printing each response with a
+        /// timestamp and how it changed from the last, e.g. for a server without Observe
+        #[arg(long, conflicts_with_all = ["urls", "etag_store", "etag", "cache_dir"])]
+        repeat: Option<usize>,
+        /// Poll the resource as --repeat does until interrupted
+        #[arg(long, conflicts_with_all = ["repeat", "urls", "etag_store", "etag", "cache_dir"])]
+        watch: bool,
+        /// Time between polls, e.g. 500ms
+        #[arg(long, value_parser = parse_duration, default_value = "5s")]
+        interval: Duration,
+        /// How to timestamp each poll
+        #[arg(long, value_enum, default_value_t = TimestampFormat::Rfc3339)]
+        timestamp: TimestampFormat,
+    },
+
+    /// Downloads a resource block by block into a file, showing progress
+    Download {
+        /// File to save the resource in [default: the last segment of the URL's path]
+        file: Option<PathBuf>,
+        /// Continue an interrupted download from the last block received, unless the resource
+        /// has changed since
+        #[arg(long)]
+        resume: bool,
+    },
+
+    /// Uploads a file to the resource block by block, showing progress
+    Upload {
+        /// File to upload
+        file: PathBuf,
+        /// Send the file with POST rather than PUT
+        #[arg(long)]
+        post: bool,
+        /// Acceptable content format for the response
+        #[arg(long, value_parser = ContentFormatParser, hide_possible_values = true)]
+        accept: Option<String>,
+        /// Content format of the file
+        #[arg(long, value_parser = ContentFormatParser, hide_possible_values = true)]
+        content_format: Option<String>,
+        /// Only apply if the resource's current ETag is this one (hex), or if the resource exists
+        /// at all when given as "" (may be repeated)
+        #[arg(long)]
+        if_match: Vec<String>,
+        /// Only apply if the resource does not exist yet
+        #[arg(long, conflicts_with = "if_match")]
+        if_none_match: bool,
     },
 
     /// Observes a resource and retrieves its representation whenever it changes
     Observe {
-        /// Acceptable content formats (comma-separated) for the responses
-        #[arg(long, value_delimiter = ',')]
+        /// Acceptable content formats (comma-separated) for the responses, of which only the first
+        /// is asked for
+        #[arg(
+            long,
+            value_delimiter = ',',
+            value_parser = ContentFormatParser,
+            hide_possible_values = true
+        )]
         accept: Vec<String>,
+        /// SQLite database to append each notification to
+        #[arg(long)]
+        sqlite: Option<PathBuf>,
+        /// Stop after this many notifications, counting the response to the registration
+        #[arg(long)]
+        count: Option<usize>,
+        /// Stop after observing for this long, e.g. 10m
+        #[arg(long, value_parser = parse_duration)]
+        duration: Option<Duration>,
+        /// How to timestamp each notification
+        #[arg(long, value_enum, default_value_t = TimestampFormat::Rfc3339)]
+        timestamp: TimestampFormat,
+        /// Wait for the next notification after registering, print it and exit, giving up after
+        /// the timeout if one is given, e.g. 30s
+        #[arg(long, num_args = 0..=1, value_parser = parse_duration)]
+        wait_first: Option<Option<Duration>>,
+        /// Send a CoAP ping this often, e.g. 30s, so that NAT bindings on the way to the server
+        /// don't expire between notifications
+        #[arg(long, value_parser = parse_duration)]
+        keepalive: Option<Duration>,
+        /// Register again when no notification arrives for longer than the last one's Max-Age
+        /// plus 10s, reconnecting with exponential back-off if the server can't be reached, so
+        /// that the observation survives server restarts and lost registrations
+        #[arg(long)]
+        auto_reregister: bool,
+        /// How long to go without a notification before registering again, e.g. 5m, in place of
+        /// the Max-Age of the last one plus 10s
+        #[arg(long, value_parser = parse_duration, requires = "auto_reregister")]
+        max_silence: Option<Duration>,
     },
 
     /// Requests that the submitted data be processed
     Post {
-        /// Acceptable content formats (comma-separated) for the response
-        #[arg(long, value_delimiter = ',')]
+        /// URLs to request in turn over one connection, relative to the URL [default: the URL]
+        urls: Vec<String>,
+        /// Acceptable content formats (comma-separated) for the response, in order of preference
+        #[arg(
+            long,
+            value_delimiter = ',',
+            value_parser = ContentFormatParser,
+            hide_possible_values = true
+        )]
         accept: Vec<String>,
         /// Content format of the submitted data
-        #[arg(long)]
+        #[arg(long, value_parser = ContentFormatParser, hide_possible_values = true)]
         content_format: Option<String>,
         /// Resource data
-        #[arg(short, long)]
+        #[arg(short, long, group = "payload")]
         data: Option<String>,
-        /// Path to file containing resource data
-        #[arg(short, long)]
+        /// Resource data as hex, e.g. a16474656d70f93e00
+        #[arg(long, group = "payload")]
+        data_hex: Option<String>,
+        /// Resource data as base64
+        #[arg(long, group = "payload")]
+        data_base64: Option<String>,
+        /// Path to file containing resource data, sent as is, or - for standard input
+        #[arg(short, long, group = "payload")]
         file: Option<PathBuf>,
+        /// Read the resource data from standard input
+        #[arg(long, group = "payload")]
+        stdin: bool,
+        /// Encode the resource data, written as JSON, in this format, which is also sent as the
+        /// content format unless another is given
+        #[arg(long, value_enum)]
+        encode: Option<content::PayloadEncoding>,
+        /// Only apply if the resource's current ETag is this one (hex), or if the resource exists
+        /// at all when given as "" (may be repeated)
+        #[arg(long)]
+        if_match: Vec<String>,
+        /// Only apply if the resource does not exist yet
+        #[arg(long, conflicts_with = "if_match")]
+        if_none_match: bool,
+        /// On 2.01 Created, get the resource created, as given by the response's Location-Path
+        /// and Location-Query, and print its representation too
+        #[arg(long)]
+        follow: bool,
     },
 
     /// Requests that the resource be updated or created with the submitted data
     Put {
-        /// Acceptable content formats (comma-separated) for the response
-        #[arg(long, value_delimiter = ',')]
+        /// URLs to request in turn over one connection, relative to the URL [default: the URL]
+        urls: Vec<String>,
+        /// Acceptable content formats (comma-separated) for the response, in order of preference
+        #[arg(
+            long,
+            value_delimiter = ',',
+            value_parser = ContentFormatParser,
+            hide_possible_values = true
+        )]
         accept: Vec<String>,
         /// Content format of the submitted data
+        #[arg(long, value_parser = ContentFormatParser, hide_possible_values = true)]
+        content_format: Option<String>,
+        /// Resource data
+        #[arg(short, long, group = "payload")]
+        data: Option<String>,
+        /// Resource data as hex, e.g. a16474656d70f93e00
+        #[arg(long, group = "payload")]
+        data_hex: Option<String>,
+        /// Resource data as base64
+        #[arg(long, group = "payload")]
+        data_base64: Option<String>,
+        /// Path to file containing resource data, sent as is, or - for standard input
+        #[arg(short, long, group = "payload")]
+        file: Option<PathBuf>,
+        /// Read the resource data from standard input
+        #[arg(long, group = "payload")]
+        stdin: bool,
+        /// Encode the resource data, written as JSON, in this format, which is also sent as the
+        /// content format unless another is given
+        #[arg(long, value_enum)]
+        encode: Option<content::PayloadEncoding>,
+        /// Only apply if the resource's current ETag is this one (hex), or if the resource exists
+        /// at all when given as "" (may be repeated)
         #[arg(long)]
+        if_match: Vec<String>,
+        /// Only apply if the resource does not exist yet
+        #[arg(long, conflicts_with = "if_match")]
+        if_none_match: bool,
+    },
+
+    /// Retrieves the parts of a resource selected by the submitted query (RFC 8132)
+    Fetch {
+        /// URLs to request in turn over one connection, relative to the URL [default: the URL]
+        urls: Vec<String>,
+        /// Acceptable content formats (comma-separated) for the response, in order of preference
+        #[arg(
+            long,
+            value_delimiter = ',',
+            value_parser = ContentFormatParser,
+            hide_possible_values = true
+        )]
+        accept: Vec<String>,
+        /// Content format of the submitted data
+        #[arg(long, value_parser = ContentFormatParser, hide_possible_values = true)]
         content_format: Option<String>,
         /// Resource data
-        #[arg(short, long)]
+        #[arg(short, long, group = "payload")]
         data: Option<String>,
-        /// Path to file containing resource data
-        #[arg(short, long)]
+        /// Resource data as hex, e.g. a16474656d70f93e00
+        #[arg(long, group = "payload")]
+        data_hex: Option<String>,
+        /// Resource data as base64
+        #[arg(long, group = "payload")]
+        data_base64: Option<String>,
+        /// Path to file containing resource data, sent as is, or - for standard input
+        #[arg(short, long, group = "payload")]
         file: Option<PathBuf>,
+        /// Read the resource data from standard input
+        #[arg(long, group = "payload")]
+        stdin: bool,
+        /// Encode the resource data, written as JSON, in this format, which is also sent as the
+        /// content format unless another is given
+        #[arg(long, value_enum)]
+        encode: Option<content::PayloadEncoding>,
     },
 
-    /// Requests that the resource be deleted
-    Delete {
-        /// Acceptable content formats (comma-separated) for the response
-        #[arg(long, value_delimiter = ',')]
+    /// Requests that the resource be modified by applying the submitted changes (RFC 8132)
+    Patch {
+        /// URLs to request in turn over one connection, relative to the URL [default: the URL]
+        urls: Vec<String>,
+        /// Acceptable content formats (comma-separated) for the response, in order of preference
+        #[arg(
+            long,
+            value_delimiter = ',',
+            value_parser = ContentFormatParser,
+            hide_possible_values = true
+        )]
+        accept: Vec<String>,
+        /// Content format of the submitted data
+        #[arg(long, value_parser = ContentFormatParser, hide_possible_values = true)]
+        content_format: Option<String>,
+        /// Resource data
+        #[arg(short, long, group = "payload")]
+        data: Option<String>,
+        /// Resource data as hex, e.g. a16474656d70f93e00
+        #[arg(long, group = "payload")]
+        data_hex: Option<String>,
+        /// Resource data as base64
+        #[arg(long, group = "payload")]
+        data_base64: Option<String>,
+        /// Path to file containing resource data, sent as is, or - for standard input
+        #[arg(short, long, group = "payload")]
+        file: Option<PathBuf>,
+        /// Read the resource data from standard input
+        #[arg(long, group = "payload")]
+        stdin: bool,
+        /// Encode the resource data, written as JSON, in this format, which is also sent as the
+        /// content format unless another is given
+        #[arg(long, value_enum)]
+        encode: Option<content::PayloadEncoding>,
+    },
+
+    /// Like patch, but for changes which can safely be applied more than once (RFC 8132)
+    Ipatch {
+        /// URLs to request in turn over one connection, relative to the URL [default: the URL]
+        urls: Vec<String>,
+        /// Acceptable content formats (comma-separated) for the response, in order of preference
+        #[arg(
+            long,
+            value_delimiter = ',',
+            value_parser = ContentFormatParser,
+            hide_possible_values = true
+        )]
         accept: Vec<String>,
+        /// Content format of the submitted data
+        #[arg(long, value_parser = ContentFormatParser, hide_possible_values = true)]
+        content_format: Option<String>,
+        /// Resource data
+        #[arg(short, long, group = "payload")]
+        data: Option<String>,
+        /// Resource data as hex, e.g. a16474656d70f93e00
+        #[arg(long, group = "payload")]
+        data_hex: Option<String>,
+        /// Resource data as base64
+        #[arg(long, group = "payload")]
+        data_base64: Option<String>,
+        /// Path to file containing resource data, sent as is, or - for standard input
+        #[arg(short, long, group = "payload")]
+        file: Option<PathBuf>,
+        /// Read the resource data from standard input
+        #[arg(long, group = "payload")]
+        stdin: bool,
+        /// Encode the resource data, written as JSON, in this format, which is also sent as the
+        /// content format unless another is given
+        #[arg(long, value_enum)]
+        encode: Option<content::PayloadEncoding>,
     },
-}
 
-async fn coap_get(client: &mut UdpCoAPClient, args: &Args, accept: &[String]) -> Result<()> {
-    eprintln!("GET {}", args.url);
+    /// Atomically updates a resource: GETs it, transforms it and PUTs it back with If-Match
+    Cas {
+        /// Shell command which reads the current representation on stdin and writes the new one
+        #[arg(long)]
+        edit: String,
+        /// Number of times to retry when the resource changed in the meantime
+        #[arg(long, default_value_t = 3)]
+        retries: u32,
+    },
 
-    let accept_cf = accept.iter().map(|a| parse_content_format(a)).collect::<Result<Vec<_>>>()?;
-    let request = build_coap_request_for_url(&args.url, RequestType::Get, None, None, Some(accept_cf))?;
-    let response = client.send(request).await?;
+    /// Gets every resource listed in /.well-known/core, several at once, and prints their
+    /// responses together as a snapshot of the server's state
+    GetAll {
+        /// Only get resources of this resource type (rt), or with a prefix of it if it ends in *
+        #[arg(long)]
+        rt: Option<String>,
+        /// Acceptable content formats (comma-separated) for the responses, in order of preference
+        #[arg(
+            long,
+            value_delimiter = ',',
+            value_parser = ContentFormatParser,
+            hide_possible_values = true
+        )]
+        accept: Vec<String>,
+    },
 
-    let content = String::from_utf8_lossy(&response.message.payload);
-    eprintln!("{}", response.message.header.get_code());
-    println!("{}", content);
+    /// Downloads every resource listed in /.well-known/core into a local directory
+    Mirror {
+        /// Directory in which to store the resources
+        dir: PathBuf,
+    },
 
-    Ok(())
-}
+    /// Uploads every file in a local directory to the corresponding path under the URL
+    Push {
+        /// Directory containing the resources
+        dir: PathBuf,
+    },
 
-async fn coap_observe(client: &mut UdpCoAPClient, args: &Args, accept: &[String]) -> Result<()> {
-    eprintln!("OBSERVE {}", args.url);
+    /// Compares a local directory with the resources on the server and applies the differences
+    /// (reported as M: modified, L: only local, R: only on the server)
+    Sync {
+        /// Directory containing the local copies of the resources
+        dir: PathBuf,
+        /// Which side to update
+        #[arg(long, value_enum, default_value_t = mirror::SyncDirection::Push)]
+        direction: mirror::SyncDirection,
+    },
 
-    let accept_cf = accept.iter().map(|a| parse_content_format(a)).collect::<Result<Vec<_>>>()?;
-    let request = build_coap_request_for_url(&args.url, RequestType::Get, None, None, Some(accept_cf))?;
+    /// Compares the resource's representation with another resource's, or with its own a while
+    /// later, and prints the differences: entry by entry for JSON and CBOR, line by line otherwise
+    Diff {
+        /// URL of the resource to compare with, which may be relative to the URL
+        #[arg(required_unless_present = "interval", conflicts_with = "interval")]
+        other: Option<String>,
+        /// Compare the resource with itself this long later, e.g. 30s
+        #[arg(long, value_parser = parse_duration)]
+        interval: Option<Duration>,
+    },
 
-    let handler = |p: Packet| {
-        let content = String::from_utf8_lossy(&p.payload);
-        let now = OffsetDateTime::now_local().unwrap().format(&Iso8601::DEFAULT).unwrap();
-        eprintln!("{}: {}", now, p.header.get_code());
-        println!("{}", content);
-    };
+    /// Runs a CoAP server on the URL's host and port
+    Serve {
+        /// TOML file describing the resources to serve
+        #[arg(long, required_unless_present_any = ["dir", "stdin"])]
+        manifest: Option<PathBuf>,
+        /// Directory whose files to serve, with GET, PUT and DELETE reading, writing and
+        /// removing them, POST echoing the request, and observers notified when a file changes
+        #[arg(long, conflicts_with = "manifest")]
+        dir: Option<PathBuf>,
+        /// Serve an observable resource at this path whose representation is the latest line
+        /// read from standard input, notifying its observers of each line, e.g. to simulate a
+        /// sensor pushing readings
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["manifest", "dir"])]
+        stdin: Option<String>,
+        /// Content format of the lines read with --stdin
+        #[arg(
+            long,
+            requires = "stdin",
+            value_parser = ContentFormatParser,
+            hide_possible_values = true
+        )]
+        content_format: Option<String>,
+    },
 
-    let sender = client.observe_with(request, handler).await?;
+    /// Runs a mock CoAP server answering from a fixtures file, reloading it when it changes
+    Mock {
+        /// TOML file mapping paths to canned responses
+        #[arg(long)]
+        fixtures: PathBuf,
+    },
 
-    ctrl_c().await.expect("failed to listen for ctrl-c");
+    /// Runs a proxy on the URL's host and port which records upstream responses and replays them
+    /// for identical requests
+    Proxy {
+        /// Server to forward requests to (only its host and port are used)
+        #[arg(long)]
+        upstream: Option<String>,
+        /// Directory in which to keep the recorded responses
+        #[arg(long)]
+        store: PathBuf,
+        /// Only replay recorded responses, never contacting the upstream server
+        #[arg(long)]
+        offline: bool,
+    },
 
-    sender.send(ObserveMessage::Terminate).unwrap();
+    /// Relays messages between clients and a device, logging them and optionally delaying,
+    /// dropping, or rewriting them on the way
+    Intercept {
+        /// Device to forward messages to (only its host and port are used)
+        #[arg(long)]
+        upstream: String,
+        /// Delay each message by this long, e.g. 200ms
+        #[arg(long, value_parser = parse_duration)]
+        delay: Option<Duration>,
+        /// Share of the messages to drop, e.g. 10%
+        #[arg(long, value_parser = bench::parse_percentage)]
+        drop: Option<f64>,
+        /// Replace an option in each message, e.g. Uri-Host=device.local, or remove it if the
+        /// value is empty (may be repeated)
+        #[arg(long, value_parser = intercept::parse_rewrite)]
+        rewrite: Vec<intercept::Rewrite>,
+        /// Which messages to delay, drop, or rewrite
+        #[arg(long, value_enum, default_value_t = intercept::Direction::Both)]
+        direction: intercept::Direction,
+    },
 
-    Ok(())
-}
+    /// Keeps connections to servers open, with their DTLS sessions and OSCORE contexts, and makes
+    /// the requests passed to it on a Unix socket, e.g. by `send --via-daemon`, over them, so
+    /// that short-lived invocations don't each set up a session; the URL is optional
+    Daemon {
+        /// Unix socket to listen on, accessible only to its owner
+        #[arg(long)]
+        socket: PathBuf,
+        /// Server to connect to at the start, along with the URL's, rather than on its first
+        /// request (may be repeated)
+        #[arg(long)]
+        device: Vec<String>,
+    },
 
-async fn coap_post(
-    client: &mut UdpCoAPClient,
-    args: &Args,
-    accept: &[String],
-    content_format: Option<&str>,
-    data: &str,
-) -> Result<()> {
-    eprintln!("POST {}", args.url);
+    /// Serves HTTP, making each request of the device at the URL as CoAP, with methods, media
+    /// types and status codes mapped as RFC 8075 has them, so that HTTP clients can reach it
+    Gateway {
+        /// Address to accept HTTP connections on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+    },
 
-    let cf = content_format.map(parse_content_format).transpose()?;
-    let accept_cf = accept.iter().map(|a| parse_content_format(a)).collect::<Result<Vec<_>>>()?;
-    let request = build_coap_request_for_url(&args.url, RequestType::Post, Some(data.as_bytes().to_vec()), cf, Some(accept_cf))?;
-    let response = client.send(request).await?;
+    /// Checks each step of communicating with the server, from resolving its name to block-wise
+    /// transfers, and reports where it breaks down
+    Doctor,
 
-    let content = String::from_utf8_lossy(&response.message.payload);
-    eprintln!("{}", response.message.header.get_code());
-    println!("{}", content);
+    /// Sends CoAP pings (empty confirmable messages) and reports how long the server takes to
+    /// answer each with a reset
+    Ping {
+        /// Stop after sending this many pings [default: until interrupted]
+        #[arg(short, long)]
+        count: Option<usize>,
+        /// Time between pings, e.g. 500ms
+        #[arg(short, long, value_parser = parse_duration, default_value = "1s")]
+        interval: Duration,
+    },
 
-    Ok(())
-}
+    /// Reports what a response says about the resource, its code, content format, ETag,
+    /// Max-Age, size and how long it stays fresh, fetching as little of its payload as possible,
+    /// e.g. for health checks
+    Check {
+        /// Fail, with exit status 7, unless the response stays fresh (by its Max-Age) for at least
+        /// this long, e.g. 5m
+        #[arg(long, value_parser = parse_duration)]
+        freshness: Option<Duration>,
+    },
 
-async fn coap_put(
-    client: &mut UdpCoAPClient,
-    args: &Args,
-    accept: &[String],
-    content_format: Option<&str>,
-    data: &str,
-) -> Result<()> {
-    eprintln!("PUT {}", args.url);
+    /// Shows the destination address and request options the URL translates to, without
+    /// sending anything
+    InspectUrl,
 
-    let cf = content_format.map(parse_content_format).transpose()?;
-    let accept_cf = accept.iter().map(|a| parse_content_format(a)).collect::<Result<Vec<_>>>()?;
-    let request = build_coap_request_for_url(&args.url, RequestType::Put, Some(data.as_bytes().to_vec()), cf, Some(accept_cf))?;
-    let response = client.send(request).await?;
+    /// Decodes CoAP messages, e.g. from a Wireshark export or a device's serial log, without
+    /// sending anything; no URL is needed
+    Decode {
+        /// Message in hex, whose bytes may be separated by spaces or colons (repeatable)
+        #[arg(value_name = "HEX", required_unless_present = "file")]
+        messages: Vec<String>,
+        /// File holding a message as raw bytes, or - for standard input
+        #[arg(long, conflicts_with = "messages")]
+        file: Option<PathBuf>,
+    },
 
-    let content = String::from_utf8_lossy(&response.message.payload);
-    eprintln!("{}", response.message.header.get_code());
-    println!("{}", content);
+    /// Prints a completion script for the shell, which completes the subcommands, options and
+    /// the content formats known by name; no URL is needed
+    Completions {
+        /// Shell to complete for
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
 
-    Ok(())
-}
+    /// Lists the content formats known by name, with their numbers; no URL is needed
+    ContentFormats,
 
-async fn coap_delete(client: &mut UdpCoAPClient, args: &Args, accept: &[String]) -> Result<()> {
-    eprintln!("DELETE {}", args.url);
+    /// Runs a suite of requests against an embedded server on the URL's host (and an ephemeral
+    /// port unless one is given), as a check that the client and server work in this environment
+    Selftest,
 
-    let accept_cf = accept.iter().map(|a| parse_content_format(a)).collect::<Result<Vec<_>>>()?;
-    let request = build_coap_request_for_url(&args.url, RequestType::Delete, None, None, Some(accept_cf))?;
-    let response = client.send(request).await?;
+    /// Runs a CoAP server that logs every request it receives and answers with a canned response
+    Inspect {
+        /// Response code to answer with
+        #[arg(long, default_value = "2.05")]
+        code: String,
+        /// Content format of the canned response
+        #[arg(long, value_parser = ContentFormatParser, hide_possible_values = true)]
+        content_format: Option<String>,
+        /// Payload of the canned response
+        #[arg(long)]
+        payload: Option<String>,
+        /// File to append the decoded requests to
+        #[arg(long)]
+        log: Option<PathBuf>,
+    },
 
-    let content = String::from_utf8_lossy(&response.message.payload);
-    eprintln!("{}", response.message.header.get_code());
-    println!("{}", content);
+    /// Lists the resources advertised by the server
+    Discover {
+        /// Browse the local network for CoAP services with multicast DNS instead of querying the
+        /// URL's server
+        #[arg(long)]
+        mdns: bool,
+        /// Only list resources of this resource type (rt), or with a prefix of it if it ends in *
+        #[arg(long, conflicts_with = "mdns")]
+        rt: Option<String>,
+        /// Only list resources with this interface description (if), or with a prefix of it if it
+        /// ends in *
+        #[arg(long = "if", conflicts_with = "mdns")]
+        interface: Option<String>,
+        /// Print the resources, or the services found with --mdns or --dns-sd, as JSON
+        #[arg(long)]
+        json: bool,
+        /// Send the request to all CoAP nodes on the local network, through their multicast group,
+        /// instead of the URL's server, and list the resources of each that answers
+        #[arg(
+            long,
+            value_enum,
+            num_args = 0..=1,
+            default_missing_value = "ipv4",
+            conflicts_with = "mdns"
+        )]
+        multicast: Option<discover::MulticastGroup>,
+        /// Look up the CoAP services the URL's domain advertises in DNS instead of querying its
+        /// server: the instances of _coap._udp and _coaps._udp found with DNS-SD (RFC 6763), and
+        /// any SRV records of those services themselves (RFC 2782)
+        #[arg(long, conflicts_with_all = ["mdns", "multicast"])]
+        dns_sd: bool,
+        /// Ask this name server rather than the system's, as ADDR or ADDR:PORT
+        #[arg(long, value_name = "ADDR", value_parser = dns::parse_name_server, requires = "dns_sd")]
+        dns_server: Option<SocketAddr>,
+        /// Fetch /.well-known/core from each service found, and list its resources
+        #[arg(long, requires = "dns_sd")]
+        probe: bool,
+    },
 
-    Ok(())
-}
+    /// Registers with and looks up resources in a CoRE Resource Directory (RFC 9176)
+    Rd {
+        #[command(subcommand)]
+        command: rd::RdCommand,
+    },
 
-fn load_data_file(file: &PathBuf) -> Result<String> {
-    if !file.is_file() {
-        return Err(Error::new(
-            ErrorKind::InvalidInput,
-            format!("Error: path must be file: {}", file.to_str().unwrap()),
-        ));
-    }
+    /// Reads, writes and discovers the objects of a LwM2M client by path, e.g. 3/0/0
+    Lwm2m {
+        #[command(subcommand)]
+        command: lwm2m::Lwm2mCommand,
+    },
 
-    let data = std::fs::read_to_string(&file)?;
-    Ok(data)
-}
+    /// Fetches the server's resources ahead of time into a store for `proxy`
+    Prefetch {
+        /// Link format file listing the resources to fetch, instead of discovering them through
+        /// /.well-known/core
+        #[arg(long)]
+        links: Option<PathBuf>,
+        /// Directory in which to keep the recorded responses
+        #[arg(long)]
+        store: PathBuf,
+    },
 
-async fn create_coap_client(args: &Args) -> Result<UdpCoAPClient> {
-    let (host, port, _, _) = parse_coap_url(&args.url)?;
-    let mut client = UdpCoAPClient::new_udp((host, port.unwrap_or(5683))).await?;
-    client.set_receive_timeout(Duration::new(args.timeout, 0));
-    Ok(client)
-}
+    /// Sends a series of requests to the resource and reports throughput and latency
+    Bench {
+        /// Request method
+        #[arg(long, value_enum, default_value_t = RequestMethod::Get)]
+        method: RequestMethod,
+        /// Request payload
+        #[arg(short, long)]
+        data: Option<String>,
+        /// Number of requests to send
+        #[arg(short = 'n', long, default_value_t = 100)]
+        requests: usize,
+        /// File to write the samples and statistics to as JSON
+        #[arg(long)]
+        json: Option<PathBuf>,
+        /// File to write the samples to as CSV; the statistics go to a "-summary" file next to it
+        #[arg(long)]
+        csv: Option<PathBuf>,
+        /// File to write the latency distribution to, in HdrHistogram's .hgrm percentile format
+        #[arg(long)]
+        hgrm: Option<PathBuf>,
+        /// JSON results of an earlier run to compare this run with
+        #[arg(long)]
+        baseline: Option<PathBuf>,
+        /// Fail if latency or throughput is worse than the baseline by more than this percentage
+        #[arg(long, requires = "baseline", value_parser = bench::parse_percentage)]
+        fail_if_slower: Option<f64>,
+        /// Additional URL to spread the requests over, round-robin with the main URL (repeatable)
+        #[arg(long = "target")]
+        targets: Vec<String>,
+        /// Relative share of the requests for the main URL and each target (comma-separated)
+        #[arg(long, value_delimiter = ',', value_parser = bench::parse_weight)]
+        weights: Vec<u32>,
+        /// Number of persistent connections to keep open to each target and spread its requests
+        /// over
+        #[arg(long, default_value_t = 1)]
+        connections: usize,
+        /// Number of requests to pipeline on each connection without waiting for their
+        /// responses (retransmission counts are approximate above 1); by default 1, or as many as
+        /// --concurrency needs
+        #[arg(long)]
+        window: Option<usize>,
+        /// Send requests at this many per second, evenly spaced, instead of as fast as responses
+        /// allow
+        #[arg(long, value_parser = bench::parse_rate)]
+        rate: Option<f64>,
+    },
 
-async fn execute_command(args: &Args) -> Result<()> {
-    let mut client = create_coap_client(args).await?;
+    /// Makes the requests listed in a file, relative to the URL, filling in variables
+    Batch {
+        /// TOML file of [[request]] tables, each with a method, path, payload, content_format,
+        /// encode, accept and options; "{name}" in any of them is replaced by a variable
+        file: PathBuf,
+        /// Value for a variable, written as NAME=VALUE (repeatable); variables not given here are
+        /// taken from the environment
+        #[arg(long = "set", value_name = "NAME=VALUE", value_parser = batch::parse_variable)]
+        variables: Vec<(String, String)>,
+    },
 
-    match &args.command {
-        Commands::Get { accept } => coap_get(&mut client, args, accept).await,
-        Commands::Observe { accept } => coap_observe(&mut client, args, accept).await,
-        Commands::Post {
-            accept,
-            content_format,
-            data,
-            file,
-        } => {
-            let data = {
-                if let Some(data) = data {
-                    Some(data.to_owned())
-                } else if let Some(file) = file {
-                    Some(load_data_file(file)?)
-                } else {
-                    None
-                }
-            };
+    /// Runs a Rhai script that makes requests of the URL's server over one connection, e.g. to
+    /// read a device's state and configure it accordingly; see the README for what it can call
+    Script {
+        /// The script to run
+        file: PathBuf,
+        /// Arguments for the script, which it gets as ARGS
+        #[arg(trailing_var_arg = true)]
+        script_args: Vec<String>,
+    },
 
-            let data = data.ok_or(Error::new(
-                ErrorKind::InvalidInput,
-                "must specify either data string or file path",
-            ))?;
+    /// Sends the requests recorded with --record to the URL's server again, in order, and
+    /// reports whether each response matches the recorded one in code, content format and
+    /// payload
+    Replay {
+        /// File the exchanges were recorded in
+        file: PathBuf,
+        /// Send the requests as far apart in time as they were recorded
+        #[arg(long)]
+        timing: bool,
+    },
 
-            coap_post(&mut client, args, accept, content_format.as_deref(), &data).await
-        }
-        Commands::Put {
-            accept,
-            content_format,
-            data,
-            file,
-        } => {
-            let data = {
-                if let Some(data) = data {
-                    Some(data.to_owned())
-                } else if let Some(file) = file {
-                    Some(load_data_file(file)?)
-                } else {
-                    None
-                }
-            };
+    /// Requests that the resource be deleted
+    Delete {
+        /// URLs to request in turn over one connection, relative to the URL [default: the URL]
+        urls: Vec<String>,
+        /// Acceptable content formats (comma-separated) for the response, in order of preference
+        #[arg(
+            long,
+            value_delimiter = ',',
+            value_parser = ContentFormatParser,
+            hide_possible_values = true
+        )]
+        accept: Vec<String>,
+        /// Only apply if the resource's current ETag is this one (hex), or if the resource exists
+        /// at all when given as "" (may be repeated)
+        #[arg(long)]
+        if_match: Vec<String>,
+        /// Only apply if the resource does not exist yet
+        #[arg(long, conflicts_with = "if_match")]
+        if_none_match: bool,
+    },
 
-            let data = data.ok_or(Error::new(
-                ErrorKind::InvalidInput,
-                "must specify either data string or file path",
-            ))?;
+    /// Sends a single request and prints the response, directly or through a `daemon` that
+    /// already has a connection to the server
+    Send {
+        /// Request method
+        #[arg(long, value_enum, default_value_t = RequestMethod::Get)]
+        method: RequestMethod,
+        /// Acceptable content format for the response
+        #[arg(long, value_parser = ContentFormatParser, hide_possible_values = true)]
+        accept: Option<String>,
+        /// Content format of the request payload
+        #[arg(long, value_parser = ContentFormatParser, hide_possible_values = true)]
+        content_format: Option<String>,
+        /// Request payload
+        #[arg(short, long)]
+        data: Option<String>,
+        /// Pass the request to the daemon listening on this socket rather than sending it
+        #[arg(long)]
+        via_daemon: Option<PathBuf>,
+    },
 
-            coap_put(&mut client, args, accept, content_format.as_deref(), &data).await
-        }
-        Commands::Delete { accept } => coap_delete(&mut client, args, accept).await,
-    }
+    /// Makes the same request, e.g. to switch them off, of every member of a group of devices,
+    /// each given as a host or URL and requested at the URL's path, reports what each answered,
+    /// and fails unless enough of them succeeded
+    Group {
+        /// Host or URL of a member of the group
+        #[arg(required = true)]
+        members: Vec<String>,
+        /// Request method
+        #[arg(long, value_enum, default_value_t = RequestMethod::Put)]
+        method: RequestMethod,
+        /// Content format of the request payload
+        #[arg(long, value_parser = ContentFormatParser, hide_possible_values = true)]
+        content_format: Option<String>,
+        /// Request payload
+        #[arg(short, long)]
+        data: Option<String>,
+        /// Make the request of one member at a time, in the order given
+        #[arg(long, conflicts_with = "parallel")]
+        serial: bool,
+        /// Make the request of every member at once [default]
+        #[arg(long)]
+        parallel: bool,
+        /// Send the request once to this multicast group, e.g. ff05::fd, rather than to each
+        /// member, and match the responses to the members by their addresses
+        #[arg(long, conflicts_with_all = ["serial", "parallel"])]
+        group: Option<String>,
+        /// How many of the members must succeed, as a number or a percentage
+        #[arg(long, value_parser = group::parse_threshold, default_value = "100%")]
+        threshold: group::Threshold,
+    },
 }
 
-#[tokio::main]
-async fn main() {
-    let cli = Args::parse();
+async fn coap_get(
+    conn: &Connection,
+    args: &Args,
+    accept: &[String],
+    etag_store: Option<&EtagStore>,
+    etags: &[String],
+    cache: Option<&ResponseCache>,
+) -> Result<()> {
+    info!("GET {}", args.url);
 
-    if let Err(err) = execute_command(&cli).await {
-        eprintln!("ERROR: {}", err);
+    let accept_cf = accept.iter().map(|a| parse_content_format(a)).collect::<Result<Vec<_>>>()?;
+    let accept_numbers: Vec<usize> = accept_cf.iter().map(|cf| usize::from(*cf)).collect();
+    let mut request = build_coap_request_for_url(&args.url, RequestType::Get, None, None, Some(accept_cf))?;
+    if let Some(etag) = etag_store.map(|s| s.load(&args.url)).transpose()?.flatten() {
+        request.message.add_option(CoapOption::ETag, etag);
+    }
+    let cached = cache.map(|c| c.load(&args.url, &accept_numbers)).transpose()?.flatten();
+    if let Some(cached) = &cached {
+        if let Some(fresh_for) = cached.fresh_for() {
+            info!("fresh in the cache for another {}s", fresh_for.as_secs());
+            return output::print_response(&cached.message, args, args.output_file.as_deref());
+        }
+        // a stale response can still be used if the server says it's current
+        if let Some(etag) = cached.etag() {
+            request.message.add_option(CoapOption::ETag, etag.clone());
+        }
+    }
+    for etag in etags {
+        let etag = parse_etag(etag)?;
+        if etag.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "ETag must not be empty"));
+        }
+        request.message.add_option(CoapOption::ETag, etag);
+    }
+    let Some(response) = send_request(conn, args, request).await? else {
+        return Ok(());
+    };
+
+    if let (Some(cache), Some(cached)) = (cache, cached)
+        && *response.get_status() == ResponseType::Valid
+    {
+        info!("{} Valid; using the cached representation", response.message.header.code);
+        let message = cache.validate(&args.url, &accept_numbers, cached, &response.message)?;
+        return output::print_response(&message, args, args.output_file.as_deref());
+    }
+    if let Some(cache) = cache
+        && *response.get_status() == ResponseType::Content
+    {
+        cache.store(&args.url, &accept_numbers, &response.message)?;
+    }
+    if *response.get_status() == ResponseType::Valid && args.output != OutputFormat::Json {
+        output::print_status(&response.message, args);
+        if !args.quiet {
+            eprintln!("not modified");
+        }
+        return output::print_write_out(&response.message, args);
+    }
+    if let (Some(store), Some(etag)) = (etag_store, response.message.get_first_option(CoapOption::ETag)) {
+        store.save(&args.url, etag)?;
+    }
+
+    output::print_response(&response.message, args, args.output_file.as_deref())?;
+
+    Ok(())
+}
+
+/// The conditions, given with `--if-match` and `--if-none-match`, under which the server should
+/// apply a request.
+#[derive(Default)]
+struct Preconditions<'a> {
+    if_match: &'a [String],
+    if_none_match: bool,
+}
+
+impl Preconditions<'_> {
+    fn add_to(&self, request: &mut CoapRequest<SocketAddr>) -> Result<()> {
+        for etag in self.if_match {
+            request.message.add_option(CoapOption::IfMatch, parse_etag(etag)?);
+        }
+        if self.if_none_match {
+            request.message.add_option(CoapOption::IfNoneMatch, vec![]);
+        }
+        Ok(())
+    }
+}
+
+async fn coap_submit(
+    conn: &Connection,
+    args: &Args,
+    method: RequestType,
+    accept: &[String],
+    content_format: Option<ContentFormat>,
+    data: Vec<u8>,
+    preconditions: Preconditions<'_>,
+) -> Result<Option<CoapResponse>> {
+    info!("{} {}", format!("{:?}", method).to_uppercase(), args.url);
+
+    let accept_cf = accept.iter().map(|a| parse_content_format(a)).collect::<Result<Vec<_>>>()?;
+    let mut request =
+        build_coap_request_for_url(&args.url, method, Some(data), content_format, Some(accept_cf))?;
+    preconditions.add_to(&mut request)?;
+    let Some(response) = send_request(conn, args, request).await? else {
+        return Ok(None);
+    };
+
+    output::print_response(&response.message, args, args.output_file.as_deref())?;
+
+    Ok(Some(response))
+}
+
+/// Gets the resource a 2.01 Created response says was created, as asked for by `--follow`.
+async fn coap_follow(
+    conn: &Connection,
+    args: &Args,
+    accept: &[String],
+    response: &CoapResponse,
+) -> Result<()> {
+    if *response.get_status() != ResponseType::Created {
+        return Ok(());
+    }
+    let Some(location) = response_location(&response.message) else {
+        warn!("{} gives no location to follow", response.message.header.code);
+        return Ok(());
+    };
+    let args = Args {
+        url: request_url(args, &location)?,
+        ..args.clone()
+    };
+    coap_get(conn, &args, accept, None, &[], None).await
+}
+
+async fn coap_delete(
+    conn: &Connection,
+    args: &Args,
+    accept: &[String],
+    preconditions: Preconditions<'_>,
+) -> Result<()> {
+    info!("DELETE {}", args.url);
+
+    let accept_cf = accept.iter().map(|a| parse_content_format(a)).collect::<Result<Vec<_>>>()?;
+    let mut request = build_coap_request_for_url(&args.url, RequestType::Delete, None, None, Some(accept_cf))?;
+    preconditions.add_to(&mut request)?;
+    let Some(response) = send_request(conn, args, request).await? else {
+        return Ok(());
+    };
+
+    output::print_response(&response.message, args, args.output_file.as_deref())?;
+
+    Ok(())
+}
+
+async fn coap_send(
+    conn: &Connection,
+    args: &Args,
+    method: RequestMethod,
+    accept: Option<&str>,
+    content_format: Option<&str>,
+    data: Option<&str>,
+) -> Result<()> {
+    info!("{} {}", method.name(), args.url);
+
+    let accept = accept.map(parse_content_format).transpose()?;
+    let content_format = content_format.map(parse_content_format).transpose()?;
+    let payload = data.map(|data| data.as_bytes().to_vec());
+    let request = build_coap_request_for_url(
+        &args.url,
+        RequestType::from(method),
+        payload,
+        content_format,
+        accept.map(|accept| vec![accept]),
+    )?;
+    let Some(response) = send_request(conn, args, request).await? else {
+        return Ok(());
+    };
+
+    output::print_response(&response.message, args, args.output_file.as_deref())
+}
+
+/// Passes a request to the daemon listening on `socket`, which makes it over the connection it
+/// keeps to the server, and prints the response it gets.
+async fn coap_send_via_daemon(
+    args: &Args,
+    socket: &Path,
+    method: RequestMethod,
+    accept: Option<&str>,
+    content_format: Option<&str>,
+    data: Option<&str>,
+) -> Result<()> {
+    info!("{} {} via the daemon on {}", method.name(), args.url, socket.display());
+    if args.dry_run {
+        return Ok(());
+    }
+    let request = daemon::DaemonRequest {
+        url: args.url.clone(),
+        method: method.name().to_string(),
+        accept: accept.map(str::to_string),
+        content_format: content_format.map(str::to_string),
+        payload: data.map(str::to_string),
+        payload_base64: None,
+    };
+    let message = daemon::send_via_daemon(socket, &request).await?;
+    output::print_response(&message, args, args.output_file.as_deref())
+}
+
+async fn coap_cas(conn: &Connection, args: &Args, edit: &str, retries: u32) -> Result<()> {
+    for attempt in 0..=retries {
+        info!("GET {}", args.url);
+        let request = build_coap_request_for_url(&args.url, RequestType::Get, None, None, None)?;
+        let Some(current) = send_request(conn, args, request).await? else {
+            return Ok(());
+        };
+        output::print_code(&current.message, args);
+        if *current.get_status() != ResponseType::Content {
+            return Err(Error::other("could not retrieve current representation"));
+        }
+        let etag = current
+            .message
+            .get_first_option(CoapOption::ETag)
+            .ok_or(Error::new(ErrorKind::Unsupported, "resource did not return an ETag"))?
+            .clone();
+
+        let data = pipe_through(edit, &current.message.payload, "edit command")?;
+
+        info!("PUT {}", args.url);
+        let mut request = build_coap_request_for_url(
+            &args.url,
+            RequestType::Put,
+            Some(data),
+            current.message.get_content_format(),
+            None,
+        )?;
+        request.message.add_option(CoapOption::IfMatch, etag);
+        let Some(response) = send_request(conn, args, request).await? else {
+            return Ok(());
+        };
+        if *response.get_status() != ResponseType::PreconditionFailed {
+            output::print_response(&response.message, args, args.output_file.as_deref())?;
+            return Ok(());
+        }
+        output::print_code(&response.message, args);
+        if attempt < retries {
+            info!("resource changed concurrently, retrying");
+        }
+    }
+
+    Err(Error::other("resource kept changing; giving up"))
+}
+
+/// Pipes data through a shell command, returning what it writes to stdout. `what` names the
+/// command in the error reported if it fails, e.g. "edit command" for `cas --edit`.
+fn pipe_through(command: &str, input: &[u8], what: &str) -> Result<Vec<u8>> {
+    let mut shell = if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.arg("/C");
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.arg("-c");
+        c
+    };
+    let mut child = shell
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::new(e.kind(), format!("could not run {}: {}", what, e)))?;
+    // written from a thread of its own, so that a command writing a lot before it has read all
+    // of its input doesn't block on a full pipe while its input is still being written
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output()?;
+    // a command may exit without reading all of its input, which is no error of ours
+    let _ = writer.join();
+    if !output.status.success() {
+        return Err(Error::other(format!("{} failed: {}", what, output.status)));
+    }
+    Ok(output.stdout)
+}
+
+/// Pipes the payload of a request through the `--pre-cmd` command before it is sent, if there is
+/// one and the request has a payload.
+fn pre_process(args: &Args, message: &mut Packet) -> Result<()> {
+    if let Some(command) = &args.pre_cmd
+        && !message.payload.is_empty()
+    {
+        message.payload = pipe_through(command, &message.payload, "--pre-cmd")?;
+    }
+    Ok(())
+}
+
+/// Pipes the payload of a response through the `--post-cmd` command before it is used, if there
+/// is one and the response carries a representation: an error response's payload is a diagnostic
+/// message, not something the command would understand.
+fn post_process(args: &Args, message: &mut Packet) -> Result<()> {
+    let error = matches!(message.header.code, MessageClass::Response(code) if code.is_error());
+    if let Some(command) = &args.post_cmd
+        && !message.payload.is_empty()
+        && !error
+    {
+        message.payload = pipe_through(command, &message.payload, "--post-cmd")?;
+    }
+    Ok(())
+}
+
+fn load_data_file(file: &PathBuf) -> Result<Vec<u8>> {
+    if !file.is_file() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Error: path must be file: {}", file.to_str().unwrap()),
+        ));
+    }
+
+    let data = std::fs::read(file)?;
+    Ok(data)
+}
+
+/// Reads the request payload from whichever of `--data`, `--data-hex`, `--data-base64`, `--file`
+/// and `--stdin` was given.
+fn load_payload(
+    data: Option<&str>,
+    data_hex: Option<&str>,
+    data_base64: Option<&str>,
+    file: Option<&PathBuf>,
+    stdin: bool,
+) -> Result<Vec<u8>> {
+    if let Some(data) = data {
+        Ok(data.as_bytes().to_vec())
+    } else if let Some(hex) = data_hex {
+        parse_hex(hex)
+    } else if let Some(encoded) = data_base64 {
+        base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("invalid base64 data: {}", e)))
+    } else if stdin || file.is_some_and(|file| file.as_os_str() == "-") {
+        let mut data = vec![];
+        std::io::stdin().lock().read_to_end(&mut data)?;
+        Ok(data)
+    } else if let Some(file) = file {
+        load_data_file(file)
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "must specify either data string or file path",
+        ))
+    }
+}
+
+async fn send_request(
+    conn: &Connection,
+    args: &Args,
+    mut request: CoapRequest<SocketAddr>,
+) -> Result<Option<CoapResponse>> {
+    assign_token(conn, args, &mut request);
+    pre_process(args, &mut request.message)?;
+    if args.dry_run || args.verbose > 0 {
+        // as the request will be sent
+        let mut prepared = request.clone();
+        prepare_request(conn, args, &mut prepared)?;
+        print_size_analysis(&prepared.message);
+    }
+    if args.dry_run {
+        return Ok(None);
+    }
+    if args.compare_families {
+        compare_families::compare_families(args, request).await?;
+        return Ok(None);
+    }
+    let method = format!("{:?}", request.get_method()).to_uppercase();
+    if conn.peer_addr.ip().is_multicast() {
+        // each server responds in its own way, so there is no falling back on another format
+        if let Some(first) = request.message.get_first_option(CoapOption::Accept).cloned() {
+            request.message.clear_option(CoapOption::Accept);
+            request.message.add_option(CoapOption::Accept, first);
+        }
+        add_custom_options(args, &mut request);
+        let responses = multicast::multicast_exchange(conn, args, request).await?;
+        match &args.template {
+            Some(template) => report::print_report(
+                template,
+                &report::group_model(&args.url, &method, &responses),
+            )?,
+            None => multicast::print_multicast_responses(&responses),
+        }
+        return Ok(None);
+    }
+    if args.non && args.no_response == Some(NO_RESPONSE_ALL) {
+        // nothing will come back, not even an acknowledgement
+        prepare_request(conn, args, &mut request)?;
+        conn.send_without_response(&request).await?;
+        info!("sent; all responses suppressed by No-Response");
+        return Ok(None);
+    }
+    let start = Instant::now();
+    let response = match exchange_negotiating(conn, args, request).await {
+        Ok(response) => response,
+        // servers needn't answer a NON request, e.g. one carrying telemetry, nor one whose
+        // response class was suppressed
+        Err(e) if (args.non || args.no_response.is_some()) && e.kind() == ErrorKind::TimedOut => {
+            info!("no response within {}s", args.timeout);
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+    let mut response = response;
+    verify_payload(args, &response.message)?;
+    post_process(args, &mut response.message)?;
+    check_expectations(args, &response.message)?;
+    if fleet::collect(&args.url, &method, &response.message, start.elapsed()) {
+        return Ok(None);
+    }
+    let Some(template) = &args.template else {
+        return Ok(Some(response));
+    };
+    let model = report::exchange_model(&args.url, &method, &response.message, start.elapsed());
+    report::print_report(template, &model)?;
+    Ok(None)
+}
+
+/// Sends a request with [`exchange_retrying`], asking for the content formats its Accept options
+/// list one at a time, in order: Accept can't be repeated (RFC 7252 section 5.10.4), so the
+/// request goes with the first, and again with the next each time the server responds 4.06 Not
+/// Acceptable. With `--accept-any`, a 4.06 to the last is followed by a request without Accept,
+/// leaving the server to choose.
+async fn exchange_negotiating(
+    conn: &Connection,
+    args: &Args,
+    mut request: CoapRequest<SocketAddr>,
+) -> Result<CoapResponse> {
+    let formats: Vec<usize> = request
+        .message
+        .get_option(CoapOption::Accept)
+        .map(|values| values.iter().map(|v| message_dump::decode_uint(v) as usize).collect())
+        .unwrap_or_default();
+    let [first, rest @ ..] = formats.as_slice() else {
+        return exchange_retrying(conn, args, request).await;
+    };
+    let mut format = *first;
+    let mut rest = rest.iter();
+    loop {
+        request.message.clear_option(CoapOption::Accept);
+        request.message.add_option(CoapOption::Accept, encode_uint(format as u32));
+        let response = exchange_retrying(conn, args, request.clone()).await?;
+        let not_acceptable = *response.get_status() == ResponseType::NotAcceptable;
+        match rest.next() {
+            Some(next) if not_acceptable => {
+                info!(
+                    "{} Not Acceptable as {}; trying {}",
+                    response.message.header.code,
+                    content_format_name(format),
+                    content_format_name(*next)
+                );
+                format = *next;
+            }
+            None if not_acceptable && args.command.accepts_any() => {
+                info!(
+                    "{} Not Acceptable as {}; trying without Accept",
+                    response.message.header.code,
+                    content_format_name(format)
+                );
+                request.message.clear_option(CoapOption::Accept);
+                let response = exchange_retrying(conn, args, request).await?;
+                if !response.get_status().is_error() {
+                    match response.message.get_content_format() {
+                        Some(cf) => info!("server chose {}", content_format_name(usize::from(cf))),
+                        None => info!("server chose no content format"),
+                    }
+                }
+                return Ok(response);
+            }
+            _ => {
+                if format != *first && !not_acceptable {
+                    info!("accepted as {}", content_format_name(format));
+                }
+                return Ok(response);
+            }
+        }
+    }
+}
+
+/// Sends a request with [`exchange`] and, with `--retry-after`, again for as long as the server
+/// responds 5.03 Service Unavailable, waiting as long as each such response's Max-Age says.
+async fn exchange_retrying(
+    conn: &Connection,
+    args: &Args,
+    request: CoapRequest<SocketAddr>,
+) -> Result<CoapResponse> {
+    let mut retries = 0;
+    loop {
+        let response = exchange(conn, args, request.clone()).await?;
+        if !args.retry_after
+            || *response.get_status() != ResponseType::ServiceUnavailable
+            || retries == args.max_retries
+        {
+            return Ok(response);
+        }
+        retries += 1;
+        let wait = observe::max_age(&response.message);
+        info!(
+            "{} Service Unavailable; retrying in {}s ({} of {})",
+            response.message.header.code,
+            wait.as_secs(),
+            retries,
+            args.max_retries
+        );
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Prints and checks the SHA-256 digest of a response payload, as asked for by `--print-digest`
+/// and `--verify-sha256`.
+fn verify_payload(args: &Args, response: &Packet) -> Result<()> {
+    if !args.print_digest && args.verify_sha256.is_none() {
+        return Ok(());
+    }
+    let digest = message_dump::hex(&Sha256::digest(&response.payload));
+    if args.print_digest {
+        eprintln!("sha256: {}", digest);
+    }
+    match &args.verify_sha256 {
+        Some(expected) if !expected.eq_ignore_ascii_case(&digest) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("payload digest mismatch: expected {}, got {}", expected, digest),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Checks a response against the expectations given with `--expect-code`,
+/// `--expect-content-format` and `--expect-body`, failing with all it falls short of.
+fn check_expectations(args: &Args, response: &Packet) -> Result<()> {
+    let expectations = expect::Expectations {
+        code: args.expect_code,
+        content_format: args.expect_content_format,
+        body: &args.expect_body,
+    };
+    let failures = expect::check(response, &expectations);
+    if failures.is_empty() {
+        return Ok(());
+    }
+    Err(Error::new(ErrorKind::InvalidData, expect::ExpectationFailed { failures }))
+}
+
+/// Gives a request without a token the one given with `--token`, or one of the length requested
+/// by `--token-length`.
+fn assign_token(conn: &Connection, args: &Args, request: &mut CoapRequest<SocketAddr>) {
+    if !request.message.get_token().is_empty() {
+        return;
+    }
+    let token = match (&args.token, args.token_length) {
+        (Some(token), _) => token.clone(),
+        (None, Some(length)) => {
+            let token = if args.reuse_token {
+                conn.shared_token
+            } else {
+                rand::random()
+            };
+            token[..usize::from(length)].to_vec()
+        }
+        (None, None) => return,
+    };
+    request.message.set_token(token);
+}
+
+/// Gives a request what the global options add to every request: a token, its message ID and
+/// type, custom options and the routing through a proxy.
+fn prepare_request(
+    conn: &Connection,
+    args: &Args,
+    request: &mut CoapRequest<SocketAddr>,
+) -> Result<()> {
+    assign_token(conn, args, request);
+    if let Some(id) = args.message_id {
+        conn.pin_message_id(id);
+    }
+    set_message_type(args, request);
+    set_uri_authority(args, request);
+    add_custom_options(args, request);
+    apply_proxy(conn, args, request)
+}
+
+/// Replaces the Uri-Host and Uri-Port options with those given by `--uri-host` and `--uri-port`.
+fn set_uri_authority(args: &Args, request: &mut CoapRequest<SocketAddr>) {
+    if let Some(host) = &args.uri_host {
+        request.message.clear_option(CoapOption::UriHost);
+        if !host.is_empty() {
+            request.message.add_option(CoapOption::UriHost, host.as_bytes().to_vec());
+        }
+    }
+    if let Some(port) = args.uri_port {
+        request.message.clear_option(CoapOption::UriPort);
+        request.message.add_option(CoapOption::UriPort, encode_uint(u32::from(port)));
+    }
+}
+
+/// Adds the options given with `--option`, `--no-response`, `--hop-limit`, `--request-tag` and
+/// `--request-size2`.
+fn add_custom_options(args: &Args, request: &mut CoapRequest<SocketAddr>) {
+    for custom in args.options.iter().chain(&args.request_tag) {
+        request.message.add_option(custom.option, custom.value.clone());
+    }
+    if let Some(classes) = args.no_response {
+        request
+            .message
+            .add_option(CoapOption::NoResponse, encode_uint(u32::from(classes)));
+    }
+    if let Some(hop_limit) = args.hop_limit
+        && request.message.get_first_option(CoapOption::Unknown(HOP_LIMIT)).is_none()
+    {
+        request
+            .message
+            .add_option(CoapOption::Unknown(HOP_LIMIT), vec![hop_limit]);
+    }
+    // a Size2 of 0 asks for the size rather than giving one (RFC 7959 section 4)
+    if args.request_size2 && request.message.get_first_option(CoapOption::Size2).is_none() {
+        request.message.add_option(CoapOption::Size2, encode_uint(0));
+    }
+}
+
+/// Addresses a request to the `--proxy`, if one was given, rather than to the server it is for.
+fn apply_proxy(conn: &Connection, args: &Args, request: &mut CoapRequest<SocketAddr>) -> Result<()> {
+    let routed = request.message.get_first_option(CoapOption::ProxyUri).is_some()
+        || request.message.get_first_option(CoapOption::ProxyScheme).is_some();
+    if args.proxy.is_none() || routed {
+        return Ok(());
+    }
+    let proxy_scheme = args.proxy_scheme || conn.oscore.is_some();
+    route_via_proxy(&mut request.message, &args.url, proxy_scheme)
+}
+
+/// Makes a request non-confirmable if `--non` was given.
+fn set_message_type(args: &Args, request: &mut CoapRequest<SocketAddr>) {
+    if args.non {
+        request.message.header.set_type(MessageType::NonConfirmable);
+    }
+}
+
+/// Sends a request and waits for the response, regardless of `--dry-run`.
+async fn exchange(
+    conn: &Connection,
+    args: &Args,
+    mut request: CoapRequest<SocketAddr>,
+) -> Result<CoapResponse> {
+    prepare_request(conn, args, &mut request)?;
+    let limit = match conn.max_message_size() {
+        Some(size) => size,
+        None => datagram_limit(args.mtu.unwrap_or(DEFAULT_MTU), &conn.peer_addr),
+    };
+    let block1_size = match (args.block_size, block1_size_for_limit(&request, limit)?) {
+        (Some(block_size), _) => Some(block_size),
+        (None, Some(block_size)) if request.message.payload.len() > block_size => {
+            if args.mtu.is_some() || args.verbose > 0 {
+                info!(
+                    "request exceeds the {}-byte message size limit; sending payload in {}-byte blocks",
+                    limit, block_size
+                );
+            }
+            Some(block_size)
+        }
+        (None, Some(_)) => {
+            warn!(
+                "request exceeds the {}-byte message size limit and may be fragmented",
+                limit
+            );
+            None
+        }
+        // the request fits in one datagram, so don't split it
+        (None, None) => None,
+    };
+
+    let token = request.message.get_token().to_vec();
+    let log = conn.log.exchange(&token);
+    let request_size = request.message.payload.len();
+    let recorded = args.record.as_ref().map(|_| request.message.clone());
+    let start = Instant::now();
+    let deadline = request_deadline(args);
+    let result = before_deadline(
+        args,
+        deadline,
+        "request",
+        blockwise::send(conn, request, block1_size, args.block_size),
+    )
+    .await;
+    let rtt = start.elapsed();
+    output::set_time_total(rtt);
+    if let (Some(path), Some(request), Ok(response)) = (&args.record, &recorded, &result) {
+        record::append(path, &args.url, request, &response.message, rtt)?;
+    }
+    if args.verbose > 0 {
+        print_transmission_report(&token, &log);
+    }
+    if args.stats {
+        print_stats(conn, &log, start, rtt, request_size, result.as_ref().ok());
+    }
+    if let (Ok(_), Some(max_rtt)) = (&result, args.max_rtt)
+        && rtt > max_rtt
+    {
+        warn!(
+            "response took {}ms, exceeding the {}ms budget",
+            rtt.as_millis(),
+            max_rtt.as_millis()
+        );
+        RTT_BUDGET_EXCEEDED.store(true, Ordering::Relaxed);
+    }
+    result
+}
+
+/// Prints the number and media type of each content format in the registry, or with
+/// `--output json`, a JSON array of them.
+fn print_content_formats(output: OutputFormat) {
+    if output == OutputFormat::Json {
+        let formats: Vec<_> = CONTENT_FORMATS
+            .iter()
+            .map(|(name, cf)| serde_json::json!({ "number": usize::from(*cf), "name": name }))
+            .collect();
+        println!("{}", serde_json::Value::Array(formats));
+        return;
+    }
+    for (name, cf) in CONTENT_FORMATS {
+        println!("{:>5}  {}", usize::from(*cf), name);
+    }
+}
+
+fn print_size_analysis(message: &Packet) {
+    let components = message_size_breakdown(message);
+    let total: usize = components.iter().map(|(_, size)| size).sum();
+    for (name, size) in &components {
+        eprintln!("{:>6}  {}", size, name);
+    }
+    eprintln!("{:>6}  total", total);
+    for warning in message_size_warnings(total) {
+        warn!("{}", warning);
+    }
+}
+
+fn print_transmission_report(token: &[u8], log: &ExchangeLog) {
+    let attempts = log.attempts();
+    let Some(start) = attempts.first().map(|(_, sends, _)| sends[0]) else {
+        return;
+    };
+    match token {
+        [] => eprintln!("token: (empty)"),
+        token => eprintln!("token: {}", message_dump::hex(token)),
+    }
+    for (mid, sends, reply) in &attempts {
+        let times = sends
+            .iter()
+            .map(|t| format!("+{}ms", t.duration_since(start).as_millis()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let reply = match reply {
+            Some(t) => format!("reply after {}ms", t.duration_since(sends[0]).as_millis()),
+            None => "no reply".to_string(),
+        };
+        eprintln!("message {:#06x}: {} attempt(s) at {}; {}", mid, sends.len(), times, reply);
+    }
+    if let Some(reply) = log.reply() {
+        eprintln!("reply: {}", describe_reply(reply));
+    }
+    eprintln!("{} retransmission(s)", log.retransmissions());
+}
+
+/// Describes how the server answered a request, e.g. `separate CON response after 1503ms,
+/// acknowledged, following an empty ACK after 2ms`.
+fn describe_reply(reply: Reply) -> String {
+    match reply {
+        Reply::Piggybacked { after } => {
+            format!("piggybacked on the ACK after {}ms", after.as_millis())
+        }
+        Reply::Separate {
+            ack_after,
+            response_after,
+            message_type,
+            acknowledged,
+        } => {
+            let mut description = format!(
+                "separate {} response after {}ms",
+                message_dump::message_type_name(message_type),
+                response_after.as_millis()
+            );
+            if acknowledged {
+                description.push_str(", acknowledged");
+            }
+            if let Some(ack_after) = ack_after {
+                let ack_after = ack_after.as_millis();
+                description.push_str(&format!(", following an empty ACK after {}ms", ack_after));
+            }
+            description
+        }
+        Reply::Reset { after } => format!("RST after {}ms", after.as_millis()),
+    }
+}
+
+/// Prints where the time of an exchange went and how much was sent, as asked for by `--stats`.
+fn print_stats(
+    conn: &Connection,
+    log: &ExchangeLog,
+    start: Instant,
+    total: Duration,
+    request_size: usize,
+    response: Option<&CoapResponse>,
+) {
+    let millis = |d: Duration| format!("{:.3} ms", d.as_secs_f64() * 1000.0);
+    // those of other exchanges made over the connection at the same time are no part of it
+    let datagrams = log.own_datagrams();
+    let sent = datagrams.iter().filter(|d| d.direction == Direction::Sent).count();
+    let received = datagrams.len() - sent;
+    let first_byte = datagrams
+        .iter()
+        .find(|d| d.direction == Direction::Received)
+        .map_or("-".to_string(), |d| millis(d.at.duration_since(start)));
+    eprintln!("resolve:          {}", millis(conn.setup.resolve));
+    eprintln!(
+        "handshake:        {}",
+        conn.setup.handshake.map_or("-".to_string(), millis)
+    );
+    eprintln!("first byte:       {}", first_byte);
+    let reply = response.and_then(|_| log.reply());
+    eprintln!("reply:            {}", reply.map_or("-".to_string(), describe_reply));
+    eprintln!("total:            {}", millis(total));
+    eprintln!("retransmissions:  {}", log.retransmissions());
+    eprintln!("messages:         {} sent, {} received", sent, received);
+    eprintln!("request payload:  {} bytes", request_size);
+    match response {
+        Some(response) => eprintln!("response payload: {} bytes", response.message.payload.len()),
+        None => eprintln!("response payload: -"),
+    }
+}
+
+/// When a request made now must be complete by, with `--deadline`.
+fn request_deadline(args: &Args) -> Option<tokio::time::Instant> {
+    args.deadline.map(|deadline| tokio::time::Instant::now() + deadline)
+}
+
+/// Awaits (a part of) a request, failing with a timeout if its deadline passes first.
+async fn before_deadline<T>(
+    args: &Args,
+    deadline: Option<tokio::time::Instant>,
+    what: &str,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(deadline) = deadline else {
+        return future.await;
+    };
+    tokio::time::timeout_at(deadline, future).await.unwrap_or_else(|_| {
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            format!(
+                "{} not complete within the {}ms deadline",
+                what,
+                args.deadline.unwrap_or_default().as_millis()
+            ),
+        ))
+    })
+}
+
+async fn create_connection(args: &Args) -> Result<Connection> {
+    create_connection_to(args, &args.url).await
+}
+
+async fn create_connection_to(args: &Args, url: &str) -> Result<Connection> {
+    // with a forward proxy, every request goes to it, whichever server it is for
+    let url = args.proxy.as_deref().unwrap_or(url);
+    let uri = CoapUri::parse(url)?;
+    let (host, port) = (uri.host.as_str(), uri.port_or_default());
+    let timeout = Duration::new(args.timeout, 0);
+    let started = Instant::now();
+    let peer_addr = match &args.connect_to {
+        Some(to) => resolve::resolve_peer(args, &to.host, to.port).await?,
+        None => resolve::resolve_peer(args, host, port).await?,
+    };
+    let local_addr = resolve::local_addr(args, peer_addr)?;
+    let resolved = Instant::now();
+    let scheme = uri.scheme.as_str();
+    let setup = async {
+        match scheme {
+            "coaps" => {
+                let config = dtls::dtls_config(args, resolve::split_zone(host).0)?;
+                let session_file = args
+                    .session_cache
+                    .as_deref()
+                    .map(|dir| dtls::session_file(dir, peer_addr, args));
+                new_dtls_connection(peer_addr, local_addr, config, timeout, session_file.as_deref())
+                    .await
+            }
+            "coap+tcp" => new_tcp_connection(peer_addr, local_addr, timeout).await,
+            "coap+ws" => {
+                let host = match resolve::split_zone(host).0 {
+                    ip if ip.contains(':') => format!("[{}]:{}", ip, port),
+                    name => format!("{}:{}", name, port),
+                };
+                new_websocket_connection(peer_addr, local_addr, &host, timeout).await
+            }
+            scheme @ ("coaps+tcp" | "coaps+ws") => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("CoAP over TLS ({}) is not supported", scheme),
+                ));
+            }
+            _ => return new_udp_connection_to_addr(peer_addr, local_addr).await,
+        }
+        // plain UDP has no handshake to fail
+        .map_err(|e| error::CoapCliError::handshake(e, resolved))
+    };
+    let mut conn = before_deadline(args, request_deadline(args), "connection setup", setup).await?;
+    conn.setup = SetupTimings {
+        resolve: resolved - started,
+        handshake: (scheme != "coap").then(|| resolved.elapsed()),
+    };
+    conn.client.set_receive_timeout(timeout);
+    conn.set_cancellation(args.cancel.clone());
+    if args.verbose > 1 {
+        conn.log.set_trace(true);
+    }
+    if args.max_retransmit.is_some() || args.ack_timeout.is_some() || args.ack_random_factor.is_some()
+    {
+        conn.set_transmission_params(TransmissionParams {
+            ack_timeout: args.ack_timeout.unwrap_or(DEFAULT_ACK_TIMEOUT),
+            ack_random_factor: args.ack_random_factor.unwrap_or(DEFAULT_ACK_RANDOM_FACTOR),
+            max_retransmit: args.max_retransmit.unwrap_or(DEFAULT_MAX_RETRANSMIT),
+            response_timeout: timeout,
+        });
+    }
+    if let Some(nstart) = args.nstart {
+        conn.set_nstart(usize::from(nstart));
+    }
+    if let Some(path) = &args.oscore_context {
+        conn.oscore = Some(Arc::new(oscore::SecurityContext::load(path)?));
+    }
+    Ok(conn)
+}
+
+async fn execute_command(args: &Args) -> Result<()> {
+    match &args.command {
+        Commands::Serve {
+            manifest,
+            dir,
+            stdin,
+            content_format,
+        } => {
+            let resources = match (manifest, dir, stdin) {
+                (Some(manifest), _, _) => serve::Resources::Manifest(serve::load_manifest(manifest)?),
+                (None, Some(dir), _) => serve::Resources::Dir(dir),
+                (None, None, Some(path)) => serve::Resources::Stdin {
+                    path,
+                    content_format: content_format.as_deref().map(parse_content_format).transpose()?,
+                },
+                (None, None, None) => {
+                    return Err(Error::new(ErrorKind::InvalidInput, "nothing to serve"));
+                }
+            };
+            return serve::coap_serve(args, resources).await;
+        }
+        Commands::Mock { fixtures } => return mock::coap_mock(args, fixtures).await,
+        Commands::Selftest => return selftest::coap_selftest(args).await,
+        Commands::InspectUrl => return inspect_url::coap_inspect_url(args).await,
+        Commands::Decode { messages, file } => return decode::coap_decode(args, messages, file.as_deref()),
+        Commands::ContentFormats => {
+            print_content_formats(args.output);
+            return Ok(());
+        }
+        Commands::Completions { shell } => {
+            // generated in full first, as generate panics if it can't write
+            let mut script = vec![];
+            clap_complete::generate(*shell, &mut Args::command(), "coap-cli", &mut script);
+            return std::io::stdout().write_all(&script);
+        }
+        Commands::Doctor => return doctor::coap_doctor(args).await,
+        Commands::Discover {
+            mdns: true, json, ..
+        } => return discover::coap_discover_mdns(args, *json).await,
+        Commands::Discover {
+            dns_sd: true,
+            dns_server,
+            probe,
+            rt,
+            interface,
+            json,
+            ..
+        } => {
+            let options = discover::DiscoverOptions {
+                rt: rt.as_deref(),
+                interface: interface.as_deref(),
+                json: *json,
+            };
+            return discover::coap_discover_dns(args, *dns_server, *probe, &options).await;
+        }
+        Commands::Discover {
+            multicast: Some(group),
+            rt,
+            interface,
+            json,
+            ..
+        } => {
+            let options = discover::DiscoverOptions {
+                rt: rt.as_deref(),
+                interface: interface.as_deref(),
+                json: *json,
+            };
+            return discover::coap_discover_multicast(args, *group, &options).await;
+        }
+        Commands::Proxy {
+            upstream,
+            store,
+            offline,
+        } => return proxy::coap_proxy(args, upstream.as_deref(), store, *offline).await,
+        Commands::Intercept {
+            upstream,
+            delay,
+            drop,
+            rewrite,
+            direction,
+        } => {
+            let tampering = intercept::Tampering {
+                delay: *delay,
+                drop: *drop,
+                rewrites: rewrite.clone(),
+                direction: *direction,
+            };
+            return intercept::coap_intercept(args, upstream, tampering).await;
+        }
+        Commands::Daemon { socket, device } => {
+            return daemon::coap_daemon(args, socket, device).await;
+        }
+        Commands::Group {
+            members,
+            method,
+            content_format,
+            data,
+            serial,
+            parallel: _,
+            group,
+            threshold,
+        } => {
+            let request = group::GroupRequest {
+                method: *method,
+                content_format: content_format.as_deref(),
+                data: data.as_deref(),
+            };
+            let group = group.as_deref();
+            return group::coap_group(args, members, group, *serial, *threshold, &request).await;
+        }
+        Commands::Gateway { listen } => {
+            return gateway::coap_gateway(create_connection(args).await?, args, listen).await;
+        }
+        Commands::Inspect {
+            code,
+            content_format,
+            payload,
+            log,
+        } => {
+            return inspect::coap_inspect(
+                args,
+                code,
+                content_format.as_deref(),
+                payload.as_deref(),
+                log.as_deref(),
+            )
+            .await;
+        }
+        Commands::Script { file, script_args } => {
+            return script::coap_script(args, file, script_args).await;
+        }
+        _ => {}
+    }
+
+    match (&args.command, &args.targets) {
+        (
+            Commands::Send {
+                via_daemon: Some(socket),
+                method,
+                accept,
+                content_format,
+                data,
+            },
+            _,
+        ) => {
+            coap_send_via_daemon(
+                args,
+                socket,
+                *method,
+                accept.as_deref(),
+                content_format.as_deref(),
+                data.as_deref(),
+            )
+            .await?
+        }
+        (_, Some(file)) => fleet::coap_fleet(args, file).await?,
+        (_, None) => run_requests(&create_connection(args).await?, args).await?,
+    }
+
+    if RTT_BUDGET_EXCEEDED.load(Ordering::Relaxed) {
+        return Err(Error::other("response time budget exceeded"));
+    }
+    match ERROR_RESPONSE_CLASS.load(Ordering::Relaxed) {
+        class @ (4 | 5) if args.fail => Err(Error::other(ErrorResponse { class })),
+        _ => Ok(()),
+    }
+}
+
+/// Runs the command over the connection, once for each of the URLs given to a request command, or
+/// for the URL if none are.
+async fn run_requests(conn: &Connection, args: &Args) -> Result<()> {
+    match args.command.urls() {
+        [] => run_command(conn, args).await,
+        urls => {
+            for url in urls {
+                let url = request_url(args, url)?;
+                run_command(conn, &Args { url, ..args.clone() }).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Resolves one of the URLs given to a request command against the URL, making sure it is on
+/// the server the connection is to.
+fn request_url(args: &Args, url: &str) -> Result<String> {
+    let resolved = mirror::resource_url(&args.url, url)?;
+    if args.proxy.is_none() && !same_server(&resolved, &args.url)? {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not on the same server as {}", resolved, args.url),
+        ));
+    }
+    Ok(resolved)
+}
+
+/// Whether two URLs are of resources on the same server, reached with the same scheme, so that
+/// one connection will do for both.
+fn same_server(a: &str, b: &str) -> Result<bool> {
+    let server = |url: &str| -> Result<_> {
+        let uri = CoapUri::parse(url)?;
+        let port = uri.port_or_default();
+        Ok((uri.scheme, uri.host, port))
+    };
+    Ok(server(a)? == server(b)?)
+}
+
+/// Runs a command that talks to the URL's server over the connection.
+async fn run_command(conn: &Connection, args: &Args) -> Result<()> {
+    match &args.command {
+        Commands::Get {
+            accept,
+            repeat,
+            watch,
+            interval,
+            timestamp,
+            ..
+        } if repeat.is_some() || *watch => {
+            let options = poll::PollOptions {
+                accept,
+                repeat: *repeat,
+                interval: *interval,
+                timestamp: *timestamp,
+            };
+            poll::coap_poll(conn, args, &options).await
+        }
+        Commands::Get {
+            accept,
+            etag_store,
+            etag,
+            cache_dir,
+            ..
+        } => {
+            let etag_store = etag_store.clone().map(EtagStore::new).transpose()?;
+            let cache = cache_dir.clone().map(ResponseCache::new).transpose()?;
+            coap_get(conn, args, accept, etag_store.as_ref(), etag, cache.as_ref()).await
+        }
+        Commands::Download { file, resume } => {
+            download::coap_download(conn, args, file.as_deref(), *resume).await
+        }
+        Commands::Upload {
+            file,
+            post,
+            accept,
+            content_format,
+            if_match,
+            if_none_match,
+        } => {
+            let method = if *post { RequestType::Post } else { RequestType::Put };
+            let content_format = content_format.as_deref().map(parse_content_format).transpose()?;
+            let preconditions = Preconditions {
+                if_match,
+                if_none_match: *if_none_match,
+            };
+            let accept = accept.as_deref().map(parse_content_format).transpose()?;
+            upload::coap_upload(conn, args, file, method, accept, content_format, preconditions)
+                .await
+        }
+        Commands::Observe {
+            accept,
+            sqlite,
+            keepalive,
+            wait_first,
+            timestamp,
+            count,
+            duration,
+            auto_reregister,
+            max_silence,
+        } => {
+            let options = observe::ObserveOptions {
+                accept,
+                sqlite: sqlite.as_deref(),
+                keepalive: *keepalive,
+                wait_first: *wait_first,
+                timestamp: *timestamp,
+                count: *count,
+                duration: *duration,
+                auto_reregister: *auto_reregister,
+                max_silence: *max_silence,
+            };
+            observe::coap_observe(conn, args, &options).await
+        }
+        Commands::Post {
+            accept,
+            content_format,
+            data,
+            data_hex,
+            data_base64,
+            file,
+            stdin,
+            encode,
+            ..
+        }
+        | Commands::Put {
+            accept,
+            content_format,
+            data,
+            data_hex,
+            data_base64,
+            file,
+            stdin,
+            encode,
+            ..
+        }
+        | Commands::Fetch {
+            accept,
+            content_format,
+            data,
+            data_hex,
+            data_base64,
+            file,
+            stdin,
+            encode,
+            ..
+        }
+        | Commands::Patch {
+            accept,
+            content_format,
+            data,
+            data_hex,
+            data_base64,
+            file,
+            stdin,
+            encode,
+            ..
+        }
+        | Commands::Ipatch {
+            accept,
+            content_format,
+            data,
+            data_hex,
+            data_base64,
+            file,
+            stdin,
+            encode,
+            ..
+        } => {
+            let (method, preconditions) = match &args.command {
+                Commands::Post {
+                    if_match,
+                    if_none_match,
+                    ..
+                } => (
+                    RequestType::Post,
+                    Preconditions {
+                        if_match,
+                        if_none_match: *if_none_match,
+                    },
+                ),
+                Commands::Put {
+                    if_match,
+                    if_none_match,
+                    ..
+                } => (
+                    RequestType::Put,
+                    Preconditions {
+                        if_match,
+                        if_none_match: *if_none_match,
+                    },
+                ),
+                Commands::Fetch { .. } => (RequestType::Fetch, Preconditions::default()),
+                Commands::Patch { .. } => (RequestType::Patch, Preconditions::default()),
+                _ => (RequestType::IPatch, Preconditions::default()),
+            };
+            let data = load_payload(
+                data.as_deref(),
+                data_hex.as_deref(),
+                data_base64.as_deref(),
+                file.as_ref(),
+                *stdin,
+            )?;
+            let content_format = content_format.as_deref().map(parse_content_format).transpose()?;
+            let (data, content_format) = match encode {
+                Some(encoding) => {
+                    let (data, content_format) =
+                        content::encode_payload(&data, *encoding, content_format)?;
+                    (data, Some(content_format))
+                }
+                None => (data, content_format),
+            };
+            if args.validate {
+                content::validate_payload(&data, content_format)?;
+            }
+
+            let response = coap_submit(
+                conn,
+                args,
+                method,
+                accept,
+                content_format,
+                data,
+                preconditions,
+            )
+            .await?;
+            match (&args.command, response) {
+                (Commands::Post { follow: true, .. }, Some(response)) => {
+                    coap_follow(conn, args, accept, &response).await
+                }
+                _ => Ok(()),
+            }
+        }
+        Commands::Cas { edit, retries } => coap_cas(conn, args, edit, *retries).await,
+        Commands::GetAll { rt, accept } => {
+            get_all::coap_get_all(conn, args, rt.as_deref(), accept).await
+        }
+        Commands::Mirror { dir } => mirror::coap_mirror(conn, args, dir).await,
+        Commands::Push { dir } => mirror::coap_push(conn, args, dir).await,
+        Commands::Sync { dir, direction } => mirror::coap_sync(conn, args, dir, *direction).await,
+        Commands::Diff { other, interval } => {
+            diff::coap_diff(conn, args, other.as_deref(), *interval).await
+        }
+        Commands::Prefetch { links, store } => {
+            prefetch::coap_prefetch(conn, args, links.as_deref(), store).await
+        }
+        Commands::Bench {
+            method,
+            data,
+            requests,
+            json,
+            csv,
+            hgrm,
+            baseline,
+            fail_if_slower,
+            targets,
+            weights,
+            connections,
+            window,
+            rate,
+        } => {
+            let options = bench::BenchOptions {
+                method: *method,
+                data: data.as_deref(),
+                requests: *requests,
+                json: json.as_deref(),
+                csv: csv.as_deref(),
+                hgrm: hgrm.as_deref(),
+                baseline: baseline.as_deref(),
+                fail_if_slower: *fail_if_slower,
+                targets,
+                weights,
+                connections: *connections,
+                window: *window,
+                concurrency: args.concurrency,
+                rate: *rate,
+            };
+            bench::coap_bench(conn, args, &options).await
+        }
+        Commands::Discover {
+            mdns: false,
+            multicast: None,
+            dns_sd: false,
+            rt,
+            interface,
+            json,
+            ..
+        } => {
+            let options = discover::DiscoverOptions {
+                rt: rt.as_deref(),
+                interface: interface.as_deref(),
+                json: *json,
+            };
+            discover::coap_discover(conn, args, &options).await
+        }
+        Commands::Rd { command } => rd::coap_rd(conn, args, command).await,
+        Commands::Lwm2m { command } => lwm2m::coap_lwm2m(conn, args, command).await,
+        Commands::Batch { file, variables } => {
+            batch::coap_batch(conn, args, file, variables, args.concurrency.unwrap_or(1)).await
+        }
+        Commands::Replay { file, timing } => record::coap_replay(conn, args, file, *timing).await,
+        Commands::Delete {
+            accept,
+            if_match,
+            if_none_match,
+            ..
+        } => {
+            let preconditions = Preconditions {
+                if_match,
+                if_none_match: *if_none_match,
+            };
+            coap_delete(conn, args, accept, preconditions).await
+        }
+        Commands::Ping { count, interval } => ping::coap_ping(conn, args, *count, *interval).await,
+        Commands::Check { freshness } => check::coap_check(conn, args, *freshness).await,
+        Commands::Send {
+            method,
+            accept,
+            content_format,
+            data,
+            ..
+        } => {
+            coap_send(
+                conn,
+                args,
+                *method,
+                accept.as_deref(),
+                content_format.as_deref(),
+                data.as_deref(),
+            )
+            .await
+        }
+        Commands::Serve { .. }
+        | Commands::Mock { .. }
+        | Commands::Proxy { .. }
+        | Commands::Intercept { .. }
+        | Commands::Daemon { .. }
+        | Commands::Gateway { .. }
+        | Commands::Group { .. }
+        | Commands::Selftest
+        | Commands::InspectUrl
+        | Commands::Decode { .. }
+        | Commands::Completions { .. }
+        | Commands::ContentFormats
+        | Commands::Doctor
+        | Commands::Discover { mdns: true, .. }
+        | Commands::Discover { dns_sd: true, .. }
+        | Commands::Discover {
+            multicast: Some(_), ..
+        }
+        | Commands::Inspect { .. }
+        | Commands::Script { .. } => {
+            unreachable!()
+        }
+    }
+}
+
+impl Commands {
+    /// The URLs given to a request command, each to be requested in place of the URL.
+    fn urls(&self) -> &[String] {
+        match self {
+            Commands::Get { urls, .. }
+            | Commands::Post { urls, .. }
+            | Commands::Put { urls, .. }
+            | Commands::Fetch { urls, .. }
+            | Commands::Patch { urls, .. }
+            | Commands::Ipatch { urls, .. }
+            | Commands::Delete { urls, .. } => urls,
+            _ => &[],
+        }
+    }
+
+    /// Whether the server may choose the content format when it finds none of those asked for
+    /// acceptable.
+    fn accepts_any(&self) -> bool {
+        matches!(self, Commands::Get { accept_any: true, .. })
+    }
+
+    /// Whether this is one of the commands that make a request of the URL and print the response.
+    fn is_request(&self) -> bool {
+        matches!(
+            self,
+            Commands::Get { .. }
+                | Commands::Post { .. }
+                | Commands::Put { .. }
+                | Commands::Fetch { .. }
+                | Commands::Patch { .. }
+                | Commands::Ipatch { .. }
+                | Commands::Delete { .. }
+        )
+    }
+}
+
+/// The failure reported by `--fail` when the server responded with an error.
+#[derive(Debug)]
+struct ErrorResponse {
+    class: u8,
+}
+
+impl std::fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the server responded with a {}.xx error", self.class)
+    }
+}
+
+impl std::error::Error for ErrorResponse {}
+
+/// The exit status for an error, as listed in `EXIT_STATUS_HELP`.
+fn exit_status(err: &Error) -> i32 {
+    if let Some(response) = err.get_ref().and_then(|e| e.downcast_ref::<ErrorResponse>()) {
+        return i32::from(response.class);
+    }
+    if err.get_ref().is_some_and(|e| e.is::<expect::ExpectationFailed>()) {
+        return expect::EXPECTATION_FAILED_STATUS;
+    }
+    match err.kind() {
+        ErrorKind::TimedOut => 3,
+        ErrorKind::ConnectionRefused
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::NotConnected
+        | ErrorKind::HostUnreachable
+        | ErrorKind::NetworkUnreachable
+        | ErrorKind::AddrNotAvailable
+        | ErrorKind::BrokenPipe => 6,
+        ErrorKind::Interrupted => cancel::INTERRUPTED_STATUS,
+        _ => 1,
+    }
+}
+
+/// Checks that a pre-shared key comes with its identity, and isn't given along with a certificate.
+/// A certificate given on the command line takes precedence over a pre-shared key from the
+/// environment, which is then ignored.
+fn check_credentials(
+    args: &mut Args,
+    matches: &clap::ArgMatches,
+) -> std::result::Result<(), clap::Error> {
+    let from_env =
+        |id: &str| matches.value_source(id) == Some(clap::parser::ValueSource::EnvVariable);
+    if args.cert.is_some() || args.ca_cert.is_some() {
+        if from_env("psk_identity") && from_env("psk_key") {
+            args.psk_identity = None;
+            args.psk_key = None;
+        } else if args.psk_identity.is_some() || args.psk_key.is_some() {
+            let cert = if args.cert.is_some() { "--cert" } else { "--ca-cert" };
+            return Err(Args::command().error(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!("the argument '{}' cannot be used with '--psk-identity'", cert),
+            ));
+        }
+    }
+    let missing = match (&args.psk_identity, &args.psk_key) {
+        (Some(_), None) => "--psk-key <PSK_KEY>",
+        (None, Some(_)) => "--psk-identity <PSK_IDENTITY>",
+        _ => return Ok(()),
+    };
+    Err(Args::command().error(
+        clap::error::ErrorKind::MissingRequiredArgument,
+        format!("the following required arguments were not provided:\n  {}", missing),
+    ))
+}
+
+/// Appends the `--path-segment` and `--query` arguments to the URL.
+fn apply_uri_parts(args: &mut Args) {
+    if !args.path_segments.is_empty() || !args.query.is_empty() {
+        args.url = append_to_url(&args.url, &args.path_segments, &args.query);
+    }
+}
+
+/// Makes the URL absolute against `--base`, if one was given.
+fn apply_base_url(args: &mut Args) -> Result<()> {
+    if let Some(base) = &args.base
+        && !args.url.contains("://")
+    {
+        args.url = mirror::resource_url(base, &args.url)?;
+    }
+    Ok(())
+}
+
+fn main() {
+    // a .env file in the current directory supplies environment variables not already set
+    // SAFETY: no other thread has been started yet
+    if let Err(err) = unsafe { dotenv::load(Path::new(".env")) } {
+        eprintln!("ERROR: {}", err);
+        std::process::exit(1);
+    }
+    tokio::runtime::Runtime::new()
+        .expect("failed to start the runtime")
+        .block_on(run());
+}
+
+async fn run() {
+    let matches = Args::command().get_matches();
+    let mut cli = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    // --quiet leaves out the progress logged at info, unless a log level is asked for
+    let log_level = match cli.log_level {
+        logging::LogLevel::Info
+            if cli.quiet
+                && matches.value_source("log_level")
+                    == Some(clap::parser::ValueSource::DefaultValue) =>
+        {
+            logging::LogLevel::Warn
+        }
+        level => level,
+    };
+    logging::init(log_level, cli.log_format);
+    let needs_url = !matches!(
+        cli.command,
+        Commands::Decode { .. }
+            | Commands::Completions { .. }
+            | Commands::ContentFormats
+            | Commands::Daemon { .. }
+    );
+    if cli.url.is_empty() && cli.base.is_none() && needs_url {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "the following required arguments were not provided:\n  <URL>",
+            )
+            .exit();
+    }
+
+    if let Err(err) = check_credentials(&mut cli, &matches) {
+        err.exit();
+    }
+
+    let prepared = apply_base_url(&mut cli).and_then(|()| profile::apply_profile(&mut cli, &matches));
+    if let Err(err) = prepared {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+    apply_uri_parts(&mut cli);
+    cancel::listen(cli.cancel.clone());
+    let result = match winds_down_when_interrupted(&cli.command) {
+        true => execute_command(&cli).await,
+        false => tokio::select! {
+            result = execute_command(&cli) => result,
+            _ = async {
+                cli.cancel.cancelled().await;
+                tokio::time::sleep(INTERRUPT_GRACE).await;
+            } => Err(cancel::interrupted()),
+        },
+    };
+    if let Err(err) = result {
+        error!("{}", err);
+        std::process::exit(exit_status(&err));
+    }
+}
+
+/// How long a command that was interrupted has to report how far it got, as its exchanges fail,
+/// before it is stopped wherever it is.
+const INTERRUPT_GRACE: Duration = Duration::from_millis(200);
+
+/// Whether a command stops by itself when interrupted, and cleans up as it does, e.g. by
+/// deregistering an observation, rather than having to be stopped.
+fn winds_down_when_interrupted(command: &Commands) -> bool {
+    match command {
+        Commands::Get { repeat, watch, .. } => repeat.is_some() || *watch,
+        Commands::Observe { .. } | Commands::Ping { .. } => true,
+        _ => false,
     }
 }
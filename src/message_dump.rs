@@ -0,0 +1,197 @@
+use coap_lite::{CoapOption, MessageClass, MessageType, Packet};
+use std::fmt::Write;
+
+use crate::coap_helper::{content_format_name, option_name, response_code_info, HOP_LIMIT};
+
+pub fn message_type_name(t: MessageType) -> &'static str {
+    match t {
+        MessageType::Confirmable => "CON",
+        MessageType::NonConfirmable => "NON",
+        MessageType::Acknowledgement => "ACK",
+        MessageType::Reset => "RST",
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_uint(value: &[u8]) -> u64 {
+    value.iter().fold(0, |acc, b| (acc << 8) | u64::from(*b))
+}
+
+/// Renders an option value according to the option's registered format.
+pub fn decode_option_value(option: CoapOption, value: &[u8]) -> String {
+    match option {
+        CoapOption::UriHost
+        | CoapOption::LocationPath
+        | CoapOption::UriPath
+        | CoapOption::UriQuery
+        | CoapOption::LocationQuery
+        | CoapOption::ProxyUri
+        | CoapOption::ProxyScheme => format!("{:?}", String::from_utf8_lossy(value)),
+        CoapOption::Observe
+        | CoapOption::UriPort
+        | CoapOption::ContentFormat
+        | CoapOption::MaxAge
+        | CoapOption::Accept
+        | CoapOption::Size1
+        | CoapOption::Size2
+        | CoapOption::NoResponse
+        | CoapOption::Unknown(HOP_LIMIT) => decode_uint(value).to_string(),
+        CoapOption::Block1 | CoapOption::Block2 => {
+            let v = decode_uint(value);
+            format!("num={} more={} size={}", v >> 4, (v >> 3) & 1 == 1, 16 << (v & 7))
+        }
+        CoapOption::IfNoneMatch => "(empty)".to_string(),
+        _ => format!("0x{}", hex(value)),
+    }
+}
+
+/// Describes each option of a message by its registered name and what its value means, e.g.
+/// `Max-Age: 60 s` or `Content-Format: application/json (50)`. The segments of Uri-Path and
+/// Location-Path, and the arguments of Uri-Query and Location-Query, are joined into one line.
+pub fn describe_options(message: &Packet) -> Vec<(String, String)> {
+    let mut described = vec![];
+    for (number, values) in message.options() {
+        let option = CoapOption::from(*number);
+        let text = |value: &Vec<u8>| String::from_utf8_lossy(value).into_owned();
+        let values: Vec<String> = match option {
+            CoapOption::UriPath | CoapOption::LocationPath => {
+                let segments: Vec<String> = values.iter().map(text).collect();
+                vec![format!("/{}", segments.join("/"))]
+            }
+            CoapOption::UriQuery | CoapOption::LocationQuery => {
+                vec![values.iter().map(text).collect::<Vec<_>>().join("&")]
+            }
+            CoapOption::ContentFormat | CoapOption::Accept => values
+                .iter()
+                .map(|value| {
+                    let number = decode_uint(value) as usize;
+                    match content_format_name(number) {
+                        name if name == number.to_string() => name,
+                        name => format!("{} ({})", name, number),
+                    }
+                })
+                .collect(),
+            CoapOption::MaxAge => values
+                .iter()
+                .map(|value| format!("{} s", decode_uint(value)))
+                .collect(),
+            CoapOption::ETag | CoapOption::IfMatch => values.iter().map(|value| hex(value)).collect(),
+            CoapOption::UriHost | CoapOption::ProxyUri | CoapOption::ProxyScheme => {
+                values.iter().map(text).collect()
+            }
+            _ => values.iter().map(|value| decode_option_value(option, value)).collect(),
+        };
+        for value in values {
+            described.push((option_name(option), value));
+        }
+    }
+    described
+}
+
+/// Produces a multi-line, human-readable description of a message.
+pub fn dump_message(message: &Packet) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{} {} mid={:#06x} token={}",
+        message_type_name(message.header.get_type()),
+        message.header.code,
+        message.header.message_id,
+        hex(message.get_token()),
+    );
+    for (number, values) in message.options() {
+        let option = CoapOption::from(*number);
+        for value in values {
+            let _ = writeln!(
+                out,
+                "  {:?} ({}): {}",
+                option,
+                number,
+                decode_option_value(option, value)
+            );
+        }
+    }
+    if !message.payload.is_empty() {
+        let _ = writeln!(out, "  payload ({} bytes):", message.payload.len());
+        match std::str::from_utf8(&message.payload) {
+            Ok(text) => {
+                for line in text.lines() {
+                    let _ = writeln!(out, "    {}", line);
+                }
+            }
+            Err(_) => {
+                for chunk in message.payload.chunks(16) {
+                    let _ = writeln!(out, "    {}", hex(chunk));
+                }
+            }
+        }
+    }
+    out
+}
+
+/// The name of a message's code, e.g. `GET` or `Content`.
+fn code_name(code: MessageClass) -> String {
+    match code {
+        MessageClass::Empty => "Empty".to_string(),
+        MessageClass::Request(method) => format!("{:?}", method).to_uppercase(),
+        MessageClass::Response(status) => response_code_info(status).0.to_string(),
+        MessageClass::Reserved(_) => "Reserved".to_string(),
+    }
+}
+
+/// Renders bytes as lines of offset, hex and ASCII, 16 bytes to a line.
+fn hex_dump(out: &mut String, bytes: &[u8]) {
+    for (i, chunk) in bytes.chunks(16).enumerate() {
+        let hex = chunk.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        let _ = writeln!(out, "    {:04x}  {:<47}  |{}|", i * 16, hex, ascii);
+    }
+}
+
+/// Describes a message as it appears on the wire, field by field: the header, the token, every
+/// option with its raw and decoded value, and the payload as a hex and ASCII dump.
+pub fn trace_message(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let message = match Packet::from_bytes(bytes) {
+        Ok(message) => message,
+        Err(e) => {
+            let _ = writeln!(out, "  undecodable message ({:?}):", e);
+            hex_dump(&mut out, bytes);
+            return out;
+        }
+    };
+    let _ = writeln!(
+        out,
+        "  version {}, type {}, code {} {}, mid {:#06x}",
+        message.header.get_version(),
+        message_type_name(message.header.get_type()),
+        message.header.code,
+        code_name(message.header.code),
+        message.header.message_id,
+    );
+    let token = message.get_token();
+    let _ = writeln!(out, "  token ({} bytes): 0x{}", token.len(), hex(token));
+    for (number, values) in message.options() {
+        let option = CoapOption::from(*number);
+        for value in values {
+            let raw = format!("0x{}", hex(value));
+            let decoded = decode_option_value(option, value);
+            let _ = write!(out, "  option {} {:?} ({} bytes): {}", number, option, value.len(), raw);
+            if decoded != raw {
+                let _ = write!(out, " = {}", decoded);
+            }
+            out.push('\n');
+        }
+    }
+    if !message.payload.is_empty() {
+        let _ = writeln!(out, "  payload ({} bytes):", message.payload.len());
+        hex_dump(&mut out, &message.payload);
+    }
+    out
+}
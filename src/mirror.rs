@@ -0,0 +1,290 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use coap_lite::{ContentFormat, RequestType, ResponseType};
+use log::info;
+use url::Url;
+
+use crate::coap_helper::*;
+use crate::link_format::{parse_links, Link};
+use crate::transport::Connection;
+use crate::{exchange, output, send_request, Args};
+
+/// Name of the file, inside a mirrored directory, which records the content format of each
+/// resource.
+pub const MANIFEST_FILE: &str = ".content-formats";
+
+/// Resources which are also the parent of other resources are stored under this name inside the
+/// directory for their path.
+const INDEX_FILE: &str = "index";
+
+pub fn resource_url(base: &str, path: &str) -> Result<String> {
+    let base = Url::parse(base)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("invalid URI '{}': {}", base, e)))?;
+    let url = base
+        .join(path)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("invalid resource path: {}", path)))?;
+    Ok(url.to_string())
+}
+
+/// Maps a file inside `dir` back to the resource path it represents.
+fn resource_path_for(dir: &Path, file: &Path) -> Option<String> {
+    let relative = file.strip_prefix(dir).ok()?;
+    let mut segments: Vec<String> = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    if segments.last().map(String::as_str) == Some(INDEX_FILE) {
+        segments.pop();
+    }
+    Some(format!("/{}", segments.join("/")))
+}
+
+pub fn walk_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if path.is_dir() {
+            walk_files(&path, files)?;
+        } else if entry.file_name() != MANIFEST_FILE {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+pub fn content_format_for_extension(file: &Path) -> Option<ContentFormat> {
+    let extension = file.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "txt" => Some(ContentFormat::TextPlain),
+        "json" => Some(ContentFormat::ApplicationJSON),
+        "xml" => Some(ContentFormat::ApplicationXML),
+        "cbor" => Some(ContentFormat::ApplicationCBOR),
+        "wlnk" => Some(ContentFormat::ApplicationLinkFormat),
+        "bin" => Some(ContentFormat::ApplicationOctetStream),
+        _ => None,
+    }
+}
+
+fn local_path_for(dir: &Path, resource: &str, is_parent: bool) -> PathBuf {
+    let mut path = dir.to_path_buf();
+    for segment in resource.split('/').filter(|s| !s.is_empty()) {
+        path.push(segment);
+    }
+    if is_parent || resource.trim_matches('/').is_empty() {
+        path.push(INDEX_FILE);
+    }
+    path
+}
+
+pub fn load_manifest(dir: &Path) -> Result<BTreeMap<String, usize>> {
+    let path = dir.join(MANIFEST_FILE);
+    if !path.is_file() {
+        return Ok(BTreeMap::new());
+    }
+    let mut manifest = BTreeMap::new();
+    for line in std::fs::read_to_string(path)?.lines() {
+        if let Some((resource, cf)) = line.rsplit_once('\t')
+            && let Ok(cf) = cf.parse()
+        {
+            manifest.insert(resource.to_string(), cf);
+        }
+    }
+    Ok(manifest)
+}
+
+fn save_manifest(dir: &Path, manifest: &BTreeMap<String, usize>) -> Result<()> {
+    let contents: String = manifest
+        .iter()
+        .map(|(resource, cf)| format!("{}\t{}\n", resource, cf))
+        .collect();
+    std::fs::write(dir.join(MANIFEST_FILE), contents)
+}
+
+/// Lists the local resources the server advertises in /.well-known/core.
+pub async fn discover_resources(conn: &Connection, args: &Args) -> Result<Vec<Link>> {
+    let url = resource_url(&args.url, "/.well-known/core")?;
+    info!("GET {}", url);
+    let request = build_coap_request_for_url(&url, RequestType::Get, None, None, None)?;
+    let response = exchange(conn, args, request).await?;
+    output::print_code(&response.message, args);
+    if *response.get_status() != ResponseType::Content {
+        return Err(Error::other("resource discovery failed"));
+    }
+    let links = parse_links(&String::from_utf8_lossy(&response.message.payload))?;
+    Ok(links
+        .into_iter()
+        .filter(|l| l.target.starts_with('/') && l.target != "/.well-known/core")
+        .collect())
+}
+
+pub async fn coap_mirror(conn: &Connection, args: &Args, dir: &Path) -> Result<()> {
+    let links = discover_resources(conn, args).await?;
+    let paths: Vec<&str> = links.iter().map(|l| l.target.as_str()).collect();
+    std::fs::create_dir_all(dir)?;
+
+    let mut manifest = BTreeMap::new();
+    for link in &links {
+        let resource = &link.target;
+        let prefix = format!("{}/", resource.trim_end_matches('/'));
+        let is_parent = paths.iter().any(|p| p.starts_with(&prefix));
+
+        let url = resource_url(&args.url, resource)?;
+        info!("GET {}", url);
+        let request = build_coap_request_for_url(&url, RequestType::Get, None, None, None)?;
+        let Some(response) = send_request(conn, args, request).await? else {
+            continue;
+        };
+        output::print_code(&response.message, args);
+        if *response.get_status() != ResponseType::Content {
+            continue;
+        }
+
+        let file = local_path_for(dir, resource, is_parent);
+        if let Some(parent) = file.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&file, &response.message.payload)?;
+        let cf = match response.message.get_content_format() {
+            Some(cf) => Some(usize::from(cf)),
+            None => link.attr("ct").and_then(|ct| ct.parse().ok()),
+        };
+        if let Some(cf) = cf {
+            manifest.insert(resource.clone(), cf);
+        }
+        println!("{} -> {}", resource, file.display());
+    }
+
+    save_manifest(dir, &manifest)
+}
+
+pub async fn coap_push(conn: &Connection, args: &Args, dir: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("path must be directory: {}", dir.display()),
+        ));
+    }
+    let manifest = load_manifest(dir)?;
+    let base = format!("{}/", args.url.trim_end_matches('/'));
+
+    let mut files = vec![];
+    walk_files(dir, &mut files)?;
+    for file in files {
+        let Some(resource) = resource_path_for(dir, &file) else {
+            continue;
+        };
+        let cf = match manifest.get(&resource) {
+            Some(cf) => ContentFormat::try_from(*cf).ok(),
+            None => content_format_for_extension(&file),
+        };
+        let data = std::fs::read(&file)?;
+
+        let url = resource_url(&base, resource.trim_start_matches('/'))?;
+        info!("PUT {}", url);
+        let request = build_coap_request_for_url(&url, RequestType::Put, Some(data), cf, None)?;
+        let Some(response) = send_request(conn, args, request).await? else {
+            continue;
+        };
+        output::print_code(&response.message, args);
+        println!("{} -> {}", file.display(), resource);
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum SyncDirection {
+    /// Update the server from the local directory
+    Push,
+    /// Update the local directory from the server
+    Pull,
+}
+
+pub async fn coap_sync(
+    conn: &Connection,
+    args: &Args,
+    dir: &Path,
+    direction: SyncDirection,
+) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let manifest = load_manifest(dir)?;
+    let remote: BTreeSet<String> = discover_resources(conn, args)
+        .await?
+        .into_iter()
+        .map(|l| l.target)
+        .collect();
+
+    let mut files = vec![];
+    walk_files(dir, &mut files)?;
+    let mut local = BTreeMap::new();
+    for file in files {
+        if let Some(resource) = resource_path_for(dir, &file) {
+            local.insert(resource, file);
+        }
+    }
+
+    let mut changes = 0;
+    for resource in remote.iter().chain(local.keys().filter(|r| !remote.contains(*r))) {
+        let url = resource_url(&args.url, resource)?;
+        let remote_data = if remote.contains(resource) {
+            let request = build_coap_request_for_url(&url, RequestType::Get, None, None, None)?;
+            let response = exchange(conn, args, request).await?;
+            (*response.get_status() == ResponseType::Content).then_some(response.message.payload)
+        } else {
+            None
+        };
+        let local_data = local.get(resource).map(std::fs::read).transpose()?;
+
+        let status = match (&local_data, &remote_data) {
+            (Some(l), Some(r)) if l == r => continue,
+            (Some(_), Some(_)) => "M",
+            (Some(_), None) => "L",
+            (None, Some(_)) => "R",
+            (None, None) => continue,
+        };
+        println!("{} {}", status, resource);
+        changes += 1;
+        if args.dry_run {
+            continue;
+        }
+
+        match (direction, local_data, remote_data) {
+            (SyncDirection::Push, Some(data), _) => {
+                let cf = match manifest.get(resource) {
+                    Some(cf) => ContentFormat::try_from(*cf).ok(),
+                    None => content_format_for_extension(&local[resource]),
+                };
+                info!("PUT {}", url);
+                let request = build_coap_request_for_url(&url, RequestType::Put, Some(data), cf, None)?;
+                let response = exchange(conn, args, request).await?;
+                output::print_code(&response.message, args);
+            }
+            (SyncDirection::Pull, _, Some(data)) => {
+                let is_parent = {
+                    let prefix = format!("{}/", resource.trim_end_matches('/'));
+                    remote.iter().any(|p| p.starts_with(&prefix))
+                };
+                let file = match local.get(resource) {
+                    Some(file) => file.clone(),
+                    None => local_path_for(dir, resource, is_parent),
+                };
+                if let Some(parent) = file.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&file, data)?;
+                info!("wrote {}", file.display());
+            }
+            // the resource only exists on the side being updated; leave it alone
+            _ => {}
+        }
+    }
+
+    if changes == 0 {
+        info!("in sync");
+    }
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use async_trait::async_trait;
+use coap_lite::{CoapRequest, ContentFormat, MessageClass, ResponseType};
+use log::{error, info};
+use serde::Deserialize;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::coap_helper::*;
+use crate::server::{normalize_path, set_response, RequestHandler, Server};
+use crate::Args;
+
+/// Canned responses served by `mock --fixtures`.
+///
+/// ```toml
+/// [[fixture]]
+/// path = "/sensors/temp"
+/// content_format = "application/json"
+/// payload = '{"celsius": 21.5}'
+///
+/// [[fixture]]
+/// path = "/actuators/valve"
+/// method = "PUT"
+/// code = "2.04"
+/// delay = 250
+/// ```
+#[derive(Debug, Deserialize)]
+struct Fixtures {
+    #[serde(rename = "fixture", default)]
+    fixtures: Vec<FixtureSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FixtureSpec {
+    path: String,
+    /// Request method the fixture applies to; any method matches when omitted
+    method: Option<String>,
+    #[serde(default = "default_code")]
+    code: String,
+    content_format: Option<String>,
+    #[serde(default)]
+    payload: String,
+    /// Milliseconds to wait before responding
+    #[serde(default)]
+    delay: u64,
+}
+
+fn default_code() -> String {
+    "2.05".to_string()
+}
+
+struct Fixture {
+    path: String,
+    method: Option<String>,
+    code: ResponseType,
+    content_format: Option<ContentFormat>,
+    payload: Vec<u8>,
+    delay: Duration,
+}
+
+fn load_fixtures(path: &Path) -> Result<Vec<Fixture>> {
+    let contents = std::fs::read_to_string(path)?;
+    let fixtures: Fixtures =
+        toml::from_str(&contents).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    fixtures
+        .fixtures
+        .into_iter()
+        .map(|spec| {
+            Ok(Fixture {
+                path: normalize_path(&spec.path),
+                method: spec.method.map(|m| m.to_uppercase()),
+                code: parse_response_code(&spec.code)?,
+                content_format: spec.content_format.as_deref().map(parse_content_format).transpose()?,
+                payload: spec.payload.into_bytes(),
+                delay: Duration::from_millis(spec.delay),
+            })
+        })
+        .collect()
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+struct MockHandler {
+    fixtures: RwLock<Vec<Fixture>>,
+}
+
+#[async_trait]
+impl RequestHandler for MockHandler {
+    async fn handle(&self, request: &mut CoapRequest<SocketAddr>) {
+        let path = normalize_path(&request.get_path());
+        let method = format!("{:?}", request.get_method()).to_uppercase();
+        let matched = self
+            .fixtures
+            .read()
+            .unwrap()
+            .iter()
+            .find(|f| f.path == path && f.method.as_ref().is_none_or(|m| *m == method))
+            .map(|f| (f.code, f.content_format, f.payload.clone(), f.delay));
+        let Some((code, content_format, payload, delay)) = matched else {
+            info!("{} {} -> 4.04 (no fixture)", method, path);
+            set_response(request, ResponseType::NotFound, vec![]);
+            return;
+        };
+        info!("{} {} -> {}", method, path, MessageClass::Response(code));
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        set_response(request, code, payload);
+        if let (Some(cf), Some(response)) = (content_format, request.response.as_mut()) {
+            response.message.set_content_format(cf);
+        }
+    }
+}
+
+pub async fn coap_mock(args: &Args, fixtures: &Path) -> Result<()> {
+    let CoapUri { host, port, .. } = CoapUri::parse(&args.url)?;
+    let handler = Arc::new(MockHandler {
+        fixtures: RwLock::new(load_fixtures(fixtures)?),
+    });
+    let server = Server::bind((&host, port.unwrap_or(5683))).await?;
+    info!("listening on {}", server.local_addr()?);
+
+    // poll the fixtures file and swap in its contents whenever it changes
+    let path: PathBuf = fixtures.to_path_buf();
+    let watched = handler.clone();
+    tokio::spawn(async move {
+        let mut last_modified = modified_time(&path);
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            let modified = modified_time(&path);
+            if modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+            match load_fixtures(&path) {
+                Ok(fixtures) => {
+                    info!("reloaded {} fixture(s) from {}", fixtures.len(), path.display());
+                    *watched.fixtures.write().unwrap() = fixtures;
+                }
+                Err(e) => error!("could not reload {}: {}", path.display(), e),
+            }
+        }
+    });
+
+    server.run(handler).await
+}
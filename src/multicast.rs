@@ -0,0 +1,115 @@
+use coap_lite::{CoapRequest, MessageClass, MessageType, Packet};
+use log::{debug, info};
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, Result};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+use crate::transport::{self, Connection};
+use crate::{resolve, Args};
+
+/// Whether two responses are the same but for their message IDs.
+fn same_response(a: &Packet, b: &Packet) -> bool {
+    a.header.code == b.header.code && a.payload == b.payload && a.options().eq(b.options())
+}
+
+/// Sends a request to a multicast group as a non-confirmable message and collects the unicast
+/// responses of the group members until the timeout expires. A response a member sends more than
+/// once, whether retransmitted or sent again as a new message, is only kept the first time.
+pub async fn multicast_exchange(
+    conn: &Connection,
+    args: &Args,
+    mut request: CoapRequest<SocketAddr>,
+) -> Result<Vec<(SocketAddr, Packet)>> {
+    let local_addr = resolve::local_addr(args, conn.peer_addr)?;
+    let socket = UdpSocket::bind(transport::bind_addr(conn.peer_addr, local_addr)).await?;
+    // responses are matched to the request by token, so make sure there is one unless it was
+    // chosen explicitly
+    let token = match args.token_chosen() {
+        true => request.message.get_token().to_vec(),
+        false => rand::random::<[u8; 4]>().to_vec(),
+    };
+    request.message.header.set_type(MessageType::NonConfirmable);
+    request.message.header.message_id = args.message_id.unwrap_or_else(rand::random);
+    request.message.set_token(token.clone());
+    let bytes = request.message.to_bytes().map_err(Error::other)?;
+    socket.send_to(&bytes, conn.peer_addr).await?;
+
+    let mut responses = vec![];
+    let mut seen = HashSet::new();
+    let mut duplicates = 0;
+    let mut buf = vec![0; 65535];
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(args.timeout);
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = received?;
+        let Ok(packet) = Packet::from_bytes(&buf[..len]) else {
+            continue;
+        };
+        if packet.get_token() != token.as_slice()
+            || !matches!(packet.header.code, MessageClass::Response(_))
+        {
+            continue;
+        }
+        if packet.header.get_type() == MessageType::Confirmable {
+            let mut ack = Packet::new();
+            ack.header.set_type(MessageType::Acknowledgement);
+            ack.header.message_id = packet.header.message_id;
+            socket.send_to(&ack.to_bytes().map_err(Error::other)?, from).await?;
+        }
+        // members may retransmit confirmable responses
+        if !seen.insert((from, packet.header.message_id)) {
+            continue;
+        }
+        let duplicate = |(source, earlier): &(SocketAddr, Packet)| {
+            *source == from && same_response(earlier, &packet)
+        };
+        if responses.iter().any(duplicate) {
+            duplicates += 1;
+            continue;
+        }
+        responses.push((from, packet));
+    }
+    if duplicates > 0 {
+        debug!("ignored {} duplicate response(s)", duplicates);
+    }
+    Ok(responses)
+}
+
+/// The source of each response, numbered, e.g. `[fe80::1]:5683 #2`, where a source sent
+/// several different responses.
+fn source_labels(responses: &[(SocketAddr, Packet)]) -> Vec<String> {
+    let mut counts: HashMap<SocketAddr, usize> = HashMap::new();
+    for (from, _) in responses {
+        *counts.entry(*from).or_default() += 1;
+    }
+    let mut numbers: HashMap<SocketAddr, usize> = HashMap::new();
+    responses
+        .iter()
+        .map(|(from, _)| {
+            let number = numbers.entry(*from).or_default();
+            *number += 1;
+            match counts[from] {
+                1 => from.to_string(),
+                _ => format!("{} #{}", from, number),
+            }
+        })
+        .collect()
+}
+
+pub fn print_multicast_responses(responses: &[(SocketAddr, Packet)]) {
+    let labels = source_labels(responses);
+    let width = labels
+        .iter()
+        .map(String::len)
+        .max()
+        .unwrap_or(0)
+        .max("SOURCE".len());
+    println!("{:width$}  CODE  PAYLOAD", "SOURCE", width = width);
+    for (label, (_, packet)) in labels.iter().zip(responses) {
+        let payload = String::from_utf8_lossy(&packet.payload).replace('\n', " ");
+        println!("{:width$}  {}  {}", label, packet.header.code, payload, width = width);
+    }
+    let sources: HashSet<_> = responses.iter().map(|(from, _)| from).collect();
+    info!("{} response(s) from {} source(s)", responses.len(), sources.len());
+}
@@ -0,0 +1,338 @@
+use coap::client::ObserveMessage;
+use coap_lite::{CoapOption, CoapRequest, ObserveOption, Packet, RequestType};
+use log::{error, info, warn};
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::cancel;
+use crate::coap_helper::*;
+use crate::output::print_response;
+use crate::oscore::RequestBinding;
+use crate::sqlite_log::NotificationDb;
+use crate::timestamp::{Clock, TimestampFormat};
+use crate::transport::Connection;
+use crate::{
+    create_connection, exchange, post_process, prepare_request, print_size_analysis,
+    print_transmission_report, Args,
+};
+
+pub struct ObserveOptions<'a> {
+    pub accept: &'a [String],
+    pub sqlite: Option<&'a Path>,
+    pub keepalive: Option<Duration>,
+    /// Skip the registration response and stop after the next notification, failing if none
+    /// arrives within the limit
+    pub wait_first: Option<Option<Duration>>,
+    pub timestamp: TimestampFormat,
+    /// Stop after this many notifications, including the registration response
+    pub count: Option<usize>,
+    /// Stop after observing for this long
+    pub duration: Option<Duration>,
+    /// Register again when notifications stop arriving, reconnecting with back-off if the server
+    /// can't be reached
+    pub auto_reregister: bool,
+    /// How long to go without a notification before registering again, instead of the latest
+    /// notification's Max-Age plus a grace period
+    pub max_silence: Option<Duration>,
+}
+
+/// How long a notification is fresh for when it doesn't say (RFC 7252 section 5.10.5).
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// How long after a notification stops being fresh to wait for the next before registering
+/// again, allowing for the server being slow to send it.
+const MAX_AGE_GRACE: Duration = Duration::from_secs(10);
+
+/// Limits between attempts to register again with a server that can't be reached.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// A registration with the server, lasting until the server is asked to deregister or another
+/// registration with the same token replaces it.
+struct Registration {
+    _sender: oneshot::Sender<ObserveMessage>,
+    // for unprotecting the notifications with OSCORE
+    binding: Option<RequestBinding>,
+}
+
+/// Registers with the server, sending the response and every notification on `packets`.
+async fn register(
+    conn: &Connection,
+    request: &CoapRequest<SocketAddr>,
+    packets: &mpsc::UnboundedSender<Packet>,
+) -> Result<Registration> {
+    let mut request = request.clone();
+    request.message.header.message_id = conn.next_message_id();
+    // with OSCORE, the registration is protected here, and each notification unprotected as it
+    // arrives, as the client's observation doesn't go through the connection
+    let binding = match &conn.oscore {
+        Some(oscore) => {
+            request.set_observe_flag(ObserveOption::Register);
+            Some(oscore.protect_request(&mut request.message)?)
+        }
+        None => None,
+    };
+    let packets = packets.clone();
+    let sender = conn
+        .client
+        .observe_with(request, move |p| {
+            let _ = packets.send(p);
+        })
+        .await?;
+    Ok(Registration {
+        _sender: sender,
+        binding,
+    })
+}
+
+/// Asks the server to deregister the observation with the given token. This is done here rather
+/// than through the registration's sender, so that it's done before exiting, and goes ahead even
+/// when the observation was interrupted.
+async fn deregister(conn: &Connection, args: &Args, token: Vec<u8>) {
+    conn.ignore_cancellation();
+    let result = async {
+        let mut request = build_coap_request_for_url(&args.url, RequestType::Get, None, None, None)?;
+        request.message.set_token(token);
+        request.set_observe_flag(ObserveOption::Deregister);
+        exchange(conn, args, request).await
+    };
+    if let Err(e) = result.await {
+        error!("could not deregister: {}", e);
+    }
+}
+
+/// Observes the URL until `count` notifications, the registration response included, have
+/// arrived or any `duration` has passed, and returns them, deregistering before it does.
+pub async fn collect(
+    conn: &Connection,
+    args: &Args,
+    count: usize,
+    duration: Option<Duration>,
+) -> Result<Vec<Packet>> {
+    let mut request = build_coap_request_for_url(&args.url, RequestType::Get, None, None, None)?;
+    prepare_request(conn, args, &mut request)?;
+    let token = request.message.get_token().to_vec();
+    let (packets_tx, mut packets_rx) = mpsc::unbounded_channel();
+    let registration = register(conn, &request, &packets_tx).await?;
+    let mut notifications = vec![];
+    let expired = sleep_until(duration.map(|d| tokio::time::Instant::now() + d));
+    tokio::pin!(expired);
+    while notifications.len() < count {
+        tokio::select! {
+            _ = &mut expired => break,
+            _ = args.cancel.cancelled() => break,
+            Some(mut p) = packets_rx.recv() => {
+                if let (Some(oscore), Some(binding)) = (&conn.oscore, &registration.binding) {
+                    oscore.unprotect_response(binding, &mut p)?;
+                }
+                notifications.push(p);
+            }
+        }
+    }
+    deregister(conn, args, token).await;
+    drop(registration);
+    if args.cancel.is_cancelled() {
+        return Err(cancel::interrupted());
+    }
+    // deregistering let the connection's exchanges run on through an interruption
+    conn.set_cancellation(args.cancel.clone());
+    Ok(notifications)
+}
+
+/// How long a notification, or any response, says it is fresh for.
+pub fn max_age(p: &Packet) -> Duration {
+    p.get_first_option(CoapOption::MaxAge)
+        .map(|bytes| bytes.iter().fold(0, |acc, b| (acc << 8) | u64::from(*b)))
+        .map_or(DEFAULT_MAX_AGE, Duration::from_secs)
+}
+
+fn print_notification(p: &Packet, args: &Args, clock: &Clock) {
+    match p.get_observe_value() {
+        _ if args.quiet => {}
+        Some(Ok(sequence)) => eprint!("{} #{}: ", clock.stamp(), sequence),
+        _ => eprint!("{}: ", clock.stamp()),
+    }
+    if let Err(e) = print_response(p, args, args.output_file.as_deref()) {
+        error!("could not write notification: {}", e);
+    }
+}
+
+/// Waits until the deadline, or forever if there is none.
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the next keep-alive tick, or forever if keep-alives are off.
+async fn tick(ticks: &mut Option<tokio::time::Interval>) {
+    match ticks {
+        Some(ticks) => {
+            ticks.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+pub async fn coap_observe(
+    conn: &Connection,
+    args: &Args,
+    options: &ObserveOptions<'_>,
+) -> Result<()> {
+    info!("OBSERVE {}", args.url);
+
+    // Accept can't be repeated, and an observation can't fall back on another format as a single
+    // request can, so only the first is asked for
+    let accept_cf = options
+        .accept
+        .first()
+        .map(|a| parse_content_format(a))
+        .transpose()?
+        .into_iter()
+        .collect();
+    let mut request =
+        build_coap_request_for_url(&args.url, RequestType::Get, None, None, Some(accept_cf))?;
+    prepare_request(conn, args, &mut request)?;
+
+    if args.dry_run || args.verbose > 0 {
+        print_size_analysis(&request.message);
+    }
+    if args.dry_run {
+        return Ok(());
+    }
+
+    let db = options.sqlite.map(NotificationDb::open).transpose()?;
+    let token = request.message.get_token().to_vec();
+    let (packets_tx, mut packets_rx) = mpsc::unbounded_channel();
+    let clock = Clock::new(options.timestamp);
+
+    let log = conn.log.exchange(&token);
+    let mut registration = tokio::select! {
+        result = register(conn, &request, &packets_tx) => result?,
+        _ = args.cancel.cancelled() => {
+            // the server may have registered the request even so
+            deregister(conn, args, token).await;
+            return Err(cancel::interrupted());
+        }
+    };
+    if args.verbose > 0 {
+        print_transmission_report(&token, &log);
+    }
+    // the notifications that follow are no part of the registration
+    drop(log);
+    // the connection notifications arrive on, replaced if the server can't be reached
+    let mut reconnected: Option<Connection> = None;
+
+    let (count, duration) = match options.wait_first {
+        Some(limit) => (Some(1), limit),
+        None => (options.count, options.duration),
+    };
+    let deadline = duration.map(|d| tokio::time::Instant::now() + d);
+    let mut ticks = options
+        .keepalive
+        .map(|interval| tokio::time::interval_at(tokio::time::Instant::now() + interval, interval));
+    let interrupted = args.cancel.cancelled();
+    tokio::pin!(interrupted);
+    let expired = sleep_until(deadline);
+    tokio::pin!(expired);
+    // until a notification says how long it's fresh for, the server may take as long as the
+    // default Max-Age to send the next
+    let initial_silence = options.max_silence.unwrap_or(DEFAULT_MAX_AGE + MAX_AGE_GRACE);
+    let mut silence = options
+        .auto_reregister
+        .then(|| tokio::time::Instant::now() + initial_silence);
+    let mut received = 0;
+    let mut notifications = 0;
+    loop {
+        let current = reconnected.as_ref().unwrap_or(conn);
+        tokio::select! {
+            _ = &mut interrupted => break,
+            _ = &mut expired => break,
+            Some(mut p) = packets_rx.recv() => {
+                if let (Some(oscore), Some(binding)) = (&current.oscore, &registration.binding)
+                    && let Err(e) = oscore.unprotect_response(binding, &mut p)
+                {
+                    error!("{}", e);
+                    continue;
+                }
+                if silence.is_some() {
+                    let limit = options.max_silence.unwrap_or(max_age(&p) + MAX_AGE_GRACE);
+                    silence = Some(tokio::time::Instant::now() + limit);
+                }
+                received += 1;
+                // when waiting for a change, the current state in the registration response
+                // doesn't count
+                if options.wait_first.is_some() && received == 1 {
+                    continue;
+                }
+                if let Err(e) = post_process(args, &mut p) {
+                    error!("{}", e);
+                    continue;
+                }
+                print_notification(&p, args, &clock);
+                if let Some(db) = &db
+                    && let Err(e) = db.record(&args.url, &p)
+                {
+                    error!("could not record notification: {}", e);
+                }
+                notifications += 1;
+                if count.is_some_and(|count| notifications >= count) {
+                    break;
+                }
+            }
+            _ = sleep_until(silence) => {
+                warn!("no notification for a while; registering again");
+                let mut backoff = MIN_BACKOFF;
+                let renewed = loop {
+                    let current = reconnected.as_ref().unwrap_or(conn);
+                    tokio::select! {
+                        _ = &mut interrupted => break None,
+                        _ = &mut expired => break None,
+                        result = register(current, &request, &packets_tx) => match result {
+                            Ok(renewed) => break Some(renewed),
+                            Err(e) => error!(
+                                "could not register again: {}; reconnecting in {}s",
+                                e,
+                                backoff.as_secs()
+                            ),
+                        },
+                    }
+                    tokio::select! {
+                        _ = &mut interrupted => break None,
+                        _ = &mut expired => break None,
+                        _ = tokio::time::sleep(backoff) => {}
+                    }
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    match create_connection(args).await {
+                        Ok(fresh) => reconnected = Some(fresh),
+                        Err(e) => error!("could not reconnect: {}", e),
+                    }
+                };
+                // the previous registration's observation ends as this one replaces it
+                let Some(renewed) = renewed else { break };
+                registration = renewed;
+                silence = Some(tokio::time::Instant::now() + initial_silence);
+            }
+            _ = tick(&mut ticks) => {
+                if args.verbose > 0 {
+                    info!("sending keep-alive ping");
+                }
+                if let Err(e) = current.ping().await {
+                    error!("could not send keep-alive ping: {}", e);
+                }
+            }
+        }
+    }
+
+    deregister(reconnected.as_ref().unwrap_or(conn), args, token).await;
+    drop(registration);
+
+    if options.wait_first.is_some() && notifications == 0 {
+        return Err(Error::new(ErrorKind::TimedOut, "no notification received"));
+    }
+    Ok(())
+}
@@ -0,0 +1,596 @@
+use aes::Aes128;
+use ccm::aead::{Aead, KeyInit, Payload};
+use ccm::consts::{U13, U8};
+use ccm::Ccm;
+use coap_lite::{CoapOption, MessageClass, Packet, RequestType};
+use hkdf::Hkdf;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::coap_helper::parse_hex;
+
+// AES-CCM-16-64-128, the mandatory AEAD algorithm, and the only one supported
+type AesCcm = Ccm<Aes128, U8, U13>;
+const ALG_AEAD: u64 = 10;
+const KEY_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 13;
+
+/// An OSCORE security context shared with a server, as read by `--oscore-context`.
+///
+/// ```toml
+/// master_secret = "0102030405060708090a0b0c0d0e0f10"
+/// master_salt = "9e7ca92223786340"
+/// sender_id = ""
+/// recipient_id = "01"
+/// ```
+///
+/// All values are hex; the salt and ID context are optional. The master secret may be left out
+/// and given by the `COAP_OSCORE_MASTER_SECRET` environment variable instead, so that the file
+/// holds nothing secret.
+#[derive(Debug, Deserialize)]
+struct ContextFile {
+    master_secret: Option<String>,
+    #[serde(default)]
+    master_salt: String,
+    sender_id: String,
+    recipient_id: String,
+    id_context: Option<String>,
+}
+
+/// The keys derived from a security context (RFC 8613 section 3.2), and the sender sequence
+/// number, which is kept in a file next to the context so that no nonce is ever used twice.
+pub struct SecurityContext {
+    sender_id: Vec<u8>,
+    recipient_id: Vec<u8>,
+    id_context: Option<Vec<u8>>,
+    sender_key: Vec<u8>,
+    recipient_key: Vec<u8>,
+    common_iv: Vec<u8>,
+    sequence_file: PathBuf,
+    // held while taking a sequence number, as requests may be protected concurrently
+    sequence_lock: Mutex<()>,
+}
+
+/// What a response has to be checked against: the sender ID and partial IV the request was
+/// protected with.
+#[derive(Debug, Clone)]
+pub struct RequestBinding {
+    kid: Vec<u8>,
+    piv: Vec<u8>,
+}
+
+// the minimal CBOR needed for the key derivation info and the additional authenticated data
+fn cbor_head(major: u8, value: u64) -> Vec<u8> {
+    let major = major << 5;
+    match value {
+        0..=23 => vec![major | value as u8],
+        24..=0xff => vec![major | 24, value as u8],
+        0x100..=0xffff => [vec![major | 25], (value as u16).to_be_bytes().to_vec()].concat(),
+        _ => [vec![major | 26], (value as u32).to_be_bytes().to_vec()].concat(),
+    }
+}
+
+fn cbor_uint(value: u64) -> Vec<u8> {
+    cbor_head(0, value)
+}
+
+fn cbor_bytes(bytes: &[u8]) -> Vec<u8> {
+    [cbor_head(2, bytes.len() as u64), bytes.to_vec()].concat()
+}
+
+fn cbor_text(text: &str) -> Vec<u8> {
+    [cbor_head(3, text.len() as u64), text.as_bytes().to_vec()].concat()
+}
+
+fn cbor_array(items: &[Vec<u8>]) -> Vec<u8> {
+    [vec![cbor_head(4, items.len() as u64)], items.to_vec()].concat().concat()
+}
+
+const CBOR_NULL: u8 = 0xf6;
+
+fn derive(
+    hkdf: &Hkdf<Sha256>,
+    id: &[u8],
+    id_context: Option<&[u8]>,
+    kind: &str,
+    length: usize,
+) -> Vec<u8> {
+    let info = cbor_array(&[
+        cbor_bytes(id),
+        id_context.map_or(vec![CBOR_NULL], cbor_bytes),
+        cbor_uint(ALG_AEAD),
+        cbor_text(kind),
+        cbor_uint(length as u64),
+    ]);
+    let mut output = vec![0; length];
+    hkdf.expand(&info, &mut output).expect("output length is valid for SHA-256");
+    output
+}
+
+/// Encodes a sequence number as a partial IV: big-endian without leading zeros, but at least
+/// one byte.
+fn partial_iv(sequence: u64) -> Vec<u8> {
+    let bytes = sequence.to_be_bytes();
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len() - 1);
+    bytes[start..].to_vec()
+}
+
+// options that stay outside the encryption, readable by proxies; everything else is protected
+fn is_outer(option: CoapOption) -> bool {
+    matches!(
+        option,
+        CoapOption::UriHost
+            | CoapOption::UriPort
+            | CoapOption::ProxyScheme
+            | CoapOption::Observe
+            | CoapOption::Oscore
+    )
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("OSCORE: {}", message))
+}
+
+impl SecurityContext {
+    pub fn load(path: &Path) -> Result<SecurityContext> {
+        let contents = std::fs::read_to_string(path)?;
+        let file: ContextFile = toml::from_str(&contents)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        let master_secret = match file.master_secret {
+            Some(secret) => secret,
+            None => std::env::var("COAP_OSCORE_MASTER_SECRET").map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "no master_secret in the security context, nor in COAP_OSCORE_MASTER_SECRET",
+                )
+            })?,
+        };
+        let master_secret = parse_hex(&master_secret)?;
+        let master_salt = parse_hex(&file.master_salt)?;
+        let sender_id = parse_hex(&file.sender_id)?;
+        let recipient_id = parse_hex(&file.recipient_id)?;
+        let id_context = file.id_context.as_deref().map(parse_hex).transpose()?;
+        // the nonce has room for IDs of up to 7 bytes
+        if sender_id.len() > NONCE_LENGTH - 6 || recipient_id.len() > NONCE_LENGTH - 6 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "OSCORE sender and recipient IDs can be at most 7 bytes",
+            ));
+        }
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&master_salt), &master_secret);
+        let id_context_ref = id_context.as_deref();
+        Ok(SecurityContext {
+            sender_key: derive(&hkdf, &sender_id, id_context_ref, "Key", KEY_LENGTH),
+            recipient_key: derive(&hkdf, &recipient_id, id_context_ref, "Key", KEY_LENGTH),
+            common_iv: derive(&hkdf, &[], id_context_ref, "IV", NONCE_LENGTH),
+            sender_id,
+            recipient_id,
+            id_context,
+            sequence_file: path.with_extension("seq"),
+            sequence_lock: Mutex::new(()),
+        })
+    }
+
+    /// Takes the next sender sequence number, recording that it's been used before it is.
+    fn next_sequence(&self) -> Result<u64> {
+        let _guard = self.sequence_lock.lock().unwrap();
+        let sequence = match std::fs::read_to_string(&self.sequence_file) {
+            Ok(contents) => contents.trim().parse().map_err(|_| {
+                invalid(&format!("corrupt sequence number in {}", self.sequence_file.display()))
+            })?,
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        // partial IVs are at most 5 bytes
+        if sequence >= 1 << 40 {
+            return Err(invalid("sequence numbers exhausted; a new security context is needed"));
+        }
+        std::fs::write(&self.sequence_file, (sequence + 1).to_string())?;
+        Ok(sequence)
+    }
+
+    fn nonce(&self, id: &[u8], piv: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_LENGTH];
+        nonce[0] = id.len() as u8;
+        nonce[NONCE_LENGTH - 5 - id.len()..NONCE_LENGTH - 5].copy_from_slice(id);
+        nonce[NONCE_LENGTH - piv.len()..].copy_from_slice(piv);
+        nonce.iter().zip(&self.common_iv).map(|(a, b)| a ^ b).collect()
+    }
+
+    fn aad(binding: &RequestBinding) -> Vec<u8> {
+        let external_aad = cbor_array(&[
+            cbor_uint(1),
+            cbor_array(&[cbor_uint(ALG_AEAD)]),
+            cbor_bytes(&binding.kid),
+            cbor_bytes(&binding.piv),
+            // no class I options are used
+            cbor_bytes(&[]),
+        ]);
+        cbor_array(&[cbor_text("Encrypt0"), cbor_bytes(&[]), cbor_bytes(&external_aad)])
+    }
+
+    /// Protects a request in place (RFC 8613 section 8.1): its code, protected options and
+    /// payload are encrypted into the payload of a POST, or a FETCH when observing.
+    pub fn protect_request(&self, request: &mut Packet) -> Result<RequestBinding> {
+        let sequence = self.next_sequence()?;
+        let binding = RequestBinding {
+            kid: self.sender_id.clone(),
+            piv: partial_iv(sequence),
+        };
+
+        let mut inner = Packet::new();
+        inner.header.code = request.header.code;
+        let mut outer = Packet::new();
+        outer.header = request.header.clone();
+        outer.set_token(request.get_token().to_vec());
+        for (number, values) in request.options() {
+            let option = CoapOption::from(*number);
+            for value in values {
+                if is_outer(option) {
+                    outer.add_option(option, value.clone());
+                }
+                if !is_outer(option) || option == CoapOption::Observe {
+                    inner.add_option(option, value.clone());
+                }
+            }
+        }
+        inner.payload = request.payload.clone();
+        let plaintext = Self::plaintext(&inner)?;
+
+        let nonce = self.nonce(&self.sender_id, &binding.piv);
+        let ciphertext = AesCcm::new(self.sender_key.as_slice().into())
+            .encrypt(
+                nonce.as_slice().into(),
+                Payload {
+                    msg: &plaintext,
+                    aad: &Self::aad(&binding),
+                },
+            )
+            .map_err(|_| invalid("encryption failed"))?;
+
+        // flags, partial IV, ID context and key ID (section 6.1)
+        let mut option = vec![binding.piv.len() as u8 | 0x08];
+        option.extend_from_slice(&binding.piv);
+        if let Some(id_context) = &self.id_context {
+            option[0] |= 0x10;
+            option.push(id_context.len() as u8);
+            option.extend_from_slice(id_context);
+        }
+        option.extend_from_slice(&self.sender_id);
+        outer.add_option(CoapOption::Oscore, option);
+        outer.header.code = match outer.get_first_option(CoapOption::Observe) {
+            Some(_) => MessageClass::Request(RequestType::Fetch),
+            None => MessageClass::Request(RequestType::Post),
+        };
+        outer.payload = ciphertext;
+        *request = outer;
+        Ok(binding)
+    }
+
+    /// Verifies and decrypts a response to a request protected with `binding`, in place. An
+    /// unprotected error response, as from a server that could not verify the request, is left
+    /// as it is, but any other unprotected response is rejected.
+    pub fn unprotect_response(&self, binding: &RequestBinding, response: &mut Packet) -> Result<()> {
+        let Some(option) = response.get_first_option(CoapOption::Oscore).cloned() else {
+            return match response.header.code {
+                MessageClass::Response(code) if code.is_error() => Ok(()),
+                _ => Err(invalid("server sent an unprotected response")),
+            };
+        };
+        // a response either has a partial IV of its own, like notifications, or reuses the
+        // request's nonce
+        let nonce = match option.first() {
+            Some(flags) if flags & 0x07 != 0 => {
+                let length = usize::from(flags & 0x07);
+                let piv = option.get(1..1 + length).ok_or(invalid("truncated option"))?;
+                if length > 5 {
+                    return Err(invalid("invalid partial IV"));
+                }
+                self.nonce(&self.recipient_id, piv)
+            }
+            _ => self.nonce(&binding.kid, &binding.piv),
+        };
+        let plaintext = AesCcm::new(self.recipient_key.as_slice().into())
+            .decrypt(
+                nonce.as_slice().into(),
+                Payload {
+                    msg: &response.payload,
+                    aad: &Self::aad(binding),
+                },
+            )
+            .map_err(|_| invalid("response could not be verified"))?;
+        let inner = Self::from_plaintext(&plaintext)?;
+
+        let outer: Vec<(CoapOption, Vec<u8>)> = response
+            .options()
+            .flat_map(|(number, values)| {
+                values.iter().map(move |value| (CoapOption::from(*number), value.clone()))
+            })
+            .filter(|(option, _)| *option == CoapOption::Observe)
+            .collect();
+        response.clear_all_options();
+        for (option, value) in outer {
+            response.add_option(option, value);
+        }
+        for (number, values) in inner.options() {
+            let option = CoapOption::from(*number);
+            if option == CoapOption::Observe {
+                continue;
+            }
+            for value in values {
+                response.add_option(option, value.clone());
+            }
+        }
+        response.header.code = inner.header.code;
+        response.payload = inner.payload;
+        Ok(())
+    }
+
+    // the code, options and payload of a message, encoded as in the message itself
+    fn plaintext(inner: &Packet) -> Result<Vec<u8>> {
+        let bytes = inner.to_bytes().map_err(|e| invalid(&e.to_string()))?;
+        let mut plaintext = vec![bytes[1]];
+        plaintext.extend_from_slice(&bytes[4..]);
+        Ok(plaintext)
+    }
+
+    fn from_plaintext(plaintext: &[u8]) -> Result<Packet> {
+        let code = *plaintext.first().ok_or(invalid("empty plaintext"))?;
+        let mut bytes = vec![0x40, code, 0, 0];
+        bytes.extend_from_slice(&plaintext[1..]);
+        let inner = Packet::from_bytes(&bytes).map_err(|e| invalid(&e.to_string()))?;
+        if !matches!(inner.header.code, MessageClass::Response(_)) {
+            return Err(invalid("protected message is not a response"));
+        }
+        Ok(inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_dump::hex;
+
+    /// A security context loaded for a test, whose sequence number file goes with it.
+    struct TestContext(SecurityContext);
+
+    impl std::ops::Deref for TestContext {
+        type Target = SecurityContext;
+
+        fn deref(&self) -> &SecurityContext {
+            &self.0
+        }
+    }
+
+    impl Drop for TestContext {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0.sequence_file);
+        }
+    }
+
+    /// Loads a security context written to a file of its own, with the sender sequence number at
+    /// `sequence`.
+    fn load(name: &str, context: &str, sequence: u64) -> TestContext {
+        let path = std::env::temp_dir()
+            .join(format!("coap-cli-oscore-{}-{}.toml", std::process::id(), name));
+        std::fs::write(&path, context).unwrap();
+        std::fs::write(path.with_extension("seq"), sequence.to_string()).unwrap();
+        let context = SecurityContext::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        TestContext(context.unwrap())
+    }
+
+    const CLIENT: &str = r#"
+        master_secret = "0102030405060708090a0b0c0d0e0f10"
+        master_salt = "9e7ca92223786340"
+        sender_id = ""
+        recipient_id = "01"
+    "#;
+
+    const SERVER: &str = r#"
+        master_secret = "0102030405060708090a0b0c0d0e0f10"
+        master_salt = "9e7ca92223786340"
+        sender_id = "01"
+        recipient_id = ""
+    "#;
+
+    /// The keys and nonces of RFC 8613 appendices C.1 to C.3, as sender key, recipient key,
+    /// common IV, sender nonce and recipient nonce.
+    #[test]
+    fn derives_rfc_8613_contexts() {
+        for (name, context, expected) in [
+            (
+                "c1-client",
+                CLIENT,
+                [
+                    "f0910ed7295e6ad4b54fc793154302ff",
+                    "ffb14e093c94c9cac9471648b4f98710",
+                    "4622d4dd6d944168eefb54987c",
+                    "4622d4dd6d944168eefb54987c",
+                    "4722d4dd6d944169eefb54987c",
+                ],
+            ),
+            (
+                "c1-server",
+                SERVER,
+                [
+                    "ffb14e093c94c9cac9471648b4f98710",
+                    "f0910ed7295e6ad4b54fc793154302ff",
+                    "4622d4dd6d944168eefb54987c",
+                    "4722d4dd6d944169eefb54987c",
+                    "4622d4dd6d944168eefb54987c",
+                ],
+            ),
+            (
+                "c2-client",
+                r#"
+                    master_secret = "0102030405060708090a0b0c0d0e0f10"
+                    sender_id = "00"
+                    recipient_id = "01"
+                "#,
+                [
+                    "321b26943253c7ffb6003b0b64d74041",
+                    "e57b5635815177cd679ab4bcec9d7dda",
+                    "be35ae297d2dace910c52e99f9",
+                    "bf35ae297d2dace910c52e99f9",
+                    "bf35ae297d2dace810c52e99f9",
+                ],
+            ),
+            (
+                "c3-client",
+                r#"
+                    master_secret = "0102030405060708090a0b0c0d0e0f10"
+                    master_salt = "9e7ca92223786340"
+                    sender_id = ""
+                    recipient_id = "01"
+                    id_context = "37cbf3210017a2d3"
+                "#,
+                [
+                    "af2a1300a5e95788b356336eeecd2b92",
+                    "e39a0c7c77b43f03b4b39ab9a268699f",
+                    "2ca58fb85ff1b81c0b7181b85e",
+                    "2ca58fb85ff1b81c0b7181b85e",
+                    "2da58fb85ff1b81d0b7181b85e",
+                ],
+            ),
+        ] {
+            let context = load(name, context, 0);
+            let derived = [
+                hex(&context.sender_key),
+                hex(&context.recipient_key),
+                hex(&context.common_iv),
+                hex(&context.nonce(&context.sender_id, &[0])),
+                hex(&context.nonce(&context.recipient_id, &[0])),
+            ];
+            assert_eq!(derived, expected, "{}", name);
+        }
+    }
+
+    /// The request of RFC 8613 appendix C.4, protected with sender sequence number 20.
+    #[test]
+    fn protects_rfc_8613_request() {
+        let context = load("c4", CLIENT, 20);
+        let mut request =
+            Packet::from_bytes(&parse_hex("44015d1f00003974396c6f63616c686f737483747631").unwrap())
+                .unwrap();
+        let binding = context.protect_request(&mut request).unwrap();
+        assert_eq!(hex(&binding.piv), "14");
+        assert_eq!(
+            hex(&SecurityContext::aad(&binding)),
+            "8368456e63727970743040488501810a40411440"
+        );
+        assert_eq!(
+            hex(&request.to_bytes().unwrap()),
+            "44025d1f00003974396c6f63616c686f7374620914ff612f1092f1776f1c1668b3825e"
+        );
+    }
+
+    /// The response of RFC 8613 appendix C.7, to the request of appendix C.4.
+    #[test]
+    fn unprotects_rfc_8613_response() {
+        let context = load("c7", CLIENT, 0);
+        let binding = RequestBinding {
+            kid: vec![],
+            piv: vec![0x14],
+        };
+        let protected = "64445d1f0000397490ffdbaad1e9a7e7b2a813d3c31524378303cdafae119106";
+        let mut response = Packet::from_bytes(&parse_hex(protected).unwrap()).unwrap();
+        context.unprotect_response(&binding, &mut response).unwrap();
+        assert_eq!(
+            hex(&response.to_bytes().unwrap()),
+            "64455d1f00003974ff48656c6c6f20576f726c6421"
+        );
+
+        let mut tampered = Packet::from_bytes(&parse_hex(protected).unwrap()).unwrap();
+        tampered.payload[0] ^= 1;
+        let e = context.unprotect_response(&binding, &mut tampered).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        let other = RequestBinding {
+            kid: vec![],
+            piv: vec![0x15],
+        };
+        let mut response = Packet::from_bytes(&parse_hex(protected).unwrap()).unwrap();
+        assert!(context.unprotect_response(&other, &mut response).is_err());
+    }
+
+    /// Protects a request as the client, opens it and answers it as the server would, and
+    /// unprotects the answer as the client.
+    #[test]
+    fn round_trips_request_and_response() {
+        let client = load("round-trip-client", CLIENT, 7);
+        let server = load("round-trip-server", SERVER, 0);
+        let mut request = Packet::new();
+        request.header.code = MessageClass::Request(RequestType::Put);
+        request.set_token(vec![1, 2, 3, 4]);
+        request.add_option(CoapOption::UriHost, b"example.com".to_vec());
+        request.add_option(CoapOption::UriPath, b"led".to_vec());
+        request.add_option(CoapOption::ContentFormat, vec![0]);
+        request.payload = b"on".to_vec();
+        let binding = client.protect_request(&mut request).unwrap();
+        assert_eq!(binding.piv, [7]);
+        assert_eq!(request.header.code, MessageClass::Request(RequestType::Post));
+        assert_eq!(request.get_first_option(CoapOption::UriHost).unwrap(), b"example.com");
+        assert!(request.get_first_option(CoapOption::UriPath).is_none());
+        assert!(request.get_first_option(CoapOption::ContentFormat).is_none());
+        assert_eq!(request.get_first_option(CoapOption::Oscore).unwrap(), &[0x09, 7]);
+        // the next request takes the next sequence number
+        assert_eq!(client.protect_request(&mut Packet::new()).unwrap().piv, [8]);
+
+        let nonce = server.nonce(&binding.kid, &binding.piv);
+        let aad = SecurityContext::aad(&binding);
+        let plaintext = AesCcm::new(server.recipient_key.as_slice().into())
+            .decrypt(
+                nonce.as_slice().into(),
+                Payload {
+                    msg: &request.payload,
+                    aad: &aad,
+                },
+            )
+            .unwrap();
+        assert_eq!(hex(&plaintext), "03b36c65641100ff6f6e");
+
+        let mut inner = Packet::new();
+        inner.header.code = MessageClass::Response(coap_lite::ResponseType::Changed);
+        inner.add_option(CoapOption::ContentFormat, vec![0]);
+        inner.payload = b"led on".to_vec();
+        let mut response = Packet::new();
+        response.header.code = MessageClass::Response(coap_lite::ResponseType::Changed);
+        response.set_token(vec![1, 2, 3, 4]);
+        response.add_option(CoapOption::Oscore, vec![]);
+        response.payload = AesCcm::new(server.sender_key.as_slice().into())
+            .encrypt(
+                nonce.as_slice().into(),
+                Payload {
+                    msg: &SecurityContext::plaintext(&inner).unwrap(),
+                    aad: &aad,
+                },
+            )
+            .unwrap();
+        client.unprotect_response(&binding, &mut response).unwrap();
+        assert_eq!(response.header.code, inner.header.code);
+        assert_eq!(response.get_first_option(CoapOption::ContentFormat).unwrap(), &[0]);
+        assert!(response.get_first_option(CoapOption::Oscore).is_none());
+        assert_eq!(response.payload, b"led on");
+    }
+
+    #[test]
+    fn leaves_unprotected_errors_alone() {
+        let context = load("unprotected", CLIENT, 0);
+        let binding = RequestBinding {
+            kid: vec![],
+            piv: vec![0],
+        };
+        let mut error = Packet::new();
+        error.header.code = MessageClass::Response(coap_lite::ResponseType::Unauthorized);
+        error.payload = b"Security context not found".to_vec();
+        context.unprotect_response(&binding, &mut error).unwrap();
+        assert_eq!(error.payload, b"Security context not found");
+        let mut content = Packet::new();
+        content.header.code = MessageClass::Response(coap_lite::ResponseType::Content);
+        assert!(context.unprotect_response(&binding, &mut content).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use coap_lite::{CoapOption, MessageClass, Packet};
+use serde_json::Value;
+use std::io::{Result, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::coap_helper::*;
+use crate::message_dump::{self, decode_uint, hex};
+use crate::report::{self, OutputFormat};
+use crate::{content, senml, Args, ERROR_RESPONSE_CLASS};
+
+/// How long the exchange whose response is printed next took, in microseconds.
+static TIME_TOTAL: AtomicU64 = AtomicU64::new(0);
+
+/// The variables `--write-out` can use.
+const VARIABLES: [&str; 7] = [
+    "code",
+    "code_name",
+    "content_format",
+    "etag",
+    "location",
+    "size",
+    "time_total",
+];
+
+/// Checks a `--write-out` format, e.g. `%{code} %{size} %{time_total}\n`, for variables it
+/// doesn't know.
+pub fn parse_write_out(s: &str) -> std::result::Result<String, String> {
+    let mut rest = s;
+    while let Some(start) = rest.find("%{") {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unterminated variable in {}", s))?;
+        let name = &rest[start + 2..start + end];
+        if !VARIABLES.contains(&name) {
+            return Err(format!(
+                "unknown variable %{{{}}}; expected one of {}",
+                name,
+                VARIABLES.join(", ")
+            ));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(s.to_string())
+}
+
+/// Notes how long the exchange whose response is printed next took, for `%{time_total}`.
+pub fn set_time_total(time: Duration) {
+    TIME_TOTAL.store(time.as_micros() as u64, Ordering::Relaxed);
+}
+
+fn is_error(message: &Packet) -> bool {
+    matches!(message.header.code, MessageClass::Response(code) if code.is_error())
+}
+
+/// The value of a `--write-out` variable for a response.
+fn variable(name: &str, message: &Packet) -> String {
+    match name {
+        "code" => message.header.code.to_string(),
+        "code_name" => match message.header.code {
+            MessageClass::Response(code) => response_code_info(code).0.to_string(),
+            _ => String::new(),
+        },
+        "content_format" => message
+            .get_first_option(CoapOption::ContentFormat)
+            .map(|cf| content_format_name(decode_uint(cf) as usize))
+            .unwrap_or_default(),
+        "etag" => message.get_first_option(CoapOption::ETag).map(|etag| hex(etag)).unwrap_or_default(),
+        "location" => response_location(message).unwrap_or_default(),
+        "size" => message.payload.len().to_string(),
+        "time_total" => {
+            format!("{:.6}", TIME_TOTAL.load(Ordering::Relaxed) as f64 / 1_000_000.0)
+        }
+        _ => String::new(),
+    }
+}
+
+/// Prints `--write-out`'s format for a response on stdout, with its variables filled in and `\n`
+/// and `\t` standing for a newline and a tab, as curl does.
+pub fn print_write_out(message: &Packet, args: &Args) -> Result<()> {
+    let Some(format) = &args.write_out else {
+        return Ok(());
+    };
+    let mut out = String::new();
+    let mut rest = format.as_str();
+    while let Some(start) = rest.find(['%', '\\']) {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("%{")
+            && let Some((name, after)) = after.split_once('}')
+        {
+            out.push_str(&variable(name, message));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("\\n") {
+            out.push('\n');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("\\t") {
+            out.push('\t');
+            rest = after;
+        } else {
+            out.push_str(&rest[..1]);
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(out.as_bytes())?;
+    stdout.flush()
+}
+
+/// Prints the response code: on stderr, or with `--include` on stdout with its name, as the
+/// first line of the head of the response; not at all with `--quiet`.
+pub fn print_code(message: &Packet, args: &Args) {
+    if args.quiet {
+        return;
+    }
+    if !args.include {
+        eprintln!("{}", message.header.code);
+        return;
+    }
+    match message.header.code {
+        MessageClass::Response(code) => {
+            println!("{} {}", message.header.code, response_code_info(code).0)
+        }
+        code => println!("{}", code),
+    }
+}
+
+/// Prints the status of a response ahead of its payload: the code and the options worth knowing
+/// (the ETag, where a created resource is, the sizes given, and every option with
+/// `--show-options`) on stderr. With `--include`, the code and every option are printed on stdout
+/// instead, followed by a blank line, as curl's `-i` does; with `--quiet`, nothing is.
+pub fn print_status(message: &Packet, args: &Args) {
+    if args.quiet {
+        return;
+    }
+    print_code(message, args);
+    if args.include {
+        for (name, value) in message_dump::describe_options(message) {
+            println!("{}: {}", name, value);
+        }
+        println!();
+        return;
+    }
+    print_etag(message);
+    print_location(message);
+    print_sizes(message);
+    if args.show_options {
+        print_options(message);
+    }
+}
+
+/// Prints the response code on stderr and the payload on stdout, or into `output_file`. Error
+/// responses are explained, and their payload is shown on stderr as the diagnostic message it is,
+/// rather than as content. With `decode`, a payload printed as text is rendered according to its
+/// Content-Format. With `--output json`, the whole response is instead printed on stdout as a
+/// single line of JSON. `--quiet`, `--include` and `--write-out` are as [`print_status`] and
+/// [`print_write_out`] say.
+pub fn print_response(message: &Packet, args: &Args, output_file: Option<&Path>) -> Result<()> {
+    print_message(message, args, output_file)?;
+    print_write_out(message, args)
+}
+
+fn print_message(message: &Packet, args: &Args, output_file: Option<&Path>) -> Result<()> {
+    let output = args.output;
+    if let Some(path) = output_file
+        && !is_error(message)
+    {
+        std::fs::write(path, &message.payload)?;
+    }
+    if output == OutputFormat::Json {
+        let records = (args.rendering() == content::Rendering::Senml)
+            .then(|| senml::message_records(message))
+            .flatten();
+        match records {
+            Some(records) => println!("{}", Value::from(records)),
+            None => println!("{}", report::message_json(message)),
+        }
+        return Ok(());
+    }
+    if is_error(message) {
+        ERROR_RESPONSE_CLASS.fetch_max(u8::from(message.header.code) >> 5, Ordering::Relaxed);
+    }
+    if let MessageClass::Response(code) = message.header.code
+        && code.is_error()
+        && !args.include
+    {
+        let (name, description) = response_code_info(code);
+        eprintln!("{} {}: {}", message.header.code, name, description);
+        // e.g. the largest request a 4.13 Request Entity Too Large says the server would accept
+        print_sizes(message);
+        if args.show_options {
+            print_options(message);
+        }
+        if !message.payload.is_empty() {
+            eprintln!("diagnostic: {}", String::from_utf8_lossy(&message.payload));
+        }
+        return Ok(());
+    }
+    print_status(message, args);
+    match (output_file, output) {
+        (Some(_), _) => {}
+        (None, OutputFormat::Raw) => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&message.payload)?;
+            stdout.flush()?;
+        }
+        (None, _) if args.rendering() != content::Rendering::Verbatim => {
+            println!("{}", content::render_payload(message, args.rendering()));
+        }
+        _ => println!("{}", String::from_utf8_lossy(&message.payload)),
+    }
+    Ok(())
+}
+
+fn print_etag(message: &Packet) {
+    if let Some(etag) = message.get_first_option(CoapOption::ETag) {
+        eprintln!("etag: {}", hex(etag));
+    }
+}
+
+/// Prints where a response says the resource it created is, from its Location-Path and
+/// Location-Query options.
+fn print_location(message: &Packet) {
+    if let Some(location) = response_location(message) {
+        eprintln!("location: {}", location);
+    }
+}
+
+/// Prints every option of a message, as asked for by `--show-options`.
+fn print_options(message: &Packet) {
+    for (name, value) in message_dump::describe_options(message) {
+        eprintln!("{}: {}", name, value);
+    }
+}
+
+/// Prints the sizes a response gives: the size of the whole representation (Size2), or of the
+/// largest request the server can take (Size1).
+fn print_sizes(message: &Packet) {
+    for (option, name) in [(CoapOption::Size1, "size1"), (CoapOption::Size2, "size2")] {
+        if let Some(size) = message.get_first_option(option) {
+            eprintln!("{}: {} bytes", name, decode_uint(size));
+        }
+    }
+}
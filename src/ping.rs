@@ -0,0 +1,82 @@
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+use crate::transport::Connection;
+use crate::Args;
+
+/// Round-trip times of the pings that were answered, for the summary.
+#[derive(Default)]
+struct Statistics {
+    sent: usize,
+    rtts: Vec<Duration>,
+}
+
+impl Statistics {
+    fn print(&self, url: &str) {
+        println!("--- {} ping statistics ---", url);
+        let lost = self.sent - self.rtts.len();
+        println!(
+            "{} pings sent, {} resets received, {}% lost",
+            self.sent,
+            self.rtts.len(),
+            lost * 100 / self.sent.max(1)
+        );
+        if self.rtts.is_empty() {
+            return;
+        }
+        let millis = |d: &Duration| d.as_secs_f64() * 1000.0;
+        let min = self.rtts.iter().map(millis).fold(f64::MAX, f64::min);
+        let max = self.rtts.iter().map(millis).fold(0.0, f64::max);
+        let avg = self.rtts.iter().map(millis).sum::<f64>() / self.rtts.len() as f64;
+        println!("rtt min/avg/max = {:.3}/{:.3}/{:.3} ms", min, avg, max);
+    }
+}
+
+/// Sends a CoAP ping every `interval` and reports how long each reset took to come back, like
+/// ICMP ping. Stops after `count` pings, or when interrupted, and fails if no reset came back.
+pub async fn coap_ping(
+    conn: &Connection,
+    args: &Args,
+    count: Option<usize>,
+    interval: Duration,
+) -> Result<()> {
+    println!("PING {} ({})", args.url, conn.peer_addr);
+    let timeout = Duration::from_secs(args.timeout);
+    let mut statistics = Statistics::default();
+    let interrupted = args.cancel.cancelled();
+    tokio::pin!(interrupted);
+    let mut next = tokio::time::Instant::now();
+    while count.is_none_or(|count| statistics.sent < count) {
+        tokio::select! {
+            _ = &mut interrupted => break,
+            _ = tokio::time::sleep_until(next) => {}
+        }
+        next += interval;
+        let sent_at = std::time::Instant::now();
+        let message_id = conn.ping().await?;
+        statistics.sent += 1;
+        let reply = tokio::select! {
+            _ = &mut interrupted => break,
+            reply = conn.log.wait_for_reply(message_id, timeout) => reply,
+        };
+        match reply {
+            Some(at) => {
+                let rtt = at.duration_since(sent_at);
+                println!(
+                    "reset from {}: mid={:#06x} time={:.3} ms",
+                    conn.peer_addr,
+                    message_id,
+                    rtt.as_secs_f64() * 1000.0
+                );
+                statistics.rtts.push(rtt);
+            }
+            None => println!("no reset for mid={:#06x} within {}s", message_id, args.timeout),
+        }
+    }
+    statistics.print(&args.url);
+
+    if statistics.rtts.is_empty() {
+        return Err(Error::new(ErrorKind::TimedOut, "no resets received"));
+    }
+    Ok(())
+}
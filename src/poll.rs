@@ -0,0 +1,109 @@
+use coap_lite::{CoapOption, MessageClass, Packet, RequestType, ResponseType};
+use log::{info, warn};
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+use crate::coap_helper::*;
+use crate::output::{self, print_response};
+use crate::timestamp::{Clock, TimestampFormat};
+use crate::transport::Connection;
+use crate::{diff, send_request, Args};
+
+/// How `get --repeat` and `get --watch` poll a resource.
+pub struct PollOptions<'a> {
+    pub accept: &'a [String],
+    /// How many times to poll it, or until interrupted if not given
+    pub repeat: Option<usize>,
+    pub interval: Duration,
+    pub timestamp: TimestampFormat,
+}
+
+fn is_error(message: &Packet) -> bool {
+    matches!(message.header.code, MessageClass::Response(code) if code.is_error())
+}
+
+/// GETs the resource every `interval` over the one connection, for servers that don't support
+/// Observe, printing each response with a timestamp. Once a representation has been received,
+/// each request carries its ETag, if it had one, so that the server can answer 2.03 Valid
+/// instead of sending it again; when it does send another, how it differs from the last is
+/// printed under it, as `diff` would. A request that fails is reported and polling goes on, but
+/// the run fails if none succeeded.
+pub async fn coap_poll(conn: &Connection, args: &Args, options: &PollOptions<'_>) -> Result<()> {
+    info!("GET {} every {}ms", args.url, options.interval.as_millis());
+    let accept_cf: Vec<_> = options
+        .accept
+        .iter()
+        .map(|a| parse_content_format(a))
+        .collect::<Result<_>>()?;
+    let clock = Clock::new(options.timestamp);
+    let interrupted = args.cancel.cancelled();
+    tokio::pin!(interrupted);
+    let mut next = tokio::time::Instant::now();
+    let mut last: Option<Packet> = None;
+    let (mut polls, mut answered, mut changes) = (0, 0, 0);
+    while options.repeat.is_none_or(|repeat| polls < repeat) {
+        tokio::select! {
+            _ = &mut interrupted => break,
+            _ = tokio::time::sleep_until(next) => {}
+        }
+        next += options.interval;
+        let mut request = build_coap_request_for_url(
+            &args.url,
+            RequestType::Get,
+            None,
+            None,
+            Some(accept_cf.clone()),
+        )?;
+        if let Some(etag) = last.as_ref().and_then(|last| last.get_first_option(CoapOption::ETag)) {
+            request.message.add_option(CoapOption::ETag, etag.clone());
+        }
+        polls += 1;
+        let result = tokio::select! {
+            _ = &mut interrupted => break,
+            result = send_request(conn, args, request) => result,
+        };
+        let response = match result {
+            Ok(Some(response)) => response,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::Interrupted => break,
+            Err(e) => {
+                warn!("{}: {}", clock.stamp(), e);
+                continue;
+            }
+        };
+        answered += 1;
+        let valid = *response.get_status() == ResponseType::Valid;
+        let message = response.message;
+        if !args.quiet {
+            eprint!("{}: ", clock.stamp());
+        }
+        if valid && last.is_some() {
+            if !args.quiet {
+                eprintln!("{} unchanged", message.header.code);
+            }
+            output::print_write_out(&message, args)?;
+            continue;
+        }
+        print_response(&message, args, args.output_file.as_deref())?;
+        if is_error(&message) {
+            continue;
+        }
+        if let Some(last) = &last {
+            let differences = diff::differences(last, &message);
+            if !differences.is_empty() {
+                changes += 1;
+            }
+            if !args.quiet {
+                for change in &differences {
+                    eprintln!("  {}", diff::describe_change(change));
+                }
+            }
+        }
+        last = Some(message);
+    }
+    info!("{} poll(s), {} answered, {} change(s)", polls, answered, changes);
+    if polls > 0 && answered == 0 {
+        return Err(Error::other("no poll was answered"));
+    }
+    Ok(())
+}
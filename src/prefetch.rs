@@ -0,0 +1,57 @@
+use coap_lite::RequestType;
+use log::{info, warn};
+use std::io::{Error, Result};
+use std::path::Path;
+
+use crate::coap_helper::*;
+use crate::link_format::parse_links;
+use crate::mirror::{discover_resources, resource_url};
+use crate::proxy::{RecordStore, Recording};
+use crate::transport::Connection;
+use crate::{send_request, Args};
+
+/// Fetches a set of resources and records the responses in a store, so that a proxy using the
+/// store can answer requests for them straight away, even offline.
+pub async fn coap_prefetch(
+    conn: &Connection,
+    args: &Args,
+    links: Option<&Path>,
+    store: &Path,
+) -> Result<()> {
+    let store = RecordStore::new(store.to_path_buf())?;
+    let mut targets = vec!["/.well-known/core".to_string()];
+    let links = match links {
+        Some(file) => parse_links(&std::fs::read_to_string(file)?)?,
+        None => discover_resources(conn, args).await?,
+    };
+    targets.extend(links.into_iter().map(|l| l.target));
+
+    let mut fetched = 0;
+    for target in &targets {
+        let url = resource_url(&args.url, target)?;
+        let request = build_coap_request_for_url(&url, RequestType::Get, None, None, None)?;
+        let message = request.message.clone();
+        match send_request(conn, args, request).await {
+            Ok(Some(response)) => {
+                info!(
+                    "GET {} -> {} ({} bytes)",
+                    url,
+                    response.message.header.code,
+                    response.message.payload.len()
+                );
+                store.save(&message, &Recording::from_response(&message, &response.message))?;
+                fetched += 1;
+            }
+            Ok(None) => info!("GET {}", url),
+            Err(e) => warn!("GET {} failed: {}", url, e),
+        }
+    }
+    if args.dry_run {
+        return Ok(());
+    }
+    info!("prefetched {} of {} resource(s)", fetched, targets.len());
+    if fetched < targets.len() {
+        return Err(Error::other("some resources could not be prefetched"));
+    }
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use url::Url;
+
+use crate::coap_helper::parse_custom_option;
+use crate::{Args, Commands};
+
+/// Named sets of defaults, read from `config.toml` in the coap-cli configuration directory.
+///
+/// ```toml
+/// [profile.lab]
+/// url = "coaps://lab-gw.local/"
+/// timeout = 5
+/// accept = ["application/json"]
+/// psk_identity = "lab-client"
+/// psk_key = "0x000102030405060708090a0b0c0d0e0f"
+/// options = ["65000:str:lab"]
+/// ```
+///
+/// With a base URL, the URL given on the command line may be relative to it, e.g. `sensors/temp`.
+#[derive(Debug, Deserialize)]
+struct Config {
+    #[serde(default)]
+    profile: HashMap<String, Profile>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Profile {
+    url: Option<String>,
+    timeout: Option<u64>,
+    #[serde(default)]
+    accept: Vec<String>,
+    psk_identity: Option<String>,
+    psk_key: Option<String>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    ca_cert: Option<PathBuf>,
+    oscore_context: Option<PathBuf>,
+    #[serde(default)]
+    options: Vec<String>,
+}
+
+/// The configuration file: `coap-cli/config.toml` under `$XDG_CONFIG_HOME`, `~/.config` or, on
+/// Windows, `%APPDATA%`.
+fn config_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(dir.join("coap-cli").join("config.toml"))
+}
+
+fn load_profile(name: &str) -> Result<Profile> {
+    let path = config_path().ok_or(Error::new(
+        ErrorKind::NotFound,
+        "could not find the configuration directory",
+    ))?;
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    let mut config: Config = toml::from_str(&contents)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+    config.profile.remove(name).ok_or(Error::new(
+        ErrorKind::InvalidInput,
+        format!("no profile named {} in {}", name, path.display()),
+    ))
+}
+
+/// Fills in whatever the command line leaves unsaid from the profile chosen by `--profile`.
+/// Anything given on the command line, or by environment variable, takes precedence.
+pub fn apply_profile(args: &mut Args, matches: &ArgMatches) -> Result<()> {
+    let Some(name) = &args.profile else {
+        return Ok(());
+    };
+    let profile = load_profile(name)?;
+    let given = |id: &str| {
+        matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    };
+
+    if let Some(base) = &profile.url
+        && !args.url.contains("://")
+    {
+        let base = Url::parse(base)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("profile url: {}", e)))?;
+        args.url = base
+            .join(&args.url)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?
+            .to_string();
+    }
+    if let Some(timeout) = profile.timeout
+        && !given("timeout")
+    {
+        args.timeout = timeout;
+    }
+    // the credentials go together, so the profile's are only used if none are given
+    let credentials = ["psk_identity", "psk_key", "cert", "key", "ca_cert"];
+    if !credentials.iter().any(|id| given(id)) {
+        args.psk_identity = profile.psk_identity;
+        args.psk_key = profile.psk_key;
+        args.cert = profile.cert;
+        args.key = profile.key;
+        args.ca_cert = profile.ca_cert;
+    }
+    if args.oscore_context.is_none() {
+        args.oscore_context = profile.oscore_context;
+    }
+    if args.options.is_empty() {
+        args.options = profile
+            .options
+            .iter()
+            .map(|option| parse_custom_option(option))
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("profile options: {}", e)))?;
+    }
+    match &mut args.command {
+        Commands::Get { accept, .. }
+        | Commands::Observe { accept, .. }
+        | Commands::Post { accept, .. }
+        | Commands::Put { accept, .. }
+        | Commands::Fetch { accept, .. }
+        | Commands::Patch { accept, .. }
+        | Commands::Ipatch { accept, .. }
+        | Commands::Delete { accept, .. }
+            if accept.is_empty() =>
+        {
+            *accept = profile.accept;
+        }
+        _ => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,233 @@
+use async_trait::async_trait;
+use coap_lite::{CoapOption, CoapRequest, MessageClass, MessageType, Packet, ResponseType};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::coap_helper::*;
+use crate::message_dump::hex;
+use crate::server::{set_response, RequestHandler, Server};
+use crate::transport::Connection;
+use crate::{create_connection_to, exchange, Args};
+
+// options which describe a single block or notification rather than the resource, and so are not
+// worth recording
+const TRANSIENT_OPTIONS: [CoapOption; 5] = [
+    CoapOption::Block1,
+    CoapOption::Block2,
+    CoapOption::Size1,
+    CoapOption::Size2,
+    CoapOption::Observe,
+];
+
+// options which identify what is being asked for, and so distinguish one recording from another
+const KEY_OPTIONS: [CoapOption; 4] = [
+    CoapOption::UriPath,
+    CoapOption::UriQuery,
+    CoapOption::Accept,
+    CoapOption::ContentFormat,
+];
+
+/// An upstream response, as saved by the proxy.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Recording {
+    /// The request that produced the response, for the benefit of anyone reading the file
+    pub request: String,
+    pub code: String,
+    /// Option numbers and hex-encoded values
+    pub options: Vec<(u16, String)>,
+    /// Hex-encoded payload
+    pub payload: String,
+}
+
+impl Recording {
+    pub fn from_response(request: &Packet, response: &Packet) -> Recording {
+        let options = response
+            .options()
+            .filter(|(number, _)| !TRANSIENT_OPTIONS.contains(&CoapOption::from(**number)))
+            .flat_map(|(number, values)| values.iter().map(|v| (*number, hex(v))))
+            .collect();
+        Recording {
+            request: describe_request(request),
+            code: response.header.code.to_string(),
+            options,
+            payload: hex(&response.payload),
+        }
+    }
+
+    /// Fills in a response message from the recording.
+    pub fn apply_to(&self, response: &mut Packet) -> Result<()> {
+        response.header.code = MessageClass::Response(parse_response_code(&self.code)?);
+        for (number, value) in &self.options {
+            response.add_option(CoapOption::from(*number), parse_hex(value)?);
+        }
+        response.payload = parse_hex(&self.payload)?;
+        Ok(())
+    }
+}
+
+fn describe_request(request: &Packet) -> String {
+    let path = request
+        .get_option(CoapOption::UriPath)
+        .map(|segments| {
+            segments
+                .iter()
+                .map(|s| String::from_utf8_lossy(s).into_owned())
+                .collect::<Vec<_>>()
+                .join("/")
+        })
+        .unwrap_or_default();
+    let query = request
+        .get_option(CoapOption::UriQuery)
+        .map(|params| {
+            let params: Vec<_> = params.iter().map(|p| String::from_utf8_lossy(p).into_owned()).collect();
+            format!("?{}", params.join("&"))
+        })
+        .unwrap_or_default();
+    let method = match request.header.code {
+        MessageClass::Request(method) => format!("{:?}", method).to_uppercase(),
+        code => code.to_string(),
+    };
+    format!("{} /{}{}", method, path, query)
+}
+
+/// Saves upstream responses in a directory, one file per distinct request.
+pub struct RecordStore {
+    dir: PathBuf,
+}
+
+impl RecordStore {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(RecordStore { dir })
+    }
+
+    fn path_for(&self, request: &Packet) -> PathBuf {
+        let mut key = request.header.code.to_string().into_bytes();
+        for option in KEY_OPTIONS {
+            for value in request.get_option(option).into_iter().flatten() {
+                key.extend(format!("\n{}:{}", u16::from(option), hex(value)).bytes());
+            }
+        }
+        key.extend(format!("\n{}", hex(&request.payload)).bytes());
+        self.dir.join(format!("{:016x}.toml", fnv1a(&key)))
+    }
+
+    pub fn load(&self, request: &Packet) -> Result<Option<Recording>> {
+        match std::fs::read_to_string(self.path_for(request)) {
+            Ok(contents) => toml::from_str(&contents)
+                .map(Some)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, request: &Packet, recording: &Recording) -> Result<()> {
+        let contents = toml::to_string(recording).map_err(Error::other)?;
+        std::fs::write(self.path_for(request), contents)
+    }
+}
+
+struct ProxyHandler {
+    args: Args,
+    store: RecordStore,
+    // absent when running offline
+    upstream: Option<Connection>,
+}
+
+impl ProxyHandler {
+    async fn forward(&self, upstream: &Connection, request: &Packet) -> Result<Recording> {
+        let mut forwarded = CoapRequest::new();
+        forwarded.message.header.set_type(MessageType::Confirmable);
+        forwarded.message.header.code = request.header.code;
+        forwarded.message.set_token(request.get_token().to_vec());
+        for (number, values) in request.options() {
+            let option = CoapOption::from(*number);
+            if TRANSIENT_OPTIONS.contains(&option) {
+                continue;
+            }
+            for value in values {
+                forwarded.message.add_option(option, value.clone());
+            }
+        }
+        forwarded.message.payload = request.payload.clone();
+        let response = exchange(upstream, &self.args, forwarded).await?;
+        Ok(Recording::from_response(request, &response.message))
+    }
+}
+
+#[async_trait]
+impl RequestHandler for ProxyHandler {
+    async fn handle(&self, request: &mut CoapRequest<SocketAddr>) {
+        let description = describe_request(&request.message);
+        let recorded = match self.store.load(&request.message) {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                warn!("{}: could not load recording: {}", description, e);
+                None
+            }
+        };
+        let recording = match (recorded, &self.upstream) {
+            (Some(recording), _) => {
+                info!("{}: replayed", description);
+                recording
+            }
+            (None, Some(upstream)) => match self.forward(upstream, &request.message).await {
+                Ok(recording) => {
+                    info!("{}: recorded", description);
+                    if let Err(e) = self.store.save(&request.message, &recording) {
+                        warn!("{}: could not save recording: {}", description, e);
+                    }
+                    recording
+                }
+                Err(e) => {
+                    warn!("{}: upstream failed: {}", description, e);
+                    set_response(request, ResponseType::GatewayTimeout, vec![]);
+                    return;
+                }
+            },
+            (None, None) => {
+                info!("{}: not recorded", description);
+                set_response(request, ResponseType::GatewayTimeout, b"not recorded".to_vec());
+                return;
+            }
+        };
+        if let Some(response) = request.response.as_mut()
+            && let Err(e) = recording.apply_to(&mut response.message)
+        {
+            warn!("{}: invalid recording: {}", description, e);
+            set_response(request, ResponseType::InternalServerError, vec![]);
+        }
+    }
+}
+
+pub async fn coap_proxy(
+    args: &Args,
+    upstream: Option<&str>,
+    store: &Path,
+    offline: bool,
+) -> Result<()> {
+    let upstream = match (upstream, offline) {
+        (_, true) => None,
+        (Some(url), false) => Some(create_connection_to(args, url).await?),
+        (None, false) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "must specify an upstream server unless running offline",
+            ));
+        }
+    };
+    let CoapUri { host, port, .. } = CoapUri::parse(&args.url)?;
+    let handler = Arc::new(ProxyHandler {
+        args: args.clone(),
+        store: RecordStore::new(store.to_path_buf())?,
+        upstream,
+    });
+    let server = Server::bind((&host, port.unwrap_or(5683))).await?;
+    info!("listening on {}", server.local_addr()?);
+    server.run(handler).await
+}
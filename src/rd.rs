@@ -0,0 +1,258 @@
+use clap::{Subcommand, ValueEnum};
+use coap_lite::{CoapOption, ContentFormat, RequestType};
+use log::info;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+use crate::coap_helper::*;
+use crate::link_format::parse_links;
+use crate::mirror::resource_url;
+use crate::output::{self, print_response};
+use crate::transport::Connection;
+use crate::{send_request, Args};
+
+/// Interactions with a CoRE Resource Directory (RFC 9176) at the URL's server.
+#[derive(Debug, Clone, Subcommand)]
+pub enum RdCommand {
+    /// Registers an endpoint and its resources, printing the location of the registration
+    Register {
+        /// Endpoint name
+        #[arg(long)]
+        ep: String,
+        /// Sector the endpoint belongs to
+        #[arg(long)]
+        sector: Option<String>,
+        /// Seconds until the registration expires unless updated
+        #[arg(long)]
+        lifetime: Option<u32>,
+        /// Base URI the links are relative to, when not the address the registration comes from
+        #[arg(long)]
+        base: Option<String>,
+        /// Resource to register, as a path or as a link with attributes, e.g.
+        /// '</sensors/temp>;rt="temperature-c"' (may be repeated)
+        #[arg(long = "link")]
+        links: Vec<String>,
+        /// File holding the links to register, in link format
+        #[arg(long, conflicts_with = "links")]
+        links_file: Option<PathBuf>,
+    },
+
+    /// Renews a registration, optionally changing its lifetime or base URI
+    Update {
+        /// Location of the registration, as printed by register, e.g. /rd/4521
+        location: String,
+        /// Seconds until the registration expires unless updated again
+        #[arg(long)]
+        lifetime: Option<u32>,
+        /// Base URI the links are relative to
+        #[arg(long)]
+        base: Option<String>,
+    },
+
+    /// Removes a registration
+    Remove {
+        /// Location of the registration, as printed by register, e.g. /rd/4521
+        location: String,
+    },
+
+    /// Looks up registered endpoints or resources
+    Lookup {
+        /// What to look up
+        #[arg(value_enum, default_value_t = LookupType::Res)]
+        kind: LookupType,
+        /// Only list those with this attribute value, as NAME=VALUE, e.g. rt=temperature-c or
+        /// ep=node1, or with a prefix of it if it ends in * (may be repeated)
+        #[arg(long = "filter")]
+        filters: Vec<String>,
+        /// Page of results to list, counting from 0, with --count per page
+        #[arg(long, requires = "count")]
+        page: Option<u32>,
+        /// Maximum number of results to list
+        #[arg(long)]
+        count: Option<u32>,
+    },
+}
+
+/// The lookup interfaces of a resource directory.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum LookupType {
+    /// Registered endpoints
+    Ep,
+    /// Resources of the registered endpoints
+    Res,
+}
+
+/// The interfaces a resource directory advertises, with the paths RFC 9176 uses in its examples
+/// for those it doesn't.
+const INTERFACES: [(&str, &str); 3] = [
+    ("core.rd", "/rd"),
+    ("core.rd-lookup-ep", "/rd-lookup/ep"),
+    ("core.rd-lookup-res", "/rd-lookup/res"),
+];
+
+/// Finds the URL of one of the directory's interfaces in its `/.well-known/core`.
+async fn discover_interface(conn: &Connection, args: &Args, rt: &str) -> Result<String> {
+    let url = format!(
+        "{}?rt={}",
+        resource_url(&args.url, "/.well-known/core")?,
+        rt
+    );
+    info!("GET {}", url);
+    let request = build_coap_request_for_url(&url, RequestType::Get, None, None, None)?;
+    let fallback = INTERFACES
+        .iter()
+        .find(|(name, _)| *name == rt)
+        .map_or("/", |(_, path)| path);
+    let target = match send_request(conn, args, request).await? {
+        Some(response) if !response.get_status().is_error() => {
+            parse_links(&String::from_utf8_lossy(&response.message.payload))?
+                .into_iter()
+                .find(|link| {
+                    link.attr("rt")
+                        .is_some_and(|value| value.split_whitespace().any(|v| v == rt))
+                })
+                .map(|link| link.target)
+        }
+        _ => None,
+    };
+    let target = target.unwrap_or_else(|| {
+        info!("no {} resource advertised; trying {}", rt, fallback);
+        fallback.to_string()
+    });
+    resource_url(&args.url, &target)
+}
+
+fn with_query(url: &str, params: &[(&str, String)]) -> String {
+    if params.is_empty() {
+        return url.to_string();
+    }
+    let query: Vec<String> = params
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", url, separator, query.join("&"))
+}
+
+/// Writes links given as bare paths in link format, leaving those already in it as they are.
+fn registration_payload(links: &[String]) -> String {
+    links
+        .iter()
+        .map(|link| {
+            if link.starts_with('<') {
+                link.clone()
+            } else {
+                format!("<{}>", link)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub async fn coap_rd(conn: &Connection, args: &Args, command: &RdCommand) -> Result<()> {
+    match command {
+        RdCommand::Register {
+            ep,
+            sector,
+            lifetime,
+            base,
+            links,
+            links_file,
+        } => {
+            let payload = match links_file {
+                Some(path) => std::fs::read(path)?,
+                None => registration_payload(links).into_bytes(),
+            };
+            let mut params = vec![("ep", ep.clone())];
+            params.extend(sector.clone().map(|d| ("d", d)));
+            params.extend(lifetime.map(|lt| ("lt", lt.to_string())));
+            params.extend(base.clone().map(|base| ("base", base)));
+            let url = with_query(&discover_interface(conn, args, "core.rd").await?, &params);
+            info!("POST {}", url);
+            let request = build_coap_request_for_url(
+                &url,
+                RequestType::Post,
+                Some(payload),
+                Some(ContentFormat::ApplicationLinkFormat),
+                None,
+            )?;
+            let Some(response) = send_request(conn, args, request).await? else {
+                return Ok(());
+            };
+            let location: Vec<String> = response
+                .message
+                .get_option(CoapOption::LocationPath)
+                .into_iter()
+                .flatten()
+                .map(|segment| String::from_utf8_lossy(segment).into_owned())
+                .collect();
+            if location.is_empty() {
+                return print_response(&response.message, args, args.output_file.as_deref());
+            }
+            output::print_code(&response.message, args);
+            println!("/{}", location.join("/"));
+            Ok(())
+        }
+        RdCommand::Update {
+            location,
+            lifetime,
+            base,
+        } => {
+            let mut params = vec![];
+            params.extend(lifetime.map(|lt| ("lt", lt.to_string())));
+            params.extend(base.clone().map(|base| ("base", base)));
+            let url = with_query(&resource_url(&args.url, location)?, &params);
+            info!("POST {}", url);
+            let request = build_coap_request_for_url(&url, RequestType::Post, None, None, None)?;
+            let Some(response) = send_request(conn, args, request).await? else {
+                return Ok(());
+            };
+            print_response(&response.message, args, args.output_file.as_deref())
+        }
+        RdCommand::Remove { location } => {
+            let url = resource_url(&args.url, location)?;
+            info!("DELETE {}", url);
+            let request = build_coap_request_for_url(&url, RequestType::Delete, None, None, None)?;
+            let Some(response) = send_request(conn, args, request).await? else {
+                return Ok(());
+            };
+            print_response(&response.message, args, args.output_file.as_deref())
+        }
+        RdCommand::Lookup {
+            kind,
+            filters,
+            page,
+            count,
+        } => {
+            let mut params = vec![];
+            for filter in filters {
+                let (name, value) = filter.split_once('=').ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("expected NAME=VALUE: {}", filter),
+                    )
+                })?;
+                params.push((name, value.to_string()));
+            }
+            params.extend(page.map(|page| ("page", page.to_string())));
+            params.extend(count.map(|count| ("count", count.to_string())));
+            let rt = match kind {
+                LookupType::Ep => "core.rd-lookup-ep",
+                LookupType::Res => "core.rd-lookup-res",
+            };
+            let url = with_query(&discover_interface(conn, args, rt).await?, &params);
+            info!("GET {}", url);
+            let request = build_coap_request_for_url(
+                &url,
+                RequestType::Get,
+                None,
+                None,
+                Some(vec![ContentFormat::ApplicationLinkFormat]),
+            )?;
+            let Some(response) = send_request(conn, args, request).await? else {
+                return Ok(());
+            };
+            print_response(&response.message, args, args.output_file.as_deref())
+        }
+    }
+}
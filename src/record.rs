@@ -0,0 +1,174 @@
+use coap_lite::{CoapOption, CoapRequest, MessageClass, Packet, RequestType};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::coap_helper::*;
+use crate::message_dump::hex;
+use crate::report::OutputFormat;
+use crate::transport::Connection;
+use crate::{exchange, Args};
+
+/// An exchange as written by `--record`, one JSON object to a line. The messages are given as
+/// they are encoded, in hex, so that they can be sent again exactly; the rest is there to be read.
+///
+/// ```json
+/// {"timestamp":"2024-03-01T12:00:00.5Z","url":"coap://device.local/fw/version","method":"GET",
+///  "code":"2.05","rtt_ms":12.4,"request":"4101…","response":"6145…"}
+/// ```
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    timestamp: String,
+    url: String,
+    method: String,
+    code: String,
+    rtt_ms: f64,
+    request: String,
+    response: String,
+}
+
+/// Appends an exchange to the recording in `path`.
+pub fn append(
+    path: &Path,
+    url: &str,
+    request: &Packet,
+    response: &Packet,
+    rtt: Duration,
+) -> Result<()> {
+    let method = match request.header.code {
+        MessageClass::Request(method) => format!("{:?}", method).to_uppercase(),
+        code => code.to_string(),
+    };
+    let record = Record {
+        timestamp: OffsetDateTime::now_utc().format(&Rfc3339).map_err(Error::other)?,
+        url: url.to_string(),
+        method,
+        code: response.header.code.to_string(),
+        rtt_ms: rtt.as_secs_f64() * 1000.0,
+        request: hex(&request.to_bytes().map_err(Error::other)?),
+        response: hex(&response.to_bytes().map_err(Error::other)?),
+    };
+    let line = serde_json::to_string(&record).map_err(Error::other)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    writeln!(file, "{}", line)
+}
+
+fn load_records(path: &Path) -> Result<Vec<Record>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path.display(), i + 1, e),
+                )
+            })
+        })
+        .collect()
+}
+
+fn parse_timestamp(record: &Record) -> Result<OffsetDateTime> {
+    OffsetDateTime::parse(&record.timestamp, &Rfc3339).map_err(|e| {
+        Error::new(ErrorKind::InvalidData, format!("invalid timestamp {}: {}", record.timestamp, e))
+    })
+}
+
+fn decode_message(hex: &str) -> Result<Packet> {
+    Packet::from_bytes(&parse_hex(hex)?)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("undecodable message: {}", e)))
+}
+
+/// The recorded request readdressed to the URL's server: with a token and message ID of its own,
+/// and the URL's Uri-Host and Uri-Port in place of those it was recorded with.
+fn readdress(url: &str, recorded: Packet) -> Result<CoapRequest<SocketAddr>> {
+    let target = build_coap_request_for_url(url, RequestType::Get, None, None, None)?;
+    let mut request = CoapRequest::new();
+    request.message = recorded;
+    request.message.set_token(vec![]);
+    request.message.header.message_id = 0;
+    for option in [CoapOption::UriHost, CoapOption::UriPort] {
+        request.message.clear_option(option);
+        for value in target.message.get_option(option).into_iter().flatten() {
+            request.message.add_option(option, value.clone());
+        }
+    }
+    Ok(request)
+}
+
+/// How a response differs from the recorded one, if it does.
+fn difference(recorded: &Packet, response: &Packet) -> Option<String> {
+    if response.header.code != recorded.header.code {
+        return Some(format!("code {} rather than {}", response.header.code, recorded.header.code));
+    }
+    if response.get_content_format() != recorded.get_content_format() {
+        return Some("content format differs".to_string());
+    }
+    if response.payload != recorded.payload {
+        return Some("payload differs".to_string());
+    }
+    None
+}
+
+/// Sends the requests in a recording to the URL's server again, in the order recorded and, with
+/// `timing`, as far apart as they were, and reports for each whether the response matches the
+/// recorded one in code, content format and payload. Fails if any response differs or doesn't
+/// arrive.
+pub async fn coap_replay(conn: &Connection, args: &Args, file: &Path, timing: bool) -> Result<()> {
+    let records = load_records(file)?;
+    let start = Instant::now();
+    let first = records.first().map(parse_timestamp).transpose()?;
+    let mut failures = 0;
+    for record in &records {
+        if let (true, Some(first)) = (timing, first) {
+            let due = Duration::try_from(parse_timestamp(record)? - first).unwrap_or_default();
+            tokio::time::sleep(due.saturating_sub(start.elapsed())).await;
+        }
+        let expected = decode_message(&record.response)?;
+        let request = readdress(&args.url, decode_message(&record.request)?)?;
+        let resource = format!("{} /{}", record.method, request.get_path());
+        let outcome = match exchange(conn, args, request).await {
+            Ok(response) => difference(&expected, &response.message),
+            Err(e) => Some(e.to_string()),
+        };
+        if outcome.is_some() {
+            failures += 1;
+        }
+        match (args.output, &outcome) {
+            (OutputFormat::Json, _) => println!(
+                "{}",
+                json!({
+                    "method": record.method,
+                    "url": record.url,
+                    "expected": record.code,
+                    "matched": outcome.is_none(),
+                    "difference": outcome,
+                })
+            ),
+            (_, None) => println!("{}  {}  ok", resource, record.code),
+            (_, Some(difference)) => println!("{}  {}  {}", resource, record.code, difference),
+        }
+    }
+    info!("{} of {} exchange(s) matched", records.len() - failures, records.len());
+    if failures > 0 {
+        return Err(Error::other(format!(
+            "{} exchange(s) differed from the recording",
+            failures
+        )));
+    }
+    Ok(())
+}
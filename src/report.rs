@@ -0,0 +1,158 @@
+use base64::Engine;
+use clap::ValueEnum;
+use coap_lite::{CoapOption, MessageClass, Packet};
+use handlebars::Handlebars;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::coap_helper::*;
+use crate::message_dump::{decode_option_value, decode_uint, hex, message_type_name};
+
+/// How responses are printed.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Raw,
+    Json,
+}
+
+/// Renders a Handlebars template file against the result of a run, printing the output to stdout.
+pub fn print_report<T: Serialize>(template: &Path, model: &T) -> Result<()> {
+    let source = std::fs::read_to_string(template)?;
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(handlebars::no_escape);
+    handlebars
+        .register_template_string("report", source)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}: {}", template.display(), e)))?;
+    let output = handlebars
+        .render("report", model)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", template.display(), e)))?;
+    print!("{}", output);
+    Ok(())
+}
+
+/// The fields of a response that templates can refer to.
+pub fn response_model(response: &Packet) -> Value {
+    let (success, name, description) = match response.header.code {
+        MessageClass::Response(status) => {
+            let (name, description) = response_code_info(status);
+            (!status.is_error(), name, description)
+        }
+        _ => (false, "", ""),
+    };
+    let options: Vec<Value> = response
+        .options()
+        .flat_map(|(number, values)| {
+            values.iter().map(move |value| {
+                json!({
+                    "number": number,
+                    "name": option_name(CoapOption::from(*number)),
+                    "value": decode_option_value(CoapOption::from(*number), value),
+                })
+            })
+        })
+        .collect();
+    json!({
+        "code": response.header.code.to_string(),
+        "name": name,
+        "description": description,
+        "success": success,
+        "content_format": response.get_content_format().map(usize::from),
+        "location": response_location(response),
+        "payload": String::from_utf8_lossy(&response.payload),
+        "payload_bytes": response.payload.len(),
+        "options": options,
+    })
+}
+
+/// An option value as JSON: text and numbers as such, blocks broken down into their fields, and
+/// anything else as hex.
+fn option_value_json(option: CoapOption, value: &[u8]) -> Value {
+    match option {
+        CoapOption::UriHost
+        | CoapOption::LocationPath
+        | CoapOption::UriPath
+        | CoapOption::UriQuery
+        | CoapOption::LocationQuery
+        | CoapOption::ProxyUri
+        | CoapOption::ProxyScheme => json!(String::from_utf8_lossy(value)),
+        CoapOption::Observe
+        | CoapOption::UriPort
+        | CoapOption::ContentFormat
+        | CoapOption::MaxAge
+        | CoapOption::Accept
+        | CoapOption::Size1
+        | CoapOption::Size2
+        | CoapOption::NoResponse
+        | CoapOption::Unknown(HOP_LIMIT) => json!(decode_uint(value)),
+        CoapOption::Block1 | CoapOption::Block2 => {
+            let v = decode_uint(value);
+            json!({ "num": v >> 4, "more": (v >> 3) & 1 == 1, "size": 16 << (v & 7) })
+        }
+        _ => json!(hex(value)),
+    }
+}
+
+/// A complete description of a message for `--output json`. The payload is given as text if it
+/// is valid UTF-8, and in base64 otherwise, as told by `payload_encoding`.
+pub fn message_json(message: &Packet) -> Value {
+    let options: Vec<Value> = message
+        .options()
+        .flat_map(|(number, values)| {
+            values.iter().map(move |value| {
+                let option = CoapOption::from(*number);
+                json!({
+                    "number": number,
+                    "name": option_name(option),
+                    "value": option_value_json(option, value),
+                })
+            })
+        })
+        .collect();
+    let (payload, encoding) = match std::str::from_utf8(&message.payload) {
+        Ok(text) => (text.to_string(), "utf-8"),
+        Err(_) => (base64::engine::general_purpose::STANDARD.encode(&message.payload), "base64"),
+    };
+    json!({
+        "code": message.header.code.to_string(),
+        "type": message_type_name(message.header.get_type()),
+        "token": hex(message.get_token()),
+        "message_id": message.header.message_id,
+        "options": options,
+        "content_format": message.get_content_format().map(usize::from),
+        "location": response_location(message),
+        "payload": payload,
+        "payload_encoding": encoding,
+    })
+}
+
+/// The model for a single request: the request itself plus its response.
+pub fn exchange_model(url: &str, method: &str, response: &Packet, rtt: Duration) -> Value {
+    json!({
+        "url": url,
+        "method": method,
+        "rtt_ms": rtt.as_secs_f64() * 1000.0,
+        "response": response_model(response),
+    })
+}
+
+/// The model for a request sent to a multicast group: the responses of each member.
+pub fn group_model(url: &str, method: &str, responses: &[(SocketAddr, Packet)]) -> Value {
+    let responses: Vec<Value> = responses
+        .iter()
+        .map(|(source, packet)| {
+            let mut model = response_model(packet);
+            model["source"] = json!(source.to_string());
+            model
+        })
+        .collect();
+    json!({
+        "url": url,
+        "method": method,
+        "responses": responses,
+    })
+}
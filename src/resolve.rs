@@ -0,0 +1,188 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use tokio::net::lookup_host;
+
+use crate::Args;
+
+/// An address given with `--resolve` to use for a host and port instead of looking it up.
+#[derive(Debug, Clone)]
+pub struct ResolveOverride {
+    host: String,
+    port: u16,
+    addr: IpAddr,
+}
+
+/// Parses an override written as `HOST:PORT:ADDR`, where an IPv6 address may be given in
+/// brackets, e.g. `device.local:5683:[fd00::17]`.
+pub fn parse_resolve(s: &str) -> std::result::Result<ResolveOverride, String> {
+    let mut parts = s.splitn(3, ':');
+    let (Some(host), Some(port), Some(addr)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(format!("expected HOST:PORT:ADDR: {}", s));
+    };
+    let port = port.parse().map_err(|_| format!("invalid port: {}", port))?;
+    let addr = addr
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_err(|_| format!("invalid address: {}", addr))?;
+    Ok(ResolveOverride {
+        host: host.to_string(),
+        port,
+        addr,
+    })
+}
+
+/// A host and port given with `--connect-to` to send requests to instead of the URL's.
+#[derive(Debug, Clone)]
+pub struct ConnectTo {
+    pub host: String,
+    pub port: u16,
+}
+
+/// Parses a destination written as `HOST:PORT`, where an IPv6 address is given in brackets, e.g.
+/// `backend.local:5683` or `[fd00::17]:5683`.
+pub fn parse_connect_to(s: &str) -> std::result::Result<ConnectTo, String> {
+    let Some((host, port)) = s.rsplit_once(':') else {
+        return Err(format!("expected HOST:PORT: {}", s));
+    };
+    let port = port.parse().map_err(|_| format!("invalid port: {}", port))?;
+    let host = match host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+        Some(ip) => ip,
+        None if host.contains(':') => return Err(format!("IPv6 address not in brackets: {}", s)),
+        None => host,
+    };
+    if host.is_empty() {
+        return Err(format!("expected HOST:PORT: {}", s));
+    }
+    Ok(ConnectTo {
+        host: host.to_string(),
+        port,
+    })
+}
+
+/// Parses a local address to send from given as `ADDR:PORT`, or just `ADDR` for any port, where
+/// an IPv6 address is given in brackets with a port, e.g. `192.168.1.10:0` or `[fd00::2]:5683`.
+pub fn parse_local_addr(s: &str) -> std::result::Result<SocketAddr, String> {
+    if let Ok(addr) = s.parse() {
+        return Ok(addr);
+    }
+    s.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, 0))
+        .map_err(|_| format!("invalid local address: {}", s))
+}
+
+/// Splits the zone identifier, e.g. `eth0`, off an IPv6 link-local address as written in a URL
+/// (RFC 6874), e.g. `fe80::1%25eth0`.
+pub fn split_zone(host: &str) -> (&str, Option<&str>) {
+    match host.split_once('%') {
+        Some((addr, zone)) => (addr, Some(zone.strip_prefix("25").unwrap_or(zone))),
+        None => (host, None),
+    }
+}
+
+/// Finds the scope ID of a zone given by interface name or number.
+fn scope_id(zone: &str) -> Result<u32> {
+    if let Ok(index) = zone.parse() {
+        return Ok(index);
+    }
+    if_addrs::get_if_addrs()?
+        .into_iter()
+        .find(|interface| interface.name == zone)
+        .and_then(|interface| interface.index)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no such interface: {}", zone)))
+}
+
+/// The address of an interface to send to a peer from: one of the peer's family, and link-local
+/// if the peer's address is, so that the peer can reach it.
+fn interface_addr(name: &str, peer_addr: SocketAddr) -> Result<SocketAddr> {
+    let interfaces: Vec<_> = if_addrs::get_if_addrs()?
+        .into_iter()
+        .filter(|interface| interface.name == name)
+        .collect();
+    if interfaces.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, format!("no such interface: {}", name)));
+    }
+    let link_local = match peer_addr.ip() {
+        IpAddr::V6(ip) => ip.is_unicast_link_local(),
+        IpAddr::V4(_) => false,
+    };
+    let interface = interfaces
+        .iter()
+        .filter(|interface| interface.ip().is_ipv4() == peer_addr.is_ipv4())
+        .min_by_key(|interface| interface.is_link_local() != link_local)
+        .ok_or_else(|| {
+            let family = if peer_addr.is_ipv4() { "IPv4" } else { "IPv6" };
+            Error::new(
+                ErrorKind::NotFound,
+                format!("{} has no {} address to reach {} from", name, family, peer_addr),
+            )
+        })?;
+    Ok(match interface.ip() {
+        IpAddr::V6(ip) if interface.is_link_local() => {
+            SocketAddr::V6(SocketAddrV6::new(ip, 0, 0, interface.index.unwrap_or(0)))
+        }
+        ip => SocketAddr::new(ip, 0),
+    })
+}
+
+/// The local address to send to a peer from, as given with `--local-addr`, or as found for
+/// `--interface`; `None` to leave it to the system.
+pub fn local_addr(args: &Args, peer_addr: SocketAddr) -> Result<Option<SocketAddr>> {
+    if let Some(name) = &args.interface {
+        return interface_addr(name, peer_addr).map(Some);
+    }
+    match args.local_addr {
+        Some(local_addr) if local_addr.is_ipv4() != peer_addr.is_ipv4() => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("can't reach {} from {}", peer_addr, local_addr),
+        )),
+        local_addr => Ok(local_addr),
+    }
+}
+
+/// Finds the address of the server at a host and port, taking `--resolve`, `-4` and `-6` into
+/// account, along with any zone identifier the host has. With `--local-addr`, only an address of
+/// its family will do.
+pub async fn resolve_peer(args: &Args, host: &str, port: u16) -> Result<SocketAddr> {
+    let (host, zone) = split_zone(host);
+    let overridden = args
+        .resolve
+        .iter()
+        .find(|o| o.host.eq_ignore_ascii_case(host) && o.port == port)
+        .map(|o| SocketAddr::new(o.addr, port));
+    let addresses: Vec<SocketAddr> = match overridden {
+        Some(addr) => vec![addr],
+        None => lookup_host((host, port)).await?.collect(),
+    };
+    let mut peer_addr = addresses
+        .into_iter()
+        .find(|addr| {
+            let ipv4 = args.ipv4 || args.local_addr.is_some_and(|local| local.is_ipv4());
+            let ipv6 = args.ipv6 || args.local_addr.is_some_and(|local| local.is_ipv6());
+            !(ipv4 && addr.is_ipv6() || ipv6 && addr.is_ipv4())
+        })
+        .ok_or_else(|| {
+            let local = args.local_addr.map(|local| local.is_ipv4());
+            let family = match (args.ipv4, args.ipv6, local) {
+                (true, _, _) | (_, _, Some(true)) => "IPv4 ",
+                (_, true, _) | (_, _, Some(false)) => "IPv6 ",
+                _ => "",
+            };
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("could not get {}socket address for {}", family, host),
+            )
+        })?;
+    if let Some(zone) = zone {
+        let SocketAddr::V6(addr) = &mut peer_addr else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("zone identifier given for an IPv4 address: {}", host),
+            ));
+        };
+        addr.set_scope_id(scope_id(zone)?);
+    }
+    Ok(peer_addr)
+}
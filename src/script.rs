@@ -0,0 +1,198 @@
+use coap_lite::{CoapOption, ContentFormat, MessageClass, Packet, RequestType};
+use log::info;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope};
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Handle;
+
+use crate::coap_helper::*;
+use crate::content::{self, PayloadEncoding};
+use crate::message_dump::hex;
+use crate::observe;
+use crate::transport::Connection;
+use crate::{create_connection, exchange, request_url, Args};
+
+/// The connection a script's requests all go over, so that they share one session with the
+/// server, e.g. one DTLS handshake.
+struct Session {
+    conn: Connection,
+    args: Args,
+    runtime: Handle,
+}
+
+impl Session {
+    /// The arguments for a request to `path`, resolved against the URL.
+    fn args_for(&self, path: &str) -> Result<Args> {
+        Ok(Args {
+            url: request_url(&self.args, path)?,
+            ..self.args.clone()
+        })
+    }
+
+    fn request(
+        &self,
+        method: RequestType,
+        path: &str,
+        payload: Option<(Vec<u8>, Option<ContentFormat>)>,
+    ) -> Result<Map> {
+        let args = self.args_for(path)?;
+        info!("{} {}", format!("{:?}", method).to_uppercase(), args.url);
+        let (payload, content_format) = match payload {
+            Some((payload, content_format)) => (Some(payload), content_format),
+            None => (None, None),
+        };
+        let request = build_coap_request_for_url(&args.url, method, payload, content_format, None)?;
+        let response = self.runtime.block_on(exchange(&self.conn, &args, request))?;
+        Ok(response_map(&args.url, &response.message))
+    }
+
+    fn observe(&self, path: &str, count: i64, seconds: Option<i64>) -> Result<Array> {
+        let args = self.args_for(path)?;
+        info!("OBSERVE {}", args.url);
+        let count = usize::try_from(count).unwrap_or(0);
+        let duration = seconds.map(|seconds| Duration::from_secs(seconds.max(0) as u64));
+        let notifications =
+            self.runtime.block_on(observe::collect(&self.conn, &args, count, duration))?;
+        Ok(notifications
+            .iter()
+            .map(|p| Dynamic::from_map(response_map(&args.url, p)))
+            .collect())
+    }
+}
+
+/// A response as scripts see it: its code, whether it succeeded, its payload as text, its
+/// content format and ETag, and the value of a JSON or CBOR payload.
+fn response_map(url: &str, message: &Packet) -> Map {
+    let success = matches!(message.header.code, MessageClass::Response(_))
+        && u8::from(message.header.code) >> 5 == 2;
+    let content_format = message
+        .get_content_format()
+        .map_or(Dynamic::UNIT, |cf| Dynamic::from_int(usize::from(cf) as i64));
+    let etag = message
+        .get_first_option(CoapOption::ETag)
+        .map_or(Dynamic::UNIT, |etag| Dynamic::from(hex(etag)));
+    let value = content::payload_json(message)
+        .and_then(|value| rhai::serde::to_dynamic(value).ok())
+        .unwrap_or(Dynamic::UNIT);
+    let mut map = Map::new();
+    map.insert("url".into(), Dynamic::from(url.to_string()));
+    map.insert("code".into(), Dynamic::from(message.header.code.to_string()));
+    map.insert("ok".into(), Dynamic::from_bool(success));
+    map.insert(
+        "payload".into(),
+        Dynamic::from(String::from_utf8_lossy(&message.payload).into_owned()),
+    );
+    map.insert("content_format".into(), content_format);
+    map.insert("etag".into(), etag);
+    map.insert("value".into(), value);
+    map
+}
+
+fn is_cbor(content_format: ContentFormat) -> bool {
+    matches!(
+        content_format,
+        ContentFormat::ApplicationCBOR
+            | ContentFormat::ApplicationSenmlCBOR
+            | ContentFormat::ApplicationSensmlCBOR
+    )
+}
+
+/// The payload for data a script submits: a string or blob as it is, and anything else, e.g. a
+/// map, as JSON, or as CBOR if the content format is a CBOR one.
+fn payload(data: Dynamic, content_format: Option<&str>) -> Result<(Vec<u8>, Option<ContentFormat>)> {
+    let content_format = content_format.map(parse_content_format).transpose()?;
+    if data.is_string() {
+        return Ok((data.into_string().unwrap_or_default().into_bytes(), content_format));
+    }
+    if data.is_blob() {
+        return Ok((data.cast::<rhai::Blob>(), content_format));
+    }
+    let value: serde_json::Value = rhai::serde::from_dynamic(&data)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+    let json = value.to_string().into_bytes();
+    match content_format {
+        Some(cf) if is_cbor(cf) => content::encode_payload(&json, PayloadEncoding::Cbor, Some(cf))
+            .map(|(data, cf)| (data, Some(cf))),
+        Some(cf) => Ok((json, Some(cf))),
+        None => Ok((json, Some(ContentFormat::ApplicationJSON))),
+    }
+}
+
+fn script_error(e: Error) -> Box<EvalAltResult> {
+    e.to_string().into()
+}
+
+type ScriptResult<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+/// Makes the requests of the session available to scripts, each failing with a runtime error,
+/// which a script can catch, if no response comes.
+fn register(engine: &mut Engine, session: &Arc<Session>) {
+    let s = session.clone();
+    engine.register_fn("get", move |path: &str| -> ScriptResult<Map> {
+        s.request(RequestType::Get, path, None).map_err(script_error)
+    });
+    let s = session.clone();
+    engine.register_fn("delete", move |path: &str| -> ScriptResult<Map> {
+        s.request(RequestType::Delete, path, None).map_err(script_error)
+    });
+    for (name, method) in [("post", RequestType::Post), ("put", RequestType::Put)] {
+        let s = session.clone();
+        engine.register_fn(name, move |path: &str, data: Dynamic| -> ScriptResult<Map> {
+            let payload = payload(data, None).map_err(script_error)?;
+            s.request(method, path, Some(payload)).map_err(script_error)
+        });
+        let s = session.clone();
+        engine.register_fn(
+            name,
+            move |path: &str, data: Dynamic, content_format: &str| -> ScriptResult<Map> {
+                let payload = payload(data, Some(content_format)).map_err(script_error)?;
+                s.request(method, path, Some(payload)).map_err(script_error)
+            },
+        );
+    }
+    let s = session.clone();
+    engine.register_fn("observe", move |path: &str, count: i64| -> ScriptResult<Array> {
+        s.observe(path, count, None).map_err(script_error)
+    });
+    let s = session.clone();
+    engine.register_fn(
+        "observe",
+        move |path: &str, count: i64, seconds: i64| -> ScriptResult<Array> {
+            s.observe(path, count, Some(seconds)).map_err(script_error)
+        },
+    );
+}
+
+/// Runs a Rhai script whose requests all go over one connection to the URL's server. Scripts
+/// call `get(path)`, `post(path, data)`, `put(path, data)` (either with a content format as a
+/// third argument), `delete(path)` and `observe(path, count)` (or with a limit in seconds as a
+/// third argument), with paths relative to the URL, given as `URL`, and get responses as maps
+/// of `code`, `ok`, `payload`, `content_format`, `etag` and `value`. The script's arguments are
+/// given as `ARGS`.
+pub async fn coap_script(args: &Args, file: &Path, script_args: &[String]) -> Result<()> {
+    let source = std::fs::read_to_string(file)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", file.display(), e)))?;
+    let mut engine = Engine::new();
+    let ast = engine
+        .compile(&source)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}: {}", file.display(), e)))?;
+    if args.dry_run {
+        return Ok(());
+    }
+
+    let session = Arc::new(Session {
+        conn: create_connection(args).await?,
+        args: args.clone(),
+        runtime: Handle::current(),
+    });
+    register(&mut engine, &session);
+    let mut scope = Scope::new();
+    scope.push_constant("URL", args.url.clone());
+    let script_args: Array = script_args.iter().cloned().map(Dynamic::from).collect();
+    scope.push_constant("ARGS", script_args);
+    // the script runs synchronously, each request blocking it until the response comes
+    tokio::task::block_in_place(|| engine.run_ast_with_scope(&mut scope, &ast))
+        .map_err(|e| Error::other(format!("{}: {}", file.display(), e)))
+}
@@ -0,0 +1,284 @@
+use coap_lite::{Packet, RequestType, ResponseType};
+use std::cell::Cell;
+use std::future::Future;
+use std::io::{Error, Result};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::coap_helper::*;
+use crate::serve::{serve_manifest, Manifest};
+use crate::server::Server;
+use crate::transport::Connection;
+use crate::{create_connection_to, exchange, Args};
+
+// the resources served to the client while testing
+const FIXTURES: &str = r#"
+[[resource]]
+path = "/hello"
+content_format = "text/plain"
+value = "hello, world"
+
+[[resource]]
+path = "/large"
+template = "{{value}}{{value}}{{value}}{{value}}"
+
+[[resource]]
+path = "/store"
+value = "initial"
+writable = true
+observable = true
+
+[[resource]]
+path = "/echo"
+template = "{{method}} {{path}}?{{query}}"
+"#;
+
+// (name, method, path, payload, expected status, expected payload)
+type Case = (
+    &'static str,
+    RequestType,
+    &'static str,
+    Option<&'static [u8]>,
+    ResponseType,
+    Option<&'static [u8]>,
+);
+
+const SIMPLE_CASES: [Case; 4] = [
+    (
+        "GET a resource",
+        RequestType::Get,
+        "/hello",
+        None,
+        ResponseType::Content,
+        Some(b"hello, world"),
+    ),
+    (
+        "GET a missing resource",
+        RequestType::Get,
+        "/missing",
+        None,
+        ResponseType::NotFound,
+        None,
+    ),
+    (
+        "GET with a query",
+        RequestType::Get,
+        "/echo?a=1",
+        None,
+        ResponseType::Content,
+        Some(b"GET /echo?a=1"),
+    ),
+    (
+        "PUT a read-only resource",
+        RequestType::Put,
+        "/hello",
+        Some(b"x"),
+        ResponseType::MethodNotAllowed,
+        None,
+    ),
+];
+
+struct Suite<'a> {
+    conn: &'a Connection,
+    args: &'a Args,
+    base: String,
+    passed: Cell<usize>,
+    failed: Cell<usize>,
+}
+
+fn check(condition: bool, message: impl FnOnce() -> String) -> Result<()> {
+    if condition {
+        Ok(())
+    } else {
+        Err(Error::other(message()))
+    }
+}
+
+impl Suite<'_> {
+    async fn run<F: Future<Output = Result<()>>>(&self, name: &str, test: F) {
+        let start = Instant::now();
+        match test.await {
+            Ok(()) => {
+                self.passed.set(self.passed.get() + 1);
+                println!("ok    {} ({}ms)", name, start.elapsed().as_millis());
+            }
+            Err(e) => {
+                self.failed.set(self.failed.get() + 1);
+                println!("FAIL  {}: {}", name, e);
+            }
+        }
+    }
+
+    async fn request(
+        &self,
+        args: &Args,
+        method: RequestType,
+        path: &str,
+        payload: Option<&[u8]>,
+    ) -> Result<(ResponseType, Vec<u8>)> {
+        let url = format!("{}{}", self.base, path);
+        let request =
+            build_coap_request_for_url(&url, method, payload.map(|p| p.to_vec()), None, None)?;
+        let response = exchange(self.conn, args, request).await?;
+        Ok((*response.get_status(), response.message.payload))
+    }
+
+    async fn expect(
+        &self,
+        method: RequestType,
+        path: &str,
+        payload: Option<&[u8]>,
+        status: ResponseType,
+        body: Option<&[u8]>,
+    ) -> Result<()> {
+        let (actual, actual_body) = self.request(self.args, method, path, payload).await?;
+        check(actual == status, || {
+            format!("expected {:?}, got {:?}", status, actual)
+        })?;
+        if let Some(body) = body {
+            check(actual_body == body, || {
+                format!(
+                    "expected {:?}, got {:?}",
+                    String::from_utf8_lossy(body),
+                    String::from_utf8_lossy(&actual_body)
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    async fn observe_store(&self) -> Result<()> {
+        let url = format!("{}/store", self.base);
+        let mut request = build_coap_request_for_url(&url, RequestType::Get, None, None, None)?;
+        // the other requests share the client and have empty tokens, so the notifications need a
+        // token of their own to be routed here
+        request.message.set_token(rand::random::<[u8; 4]>().to_vec());
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let sender = self
+            .conn
+            .client
+            .observe_with(request, move |p: Packet| {
+                let _ = tx.send(p.payload);
+            })
+            .await?;
+        let mut next = async || {
+            tokio::time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .ok()
+                .flatten()
+                .ok_or_else(|| Error::other("no notification received"))
+        };
+        let initial = next().await?;
+        self.request(self.args, RequestType::Put, "/store", Some(b"observed"))
+            .await?;
+        let notified = next().await?;
+        let _ = sender.send(coap::client::ObserveMessage::Terminate);
+        check(!initial.is_empty(), || {
+            "empty initial notification".to_string()
+        })?;
+        check(notified == b"observed", || {
+            format!(
+                "expected \"observed\", got {:?}",
+                String::from_utf8_lossy(&notified)
+            )
+        })
+    }
+}
+
+pub async fn coap_selftest(args: &Args) -> Result<()> {
+    let CoapUri { host, port, .. } = CoapUri::parse(&args.url)?;
+    let mut manifest: Manifest =
+        toml::from_str(FIXTURES).map_err(|e| Error::other(e.to_string()))?;
+    let large = (0..256)
+        .map(|i| format!("{:02x}", i % 256))
+        .collect::<String>();
+    manifest.resources[1].value = large.clone();
+
+    let server = Server::bind((&host, port.unwrap_or(0))).await?;
+    let addr = server.local_addr()?;
+    println!("testing against embedded server on {}", addr);
+    let server_task = tokio::spawn(serve_manifest(server, manifest));
+
+    let base = format!("coap://{}", addr);
+    let conn = create_connection_to(args, &base).await?;
+    let suite = Suite {
+        conn: &conn,
+        args,
+        base,
+        passed: Cell::new(0),
+        failed: Cell::new(0),
+    };
+
+    let large = large.repeat(4);
+    let mut small_mtu = args.clone();
+    small_mtu.mtu = Some(256);
+    let upload = vec![b'x'; 2000];
+
+    for (name, method, path, payload, status, body) in SIMPLE_CASES {
+        suite
+            .run(name, suite.expect(method, path, payload, status, body))
+            .await;
+    }
+    suite
+        .run("PUT then GET", async {
+            suite
+                .expect(
+                    RequestType::Put,
+                    "/store",
+                    Some(b"updated"),
+                    ResponseType::Changed,
+                    None,
+                )
+                .await?;
+            suite
+                .expect(
+                    RequestType::Get,
+                    "/store",
+                    None,
+                    ResponseType::Content,
+                    Some(b"updated"),
+                )
+                .await
+        })
+        .await;
+    suite
+        .run(
+            "block-wise GET",
+            suite.expect(
+                RequestType::Get,
+                "/large",
+                None,
+                ResponseType::Content,
+                Some(large.as_bytes()),
+            ),
+        )
+        .await;
+    suite
+        .run("block-wise PUT", async {
+            let (status, _) = suite
+                .request(&small_mtu, RequestType::Put, "/store", Some(&upload))
+                .await?;
+            check(status == ResponseType::Changed, || {
+                format!("expected Changed, got {:?}", status)
+            })?;
+            suite
+                .expect(
+                    RequestType::Get,
+                    "/store",
+                    None,
+                    ResponseType::Content,
+                    Some(&upload),
+                )
+                .await
+        })
+        .await;
+    suite.run("observe", suite.observe_store()).await;
+
+    server_task.abort();
+    let (passed, failed) = (suite.passed.get(), suite.failed.get());
+    println!("{} passed, {} failed", passed, failed);
+    if failed > 0 {
+        return Err(Error::other(format!("{} test(s) failed", failed)));
+    }
+    Ok(())
+}
@@ -0,0 +1,257 @@
+use coap_lite::{ContentFormat, Packet};
+use serde_json::{Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+use time::format_description::well_known::Rfc3339;
+use time::{OffsetDateTime, UtcOffset};
+
+use crate::content;
+
+/// Times below 2**28 are relative to the present rather than since the Unix epoch (RFC 8428
+/// section 4.5.3).
+const RELATIVE_TIME_LIMIT: f64 = 268_435_456.0;
+
+/// The fields a record's value can be given in, one of which it should have.
+const VALUE_FIELDS: [&str; 4] = ["v", "vs", "vb", "vd"];
+
+/// The base fields in force for a record, carried over from the records before it.
+#[derive(Default)]
+struct Base {
+    name: String,
+    time: f64,
+    unit: Option<String>,
+    value: Option<f64>,
+    sum: Option<f64>,
+}
+
+impl Base {
+    fn update(&mut self, record: &Map<String, Value>) -> Option<()> {
+        if let Some(name) = record.get("bn") {
+            self.name = name.as_str()?.to_string();
+        }
+        if let Some(time) = record.get("bt") {
+            self.time = time.as_f64()?;
+        }
+        if let Some(unit) = record.get("bu") {
+            self.unit = Some(unit.as_str()?.to_string());
+        }
+        if let Some(value) = record.get("bv") {
+            self.value = Some(value.as_f64()?);
+        }
+        if let Some(sum) = record.get("bs") {
+            self.sum = Some(sum.as_f64()?);
+        }
+        Some(())
+    }
+}
+
+/// Resolves a SenML pack (RFC 8428 section 4.6): applies each record's base name, time, unit,
+/// value and sum, which hold until a later record changes them, and makes relative times
+/// absolute against `now`, in seconds since the Unix epoch. Base fields are dropped, leaving
+/// records that stand on their own. Returns `None` if the value isn't a pack.
+pub fn resolve(pack: &Value, now: f64) -> Option<Vec<Map<String, Value>>> {
+    let mut base = Base::default();
+    let mut resolved = vec![];
+    for record in pack.as_array()? {
+        let record = record.as_object()?;
+        base.update(record)?;
+        // a record with neither a value nor a sum takes the base value and sum as its own
+        let bare = !VALUE_FIELDS.iter().chain(&["s"]).any(|field| record.contains_key(*field));
+        if bare && base.value.is_none() && base.sum.is_none() {
+            // only sets base fields for the records after it
+            continue;
+        }
+        let name = record.get("n").and_then(Value::as_str).unwrap_or("");
+        let mut time = base.time + record.get("t").and_then(Value::as_f64).unwrap_or(0.0);
+        if time < RELATIVE_TIME_LIMIT {
+            time += now;
+        }
+        let mut out = Map::new();
+        out.insert("n".to_string(), Value::from(format!("{}{}", base.name, name)));
+        let unit = record.get("u").and_then(Value::as_str).or(base.unit.as_deref());
+        if let Some(unit) = unit {
+            out.insert("u".to_string(), Value::from(unit));
+        }
+        match (record.get("v"), base.value) {
+            (Some(value), None) => {
+                out.insert("v".to_string(), value.clone());
+            }
+            (Some(value), Some(base_value)) => {
+                let value = value.as_f64()? + base_value;
+                out.insert("v".to_string(), Value::from(value));
+            }
+            (None, Some(base_value)) if bare => {
+                out.insert("v".to_string(), Value::from(base_value));
+            }
+            _ => {}
+        }
+        for field in ["vs", "vb", "vd"] {
+            if let Some(value) = record.get(field) {
+                out.insert(field.to_string(), value.clone());
+            }
+        }
+        match (record.get("s"), base.sum) {
+            (Some(sum), None) => {
+                out.insert("s".to_string(), sum.clone());
+            }
+            (Some(sum), Some(base_sum)) => {
+                out.insert("s".to_string(), Value::from(sum.as_f64()? + base_sum));
+            }
+            (None, Some(base_sum)) if bare => {
+                out.insert("s".to_string(), Value::from(base_sum));
+            }
+            _ => {}
+        }
+        out.insert("t".to_string(), Value::from(time));
+        if let Some(update_time) = record.get("ut") {
+            out.insert("ut".to_string(), update_time.clone());
+        }
+        resolved.push(out);
+    }
+    Some(resolved)
+}
+
+fn is_senml(content_format: ContentFormat) -> bool {
+    matches!(
+        content_format,
+        ContentFormat::ApplicationSenmlJSON
+            | ContentFormat::ApplicationSenmlCBOR
+            | ContentFormat::ApplicationSensmlJSON
+            | ContentFormat::ApplicationSensmlCBOR
+    )
+}
+
+/// The resolved records of a SenML payload, in JSON or CBOR, if the message has one.
+pub fn message_records(message: &Packet) -> Option<Vec<Map<String, Value>>> {
+    if !is_senml(message.get_content_format()?) {
+        return None;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64());
+    resolve(&content::payload_json(message)?, now)
+}
+
+fn value_text(record: &Map<String, Value>) -> String {
+    let value = VALUE_FIELDS.iter().find_map(|field| record.get(*field));
+    match (value, record.get("s")) {
+        (Some(Value::String(text)), _) => text.clone(),
+        (Some(value), _) => value.to_string(),
+        (None, Some(sum)) => format!("{} (sum)", sum),
+        (None, None) => "-".to_string(),
+    }
+}
+
+fn time_text(time: f64, offset: UtcOffset) -> String {
+    // to the millisecond, as finer parts are more likely rounding errors than readings
+    let millis = (time * 1000.0).round() as i128;
+    OffsetDateTime::from_unix_timestamp_nanos(millis * 1_000_000)
+        .ok()
+        .and_then(|time| time.to_offset(offset).format(&Rfc3339).ok())
+        .unwrap_or_else(|| format!("{}", time))
+}
+
+/// Lays out resolved records as a table of their name, value, unit and time, one line per
+/// record, with times in the local time zone.
+pub fn records_table(records: &[Map<String, Value>]) -> String {
+    let offset = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
+    let rows: Vec<[String; 4]> = records
+        .iter()
+        .map(|record| {
+            let text = |field| record.get(field).and_then(Value::as_str).unwrap_or("-").to_string();
+            let time = record.get("t").and_then(Value::as_f64).unwrap_or(0.0);
+            [text("n"), value_text(record), text("u"), time_text(time, offset)]
+        })
+        .collect();
+    let headings = ["NAME", "VALUE", "UNIT", "TIME"];
+    let widths: Vec<usize> = (0..headings.len())
+        .map(|i| rows.iter().map(|row| row[i].len()).chain([headings[i].len()]).max().unwrap())
+        .collect();
+    let line = |cells: [&str; 4]| {
+        let padded: Vec<String> =
+            cells.iter().zip(&widths).map(|(cell, width)| format!("{:width$}", cell)).collect();
+        padded.join("  ").trim_end().to_string()
+    };
+    let mut lines = vec![line(headings)];
+    for row in &rows {
+        lines.push(line([&row[0], &row[1], &row[2], &row[3]]));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn assert_close(actual: &Value, expected: f64) {
+        let actual = actual.as_f64().unwrap();
+        assert!((actual - expected).abs() < 1e-6, "{} != {}", actual, expected);
+    }
+
+    /// The pack of RFC 8428 section 5.1.2, which has times relative to its base time.
+    #[test]
+    fn resolves_rfc_8428_pack() {
+        let pack = json!([
+            {"bn": "urn:dev:ow:10e2073a0108006:", "bt": 1.276020076001e+09, "bu": "A",
+                "bver": 5, "n": "voltage", "u": "V", "v": 120.1},
+            {"n": "current", "t": -5, "v": 1.2},
+            {"n": "current", "t": -4, "v": 1.3},
+            {"n": "current", "t": -3, "v": 1.4},
+            {"n": "current", "t": -2, "v": 1.5},
+            {"n": "current", "t": -1, "v": 1.6},
+            {"n": "current", "v": 1.7}
+        ]);
+        let records = resolve(&pack, 0.0).unwrap();
+        assert_eq!(records.len(), 7);
+        assert_eq!(records[0]["n"], "urn:dev:ow:10e2073a0108006:voltage");
+        assert_eq!(records[0]["u"], "V");
+        assert_eq!(records[0]["v"], 120.1);
+        assert_close(&records[0]["t"], 1.276020076001e+09);
+        for (i, record) in records[1..].iter().enumerate() {
+            assert_eq!(record["n"], "urn:dev:ow:10e2073a0108006:current");
+            assert_eq!(record["u"], "A");
+            assert_eq!(record["v"], [1.2, 1.3, 1.4, 1.5, 1.6, 1.7][i]);
+            assert_close(&record["t"], 1.276020071001e+09 + i as f64);
+            assert!(!record.contains_key("bn") && !record.contains_key("bver"));
+        }
+    }
+
+    #[test]
+    fn resolves_relative_times_and_base_values() {
+        let pack = json!([
+            {"bn": "dev/", "bt": -10, "bu": "Cel"},
+            {"n": "temp", "v": 21.5},
+            {"n": "temp", "t": 5, "vs": "warm", "ut": 60},
+            {"bv": 100, "bs": 1000, "n": "count", "v": 5, "s": 10},
+            {"n": "count"},
+        ]);
+        let records = resolve(&pack, 1_700_000_000.0).unwrap();
+        // the first record only sets base fields
+        assert_eq!(records.len(), 4);
+        assert_eq!(
+            Value::from(records[0].clone()),
+            json!({"n": "dev/temp", "u": "Cel", "v": 21.5, "t": 1_699_999_990.0})
+        );
+        assert_eq!(
+            Value::from(records[1].clone()),
+            json!({"n": "dev/temp", "u": "Cel", "vs": "warm", "t": 1_699_999_995.0, "ut": 60})
+        );
+        assert_eq!(records[2]["v"], 105.0);
+        assert_eq!(records[2]["s"], 1010.0);
+        // a record with no value or sum takes the base ones
+        assert_eq!(records[3]["v"], 100.0);
+        assert_eq!(records[3]["s"], 1000.0);
+
+        // times from 2**28 on are since the Unix epoch
+        let records = resolve(&json!([{"n": "x", "v": 1, "t": 268_435_456}]), 1e9).unwrap();
+        assert_eq!(records[0]["t"], 268_435_456.0);
+    }
+
+    #[test]
+    fn rejects_what_isnt_a_pack() {
+        for value in [json!({"n": "x", "v": 1}), json!([1]), json!([{"bn": 5}]), json!("x")] {
+            assert!(resolve(&value, 0.0).is_none(), "{}", value);
+        }
+        assert_eq!(resolve(&json!([]), 0.0), Some(vec![]));
+    }
+}
@@ -0,0 +1,511 @@
+use async_trait::async_trait;
+use coap_lite::{CoapOption, CoapRequest, ContentFormat, RequestType, ResponseType};
+use log::info;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::coap_helper::*;
+use crate::mirror::{content_format_for_extension, walk_files};
+use crate::server::{normalize_path, set_response, RequestHandler, Server};
+use crate::Args;
+
+/// Describes the dynamic resources served by `serve --manifest`.
+///
+/// ```toml
+/// [[resource]]
+/// path = "/counter"
+/// content_format = "application/json"
+/// template = '{"count": {{counter}}}'
+/// observable = true
+/// counter = { interval = 5 }
+///
+/// [[resource]]
+/// path = "/config"
+/// value = "initial"
+/// writable = true
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct Manifest {
+    #[serde(rename = "resource", default)]
+    pub resources: Vec<ResourceSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResourceSpec {
+    pub path: String,
+    pub content_format: Option<String>,
+    /// Response body with `{{value}}`, `{{counter}}`, `{{path}}`, `{{query}}`, `{{method}}`,
+    /// `{{payload}}` and `{{time}}` placeholders
+    pub template: Option<String>,
+    /// Initial state of the resource, replaced by PUT when writable
+    #[serde(default)]
+    pub value: String,
+    #[serde(default)]
+    pub writable: bool,
+    #[serde(default)]
+    pub observable: bool,
+    pub counter: Option<CounterSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CounterSpec {
+    #[serde(default)]
+    pub start: i64,
+    #[serde(default = "default_step")]
+    pub step: i64,
+    /// Seconds between increments; without it the counter advances on every GET
+    pub interval: Option<u64>,
+}
+
+fn default_step() -> i64 {
+    1
+}
+
+struct ResourceState {
+    value: Vec<u8>,
+    counter: i64,
+}
+
+pub struct ManifestHandler {
+    specs: Vec<ResourceSpec>,
+    state: Mutex<HashMap<String, ResourceState>>,
+}
+
+impl ManifestHandler {
+    pub fn new(manifest: Manifest) -> Result<Self> {
+        let mut state = HashMap::new();
+        for spec in &manifest.resources {
+            if let Some(cf) = &spec.content_format {
+                parse_content_format(cf)?;
+            }
+            state.insert(
+                normalize_path(&spec.path),
+                ResourceState {
+                    value: spec.value.as_bytes().to_vec(),
+                    counter: spec.counter.as_ref().map_or(0, |c| c.start),
+                },
+            );
+        }
+        Ok(ManifestHandler {
+            specs: manifest.resources,
+            state: Mutex::new(state),
+        })
+    }
+
+    fn spec(&self, path: &str) -> Option<&ResourceSpec> {
+        self.specs.iter().find(|s| normalize_path(&s.path) == path)
+    }
+
+    fn step_counter(&self, spec: &ResourceSpec) {
+        if let Some(counter) = &spec.counter {
+            let mut state = self.state.lock().unwrap();
+            if let Some(s) = state.get_mut(&normalize_path(&spec.path)) {
+                s.counter += counter.step;
+            }
+        }
+    }
+
+    fn render(&self, spec: &ResourceSpec, request: &CoapRequest<SocketAddr>) -> Vec<u8> {
+        let path = normalize_path(&spec.path);
+        let state = self.state.lock().unwrap();
+        let state = &state[&path];
+        let Some(template) = &spec.template else {
+            return state.value.clone();
+        };
+        let query = request
+            .message
+            .get_option(coap_lite::CoapOption::UriQuery)
+            .map(|q| {
+                q.iter()
+                    .map(|v| String::from_utf8_lossy(v).into_owned())
+                    .collect::<Vec<_>>()
+                    .join("&")
+            })
+            .unwrap_or_default();
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let vars = [
+            ("value", String::from_utf8_lossy(&state.value).into_owned()),
+            ("counter", state.counter.to_string()),
+            ("path", path.clone()),
+            ("query", query),
+            ("method", format!("{:?}", request.get_method()).to_uppercase()),
+            ("payload", String::from_utf8_lossy(&request.message.payload).into_owned()),
+            ("time", time.to_string()),
+        ];
+        render_template(template, &vars).into_bytes()
+    }
+}
+
+pub fn render_template(template: &str, vars: &[(&str, String)]) -> String {
+    let mut out = template.to_string();
+    for (name, value) in vars {
+        out = out.replace(&format!("{{{{{}}}}}", name), value);
+    }
+    out
+}
+
+#[async_trait]
+impl RequestHandler for ManifestHandler {
+    async fn handle(&self, request: &mut CoapRequest<SocketAddr>) {
+        let path = normalize_path(&request.get_path());
+        let Some(spec) = self.spec(&path) else {
+            set_response(request, ResponseType::NotFound, vec![]);
+            return;
+        };
+        match *request.get_method() {
+            RequestType::Get => {
+                let payload = self.render(spec, request);
+                if spec.counter.as_ref().is_some_and(|c| c.interval.is_none()) {
+                    self.step_counter(spec);
+                }
+                set_response(request, ResponseType::Content, payload);
+                if let (Some(cf), Some(response)) = (&spec.content_format, request.response.as_mut()) {
+                    response.message.set_content_format(parse_content_format(cf).unwrap());
+                }
+            }
+            RequestType::Put if spec.writable => {
+                let payload = request.message.payload.clone();
+                self.state.lock().unwrap().get_mut(&path).unwrap().value = payload;
+                set_response(request, ResponseType::Changed, vec![]);
+            }
+            _ => set_response(request, ResponseType::MethodNotAllowed, vec![]),
+        }
+    }
+
+    fn is_observable(&self, path: &str) -> bool {
+        self.spec(path).is_some_and(|s| s.observable)
+    }
+}
+
+/// Wraps a handler so that observers are notified whenever a PUT changes a resource.
+struct NotifyOnPut<H> {
+    inner: Arc<H>,
+    server: Arc<Server>,
+}
+
+#[async_trait]
+impl<H: RequestHandler> RequestHandler for NotifyOnPut<H> {
+    async fn handle(&self, request: &mut CoapRequest<SocketAddr>) {
+        self.inner.handle(request).await;
+        let changed = *request.get_method() == RequestType::Put
+            && request
+                .response
+                .as_ref()
+                .is_some_and(|r| *r.get_status() == ResponseType::Changed);
+        if changed {
+            let server = self.server.clone();
+            let path = request.get_path();
+            let inner = self.inner.clone();
+            tokio::spawn(async move {
+                let _ = server.notify(&path, inner.as_ref()).await;
+            });
+        }
+    }
+
+    fn is_observable(&self, path: &str) -> bool {
+        self.inner.is_observable(path)
+    }
+}
+
+pub fn load_manifest(path: &Path) -> Result<Manifest> {
+    let contents = std::fs::read_to_string(path)?;
+    toml::from_str(&contents).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+}
+
+/// What `serve` serves.
+pub enum Resources<'a> {
+    /// The resources described by a manifest
+    Manifest(Manifest),
+    /// The files in a directory
+    Dir(&'a Path),
+    /// The lines read from standard input, at a path
+    Stdin {
+        path: &'a str,
+        content_format: Option<ContentFormat>,
+    },
+}
+
+pub async fn coap_serve(args: &Args, resources: Resources<'_>) -> Result<()> {
+    let CoapUri { host, port, .. } = CoapUri::parse(&args.url)?;
+    let server = Server::bind((&host, port.unwrap_or(5683))).await?;
+    info!("listening on {}", server.local_addr()?);
+    match resources {
+        Resources::Manifest(manifest) => serve_manifest(server, manifest).await,
+        Resources::Dir(dir) => serve_dir(server, dir.to_path_buf()).await,
+        Resources::Stdin {
+            path,
+            content_format,
+        } => serve_stdin(server, path, content_format).await,
+    }
+}
+
+/// Serves the resources described by the manifest until the server fails.
+pub async fn serve_manifest(server: Arc<Server>, manifest: Manifest) -> Result<()> {
+    let handler = Arc::new(ManifestHandler::new(manifest)?);
+    for spec in &handler.specs {
+        let Some(interval) = spec.counter.as_ref().and_then(|c| c.interval) else {
+            continue;
+        };
+        let path = normalize_path(&spec.path);
+        let handler = handler.clone();
+        let server = server.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Some(spec) = handler.spec(&path) {
+                    handler.step_counter(spec);
+                }
+                let _ = server.notify(&path, handler.as_ref()).await;
+            }
+        });
+    }
+
+    let handler = Arc::new(NotifyOnPut {
+        inner: handler,
+        server: server.clone(),
+    });
+    server.run(handler).await
+}
+
+/// Serves the files in a directory as resources: GET reads a file, PUT writes it and DELETE
+/// removes it, while POST to any path echoes the request back. `/.well-known/core` lists the
+/// files, each of which may be observed.
+struct DirHandler {
+    dir: PathBuf,
+}
+
+impl DirHandler {
+    /// The file for a request's path, or `None` if the path could escape the directory.
+    fn file_for(&self, request: &CoapRequest<SocketAddr>) -> Option<PathBuf> {
+        let mut file = self.dir.clone();
+        for segment in request.message.get_option(CoapOption::UriPath).into_iter().flatten() {
+            let segment = std::str::from_utf8(segment).ok()?;
+            if matches!(segment, "" | "." | "..") || segment.contains(['/', '\\']) {
+                return None;
+            }
+            file.push(segment);
+        }
+        Some(file)
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        let mut files = vec![];
+        let _ = walk_files(&self.dir, &mut files);
+        files
+    }
+
+    /// The resource path at which a file in the directory is served.
+    fn resource_path(&self, file: &Path) -> String {
+        let relative = file.strip_prefix(&self.dir).unwrap_or(file);
+        let segments: Vec<_> = relative.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+        format!("/{}", segments.join("/"))
+    }
+
+    fn links(&self) -> String {
+        self.files()
+            .iter()
+            .map(|file| {
+                let mut link = format!("<{}>;obs", self.resource_path(file));
+                if let Some(cf) = content_format_for_extension(file) {
+                    link.push_str(&format!(";ct={}", usize::from(cf)));
+                }
+                link
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+#[async_trait]
+impl RequestHandler for DirHandler {
+    async fn handle(&self, request: &mut CoapRequest<SocketAddr>) {
+        let method = *request.get_method();
+        if method == RequestType::Post {
+            let payload = request.message.payload.clone();
+            let content_format = request.message.get_content_format();
+            set_response(request, ResponseType::Content, payload);
+            if let (Some(cf), Some(response)) = (content_format, request.response.as_mut()) {
+                response.message.set_content_format(cf);
+            }
+            return;
+        }
+        if normalize_path(&request.get_path()) == "/.well-known/core" && method == RequestType::Get {
+            let links = self.links().into_bytes();
+            set_response(request, ResponseType::Content, links);
+            if let Some(response) = request.response.as_mut() {
+                response.message.set_content_format(ContentFormat::ApplicationLinkFormat);
+            }
+            return;
+        }
+        let Some(file) = self.file_for(request) else {
+            set_response(request, ResponseType::Forbidden, vec![]);
+            return;
+        };
+        let status = match method {
+            RequestType::Get => match std::fs::read(&file) {
+                Ok(contents) if file.is_file() => {
+                    set_response(request, ResponseType::Content, contents);
+                    if let (Some(cf), Some(response)) =
+                        (content_format_for_extension(&file), request.response.as_mut())
+                    {
+                        response.message.set_content_format(cf);
+                    }
+                    return;
+                }
+                _ => ResponseType::NotFound,
+            },
+            RequestType::Put => {
+                let existed = file.is_file();
+                let written = match file.parent() {
+                    Some(parent) => std::fs::create_dir_all(parent),
+                    None => Ok(()),
+                }
+                .and_then(|_| std::fs::write(&file, &request.message.payload));
+                match written {
+                    Ok(()) if existed => ResponseType::Changed,
+                    Ok(()) => ResponseType::Created,
+                    Err(_) => ResponseType::InternalServerError,
+                }
+            }
+            RequestType::Delete => match std::fs::remove_file(&file) {
+                Ok(()) => ResponseType::Deleted,
+                Err(e) if e.kind() == ErrorKind::NotFound => ResponseType::NotFound,
+                Err(_) => ResponseType::InternalServerError,
+            },
+            _ => ResponseType::MethodNotAllowed,
+        };
+        set_response(request, status, vec![]);
+    }
+
+    fn is_observable(&self, _path: &str) -> bool {
+        true
+    }
+}
+
+/// Serves the files in `dir` until the server fails, notifying observers of a file whenever it
+/// changes, whether through a request or otherwise.
+pub async fn serve_dir(server: Arc<Server>, dir: PathBuf) -> Result<()> {
+    if !dir.is_dir() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("not a directory: {}", dir.display()),
+        ));
+    }
+    let handler = Arc::new(DirHandler { dir });
+
+    // poll the files and notify observers of those modified, created or deleted since last time
+    let watched = handler.clone();
+    let notifier = server.clone();
+    tokio::spawn(async move {
+        let modified_times = |handler: &DirHandler| -> HashMap<PathBuf, Option<SystemTime>> {
+            handler
+                .files()
+                .into_iter()
+                .map(|file| {
+                    let modified = std::fs::metadata(&file).and_then(|m| m.modified()).ok();
+                    (file, modified)
+                })
+                .collect()
+        };
+        let mut last = modified_times(&watched);
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            let current = modified_times(&watched);
+            let changed = current
+                .iter()
+                .filter(|(file, modified)| last.get(*file) != Some(modified))
+                .map(|(file, _)| file)
+                .chain(last.keys().filter(|file| !current.contains_key(*file)));
+            for file in changed {
+                let path = watched.resource_path(file);
+                let _ = notifier.notify(&path, watched.as_ref()).await;
+            }
+            last = current;
+        }
+    });
+
+    server.run(handler).await
+}
+
+/// Serves a single observable resource whose representation is the latest line read from
+/// standard input, with `/.well-known/core` listing it.
+struct LineHandler {
+    path: String,
+    content_format: Option<ContentFormat>,
+    line: Mutex<Vec<u8>>,
+}
+
+#[async_trait]
+impl RequestHandler for LineHandler {
+    async fn handle(&self, request: &mut CoapRequest<SocketAddr>) {
+        let path = normalize_path(&request.get_path());
+        let method = *request.get_method();
+        let (payload, content_format) = match path.as_str() {
+            "/.well-known/core" if method == RequestType::Get => {
+                let mut link = format!("<{}>;obs", self.path);
+                if let Some(cf) = self.content_format {
+                    link.push_str(&format!(";ct={}", usize::from(cf)));
+                }
+                (link.into_bytes(), Some(ContentFormat::ApplicationLinkFormat))
+            }
+            path if path == self.path && method == RequestType::Get => {
+                (self.line.lock().unwrap().clone(), self.content_format)
+            }
+            path if path == self.path => {
+                set_response(request, ResponseType::MethodNotAllowed, vec![]);
+                return;
+            }
+            _ => {
+                set_response(request, ResponseType::NotFound, vec![]);
+                return;
+            }
+        };
+        set_response(request, ResponseType::Content, payload);
+        if let (Some(cf), Some(response)) = (content_format, request.response.as_mut()) {
+            response.message.set_content_format(cf);
+        }
+    }
+
+    fn is_observable(&self, path: &str) -> bool {
+        path == self.path
+    }
+}
+
+/// Serves the lines read from standard input at `path` until the server fails, notifying the
+/// resource's observers of each line as it is read. Until the first line arrives the resource is
+/// empty, and once standard input ends it keeps the last line.
+pub async fn serve_stdin(
+    server: Arc<Server>,
+    path: &str,
+    content_format: Option<ContentFormat>,
+) -> Result<()> {
+    let handler = Arc::new(LineHandler {
+        path: normalize_path(path),
+        content_format,
+        line: Mutex::new(vec![]),
+    });
+
+    let reader = handler.clone();
+    let notifier = server.clone();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            *reader.line.lock().unwrap() = line.into_bytes();
+            let _ = notifier.notify(&reader.path, reader.as_ref()).await;
+        }
+        info!("end of standard input; {} keeps the last line", reader.path);
+    });
+
+    server.run(handler).await
+}
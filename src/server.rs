@@ -0,0 +1,243 @@
+use async_trait::async_trait;
+use coap_lite::block_handler::{BlockHandler, BlockHandlerConfig};
+use coap_lite::{
+    CoapOption, CoapRequest, MessageClass, MessageType, ObserveOption, Packet, RequestType,
+    ResponseType,
+};
+use std::collections::HashMap;
+use std::io::Result;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+
+#[async_trait]
+pub trait RequestHandler: Send + Sync + 'static {
+    /// Fills in `request.response` for an incoming request.
+    async fn handle(&self, request: &mut CoapRequest<SocketAddr>);
+
+    /// Whether clients may register to observe the resource at `path`.
+    fn is_observable(&self, _path: &str) -> bool {
+        false
+    }
+}
+
+struct Observation {
+    addr: SocketAddr,
+    token: Vec<u8>,
+    last_message_id: u16,
+}
+
+const RESPONSE_CACHE_SIZE: usize = 256;
+
+/// A minimal CoAP server over UDP with support for block-wise transfers and observation of
+/// resources whose state changes are signalled by calling `notify`.
+pub struct Server {
+    socket: UdpSocket,
+    observations: Mutex<HashMap<String, Vec<Observation>>>,
+    block_handler: tokio::sync::Mutex<BlockHandler<SocketAddr>>,
+    // responses to recent confirmable requests, so that retransmissions get the same answer
+    recent_responses: Mutex<HashMap<(SocketAddr, u16), Vec<u8>>>,
+    message_id: AtomicU16,
+    sequence: AtomicU32,
+}
+
+impl Server {
+    pub async fn bind(addr: (&str, u16)) -> Result<Arc<Server>> {
+        let socket = UdpSocket::bind(addr).await?;
+        Ok(Arc::new(Server {
+            socket,
+            observations: Mutex::new(HashMap::new()),
+            block_handler: tokio::sync::Mutex::new(BlockHandler::new(BlockHandlerConfig::default())),
+            recent_responses: Mutex::new(HashMap::new()),
+            message_id: AtomicU16::new(rand::random()),
+            sequence: AtomicU32::new(2),
+        }))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub async fn run<H: RequestHandler>(self: Arc<Self>, handler: Arc<H>) -> Result<()> {
+        let mut buf = vec![0; 65535];
+        loop {
+            let (len, addr) = self.socket.recv_from(&mut buf).await?;
+            let Ok(packet) = Packet::from_bytes(&buf[..len]) else {
+                continue;
+            };
+            let server = self.clone();
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let _ = server.handle_packet(packet, addr, handler.as_ref()).await;
+            });
+        }
+    }
+
+    async fn handle_packet<H: RequestHandler>(
+        &self,
+        packet: Packet,
+        addr: SocketAddr,
+        handler: &H,
+    ) -> Result<()> {
+        match (packet.header.get_type(), packet.header.code) {
+            (MessageType::Reset, _) => {
+                self.forget_observer(addr, packet.header.message_id);
+                return Ok(());
+            }
+            (MessageType::Confirmable, MessageClass::Empty) => {
+                // CoAP ping
+                let mut reset = Packet::new();
+                reset.header.set_type(MessageType::Reset);
+                reset.header.message_id = packet.header.message_id;
+                self.send_to(&reset, addr).await?;
+                return Ok(());
+            }
+            (_, MessageClass::Request(_)) => {}
+            _ => return Ok(()),
+        }
+
+        let key = (addr, packet.header.message_id);
+        let cached = self.recent_responses.lock().unwrap().get(&key).cloned();
+        if let Some(bytes) = cached {
+            self.socket.send_to(&bytes, addr).await?;
+            return Ok(());
+        }
+
+        let mut request = CoapRequest::from_packet(packet, addr);
+        let handled_by_block = self
+            .block_handler
+            .lock()
+            .await
+            .intercept_request(&mut request)
+            .unwrap_or(true);
+        if !handled_by_block {
+            handler.handle(&mut request).await;
+            self.update_observation(&mut request, handler);
+            // the block handler fails on a response with no payload to split, when the client
+            // asked for Block2 blocks of it
+            let has_payload = request
+                .response
+                .as_ref()
+                .is_some_and(|response| !response.message.payload.is_empty());
+            if has_payload
+                && let Err(e) = self.block_handler.lock().await.intercept_response(&mut request)
+            {
+                request.apply_from_error(e);
+            }
+        }
+
+        let Some(response) = &request.response else {
+            return Ok(());
+        };
+        let bytes = response.message.to_bytes().map_err(std::io::Error::other)?;
+        if request.message.header.get_type() == MessageType::Confirmable {
+            let mut recent = self.recent_responses.lock().unwrap();
+            if recent.len() >= RESPONSE_CACHE_SIZE {
+                recent.clear();
+            }
+            recent.insert(key, bytes.clone());
+        }
+        self.socket.send_to(&bytes, addr).await?;
+        Ok(())
+    }
+
+    fn update_observation<H: RequestHandler>(&self, request: &mut CoapRequest<SocketAddr>, handler: &H) {
+        if *request.get_method() != RequestType::Get {
+            return;
+        }
+        let path = normalize_path(&request.get_path());
+        let addr = request.source.unwrap();
+        let token = request.message.get_token().to_vec();
+        let mut observations = self.observations.lock().unwrap();
+        match request.get_observe_flag() {
+            Some(Ok(ObserveOption::Register)) if handler.is_observable(&path) => {
+                let Some(response) = request.response.as_mut() else {
+                    return;
+                };
+                if response.get_status().is_error() {
+                    return;
+                }
+                let list = observations.entry(path).or_default();
+                list.retain(|o| !(o.addr == addr && o.token == token));
+                list.push(Observation {
+                    addr,
+                    token,
+                    last_message_id: 0,
+                });
+                let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+                response.message.set_observe_value(sequence & 0xffffff);
+            }
+            Some(Ok(ObserveOption::Deregister)) => {
+                if let Some(list) = observations.get_mut(&path) {
+                    list.retain(|o| !(o.addr == addr && o.token == token));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn forget_observer(&self, addr: SocketAddr, message_id: u16) {
+        for list in self.observations.lock().unwrap().values_mut() {
+            list.retain(|o| !(o.addr == addr && o.last_message_id == message_id));
+        }
+    }
+
+    /// Sends the current representation of `path`, as produced by the handler, to every client
+    /// observing it.
+    pub async fn notify<H: RequestHandler>(&self, path: &str, handler: &H) -> Result<()> {
+        let path = normalize_path(path);
+        let targets: Vec<(SocketAddr, Vec<u8>)> = match self.observations.lock().unwrap().get(&path) {
+            Some(list) => list.iter().map(|o| (o.addr, o.token.clone())).collect(),
+            None => return Ok(()),
+        };
+        for (addr, token) in targets {
+            let mut packet = Packet::new();
+            packet.header.set_type(MessageType::NonConfirmable);
+            packet.header.code = MessageClass::Request(RequestType::Get);
+            packet.header.message_id = self.message_id.fetch_add(1, Ordering::Relaxed);
+            packet.set_token(token.clone());
+            for segment in path.split('/').filter(|s| !s.is_empty()) {
+                packet.add_option(CoapOption::UriPath, segment.as_bytes().to_vec());
+            }
+            let mut request = CoapRequest::from_packet(packet, addr);
+            handler.handle(&mut request).await;
+            let Some(mut response) = request.response else {
+                continue;
+            };
+            let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+            response.message.set_observe_value(sequence & 0xffffff);
+            let message_id = response.message.header.message_id;
+            self.send_to(&response.message, addr).await?;
+
+            let is_error = response.get_status().is_error();
+            let mut observations = self.observations.lock().unwrap();
+            if let Some(list) = observations.get_mut(&path) {
+                if is_error {
+                    // an error response ends the observation
+                    list.retain(|o| !(o.addr == addr && o.token == token));
+                } else if let Some(o) = list.iter_mut().find(|o| o.addr == addr && o.token == token) {
+                    o.last_message_id = message_id;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_to(&self, packet: &Packet, addr: SocketAddr) -> Result<()> {
+        let bytes = packet.to_bytes().map_err(std::io::Error::other)?;
+        self.socket.send_to(&bytes, addr).await?;
+        Ok(())
+    }
+}
+
+pub fn normalize_path(path: &str) -> String {
+    format!("/{}", path.trim_matches('/'))
+}
+
+pub fn set_response(request: &mut CoapRequest<SocketAddr>, status: ResponseType, payload: Vec<u8>) {
+    if let Some(response) = request.response.as_mut() {
+        response.set_status(status);
+        response.message.payload = payload;
+    }
+}
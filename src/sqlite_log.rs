@@ -0,0 +1,66 @@
+use coap_lite::Packet;
+use rusqlite::{params, Connection};
+use std::io::{Error, Result};
+use std::path::Path;
+use time::format_description::well_known::Iso8601;
+use time::OffsetDateTime;
+
+/// Appends notifications to a SQLite database, creating the table on first use.
+pub struct NotificationDb {
+    db: Connection,
+}
+
+impl NotificationDb {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = Connection::open(path).map_err(Error::other)?;
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS notifications (
+                id INTEGER PRIMARY KEY,
+                timestamp TEXT NOT NULL,
+                resource TEXT NOT NULL,
+                code TEXT NOT NULL,
+                value REAL,
+                payload BLOB NOT NULL
+            )",
+            [],
+        )
+        .map_err(Error::other)?;
+        Ok(NotificationDb { db })
+    }
+
+    pub fn record(&self, resource: &str, message: &Packet) -> Result<()> {
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Iso8601::DEFAULT)
+            .map_err(Error::other)?;
+        self.db
+            .execute(
+                "INSERT INTO notifications (timestamp, resource, code, value, payload)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    timestamp,
+                    resource,
+                    message.header.code.to_string(),
+                    numeric_value(&message.payload),
+                    message.payload,
+                ],
+            )
+            .map_err(Error::other)?;
+        Ok(())
+    }
+}
+
+/// Extracts a number from a payload which is just a number, either as plain text or as a JSON
+/// document, or a JSON object with a single numeric member such as `{"celsius": 21.5}`.
+fn numeric_value(payload: &[u8]) -> Option<f64> {
+    let text = std::str::from_utf8(payload).ok()?.trim();
+    if let Ok(value) = text.parse() {
+        return Some(value);
+    }
+    match serde_json::from_str(text).ok()? {
+        serde_json::Value::Number(n) => n.as_f64(),
+        serde_json::Value::Object(members) if members.len() == 1 => {
+            members.values().next()?.as_f64()
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,488 @@
+use coap_lite::{CoapOption, Packet};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::coap_helper::encode_uint;
+use crate::message_dump::decode_uint;
+use crate::websocket;
+
+// signaling codes (RFC 8323 section 5)
+const CSM: u8 = 0xe1;
+const PING: u8 = 0xe2;
+const PONG: u8 = 0xe3;
+const RELEASE: u8 = 0xe4;
+const ABORT: u8 = 0xe5;
+
+// the Max-Message-Size option of a CSM, and the size assumed when a peer doesn't give one
+const MAX_MESSAGE_SIZE_OPTION: u16 = 2;
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 1152;
+// what we tell the server we accept, as the client reads each message into a 1500-byte buffer
+const OUR_MAX_MESSAGE_SIZE: u32 = 1152;
+
+/// How messages are delimited on a connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// By the length in their header
+    Tcp,
+    /// By WebSocket messages, one per CoAP message, whose header leaves the length out (RFC 8323
+    /// section 4)
+    WebSocket,
+}
+
+type Writer = Arc<tokio::sync::Mutex<OwnedWriteHalf>>;
+
+/// A message as framed over TCP: there is no type or message ID, and the header instead gives
+/// the length of the options and payload.
+struct Frame {
+    code: u8,
+    token: Vec<u8>,
+    // the options and payload, encoded as in a UDP message
+    body: Vec<u8>,
+}
+
+impl Frame {
+    fn encode(&self, framing: Framing) -> Vec<u8> {
+        let tkl = self.token.len() as u8;
+        let len = self.body.len();
+        let mut bytes = match len {
+            _ if framing == Framing::WebSocket => vec![tkl],
+            0..=12 => vec![(len as u8) << 4 | tkl],
+            13..=268 => vec![13 << 4 | tkl, (len - 13) as u8],
+            269..=65804 => {
+                let mut bytes = vec![14 << 4 | tkl];
+                bytes.extend_from_slice(&((len - 269) as u16).to_be_bytes());
+                bytes
+            }
+            _ => {
+                let mut bytes = vec![15 << 4 | tkl];
+                bytes.extend_from_slice(&((len - 65805) as u32).to_be_bytes());
+                bytes
+            }
+        };
+        bytes.push(self.code);
+        bytes.extend_from_slice(&self.token);
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+
+    async fn write(&self, writer: &Writer, framing: Framing) -> Result<()> {
+        let bytes = self.encode(framing);
+        let mut writer = writer.lock().await;
+        match framing {
+            Framing::Tcp => writer.write_all(&bytes).await,
+            Framing::WebSocket => websocket::write_message(&mut *writer, &bytes).await,
+        }
+    }
+
+    async fn read(reader: &mut OwnedReadHalf, writer: &Writer, framing: Framing) -> Result<Frame> {
+        if framing == Framing::WebSocket {
+            return Frame::read_websocket(reader, writer).await;
+        }
+        let first = reader.read_u8().await?;
+        let tkl = usize::from(first & 0x0f);
+        if tkl > 8 {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid token length"));
+        }
+        let len = match first >> 4 {
+            13 => usize::from(reader.read_u8().await?) + 13,
+            14 => usize::from(reader.read_u16().await?) + 269,
+            15 => reader.read_u32().await? as usize + 65805,
+            len => usize::from(len),
+        };
+        if len > OUR_MAX_MESSAGE_SIZE as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("server sent a {}-byte message, more than it was allowed", len),
+            ));
+        }
+        let code = reader.read_u8().await?;
+        let mut token = vec![0; tkl];
+        reader.read_exact(&mut token).await?;
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).await?;
+        Ok(Frame { code, token, body })
+    }
+
+    /// Reads the next message from a WebSocket, answering any pings that come first.
+    async fn read_websocket(reader: &mut OwnedReadHalf, writer: &Writer) -> Result<Frame> {
+        // the largest message allowed, plus its header and token
+        let max_len = OUR_MAX_MESSAGE_SIZE as usize + 2 + 8;
+        let bytes = loop {
+            match websocket::read(reader, max_len).await? {
+                websocket::Received::Message(bytes) => break bytes,
+                websocket::Received::Ping(payload) => {
+                    websocket::write_pong(&mut *writer.lock().await, &payload).await?;
+                }
+            }
+        };
+        let tkl = usize::from(bytes.first().map_or(0, |b| b & 0x0f));
+        if bytes.len() < 2 + tkl || tkl > 8 {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid message"));
+        }
+        Ok(Frame {
+            code: bytes[1],
+            token: bytes[2..2 + tkl].to_vec(),
+            body: bytes[2 + tkl..].to_vec(),
+        })
+    }
+
+    /// Takes a message in its UDP encoding apart into its message ID and frame.
+    fn from_udp(bytes: &[u8]) -> Result<(u16, Frame)> {
+        let tkl = usize::from(bytes.first().map_or(0, |b| b & 0x0f));
+        if bytes.len() < 4 + tkl || tkl > 8 {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid message"));
+        }
+        let frame = Frame {
+            code: bytes[1],
+            token: bytes[4..4 + tkl].to_vec(),
+            body: bytes[4 + tkl..].to_vec(),
+        };
+        Ok((u16::from_be_bytes([bytes[2], bytes[3]]), frame))
+    }
+
+    /// Encodes the frame as a non-confirmable UDP message with the given message ID, or a pong as
+    /// the reset that answers a ping over UDP.
+    fn to_udp(&self, message_id: u16) -> Vec<u8> {
+        if self.code == PONG {
+            let mut bytes = vec![0x70, 0];
+            bytes.extend_from_slice(&message_id.to_be_bytes());
+            return bytes;
+        }
+        let mut bytes = vec![0x50 | self.token.len() as u8, self.code];
+        bytes.extend_from_slice(&message_id.to_be_bytes());
+        bytes.extend_from_slice(&self.token);
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+/// A CoAP over TCP or WebSockets connection (RFC 8323). The client exchanges messages with it in
+/// their UDP encoding, which are converted to and from the connection's framing on the way
+/// through.
+pub struct TcpChannel {
+    writer: Writer,
+    framing: Framing,
+    // frames are read by a task of their own, as the client gives up on receiving every so
+    // often, and a partly read frame would be lost
+    frames: tokio::sync::Mutex<mpsc::UnboundedReceiver<Result<Frame>>>,
+    // the message ID of the last request sent with each token, to give to the responses to it
+    message_ids: Mutex<HashMap<Vec<u8>, u16>>,
+    /// The largest message the server accepts, from its capabilities and settings message
+    pub max_message_size: usize,
+}
+
+/// Reads frames from the server until the connection fails or is closed, answering pings and
+/// passing requests, responses and pongs on.
+async fn read_frames(
+    mut reader: OwnedReadHalf,
+    writer: Writer,
+    framing: Framing,
+    frames: mpsc::UnboundedSender<Result<Frame>>,
+) {
+    loop {
+        let frame = match Frame::read(&mut reader, &writer, framing).await {
+            Ok(frame) => frame,
+            Err(e) => {
+                let _ = frames.send(Err(e));
+                return;
+            }
+        };
+        match frame.code {
+            CSM => {}
+            PING => {
+                let pong = Frame {
+                    code: PONG,
+                    token: frame.token,
+                    body: vec![],
+                };
+                if let Err(e) = pong.write(&writer, framing).await {
+                    let _ = frames.send(Err(e));
+                    return;
+                }
+            }
+            RELEASE | ABORT => {
+                let _ = frames.send(Err(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "server closed the CoAP connection",
+                )));
+                return;
+            }
+            _ => {
+                if frames.send(Ok(frame)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+async fn connect_stream(
+    peer_addr: SocketAddr,
+    local_addr: Option<SocketAddr>,
+    timeout: Duration,
+) -> Result<TcpStream> {
+    let socket = match peer_addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(local_addr) = local_addr {
+        socket
+            .bind(local_addr)
+            .map_err(|e| Error::new(e.kind(), format!("can't bind to {}: {}", local_addr, e)))?;
+    }
+    let stream = tokio::time::timeout(timeout, socket.connect(peer_addr))
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "TCP connection timed out"))??;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+impl TcpChannel {
+    /// Connects to the server and exchanges capabilities and settings messages (CSMs) with it,
+    /// failing if it doesn't send its own in time.
+    pub async fn connect(
+        peer_addr: SocketAddr,
+        local_addr: Option<SocketAddr>,
+        timeout: Duration,
+    ) -> Result<TcpChannel> {
+        let stream = connect_stream(peer_addr, local_addr, timeout).await?;
+        TcpChannel::open(stream, Framing::Tcp, timeout).await
+    }
+
+    /// Connects to the server, opens a WebSocket to its CoAP endpoint, giving `host` as the host
+    /// the WebSocket is for, and exchanges CSMs with it over that.
+    pub async fn connect_websocket(
+        peer_addr: SocketAddr,
+        local_addr: Option<SocketAddr>,
+        host: &str,
+        timeout: Duration,
+    ) -> Result<TcpChannel> {
+        let mut stream = connect_stream(peer_addr, local_addr, timeout).await?;
+        tokio::time::timeout(timeout, websocket::handshake(&mut stream, host))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "WebSocket handshake timed out"))??;
+        TcpChannel::open(stream, Framing::WebSocket, timeout).await
+    }
+
+    async fn open(stream: TcpStream, framing: Framing, timeout: Duration) -> Result<TcpChannel> {
+        let (mut reader, writer) = stream.into_split();
+        let writer = Arc::new(tokio::sync::Mutex::new(writer));
+
+        let mut csm = Packet::new();
+        csm.add_option(
+            CoapOption::from(MAX_MESSAGE_SIZE_OPTION),
+            encode_uint(OUR_MAX_MESSAGE_SIZE),
+        );
+        let (_, mut frame) = Frame::from_udp(&csm.to_bytes().map_err(Error::other)?)?;
+        frame.code = CSM;
+        frame.write(&writer, framing).await?;
+
+        let frame = tokio::time::timeout(timeout, Frame::read(&mut reader, &writer, framing))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "server sent no CSM"))??;
+        if frame.code != CSM {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "server did not start with a CSM; does it support RFC 8323?",
+            ));
+        }
+        // the option numbers of signaling messages are specific to their code, but their encoding
+        // is the same as in any other message
+        let options = Packet::from_bytes(&frame.to_udp(0)).map_err(Error::other)?;
+        let max_message_size = options
+            .get_first_option(CoapOption::from(MAX_MESSAGE_SIZE_OPTION))
+            .map_or(DEFAULT_MAX_MESSAGE_SIZE, |value| decode_uint(value) as usize);
+
+        let (sender, frames) = mpsc::unbounded_channel();
+        tokio::spawn(read_frames(reader, writer.clone(), framing, sender));
+        Ok(TcpChannel {
+            writer,
+            framing,
+            frames: tokio::sync::Mutex::new(frames),
+            message_ids: Mutex::new(HashMap::new()),
+            max_message_size,
+        })
+    }
+
+    /// Receives the next request or response into `buf` in its UDP encoding.
+    pub async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let frame = self.frames.lock().await.recv().await.ok_or(Error::new(
+            ErrorKind::ConnectionAborted,
+            "CoAP connection closed",
+        ))??;
+        let message_id = self
+            .message_ids
+            .lock()
+            .unwrap()
+            .get(&frame.token)
+            .copied()
+            .unwrap_or(0);
+        let bytes = frame.to_udp(message_id);
+        if bytes.len() > buf.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "message too large"));
+        }
+        buf[..bytes.len()].copy_from_slice(&bytes);
+        Ok(bytes.len())
+    }
+
+    /// Sends a message given in its UDP encoding. An empty message, which over UDP is a ping, is
+    /// sent as a ping signal.
+    pub async fn send(&self, buf: &[u8]) -> Result<usize> {
+        let (message_id, mut frame) = Frame::from_udp(buf)?;
+        if frame.code == 0 {
+            frame.code = PING;
+        }
+        self.message_ids.lock().unwrap().insert(frame.token.clone(), message_id);
+        frame.write(&self.writer, self.framing).await?;
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_dump::hex;
+    use tokio::net::TcpListener;
+
+    fn frame(code: u8, token: &[u8], body: &[u8]) -> Vec<u8> {
+        let frame = Frame {
+            code,
+            token: token.to_vec(),
+            body: body.to_vec(),
+        };
+        frame.encode(Framing::Tcp)
+    }
+
+    #[test]
+    fn encodes_lengths() {
+        for (len, header) in [
+            (0, "0145"),
+            (12, "c145"),
+            (13, "d10045"),
+            (268, "d1ff45"),
+            (269, "e1000045"),
+            (65804, "e1ffff45"),
+            (65805, "f10000000045"),
+            (70000, "f10000106345"),
+        ] {
+            let bytes = frame(0x45, &[0xab], &vec![0; len]);
+            assert_eq!(hex(&bytes[..bytes.len() - len - 1]), header, "{}", len);
+            assert_eq!(bytes.len(), header.len() / 2 + 1 + len);
+        }
+        let frame = Frame {
+            code: 0x45,
+            token: vec![0xab],
+            body: vec![0; 300],
+        };
+        assert_eq!(hex(&frame.encode(Framing::WebSocket)[..3]), "0145ab");
+    }
+
+    /// A connection to a listener of our own, as the server end and the client's halves.
+    async fn connection() -> (TcpStream, OwnedReadHalf, Writer) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, server) = tokio::join!(client, listener.accept());
+        let (reader, writer) = client.unwrap().into_split();
+        (server.unwrap().0, reader, Arc::new(tokio::sync::Mutex::new(writer)))
+    }
+
+    #[tokio::test]
+    async fn decodes_lengths() {
+        let (mut server, mut reader, writer) = connection().await;
+        for len in [0, 12, 13, 268, 269, 1152] {
+            let body: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            server.write_all(&frame(0x45, &[1, 2], &body)).await.unwrap();
+            let read = Frame::read(&mut reader, &writer, Framing::Tcp).await.unwrap();
+            assert_eq!((read.code, read.token, read.body), (0x45, vec![1, 2], body), "{}", len);
+        }
+        // the length in the header is enough to refuse a message larger than we said we accept
+        for len in [1153, 65804, 65805] {
+            let header = &frame(0x45, &[], &vec![0; len])[..5];
+            let (mut server, mut reader, writer) = connection().await;
+            server.write_all(header).await.unwrap();
+            let e = Frame::read(&mut reader, &writer, Framing::Tcp).await.err().unwrap();
+            assert_eq!(e.kind(), ErrorKind::InvalidData, "{}", len);
+        }
+        server.write_all(&[0x09, 0x45]).await.unwrap();
+        let e = Frame::read(&mut reader, &writer, Framing::Tcp).await.err().unwrap();
+        assert_eq!(e.to_string(), "invalid token length");
+    }
+
+    /// Opens a channel to a server that answers the client's CSM with `reply`, returning it with
+    /// the CSM the client sent and the server's end of the connection.
+    async fn open(reply: &[u8]) -> (Result<TcpChannel>, Vec<u8>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let timeout = Duration::from_millis(200);
+        let server = async {
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut csm = vec![0; 5];
+            server.read_exact(&mut csm).await.unwrap();
+            server.write_all(reply).await.unwrap();
+            (csm, server)
+        };
+        let client = TcpChannel::connect(addr, None, timeout);
+        let (channel, (csm, server)) = tokio::join!(client, server);
+        (channel, csm, server)
+    }
+
+    #[tokio::test]
+    async fn exchanges_csms() {
+        // Max-Message-Size of 4096
+        let (channel, csm, _server) = open(&frame(CSM, &[], &[0x22, 0x10, 0x00])).await;
+        // ours is 1152
+        assert_eq!(hex(&csm), "30e1220480");
+        assert_eq!(channel.unwrap().max_message_size, 4096);
+
+        let (channel, _, _server) = open(&frame(CSM, &[], &[])).await;
+        assert_eq!(channel.unwrap().max_message_size, DEFAULT_MAX_MESSAGE_SIZE);
+
+        let (channel, _, _server) = open(&frame(0x45, &[], &[])).await;
+        assert_eq!(channel.err().unwrap().kind(), ErrorKind::InvalidData);
+
+        let (channel, _, _server) = open(&[]).await;
+        assert_eq!(channel.err().unwrap().kind(), ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn handles_signals() {
+        let (channel, _, mut server) = open(&frame(CSM, &[], &[])).await;
+        let channel = channel.unwrap();
+
+        // a ping from the server is answered with a pong, without the client seeing either
+        server.write_all(&frame(PING, &[7], &[])).await.unwrap();
+        let mut pong = vec![0; 3];
+        server.read_exact(&mut pong).await.unwrap();
+        assert_eq!(hex(&pong), "01e307");
+
+        // a ping from the client goes as a signal, and the pong comes back as a reset
+        channel.send(&[0x40, 0x00, 0x12, 0x34]).await.unwrap();
+        let mut ping = vec![0; 2];
+        server.read_exact(&mut ping).await.unwrap();
+        assert_eq!(hex(&ping), "00e2");
+        server.write_all(&frame(PONG, &[], &[])).await.unwrap();
+        let mut buf = [0; 1500];
+        let len = channel.recv(&mut buf).await.unwrap();
+        assert_eq!(hex(&buf[..len]), "70001234");
+
+        // a response gets the message ID of the request with its token
+        channel.send(&[0x41, 0x01, 0x56, 0x78, 0xaa, 0xb3, b'a', b'b', b'c']).await.unwrap();
+        let mut request = vec![0; 7];
+        server.read_exact(&mut request).await.unwrap();
+        assert_eq!(hex(&request), "4101aab3616263");
+        server.write_all(&frame(0x45, &[0xaa], &[0xff, b'o', b'k'])).await.unwrap();
+        let len = channel.recv(&mut buf).await.unwrap();
+        assert_eq!(hex(&buf[..len]), "51455678aaff6f6b");
+
+        server.write_all(&frame(RELEASE, &[], &[])).await.unwrap();
+        let e = channel.recv(&mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionAborted);
+    }
+}
@@ -0,0 +1,44 @@
+use clap::ValueEnum;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// How the records of a stream of output are timestamped.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum TimestampFormat {
+    /// Local date and time, e.g. 2024-05-01T12:00:00.123+02:00
+    Rfc3339,
+    /// Seconds since the Unix epoch, e.g. 1714557600.123
+    Unix,
+    /// Seconds since the stream started, e.g. +12.345s
+    Relative,
+}
+
+pub struct Clock {
+    format: TimestampFormat,
+    // monotonic, so that relative timestamps don't jump when the system clock is adjusted
+    start: Instant,
+}
+
+impl Clock {
+    pub fn new(format: TimestampFormat) -> Self {
+        Clock {
+            format,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn stamp(&self) -> String {
+        match self.format {
+            TimestampFormat::Rfc3339 => OffsetDateTime::now_local()
+                .unwrap_or_else(|_| OffsetDateTime::now_utc())
+                .format(&Rfc3339)
+                .unwrap(),
+            TimestampFormat::Unix => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                format!("{:.3}", now.as_secs_f64())
+            }
+            TimestampFormat::Relative => format!("+{:.3}s", self.start.elapsed().as_secs_f64()),
+        }
+    }
+}
@@ -0,0 +1,850 @@
+use async_trait::async_trait;
+use coap::client::{ClientTransport, CoAPClient};
+use coap_lite::{CoapOption, CoapRequest, CoapResponse, MessageClass, MessageType, Packet, ResponseType};
+use log::{debug, info, trace, warn};
+use rand::Rng;
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{Notify, Semaphore};
+use webrtc_dtls::conn::DTLSConn;
+use webrtc_dtls::state::State;
+
+use crate::cancel::{self, Cancellation};
+use crate::coap_helper::ECHO;
+use crate::dtls;
+use crate::error::CoapCliError;
+use crate::message_dump::trace_message;
+use crate::oscore::SecurityContext;
+use crate::tcp::TcpChannel;
+
+pub type CoapClient = CoAPClient<TracedTransport>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone)]
+pub struct Datagram {
+    pub direction: Direction,
+    pub at: Instant,
+    pub message_id: Option<u16>,
+    pub message_type: Option<MessageType>,
+    pub code: Option<MessageClass>,
+    pub token: Vec<u8>,
+}
+
+/// How the server answered a request, as seen in the datagrams exchanged for it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reply {
+    /// The response came in the acknowledgement of the request
+    Piggybacked { after: Duration },
+    /// The response came in a message of its own, of the given type: after an empty
+    /// acknowledgement of the request if that arrived (as it must for a confirmable request), and
+    /// acknowledged in turn if confirmable
+    Separate {
+        ack_after: Option<Duration>,
+        response_after: Duration,
+        message_type: MessageType,
+        acknowledged: bool,
+    },
+    /// The server rejected the request with a reset
+    Reset { after: Duration },
+}
+
+// how long a message may go on being retransmitted and answered (EXCHANGE_LIFETIME, RFC 7252
+// section 4.8.2), after which its datagrams are forgotten unless an exchange still needs them
+const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+
+/// Records every datagram passing through a transport so that retransmissions and timings can be
+/// reported after an exchange completes. It is shared by every exchange made over the
+/// connection, each of which picks out its own datagrams with [`TransmissionLog::exchange`].
+#[derive(Debug, Clone, Default)]
+pub struct TransmissionLog {
+    datagrams: Arc<Mutex<Vec<Datagram>>>,
+    // when each exchange still picking out its datagrams began, so that they are kept until it's
+    // done
+    open: Arc<Mutex<Vec<Instant>>>,
+    // woken whenever a datagram is received
+    received: Arc<Notify>,
+    // whether to print every datagram in full as it passes
+    trace: Arc<AtomicBool>,
+}
+
+impl TransmissionLog {
+    fn record(&self, direction: Direction, bytes: &[u8]) {
+        if self.trace.load(Ordering::Relaxed) {
+            let arrow = match direction {
+                Direction::Sent => "-->",
+                Direction::Received => "<--",
+            };
+            eprint!("{} {} bytes\n{}", arrow, bytes.len(), trace_message(bytes));
+        }
+        let packet = Packet::from_bytes(bytes).ok();
+        let message_id = packet.as_ref().map(|p| p.header.message_id);
+        let mut datagrams = self.datagrams.lock().unwrap();
+        if let (Direction::Sent, Some(mid)) = (direction, message_id)
+            && datagrams
+                .iter()
+                .any(|d| d.direction == Direction::Sent && d.message_id == Some(mid))
+        {
+            debug!("retransmitting message {:#06x}", mid);
+        }
+        match direction {
+            Direction::Sent => trace!("sent {} bytes", bytes.len()),
+            Direction::Received => trace!("received {} bytes", bytes.len()),
+        }
+        let now = Instant::now();
+        datagrams.push(Datagram {
+            direction,
+            at: now,
+            message_id,
+            message_type: packet.as_ref().map(|p| p.header.get_type()),
+            code: packet.as_ref().map(|p| p.header.code),
+            token: packet.map(|p| p.get_token().to_vec()).unwrap_or_default(),
+        });
+        // e.g. a daemon's connection would otherwise keep every datagram it ever passed
+        if let Some(expired) = now.checked_sub(EXCHANGE_LIFETIME) {
+            let oldest_open = self.open.lock().unwrap().iter().min().copied();
+            let horizon = oldest_open.map_or(expired, |open| open.min(expired));
+            let stale = datagrams.partition_point(|d| d.at < horizon);
+            datagrams.drain(..stale);
+        }
+        drop(datagrams);
+        if direction == Direction::Received {
+            self.received.notify_waiters();
+        }
+    }
+
+    /// Prints every datagram sent or received from now on, decoded field by field.
+    pub fn set_trace(&self, trace: bool) {
+        self.trace.store(trace, Ordering::Relaxed);
+    }
+
+    fn datagrams(&self) -> Vec<Datagram> {
+        self.datagrams.lock().unwrap().clone()
+    }
+
+    /// Starts picking out the datagrams of an exchange of requests with the given token, from
+    /// those of any other made over the connection at the same time.
+    pub fn exchange(&self, token: &[u8]) -> ExchangeLog {
+        let since = Instant::now();
+        self.open.lock().unwrap().push(since);
+        ExchangeLog {
+            log: self.clone(),
+            token: token.to_vec(),
+            since,
+        }
+    }
+
+    /// Waits for a datagram that `matches` to be received, returning when it arrived.
+    async fn wait_for_received(&self, matches: impl Fn(&Datagram) -> bool) -> Instant {
+        loop {
+            let received = self.received.notified();
+            tokio::pin!(received);
+            received.as_mut().enable();
+            let found = self.datagrams.lock().unwrap().iter().find_map(|d| {
+                (d.direction == Direction::Received && matches(d)).then_some(d.at)
+            });
+            if let Some(at) = found {
+                return at;
+            }
+            received.await;
+        }
+    }
+
+    /// Waits for a datagram with the given message ID to be received, returning when it arrived,
+    /// or `None` if it doesn't within the timeout. This sees replies the client itself ignores,
+    /// such as resets.
+    pub async fn wait_for_reply(&self, message_id: u16, timeout: Duration) -> Option<Instant> {
+        let reply = self.wait_for_received(|d| d.message_id == Some(message_id));
+        tokio::time::timeout(timeout, reply).await.ok()
+    }
+
+    /// Waits for the server to reject the message with the given ID, sent at `since`, with a
+    /// reset, which the client itself ignores.
+    async fn wait_for_reset(&self, message_id: u16, since: Instant) {
+        self.wait_for_received(|d| {
+            d.at >= since
+                && d.message_id == Some(message_id)
+                && d.message_type == Some(MessageType::Reset)
+        })
+        .await;
+    }
+
+    /// How many times the message with the given ID, sent at `since`, was sent again after the
+    /// first time.
+    pub fn retransmissions_of(&self, message_id: u16, since: Instant) -> usize {
+        let sends = self
+            .datagrams
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|d| {
+                d.at >= since && d.direction == Direction::Sent && d.message_id == Some(message_id)
+            })
+            .count();
+        sends.saturating_sub(1)
+    }
+}
+
+/// The datagrams of one exchange, as picked out of the log of the connection it was made over:
+/// those of the requests sent with its token since it began and the server's replies to them.
+pub struct ExchangeLog {
+    log: TransmissionLog,
+    token: Vec<u8>,
+    since: Instant,
+}
+
+impl ExchangeLog {
+    /// Every datagram sent or received since the exchange began, its own and those of any other
+    /// exchange made at the same time.
+    fn datagrams(&self) -> Vec<Datagram> {
+        let mut datagrams = self.log.datagrams();
+        datagrams.retain(|d| d.at >= self.since);
+        datagrams
+    }
+
+    /// The exchange's own datagrams: its requests, with their acknowledgements or resets, and the
+    /// responses with its token, with the acknowledgements sent for them.
+    pub fn own_datagrams(&self) -> Vec<Datagram> {
+        let datagrams = self.datagrams();
+        let with_token = |d: &Datagram, direction: Direction, request: bool| {
+            d.direction == direction
+                && d.token == self.token
+                && match d.code {
+                    Some(MessageClass::Request(_)) => request,
+                    Some(MessageClass::Response(_)) => !request,
+                    _ => false,
+                }
+        };
+        let message_ids = |direction: Direction, request: bool| -> HashSet<u16> {
+            datagrams
+                .iter()
+                .filter(|d| with_token(d, direction, request))
+                .filter_map(|d| d.message_id)
+                .collect()
+        };
+        let requests = message_ids(Direction::Sent, true);
+        let responses = message_ids(Direction::Received, false);
+        let empty = |d: &Datagram| {
+            matches!(d.message_type, Some(MessageType::Acknowledgement | MessageType::Reset))
+                && d.code == Some(MessageClass::Empty)
+        };
+        datagrams
+            .into_iter()
+            .filter(|d| {
+                let mid = |ids: &HashSet<u16>| d.message_id.is_some_and(|mid| ids.contains(&mid));
+                match d.direction {
+                    Direction::Sent => {
+                        with_token(d, Direction::Sent, true) || empty(d) && mid(&responses)
+                    }
+                    Direction::Received => {
+                        with_token(d, Direction::Received, false)
+                            || mid(&requests)
+                                && matches!(
+                                    d.message_type,
+                                    Some(MessageType::Acknowledgement | MessageType::Reset)
+                                )
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// How the server answered the last request of the exchange, if it has.
+    pub fn reply(&self) -> Option<Reply> {
+        let datagrams = self.own_datagrams();
+        let token = self.token.as_slice();
+        let request = datagrams.iter().rev().find(|d| {
+            d.direction == Direction::Sent
+                && d.token == token
+                && matches!(d.code, Some(MessageClass::Request(_)))
+        })?;
+        let message_id = request.message_id?;
+        let sent = datagrams
+            .iter()
+            .find(|d| d.direction == Direction::Sent && d.message_id == Some(message_id))?
+            .at;
+        let received = || {
+            datagrams
+                .iter()
+                .filter(move |d| d.direction == Direction::Received && d.at >= sent)
+        };
+        let mut ack_after = None;
+        for d in received().filter(|d| d.message_id == Some(message_id)) {
+            let after = d.at.duration_since(sent);
+            match (d.message_type, d.code) {
+                (Some(MessageType::Reset), _) => return Some(Reply::Reset { after }),
+                (Some(MessageType::Acknowledgement), Some(MessageClass::Empty)) => {
+                    ack_after.get_or_insert(after);
+                }
+                (Some(MessageType::Acknowledgement), Some(MessageClass::Response(_))) => {
+                    return Some(Reply::Piggybacked { after });
+                }
+                _ => {}
+            }
+        }
+        let response = received().find(|d| {
+            d.token == token
+                && matches!(d.code, Some(MessageClass::Response(_)))
+                && matches!(
+                    d.message_type,
+                    Some(MessageType::Confirmable | MessageType::NonConfirmable)
+                )
+        })?;
+        let acknowledged = datagrams.iter().any(|d| {
+            d.direction == Direction::Sent
+                && d.message_id == response.message_id
+                && d.message_type == Some(MessageType::Acknowledgement)
+        });
+        Some(Reply::Separate {
+            ack_after,
+            response_after: response.at.duration_since(sent),
+            message_type: response.message_type?,
+            acknowledged,
+        })
+    }
+
+    /// Groups the exchange's requests by message ID, in the order they were first sent. Each
+    /// entry holds the send time of every attempt and the time the matching reply arrived, if
+    /// any.
+    pub fn attempts(&self) -> Vec<(u16, Vec<Instant>, Option<Instant>)> {
+        let mut attempts: Vec<(u16, Vec<Instant>, Option<Instant>)> = vec![];
+        for d in self.own_datagrams() {
+            let Some(mid) = d.message_id else { continue };
+            match d.direction {
+                Direction::Sent if matches!(d.code, Some(MessageClass::Request(_))) => {
+                    match attempts.iter_mut().find(|(m, _, _)| *m == mid) {
+                        Some((_, sends, _)) => sends.push(d.at),
+                        None => attempts.push((mid, vec![d.at], None)),
+                    }
+                }
+                Direction::Sent => {}
+                Direction::Received => {
+                    if let Some((_, _, reply)) = attempts.iter_mut().find(|(m, _, _)| *m == mid) {
+                        reply.get_or_insert(d.at);
+                    }
+                }
+            }
+        }
+        attempts
+    }
+
+    pub fn retransmissions(&self) -> usize {
+        self.attempts().iter().map(|(_, sends, _)| sends.len() - 1).sum()
+    }
+}
+
+impl Drop for ExchangeLog {
+    fn drop(&mut self) {
+        let mut open = self.log.open.lock().unwrap();
+        if let Some(index) = open.iter().position(|since| *since == self.since) {
+            open.swap_remove(index);
+        }
+    }
+}
+
+/// The channel a transport carries messages over.
+#[derive(Clone)]
+enum Channel {
+    Udp {
+        socket: Arc<UdpSocket>,
+        peer_addr: SocketAddr,
+    },
+    Dtls(Arc<DtlsChannel>),
+    Tcp {
+        channel: Arc<TcpChannel>,
+        peer_addr: SocketAddr,
+    },
+}
+
+/// A DTLS session with the server, saved after every message sent to the file it came with, if
+/// any, for a later run to resume.
+struct DtlsChannel {
+    conn: DTLSConn,
+    peer_addr: SocketAddr,
+    session_file: Option<PathBuf>,
+    local_port: u16,
+}
+
+impl DtlsChannel {
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        let sent = self.conn.write(buf, None).await.map_err(Error::other)?;
+        // with the sequence number it has reached, which the server's replay detection checks
+        if let Some(path) = &self.session_file
+            && let Err(e) = dtls::save_session(path, &self.conn, self.local_port).await
+        {
+            warn!("could not save the DTLS session: {}", e);
+        }
+        Ok(sent)
+    }
+}
+
+#[derive(Clone)]
+pub struct TracedTransport {
+    channel: Channel,
+    log: TransmissionLog,
+    // the tokens of exchanges given up on when interrupted, whose responses are rejected
+    abandoned: Arc<Mutex<HashSet<Vec<u8>>>>,
+}
+
+impl TracedTransport {
+    /// Rejects a message with a reset if it's a response to an exchange that was given up on,
+    /// so that the server stops retransmitting it, or stops sending notifications if it's one
+    /// (RFC 7641 section 3.6), rather than have the client acknowledge it.
+    async fn reject_abandoned(&self, bytes: &[u8]) -> Result<bool> {
+        if matches!(self.channel, Channel::Tcp { .. }) {
+            return Ok(false);
+        }
+        let Ok(message) = Packet::from_bytes(bytes) else {
+            return Ok(false);
+        };
+        let unwanted = matches!(message.header.code, MessageClass::Response(_))
+            && message.header.get_type() != MessageType::Acknowledgement
+            && self.abandoned.lock().unwrap().contains(message.get_token());
+        if !unwanted {
+            return Ok(false);
+        }
+        debug!(
+            "rejecting message {:#06x}, a response to an abandoned request",
+            message.header.message_id
+        );
+        let mut reset = Packet::new();
+        reset.header.set_type(MessageType::Reset);
+        reset.header.code = MessageClass::Empty;
+        reset.header.message_id = message.header.message_id;
+        self.send(&reset.to_bytes().map_err(Error::other)?).await?;
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl ClientTransport for TracedTransport {
+    async fn recv(&self, buf: &mut [u8]) -> Result<(usize, Option<SocketAddr>)> {
+        loop {
+            let (read, addr) = match &self.channel {
+                Channel::Udp { socket, .. } => {
+                    let (read, addr) = socket.recv_from(buf).await?;
+                    (read, Some(addr))
+                }
+                Channel::Dtls(channel) => {
+                    let read = channel.conn.read(buf, None).await.map_err(Error::other)?;
+                    (read, Some(channel.peer_addr))
+                }
+                Channel::Tcp { channel, peer_addr } => (channel.recv(buf).await?, Some(*peer_addr)),
+            };
+            self.log.record(Direction::Received, &buf[..read]);
+            if !self.reject_abandoned(&buf[..read]).await? {
+                return Ok((read, addr));
+            }
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.log.record(Direction::Sent, buf);
+        match &self.channel {
+            Channel::Udp { socket, peer_addr } => socket.send_to(buf, peer_addr).await,
+            Channel::Dtls(channel) => channel.send(buf).await,
+            Channel::Tcp { channel, .. } => channel.send(buf).await,
+        }
+    }
+}
+
+// the defaults of RFC 7252 section 4.8
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(2);
+pub const DEFAULT_ACK_RANDOM_FACTOR: f64 = 1.5;
+pub const DEFAULT_MAX_RETRANSMIT: u32 = 4;
+
+/// Transmission parameters (RFC 7252 section 4.8) for retransmitting confirmable requests with
+/// exponential back-off, used in place of the client's fixed number of attempts at a fixed
+/// timeout.
+#[derive(Debug, Clone, Copy)]
+pub struct TransmissionParams {
+    pub ack_timeout: Duration,
+    pub ack_random_factor: f64,
+    pub max_retransmit: u32,
+    /// How long to wait for a response that won't be retransmitted for, e.g. to a NON request
+    pub response_timeout: Duration,
+}
+
+/// How long setting up a connection took.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SetupTimings {
+    /// Looking up the server's address
+    pub resolve: Duration,
+    /// The DTLS handshake, or opening a TCP connection or WebSocket; none for plain UDP
+    pub handshake: Option<Duration>,
+}
+
+pub struct Connection {
+    pub client: CoapClient,
+    pub log: TransmissionLog,
+    pub peer_addr: SocketAddr,
+    /// Token shared by every request on this connection when tokens are deliberately reused
+    pub shared_token: [u8; 8],
+    /// OSCORE security context that requests and responses are protected with, if any
+    pub oscore: Option<Arc<SecurityContext>>,
+    /// How long setting the connection up took
+    pub setup: SetupTimings,
+    transmission: Option<TransmissionParams>,
+    // limits the number of requests awaiting a response (NSTART), if set
+    outstanding: Option<Arc<Semaphore>>,
+    // the client's transport, for sending messages outside of its exchanges
+    transport: TracedTransport,
+    next_message_id: Arc<AtomicU16>,
+    // the ID the next message should take in place of the next in sequence, if pinned
+    pinned_message_id: Mutex<Option<u16>>,
+    // gives up on exchanges once cancelled, unless they're cleaning up after that
+    cancellation: Mutex<Cancellation>,
+}
+
+impl Connection {
+    fn new(channel: Channel, peer_addr: SocketAddr) -> Connection {
+        let log = TransmissionLog::default();
+        let reliable = matches!(channel, Channel::Tcp { .. });
+        let transport = TracedTransport {
+            channel,
+            log: log.clone(),
+            abandoned: Arc::default(),
+        };
+        let mut client = CoAPClient::from_transport(transport.clone());
+        if reliable {
+            // TCP takes care of retransmission
+            client.set_transport_retries(1);
+        }
+        Connection {
+            client,
+            log,
+            peer_addr,
+            shared_token: rand::random(),
+            oscore: None,
+            setup: SetupTimings::default(),
+            transmission: None,
+            outstanding: None,
+            transport,
+            next_message_id: Arc::new(AtomicU16::new(rand::random())),
+            pinned_message_id: Mutex::new(None),
+            cancellation: Mutex::default(),
+        }
+    }
+
+    /// Gives up on the exchanges on this connection, failing them as interrupted, once
+    /// `cancellation` is cancelled.
+    pub fn set_cancellation(&self, cancellation: Cancellation) {
+        *self.cancellation.lock().unwrap() = cancellation;
+    }
+
+    /// Lets exchanges on this connection run their course even though cancelled, to clean up
+    /// after being interrupted, e.g. to deregister an observation.
+    pub fn ignore_cancellation(&self) {
+        *self.cancellation.lock().unwrap() = Cancellation::default();
+    }
+
+    /// Returns a message ID for the next message sent on this connection. IDs are sequential so
+    /// that they aren't reused while the server might still remember them for deduplication.
+    pub fn next_message_id(&self) -> u16 {
+        match self.pinned_message_id.lock().unwrap().take() {
+            Some(id) => id,
+            None => self.next_message_id.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Gives the next message sent on this connection the given ID instead of the next in
+    /// sequence. Only that message takes it; any blocks that follow get IDs of their own.
+    pub fn pin_message_id(&self, id: u16) {
+        *self.pinned_message_id.lock().unwrap() = Some(id);
+    }
+
+    /// Takes over retransmission from the client, using the given transmission parameters.
+    pub fn set_transmission_params(&mut self, params: TransmissionParams) {
+        self.client.set_transport_retries(1);
+        // the client only has to wait for as long as any single attempt
+        self.client.set_receive_timeout(Duration::from_secs(24 * 60 * 60));
+        self.transmission = Some(params);
+    }
+
+    /// Limits the number of requests awaiting a response at any one time.
+    pub fn set_nstart(&mut self, nstart: usize) {
+        self.outstanding = Some(Arc::new(Semaphore::new(nstart)));
+    }
+
+    /// Sends a request and waits for the response, without following blocks, protecting both
+    /// with OSCORE if the connection has a security context. A server that wants to know the
+    /// request is fresh answers 4.01 Unauthorized with an Echo option (RFC 9175 section 2), in
+    /// which case the request is sent again as a new message carrying the Echo value.
+    pub async fn send_single_request(&self, request: &CoapRequest<SocketAddr>) -> Result<CoapResponse> {
+        let response = self.send_once(request).await?;
+        let challenge = match response.message.get_first_option(CoapOption::Unknown(ECHO)) {
+            Some(echo) if *response.get_status() == ResponseType::Unauthorized => echo,
+            _ => return Ok(response),
+        };
+        // a server still unsatisfied by its own Echo value isn't asking for freshness
+        if request.message.get_first_option(CoapOption::Unknown(ECHO)) == Some(challenge) {
+            return Ok(response);
+        }
+        info!(
+            "{} Unauthorized with Echo; sending the request again with it",
+            response.message.header.code
+        );
+        let mut retry = request.clone();
+        retry.message.clear_option(CoapOption::Unknown(ECHO));
+        retry.message.add_option(CoapOption::Unknown(ECHO), challenge.clone());
+        retry.message.header.message_id = self.next_message_id();
+        self.send_once(&retry).await
+    }
+
+    async fn send_once(&self, request: &CoapRequest<SocketAddr>) -> Result<CoapResponse> {
+        let _permit = match &self.outstanding {
+            Some(outstanding) => Some(outstanding.acquire().await.map_err(Error::other)?),
+            None => None,
+        };
+        let Some(oscore) = &self.oscore else {
+            return self.transmit(request).await;
+        };
+        let mut protected = request.clone();
+        let binding = oscore.protect_request(&mut protected.message)?;
+        let mut response = self.transmit(&protected).await?;
+        oscore.unprotect_response(&binding, &mut response.message)?;
+        Ok(response)
+    }
+
+    /// Sends a request once without waiting for anything in return, for a non-confirmable
+    /// request the server has been asked not to respond to.
+    pub async fn send_without_response(&self, request: &CoapRequest<SocketAddr>) -> Result<()> {
+        let mut message = request.message.clone();
+        message.header.message_id = self.next_message_id();
+        if let Some(oscore) = &self.oscore {
+            oscore.protect_request(&mut message)?;
+        }
+        let bytes = message.to_bytes().map_err(Error::other)?;
+        self.transport.send(&bytes).await?;
+        Ok(())
+    }
+
+    /// Sends a request until a response arrives, as [`Connection::retransmit`] does, failing as
+    /// soon as the server rejects it with a reset rather than waiting in vain for a response. If
+    /// interrupted, the request is given up on, and any response to it that arrives later is
+    /// rejected with a reset. A failure is reported with how many retransmissions were made and
+    /// how long was spent.
+    async fn transmit(&self, request: &CoapRequest<SocketAddr>) -> Result<CoapResponse> {
+        let message_id = request.message.header.message_id;
+        let token = request.message.get_token();
+        let cancellation = self.cancellation.lock().unwrap().clone();
+        // e.g. a deregistration reusing the token of a registration that was interrupted
+        self.transport.abandoned.lock().unwrap().remove(token);
+        let started = Instant::now();
+        let result = tokio::select! {
+            result = self.retransmit(request) => result,
+            _ = self.log.wait_for_reset(message_id, Instant::now()) => Err(Error::new(
+                ErrorKind::ConnectionReset,
+                format!("the server rejected message {:#06x} with a reset", message_id),
+            )),
+            _ = cancellation.cancelled() => {
+                self.transport.abandoned.lock().unwrap().insert(token.to_vec());
+                Err(cancel::interrupted())
+            }
+        };
+        result.map_err(|e| {
+            CoapCliError::request(e, self.log.retransmissions_of(message_id, started), started)
+        })
+    }
+
+    /// Sends a request until a response arrives, retransmitting it at exponentially growing
+    /// intervals if transmission parameters were given, and otherwise as the client does.
+    async fn retransmit(&self, request: &CoapRequest<SocketAddr>) -> Result<CoapResponse> {
+        let Some(params) = self.transmission else {
+            return self.client.send_single_request(request).await;
+        };
+        let reliable = matches!(self.transport.channel, Channel::Tcp { .. });
+        if reliable || request.message.header.get_type() != MessageType::Confirmable {
+            return tokio::time::timeout(params.response_timeout, self.client.send_single_request(request))
+                .await
+                .map_err(|_| {
+                    debug!("no response within {}ms", params.response_timeout.as_millis());
+                    Error::new(ErrorKind::TimedOut, "no response")
+                })?;
+        }
+        let mut timeout = params
+            .ack_timeout
+            .mul_f64(rand::thread_rng().gen_range(1.0..=params.ack_random_factor));
+        for _ in 0..=params.max_retransmit {
+            // a retransmission is the same message, so the server can recognise it as a duplicate
+            if let Ok(result) =
+                tokio::time::timeout(timeout, self.client.send_single_request(request)).await
+            {
+                return result;
+            }
+            debug!(
+                "no response to message {:#06x} within {}ms",
+                request.message.header.message_id,
+                timeout.as_millis()
+            );
+            timeout *= 2;
+        }
+        // the retransmissions made are reported by transmit
+        Err(Error::new(ErrorKind::TimedOut, "no response"))
+    }
+
+    /// The largest message the server accepts over a stream transport, which unlike datagrams
+    /// have no size limit of their own.
+    pub fn max_message_size(&self) -> Option<usize> {
+        match &self.transport.channel {
+            Channel::Tcp { channel, .. } => Some(channel.max_message_size),
+            _ => None,
+        }
+    }
+
+    /// Sends a CoAP ping (an empty confirmable message) and returns its message ID. The peer's
+    /// reset is not waited for here; see [`TransmissionLog::wait_for_reply`].
+    pub async fn ping(&self) -> Result<u16> {
+        let mut ping = Packet::new();
+        ping.header.set_type(MessageType::Confirmable);
+        ping.header.code = MessageClass::Empty;
+        ping.header.message_id = self.next_message_id();
+        let bytes = ping.to_bytes().map_err(Error::other)?;
+        self.transport.send(&bytes).await?;
+        Ok(ping.header.message_id)
+    }
+}
+
+/// The address to send to a peer from: `local_addr` if given, and otherwise any address of the
+/// peer's family and any port.
+pub fn bind_addr(peer_addr: SocketAddr, local_addr: Option<SocketAddr>) -> SocketAddr {
+    local_addr.unwrap_or(match peer_addr {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    })
+}
+
+async fn bind_for(peer_addr: SocketAddr, local_addr: Option<SocketAddr>) -> Result<UdpSocket> {
+    let bind_addr = bind_addr(peer_addr, local_addr);
+    UdpSocket::bind(bind_addr)
+        .await
+        .map_err(|e| Error::new(e.kind(), format!("can't bind to {}: {}", bind_addr, e)))
+}
+
+/// Opens a UDP socket to the server, from `local_addr` if given.
+pub async fn new_udp_connection_to_addr(
+    peer_addr: SocketAddr,
+    local_addr: Option<SocketAddr>,
+) -> Result<Connection> {
+    let socket = Arc::new(bind_for(peer_addr, local_addr).await?);
+    Ok(Connection::new(
+        Channel::Udp { socket, peer_addr },
+        peer_addr,
+    ))
+}
+
+/// Sets up a DTLS session with the server from `local_addr`, resuming `state` without a
+/// handshake if given, and otherwise failing if the handshake doesn't complete in time.
+async fn dtls_channel(
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    config: webrtc_dtls::config::Config,
+    handshake_timeout: Duration,
+    state: Option<State>,
+    session_file: Option<&Path>,
+) -> Result<DtlsChannel> {
+    let socket = bind_for(peer_addr, Some(local_addr)).await?;
+    socket.connect(peer_addr).await?;
+    let local_port = socket.local_addr()?.port();
+    let conn = tokio::time::timeout(
+        handshake_timeout,
+        DTLSConn::new(Arc::new(socket), config, true, state),
+    )
+    .await
+    .map_err(|_| Error::new(ErrorKind::TimedOut, "Received no response on DTLS handshake"))?
+    .map_err(Error::other)?;
+    Ok(DtlsChannel {
+        conn,
+        peer_addr,
+        session_file: session_file.map(Path::to_path_buf),
+        local_port,
+    })
+}
+
+/// Resumes the session saved in `session_file`, if there is one and the server still has it,
+/// which is checked with a CoAP ping, as the server silently drops what it can't decrypt. The
+/// session is resumed from the port it was saved with, at `local_addr`.
+async fn resume_dtls_session(
+    peer_addr: SocketAddr,
+    mut local_addr: SocketAddr,
+    config: webrtc_dtls::config::Config,
+    timeout: Duration,
+    session_file: &Path,
+) -> Option<Connection> {
+    let (local_port, state) = dtls::load_session(session_file).await?;
+    local_addr.set_port(local_port);
+    let session = Some(session_file);
+    let channel = dtls_channel(peer_addr, local_addr, config, timeout, Some(state), session)
+        .await
+        .inspect_err(|e| debug!("could not resume the DTLS session: {}", e))
+        .ok()?;
+    let conn = Connection::new(Channel::Dtls(Arc::new(channel)), peer_addr);
+    let message_id = conn.ping().await.ok()?;
+    conn.log.wait_for_reply(message_id, timeout).await?;
+    Some(conn)
+}
+
+/// Opens a DTLS session with the server, failing if the handshake doesn't complete in time. With
+/// a session file, the session saved in it is resumed if the server still has it, and the session
+/// is kept in it as it goes on.
+pub async fn new_dtls_connection(
+    peer_addr: SocketAddr,
+    local_addr: Option<SocketAddr>,
+    config: webrtc_dtls::config::Config,
+    handshake_timeout: Duration,
+    session_file: Option<&Path>,
+) -> Result<Connection> {
+    let local_addr = bind_addr(peer_addr, local_addr);
+    if let Some(path) = session_file
+        && path.exists()
+    {
+        let resumed =
+            resume_dtls_session(peer_addr, local_addr, config.clone(), handshake_timeout, path);
+        match resumed.await {
+            Some(conn) => {
+                info!("resumed the DTLS session saved in {}", path.display());
+                return Ok(conn);
+            }
+            None => {
+                info!("could not resume the saved DTLS session; starting a new one");
+                std::fs::remove_file(path)?;
+            }
+        }
+    }
+    let channel =
+        dtls_channel(peer_addr, local_addr, config, handshake_timeout, None, session_file).await?;
+    Ok(Connection::new(Channel::Dtls(Arc::new(channel)), peer_addr))
+}
+
+/// Opens a CoAP over TCP connection to the server, from `local_addr` if given.
+pub async fn new_tcp_connection(
+    peer_addr: SocketAddr,
+    local_addr: Option<SocketAddr>,
+    timeout: Duration,
+) -> Result<Connection> {
+    let channel = Arc::new(TcpChannel::connect(peer_addr, local_addr, timeout).await?);
+    Ok(Connection::new(Channel::Tcp { channel, peer_addr }, peer_addr))
+}
+
+/// Opens a CoAP over WebSockets connection to the server, which is known to it as `host`, from
+/// `local_addr` if given.
+pub async fn new_websocket_connection(
+    peer_addr: SocketAddr,
+    local_addr: Option<SocketAddr>,
+    host: &str,
+    timeout: Duration,
+) -> Result<Connection> {
+    let channel =
+        Arc::new(TcpChannel::connect_websocket(peer_addr, local_addr, host, timeout).await?);
+    Ok(Connection::new(Channel::Tcp { channel, peer_addr }, peer_addr))
+}
@@ -0,0 +1,81 @@
+use coap_lite::{ContentFormat, RequestType};
+use log::info;
+use std::fs::File;
+use std::io::{Error, Read, Result, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::blockwise;
+use crate::coap_helper::*;
+use crate::output::{self, print_response};
+use crate::transport::Connection;
+use crate::{
+    before_deadline, prepare_request, print_stats, print_transmission_report, request_deadline, Args,
+    Preconditions,
+};
+
+const DEFAULT_BLOCK_SIZE: usize = 1024;
+
+/// Reads `len` bytes of the file from `offset`, for the block being sent.
+fn read_block(file: &mut File, offset: usize, len: usize) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset as u64))?;
+    let mut block = vec![0; len];
+    file.read_exact(&mut block)?;
+    Ok(block)
+}
+
+/// Uploads a file to the resource, reading it a block at a time as the server acknowledges each,
+/// so that a firmware image needn't fit in memory.
+pub async fn coap_upload(
+    conn: &Connection,
+    args: &Args,
+    path: &Path,
+    method: RequestType,
+    accept: Option<ContentFormat>,
+    content_format: Option<ContentFormat>,
+    preconditions: Preconditions<'_>,
+) -> Result<()> {
+    info!("{} {}", format!("{:?}", method).to_uppercase(), args.url);
+
+    let mut file = File::open(path)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    let len = file.metadata()?.len() as usize;
+    let accept = accept.into_iter().collect();
+    let mut request =
+        build_coap_request_for_url(&args.url, method, None, content_format, Some(accept))?;
+    preconditions.add_to(&mut request)?;
+    if args.dry_run {
+        info!("would send {} bytes from {}", len, path.display());
+        return Ok(());
+    }
+    prepare_request(conn, args, &mut request)?;
+
+    let token = request.message.get_token().to_vec();
+    let log = conn.log.exchange(&token);
+    let size = args.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+    let start = Instant::now();
+    let mut source = |offset, len| {
+        read_block(&mut file, offset, len)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    };
+    let result = before_deadline(
+        args,
+        request_deadline(args),
+        "upload",
+        blockwise::send_streamed(conn, request, len, size, args.block_size, &mut source),
+    )
+    .await;
+    let rtt = start.elapsed();
+    output::set_time_total(rtt);
+    if args.verbose > 0 {
+        print_transmission_report(&token, &log);
+    }
+    if args.stats {
+        print_stats(conn, &log, start, rtt, len, result.as_ref().ok());
+    }
+    let response = result?;
+
+    print_response(&response.message, args, args.output_file.as_deref())?;
+
+    Ok(())
+}
@@ -0,0 +1,157 @@
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::io::{Error, ErrorKind, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// the GUID a server appends to the client's key to accept a WebSocket (RFC 6455 section 1.3)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// the path CoAP endpoints are reached at over WebSockets (RFC 8323 section 8.4)
+const COAP_PATH: &str = "/.well-known/coap";
+
+const CONTINUATION: u8 = 0x0;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+/// Asks the server to switch the connection to a WebSocket with the `coap` subprotocol, failing
+/// if it doesn't.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, host: &str) -> Result<()> {
+    let key = base64::engine::general_purpose::STANDARD.encode(rand::random::<[u8; 16]>());
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: coap\r\n\r\n",
+        COAP_PATH, host, key
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    // read up to the end of the headers and no further, as the server may follow them at once
+    // with its CSM
+    let mut response = vec![];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > 8192 {
+            return Err(Error::new(ErrorKind::InvalidData, "WebSocket handshake response too long"));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let mut lines = response.lines();
+    let status = lines.next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("server refused the WebSocket: {}", status),
+        ));
+    }
+    let header = |name: &str| {
+        response.lines().find_map(|line| {
+            let (n, value) = line.split_once(':')?;
+            n.trim().eq_ignore_ascii_case(name).then(|| value.trim().to_string())
+        })
+    };
+    let expected = base64::engine::general_purpose::STANDARD
+        .encode(Sha1::digest(format!("{}{}", key, ACCEPT_GUID)));
+    if header("Sec-WebSocket-Accept").as_deref() != Some(expected.as_str()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "server did not accept the WebSocket key",
+        ));
+    }
+    if !header("Sec-WebSocket-Protocol").is_some_and(|p| p.eq_ignore_ascii_case("coap")) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "server did not agree to the coap subprotocol",
+        ));
+    }
+    Ok(())
+}
+
+/// Writes a WebSocket frame, masked as every frame from a client must be.
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, opcode: u8, payload: &[u8]) -> Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(0x80 | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(0x80 | 126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    let mask: [u8; 4] = rand::random();
+    frame.extend(mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    writer.write_all(&frame).await
+}
+
+/// Sends a binary message.
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    write_frame(writer, BINARY, payload).await
+}
+
+/// What reading from a WebSocket gives: a whole data message, or a ping to answer with
+/// [`write_pong`].
+pub enum Received {
+    Message(Vec<u8>),
+    Ping(Vec<u8>),
+}
+
+pub async fn write_pong<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    write_frame(writer, PONG, payload).await
+}
+
+/// Reads the next data message, put back together from its fragments, or ping. Messages longer
+/// than `max_len` are refused.
+pub async fn read<R: AsyncRead + Unpin>(reader: &mut R, max_len: usize) -> Result<Received> {
+    let mut message = vec![];
+    loop {
+        let first = reader.read_u8().await?;
+        let second = reader.read_u8().await?;
+        let (fin, opcode) = (first & 0x80 != 0, first & 0x0f);
+        let len = match second & 0x7f {
+            126 => u64::from(reader.read_u16().await?),
+            127 => reader.read_u64().await?,
+            len => u64::from(len),
+        };
+        if message.len() as u64 + len > max_len as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("server sent a WebSocket message of more than {} bytes", max_len),
+            ));
+        }
+        let mask = match second & 0x80 {
+            0 => None,
+            _ => Some(reader.read_u32().await?.to_be_bytes()),
+        };
+        let mut payload = vec![0; len as usize];
+        reader.read_exact(&mut payload).await?;
+        if let Some(mask) = mask {
+            payload.iter_mut().enumerate().for_each(|(i, b)| *b ^= mask[i % 4]);
+        }
+        match opcode {
+            CLOSE => {
+                return Err(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "server closed the WebSocket",
+                ));
+            }
+            PING => return Ok(Received::Ping(payload)),
+            PONG => {}
+            BINARY | CONTINUATION => {
+                message.extend(payload);
+                if fin {
+                    return Ok(Received::Message(message));
+                }
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unexpected WebSocket opcode {}", opcode),
+                ));
+            }
+        }
+    }
+}
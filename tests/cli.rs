@@ -0,0 +1,149 @@
+//! Runs the binary against servers of its own: the embedded server of `selftest`, and `serve`
+//! with the resources of tests/fixtures/resources.toml.
+
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Output, Stdio};
+
+const BIN: &str = env!("CARGO_BIN_EXE_coap-cli");
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/resources.toml");
+
+fn coap_cli(args: &[&str]) -> Output {
+    Command::new(BIN).args(args).output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// `serve` running the fixtures on a port of its choosing, stopped when dropped.
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Server {
+    fn start() -> Server {
+        let mut child = Command::new(BIN)
+            .args(["coap://127.0.0.1:0", "serve", "--manifest", FIXTURES])
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut lines = BufReader::new(child.stderr.take().unwrap()).lines();
+        let line = lines.next().unwrap().unwrap();
+        let addr = match line.strip_prefix("listening on ") {
+            Some(addr) => addr.to_string(),
+            None => panic!("unexpected output from serve: {}", line),
+        };
+        // keep reading what it logs, so that it never finds the pipe closed or full
+        std::thread::spawn(move || lines.for_each(drop));
+        Server { child, addr }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("coap://{}{}", self.addr, path)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn selftest_passes() {
+    let output = coap_cli(&["coap://127.0.0.1", "selftest"]);
+    assert!(output.status.success(), "{}{}", stdout(&output), stderr(&output));
+    assert!(stdout(&output).ends_with("8 passed, 0 failed\n"), "{}", stdout(&output));
+}
+
+#[test]
+fn gets_a_resource() {
+    let server = Server::start();
+    let output = coap_cli(&[&server.url("/hello"), "get"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "hello, world\n");
+    assert!(stderr(&output).contains("2.05"), "{}", stderr(&output));
+
+    let output = coap_cli(&[&server.url("/echo?a=1"), "get"]);
+    assert_eq!(stdout(&output), "GET /echo?a=1\n");
+}
+
+#[test]
+fn prints_json() {
+    let server = Server::start();
+    let output = coap_cli(&[&server.url("/hello"), "--output", "json", "get"]);
+    assert!(output.status.success());
+    let response: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(response["code"], "2.05");
+    assert_eq!(response["content_format"], 0);
+    assert_eq!(response["payload"], "hello, world");
+}
+
+#[test]
+fn fails_on_error_responses() {
+    let server = Server::start();
+    let output = coap_cli(&[&server.url("/missing"), "get"]);
+    assert!(output.status.success());
+    assert!(stderr(&output).contains("4.04 Not Found"), "{}", stderr(&output));
+
+    // --fail exits with the class of the response
+    let output = coap_cli(&[&server.url("/missing"), "--fail", "get"]);
+    assert_eq!(output.status.code(), Some(4));
+    let output = coap_cli(&[&server.url("/hello"), "--fail", "put", "-d", "x"]);
+    assert_eq!(output.status.code(), Some(4));
+    assert!(stderr(&output).contains("4.05"), "{}", stderr(&output));
+}
+
+#[test]
+fn puts_then_gets() {
+    let server = Server::start();
+    let output = coap_cli(&[&server.url("/store"), "put", "-d", "updated"]);
+    assert!(output.status.success());
+    assert!(stderr(&output).contains("2.04"), "{}", stderr(&output));
+    let output = coap_cli(&[&server.url("/store"), "get"]);
+    assert_eq!(stdout(&output), "updated\n");
+}
+
+#[test]
+fn transfers_blockwise() {
+    let server = Server::start();
+    let output = coap_cli(&[&server.url("/large"), "--block-size", "64", "get"]);
+    assert!(output.status.success());
+    let value: String = (0..64).map(|i| format!("{:02x}", i)).collect();
+    assert_eq!(stdout(&output), format!("{}\n", value.repeat(4)));
+    let report = stderr(&output);
+    assert!(report.contains("received 512 bytes in 8 block(s)"), "{}", report);
+
+    let upload = "y".repeat(100);
+    let output = coap_cli(&[&server.url("/store"), "--block-size", "16", "put", "-d", &upload]);
+    assert!(output.status.success());
+    let report = stderr(&output);
+    assert!(report.contains("sent 100/100 bytes (100%) in 7 block(s)"), "{}", report);
+    let output = coap_cli(&[&server.url("/store"), "get"]);
+    assert_eq!(stdout(&output), format!("{}\n", upload));
+}
+
+#[test]
+fn observes_changes() {
+    let server = Server::start();
+    let mut observer = Command::new(BIN)
+        .args([&server.url("/store"), "observe", "--count", "2"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(observer.stdout.take().unwrap()).lines();
+    // the first notification comes once the observer is registered
+    assert_eq!(lines.next().unwrap().unwrap(), "initial");
+    let output = coap_cli(&[&server.url("/store"), "put", "-d", "observed"]);
+    assert!(output.status.success());
+    assert_eq!(lines.next().unwrap().unwrap(), "observed");
+    assert!(observer.wait().unwrap().success());
+}